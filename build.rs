@@ -1,12 +1,25 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=Cargo.toml");
     println!("cargo:rerun-if-env-changed=CARGO_FEATURE_ONNXRUNTIME_FROM_SOURCE");
+    println!("cargo:rerun-if-env-changed=CARGO_FEATURE_ONNXRUNTIME_SYSTEM");
     build::build()?;
     built::write_built_file()?;
+    compile_protos()?;
     Ok(())
 }
 
-#[cfg(not(feature = "onnxruntime-from-source"))]
+/// Generates the gRPC service from proto/musicgpt.proto. The proto is parsed with protox
+/// rather than protoc, so that building MusicGPT doesn't need protoc installed.
+fn compile_protos() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/musicgpt.proto");
+    let fds = protox::compile(["musicgpt.proto"], ["proto"])?;
+    tonic_prost_build::configure()
+        .build_transport(false)
+        .compile_fds(fds)?;
+    Ok(())
+}
+
+#[cfg(not(any(feature = "onnxruntime-from-source", feature = "onnxruntime-system")))]
 mod build {
     pub(crate) fn build() -> Result<(), Box<dyn std::error::Error>> {
         // nothing.
@@ -14,21 +27,92 @@ mod build {
     }
 }
 
+/// Locates the onnxruntime installed in the system, either in the directory set in the
+/// ORT_LIB_LOCATION env variable or in the one reported by pkg-config, so that the binary
+/// loads it from there at runtime.
+#[cfg(feature = "onnxruntime-system")]
+mod build {
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::{env, fs};
+
+    #[cfg(target_os = "macos")]
+    const MAIN_DYNLIB_FILENAME: &str = "libonnxruntime.dylib";
+    #[cfg(target_os = "windows")]
+    const MAIN_DYNLIB_FILENAME: &str = "onnxruntime.dll";
+    #[cfg(target_os = "linux")]
+    const MAIN_DYNLIB_FILENAME: &str = "libonnxruntime.so";
+
+    const LIB_LOCATION_ENV: &str = "ORT_LIB_LOCATION";
+
+    pub(crate) fn build() -> Result<(), Box<dyn std::error::Error>> {
+        println!("cargo:rerun-if-env-changed={LIB_LOCATION_ENV}");
+        println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+        let Some(dynlib) = find_dynlib() else {
+            return Err(format!(
+                "Could not find {MAIN_DYNLIB_FILENAME}, set the {LIB_LOCATION_ENV} env variable \
+                to the directory in which it is, or install onnxruntime with its pkg-config file"
+            )
+            .into());
+        };
+        println!("[onnxruntime-build-system] using the system onnxruntime in {dynlib:?}");
+        let file = PathBuf::from(env::var("OUT_DIR")?).join("build_info.rs");
+        fs::write(
+            file,
+            format!("pub const SYSTEM_DYNLIB_FILEPATH: &str = {dynlib:?};\n"),
+        )?;
+        Ok(())
+    }
+
+    fn find_dynlib() -> Option<PathBuf> {
+        let dirs = match env::var(LIB_LOCATION_ENV) {
+            Ok(location) => {
+                let location = PathBuf::from(location);
+                if location.is_file() {
+                    return Some(location);
+                }
+                vec![location.join("lib"), location]
+            }
+            Err(_) => pkg_config_libdir().into_iter().collect(),
+        };
+        dirs.into_iter()
+            .map(|dir| dir.join(MAIN_DYNLIB_FILENAME))
+            .find(|file| file.is_file())
+    }
+
+    fn pkg_config_libdir() -> Option<PathBuf> {
+        let output = Command::new("pkg-config")
+            .args(["--variable=libdir", "libonnxruntime"])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let dir = String::from_utf8(output.stdout).ok()?;
+        Some(PathBuf::from(dir.trim()))
+    }
+}
+
 #[cfg(feature = "onnxruntime-from-source")]
 mod build {
     use flate2::read::GzDecoder;
     use indicatif::{ProgressBar, ProgressStyle};
     use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
     use std::fmt::Display;
     use std::fs::File;
     use std::hash::{DefaultHasher, Hasher};
     use std::io::{BufRead, BufReader, Read, Write};
     use std::path::{Path, PathBuf};
     use std::process::{Command, Stdio};
-    use std::{env, fs};
+    use std::{env, fs, io};
     use tar::Archive;
 
-    const ONNX_RELEASE: &str = "1.20.1";
+    /// The onnxruntime release that is built unless another one is set in the
+    /// ONNXRUNTIME_RELEASE env variable, and the one of the dynamic libraries uploaded to
+    /// MusicGPT's GitHub releases.
+    const DEFAULT_ONNX_RELEASE: &str = "1.20.1";
+    const RELEASE_ENV: &str = "ONNXRUNTIME_RELEASE";
     const PROFILE: &str = "Release";
 
     #[cfg(target_os = "macos")]
@@ -47,14 +131,27 @@ mod build {
     const MAIN_DYNLIB_FILENAME: &str = "libonnxruntime.so";
 
     const BUILD_HASH_FILE_ENV: &str = "BUILD_HASH_FILE";
+    const COMPILER_CACHE_ENV: &str = "ONNXRUNTIME_COMPILER_CACHE";
+    const SOURCE_SHA256_ENV: &str = "ONNXRUNTIME_SOURCE_SHA256";
+
+    /// The SHA-256 of the source archive of each onnxruntime release, taken from a trusted
+    /// download of https://github.com/microsoft/onnxruntime/archive/refs/tags/v{release}.tar.gz.
+    /// Releases without one here cannot be built unless it's provided in the
+    /// ONNXRUNTIME_SOURCE_SHA256 env variable, archives are never used unverified.
+    const SOURCE_SHA256S: &[(&str, &str)] = &[];
 
     pub(crate) fn build() -> Result<(), Box<dyn std::error::Error>> {
         println!("cargo:rerun-if-changed=build-system");
         println!("cargo:rerun-if-env-changed=CARGO_FEATURE_COREML");
         println!("cargo:rerun-if-env-changed=CARGO_FEATURE_TENSORRT");
         println!("cargo:rerun-if-env-changed=CARGO_FEATURE_CUDA");
+        println!("cargo:rerun-if-env-changed=CARGO_FEATURE_OPENVINO");
+        println!("cargo:rerun-if-env-changed=CARGO_FEATURE_DIRECTML");
         println!("cargo:rerun-if-env-changed=ONNXRUNTIME_BUILD_DIR");
         println!("cargo:rerun-if-env-changed=BUILD_HASH_FILE");
+        println!("cargo:rerun-if-env-changed=ONNXRUNTIME_COMPILER_CACHE");
+        println!("cargo:rerun-if-env-changed=ONNXRUNTIME_SOURCE_SHA256");
+        println!("cargo:rerun-if-env-changed=ONNXRUNTIME_RELEASE");
         let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set");
 
         let dir = match env::var("ONNXRUNTIME_BUILD_DIR") {
@@ -96,6 +193,10 @@ mod build {
                 "pub const ONNXRUNTIME_VERSION: &str = \"{}\";",
                 self.onnxruntime_version
             )?;
+            writeln!(
+                f,
+                "pub const RELEASED_ONNXRUNTIME_VERSION: &str = \"{DEFAULT_ONNX_RELEASE}\";"
+            )?;
             writeln!(
                 f,
                 "pub const MAIN_DYNLIB_FILENAME: &str = \"{}\";",
@@ -200,10 +301,11 @@ mod build {
     ///
     /// returns: all the information regarding the compilation artifacts.
     pub fn compile_from_source(dir: PathBuf) -> BuildInfo {
+        let release = onnx_release();
         let url = format!(
-            "https://github.com/microsoft/onnxruntime/archive/refs/tags/v{ONNX_RELEASE}.tar.gz"
+            "https://github.com/microsoft/onnxruntime/archive/refs/tags/v{release}.tar.gz"
         );
-        let name = format!("onnxruntime-{ONNX_RELEASE}");
+        let name = format!("onnxruntime-{release}");
 
         must!(
             fs::create_dir_all(&dir),
@@ -217,7 +319,13 @@ mod build {
         let tar_gz = tar_gz.to_str().unwrap();
 
         let repo = dir.join(&name);
-        let build_dir = repo.join("build");
+        // Each set of accelerators is built in its own dir, so that switching between them
+        // recompiles only what changed since the last build with the same ones.
+        let accelerators = accelerators();
+        let build_dir = repo.join("build").join(match accelerators.is_empty() {
+            true => "cpu".to_string(),
+            false => accelerators.join("-"),
+        });
 
         let mut cmd = match cfg!(target_os = "windows") {
             true => Command::new("cmd"),
@@ -244,15 +352,30 @@ mod build {
             .arg("--skip_submodule_sync")
             .arg("--skip_tests");
 
-        #[cfg(feature = "cuda")]
-        cmd.arg("--use_cuda");
-        #[cfg(feature = "coreml")]
-        cmd.arg("--use_coreml");
-        #[cfg(feature = "tensorrt")]
-        cmd.arg("--use_tensorrt");
+        for accelerator in &accelerators {
+            match *accelerator {
+                "cuda" => cmd.arg("--use_cuda"),
+                "coreml" => cmd.arg("--use_coreml"),
+                "tensorrt" => cmd.arg("--use_tensorrt"),
+                "directml" => cmd.arg("--use_dml"),
+                "openvino" => cmd.arg("--use_openvino").arg("AUTO:GPU,CPU"),
+                _ => unreachable!(),
+            };
+        }
 
         log!("build command is: {cmd:?}");
         let cmd_hash = calculate_hash(format!("{cmd:?}"));
+
+        // Not part of the hash, as it does not change the result of the build.
+        if let Some(cache) = compiler_cache() {
+            log!("using {cache} as compiler cache");
+            cmd.arg("--cmake_extra_defines")
+                .arg(format!("CMAKE_C_COMPILER_LAUNCHER={cache}"))
+                .arg(format!("CMAKE_CXX_COMPILER_LAUNCHER={cache}"));
+            if accelerators.contains(&"cuda") {
+                cmd.arg(format!("CMAKE_CUDA_COMPILER_LAUNCHER={cache}"));
+            }
+        }
         if let Ok(file) = env::var(BUILD_HASH_FILE_ENV) {
             must!(fs::write(&file, &cmd_hash), "Cannot write build hash");
         }
@@ -273,9 +396,33 @@ mod build {
             log!("BuildInfo not found in {build_info_dir:?}, compiling onnxruntime project from source");
         }
 
+        let Some(expected_sha256) = source_sha256(&release) else {
+            panic!(
+                "No SHA-256 is pinned for the source archive of onnxruntime {release}, \
+                set the one of {url} in the {SOURCE_SHA256_ENV} env variable"
+            );
+        };
+        if file_exists(tar_gz) && !sha256_matches(tar_gz, &expected_sha256) {
+            // Whatever was extracted from the archive cannot be trusted either.
+            log!("File {tar_gz:?} does not match the expected SHA-256, removing it");
+            must!(fs::remove_file(tar_gz), "Error removing {tar_gz:?}");
+            if dir_exists(&repo) {
+                must!(fs::remove_dir_all(&repo), "Error removing {repo:?}");
+            }
+        }
         if !file_exists(tar_gz) {
             log!("File {tar_gz:?} does not exist, downloading it from {url}...");
             download_file(&url, tar_gz);
+            if !sha256_matches(tar_gz, &expected_sha256) {
+                log!("Downloaded {tar_gz:?} does not match the expected SHA-256, retrying...");
+                download_file(&url, tar_gz);
+            }
+            if !sha256_matches(tar_gz, &expected_sha256) {
+                let _ = fs::remove_file(tar_gz);
+                panic!(
+                    "The source archive downloaded from {url} does not match the expected SHA-256 {expected_sha256}"
+                );
+            }
         }
         if !dir_exists(&repo) {
             log!("Extracting {tar_gz}...");
@@ -316,7 +463,7 @@ mod build {
 
         let build_info = BuildInfo {
             local_dynlib_filepaths,
-            onnxruntime_version: ONNX_RELEASE.to_string(),
+            onnxruntime_version: release,
             main_dynlib_filename: MAIN_DYNLIB_FILENAME.to_string(),
             dynlib_filenames,
         };
@@ -324,6 +471,86 @@ mod build {
         build_info
     }
 
+    /// The execution providers that onnxruntime is built with, given by the enabled features.
+    fn accelerators() -> Vec<&'static str> {
+        let mut result = vec![];
+        if cfg!(feature = "cuda") {
+            result.push("cuda")
+        }
+        if cfg!(feature = "coreml") {
+            result.push("coreml")
+        }
+        if cfg!(feature = "tensorrt") {
+            result.push("tensorrt")
+        }
+        if cfg!(feature = "directml") {
+            result.push("directml")
+        }
+        if cfg!(feature = "openvino") {
+            result.push("openvino")
+        }
+        result
+    }
+
+    /// The compiler cache used for building onnxruntime, which is either the one in the
+    /// ONNXRUNTIME_COMPILER_CACHE env variable, or sccache or ccache if installed. Setting
+    /// the env variable to "none" disables it.
+    fn compiler_cache() -> Option<String> {
+        if let Ok(cache) = env::var(COMPILER_CACHE_ENV) {
+            return match cache.as_str() {
+                "none" | "" => None,
+                _ => Some(cache),
+            };
+        }
+        ["sccache", "ccache"]
+            .into_iter()
+            .find(|cache| {
+                Command::new(cache)
+                    .arg("--version")
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .is_ok_and(|status| status.success())
+            })
+            .map(|cache| cache.to_string())
+    }
+
+    /// The onnxruntime release to build, like "1.20.1".
+    fn onnx_release() -> String {
+        let Ok(release) = env::var(RELEASE_ENV) else {
+            return DEFAULT_ONNX_RELEASE.to_string();
+        };
+        let release = release.trim().trim_start_matches('v');
+        if release.is_empty() || !release.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            panic!("Invalid {RELEASE_ENV} {release:?}, expected a release like {DEFAULT_ONNX_RELEASE}");
+        }
+        release.to_string()
+    }
+
+    /// The expected SHA-256 of the source archive of `release`, either from the
+    /// ONNXRUNTIME_SOURCE_SHA256 env variable or pinned in [SOURCE_SHA256S].
+    fn source_sha256(release: &str) -> Option<String> {
+        if let Ok(sha256) = env::var(SOURCE_SHA256_ENV) {
+            return Some(sha256.trim().to_lowercase());
+        }
+        SOURCE_SHA256S
+            .iter()
+            .find(|(pinned, _)| *pinned == release)
+            .map(|(_, sha256)| sha256.to_string())
+    }
+
+    /// Returns true if the SHA-256 of the file is `expected`.
+    fn sha256_matches(path: &str, expected: &str) -> bool {
+        let mut file = must!(File::open(path), "Error opening {path:?}");
+        let mut hasher = Sha256::new();
+        must!(io::copy(&mut file, &mut hasher), "Error reading {path:?}");
+        let actual = format!("{:x}", hasher.finalize());
+        if actual != expected {
+            log!("SHA-256 of {path:?} is {actual}, expected {expected}");
+        }
+        actual == expected
+    }
+
     /// Downloads a file from an url and dumps it into `output_path`. It first
     /// downloads the content into a temporal file and then moves it to the
     /// final destination, reporting the progress in the way.
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+pub type OnPartialAudio = Box<dyn Fn(VecDeque<f32>) + Sync + Send + 'static>;
+
+/// Called with the stage a generation is in, aborts the generation if it returns true.
+pub type OnProgress = Box<dyn Fn(GenerationStage) -> bool + Sync + Send + 'static>;
+
+/// What a generation is doing, along with how far it is in it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "specta", derive(specta::Type))]
+pub enum GenerationStage {
+    /// Encoding the prompt with the text encoder.
+    TextEncoding,
+    /// Generating the audio tokens with the decoder.
+    TokenGeneration { done: usize, total: usize },
+    /// Decoding the tokens into audio with Encodec.
+    EncodecDecoding { done: usize, total: usize },
+}
+
+impl GenerationStage {
+    /// The progress of the whole generation from 0 to 1. Most of the time is spent
+    /// generating the tokens, so it is based on their progress.
+    pub fn progress(&self) -> f32 {
+        match self {
+            Self::TextEncoding => 0.0,
+            Self::TokenGeneration { done, total } if *total > 0 => *done as f32 / *total as f32,
+            Self::TokenGeneration { .. } => 0.0,
+            Self::EncodecDecoding { .. } => 1.0,
+        }
+    }
+}
+
+/// The parameters of a generation besides its prompt and duration.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "specta", derive(specta::Type))]
+pub struct GenerationParams {
+    /// Seeds the sampling of the audio tokens, so that generating the same prompt with the
+    /// same seed gives the same audio. A random one is used if none.
+    pub seed: Option<u64>,
+    /// Audio the generation is conditioned on, like a melody for it to follow. Only for
+    /// processors that [JobProcessor::supports_reference], the rest fail if given one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<ReferenceSamples>,
+}
+
+/// Mono audio that a generation is conditioned on, at the rate it was recorded or decoded
+/// with. Processors resample it to the rate of their model if needed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "specta", derive(specta::Type))]
+pub struct ReferenceSamples {
+    pub samples: Vec<f32>,
+    pub sampling_rate: u32,
+}
+
+/// The seconds spent in each phase of a generation, for knowing which one is the bottleneck.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "specta", derive(specta::Type))]
+pub struct GenerationTimings {
+    /// Encoding the prompt with the text encoder.
+    pub text_encoding_secs: f32,
+    /// Generating the audio tokens with the decoder.
+    pub token_generation_secs: f32,
+    /// Decoding the tokens into audio with Encodec, including the partial audios.
+    pub audio_decoding_secs: f32,
+}
+
+impl Display for GenerationTimings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "text encoding {:.2}s, token generation {:.2}s, audio decoding {:.2}s",
+            self.text_encoding_secs, self.token_generation_secs, self.audio_decoding_secs
+        )
+    }
+}
+
+pub trait JobProcessor: Send + Sync {
+    /// Generates `secs` seconds of audio based on `prompt` and `params`. `on_progress` is
+    /// called as the generation goes through each [GenerationStage], and aborts it if it
+    /// returns true. If provided, `on_partial_audio` is called with new samples as they
+    /// become available, before the whole audio is generated. Returns the samples along with
+    /// the time each phase took.
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        params: &GenerationParams,
+        on_progress: OnProgress,
+        on_partial_audio: Option<OnPartialAudio>,
+    ) -> ort::Result<(VecDeque<f32>, GenerationTimings)>;
+
+    /// Whether generations can be conditioned on a [GenerationParams::reference].
+    fn supports_reference(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_the_progress_of_stages() {
+        assert_eq!(GenerationStage::TextEncoding.progress(), 0.0);
+        let stage = GenerationStage::TokenGeneration {
+            done: 25,
+            total: 100,
+        };
+        assert_eq!(stage.progress(), 0.25);
+        let stage = GenerationStage::TokenGeneration { done: 0, total: 0 };
+        assert_eq!(stage.progress(), 0.0);
+        let stage = GenerationStage::EncodecDecoding { done: 0, total: 1 };
+        assert_eq!(stage.progress(), 1.0);
+    }
+}
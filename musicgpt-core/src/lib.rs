@@ -0,0 +1,44 @@
+//! MusicGen inference with ONNX Runtime, the library behind
+//! [MusicGPT](https://github.com/gabotechs/MusicGPT), for generating music from natural
+//! language prompts inside other Rust applications.
+//!
+//! The models are the ones in <https://huggingface.co/gabotechs/music_gen>, which need to be
+//! downloaded beforehand:
+//!
+//! ```no_run
+//! use musicgpt_core::{
+//!     DecoderFiles, GenerationParams, JobProcessor, ModelFiles, MusicGenModels, SessionOptions,
+//! };
+//!
+//! let files = ModelFiles {
+//!     config: "small/config.json".into(),
+//!     tokenizer: "small/tokenizer.json".into(),
+//!     text_encoder: "small_fp32/text_encoder.onnx".into(),
+//!     decoder: DecoderFiles::Merged("small_fp32/decoder_model_merged.onnx".into()),
+//!     encodec: "small_fp32/encodec_decode.onnx".into(),
+//!     fp16: false,
+//! };
+//! let models = MusicGenModels::load(files, &SessionOptions::default())?;
+//! let params = GenerationParams::default();
+//! let (samples, _timings) =
+//!     models.process("80s pop track", 10, &params, Box::new(|_| false), None)?;
+//! println!("{} samples at {}Hz", samples.len(), models.sampling_rate());
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+mod job_processor;
+mod models;
+/// The text encoder, decoders and Encodec that [MusicGenModels] is made of, for running
+/// them in other ways.
+pub mod musicgen;
+
+pub use job_processor::{
+    GenerationParams, GenerationStage, GenerationTimings, JobProcessor, OnPartialAudio, OnProgress,
+    ReferenceSamples,
+};
+pub use models::{
+    ArenaConfig, DecoderFiles, ModelFiles, ModelPart, MusicGenModels, SessionOptions,
+};
+
+/// The audio tokens that the decoder generates for each second of audio.
+pub const INPUT_IDS_BATCH_PER_SECOND: usize = 50;
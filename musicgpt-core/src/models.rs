@@ -0,0 +1,742 @@
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::ScopedJoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use half::f16;
+use memmap2::Mmap;
+use ort::environment::get_environment;
+use ort::execution_providers::{ArenaExtendStrategy, ExecutionProviderDispatch};
+use ort::memory::{AllocationDevice, AllocatorType, MemoryInfo, MemoryType};
+use ort::session::builder::SessionBuilder;
+use ort::session::Session;
+use ort::sys::OrtStatusPtr;
+use ort::value::DynValue;
+use ort::AsPointer;
+use tokenizers::Tokenizer;
+use tracing::{info_span, warn};
+
+use crate::job_processor::{
+    GenerationParams, GenerationStage, GenerationTimings, JobProcessor, OnPartialAudio, OnProgress,
+};
+use crate::musicgen::{
+    MusicGenAudioEncodec, MusicGenConfig, MusicGenDecoder, MusicGenMergedDecoder,
+    MusicGenSplitDecoder, MusicGenTextEncoder,
+};
+use crate::INPUT_IDS_BATCH_PER_SECOND;
+
+/// The files of a MusicGen model, like the ones in each variant's directory of
+/// <https://huggingface.co/gabotechs/music_gen>.
+#[derive(Clone, Debug)]
+pub struct ModelFiles {
+    pub config: PathBuf,
+    pub tokenizer: PathBuf,
+    pub text_encoder: PathBuf,
+    pub decoder: DecoderFiles,
+    /// The `encodec_decode.onnx` file.
+    pub encodec: PathBuf,
+    /// Whether the decoder works in half precision, like in the fp16 variants.
+    pub fp16: bool,
+}
+
+/// The decoder of a model, which is exported either in a single file or split in two. The
+/// `.onnx_data` files of the big models must be next to the `.onnx` ones.
+#[derive(Clone, Debug)]
+pub enum DecoderFiles {
+    /// The `decoder_model_merged.onnx` file.
+    Merged(PathBuf),
+    /// The `decoder_model.onnx` and `decoder_with_past_model.onnx` files.
+    Split {
+        decoder: PathBuf,
+        decoder_with_past: PathBuf,
+    },
+}
+
+impl DecoderFiles {
+    fn files(&self) -> Vec<&Path> {
+        match self {
+            Self::Merged(file) => vec![file.as_path()],
+            Self::Split {
+                decoder,
+                decoder_with_past,
+            } => vec![decoder.as_path(), decoder_with_past.as_path()],
+        }
+    }
+}
+
+/// How the ONNX Runtime sessions of a model are configured.
+#[derive(Clone)]
+pub struct SessionOptions {
+    /// Threads used for parallelizing the work inside each operator, the ORT default if none.
+    pub intra_threads: Option<usize>,
+    /// Threads used for running independent operators in parallel. If set, operators are
+    /// executed in parallel instead of sequentially.
+    pub inter_threads: Option<usize>,
+    /// Allocate the CPU memory from ORT's arena, which is faster but grows up to the peak
+    /// usage and never gives the memory back.
+    pub memory_arena: bool,
+    /// Caps the arena and sets how it grows. The arena is then shared by all the sessions,
+    /// instead of each one having its own with ORT's defaults.
+    pub arena_config: Option<ArenaConfig>,
+    /// Preallocate the memory of each run based on the previous ones.
+    pub memory_pattern: bool,
+    /// Directory in which ORT's profiler writes a JSON trace of the decoder for each
+    /// generation.
+    pub decoder_profile_dir: Option<PathBuf>,
+    /// The name of the device and the provider for running the models in it, the CPU if
+    /// none.
+    pub execution_provider: Option<(&'static str, ExecutionProviderDispatch)>,
+    /// Run the models in the CPU if they cannot be run in the device of
+    /// [SessionOptions::execution_provider], or if it runs out of memory in a generation,
+    /// instead of failing.
+    pub cpu_fallback: bool,
+    /// Providers for the parts of the model that run in another device of the same kind as
+    /// [SessionOptions::execution_provider], like a second GPU.
+    pub device_map: Vec<(ModelPart, ExecutionProviderDispatch)>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            intra_threads: None,
+            inter_threads: None,
+            memory_arena: true,
+            arena_config: None,
+            memory_pattern: true,
+            decoder_profile_dir: None,
+            execution_provider: None,
+            cpu_fallback: true,
+            device_map: vec![],
+        }
+    }
+}
+
+/// How ORT's CPU memory arena grows, see [SessionOptions::arena_config].
+#[derive(Clone, Debug, Default)]
+pub struct ArenaConfig {
+    /// The bytes the arena can take at most, unlimited if none. Allocations that do not fit
+    /// fail instead of growing it further.
+    pub max_bytes: Option<usize>,
+    /// How much the arena grows once it is full.
+    pub extend_strategy: ArenaExtendStrategy,
+}
+
+impl ArenaConfig {
+    /// Registers the arena in ORT's environment, for the sessions built with
+    /// [SessionBuilder::with_env_allocators]. ORT cannot replace an arena once registered,
+    /// so only the first call does it.
+    fn register(&self) -> ort::Result<()> {
+        static REGISTERED: Mutex<bool> = Mutex::new(false);
+        let mut registered = REGISTERED.lock().expect("poisoned lock");
+        if *registered {
+            return Ok(());
+        }
+        let keys = [c"max_mem".as_ptr(), c"arena_extend_strategy".as_ptr()];
+        let values = [
+            // Zero lets the arena grow without limits.
+            self.max_bytes.unwrap_or(0),
+            match self.extend_strategy {
+                ArenaExtendStrategy::NextPowerOfTwo => 0,
+                ArenaExtendStrategy::SameAsRequested => 1,
+            },
+        ];
+        let env = get_environment()?;
+        let memory_info = MemoryInfo::new(
+            AllocationDevice::CPU,
+            0,
+            AllocatorType::Arena,
+            MemoryType::Default,
+        )?;
+        let api = ort::api();
+        let create_cfg = api.CreateArenaCfgV2.expect("missing CreateArenaCfgV2");
+        let register = api
+            .CreateAndRegisterAllocator
+            .expect("missing CreateAndRegisterAllocator");
+        let release_cfg = api.ReleaseArenaCfg.expect("missing ReleaseArenaCfg");
+        let mut cfg = std::ptr::null_mut();
+        unsafe {
+            status_to_result(create_cfg(
+                keys.as_ptr(),
+                values.as_ptr(),
+                keys.len(),
+                &mut cfg,
+            ))?;
+            let result = status_to_result(register(env.ptr().cast_mut(), memory_info.ptr(), cfg));
+            release_cfg(cfg);
+            result?;
+        }
+        *registered = true;
+        Ok(())
+    }
+}
+
+/// Turns the status returned by a function of ORT's C API into an error, if it failed.
+unsafe fn status_to_result(status: OrtStatusPtr) -> ort::Result<()> {
+    if status.is_null() {
+        return Ok(());
+    }
+    let api = ort::api();
+    let msg = api.GetErrorMessage.expect("missing GetErrorMessage")(status);
+    let msg = CStr::from_ptr(msg).to_string_lossy().into_owned();
+    api.ReleaseStatus.expect("missing ReleaseStatus")(status);
+    Err(ort::Error::new(msg))
+}
+
+/// The parts in which the models are split, each one loaded in its own sessions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelPart {
+    TextEncoder,
+    Decoder,
+    Encodec,
+}
+
+impl ModelPart {
+    const ALL: [Self; 3] = [Self::TextEncoder, Self::Decoder, Self::Encodec];
+}
+
+impl Display for ModelPart {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TextEncoder => write!(f, "text-encoder"),
+            Self::Decoder => write!(f, "decoder"),
+            Self::Encodec => write!(f, "encodec"),
+        }
+    }
+}
+
+impl FromStr for ModelPart {
+    type Err = anyhow::Error;
+
+    /// Parses the names given by [Display], ignoring the case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|part| part.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("Unknown model part {s}, use text-encoder, decoder or encodec"))
+    }
+}
+
+impl SessionOptions {
+    fn builder(&self, part: ModelPart) -> ort::Result<SessionBuilder> {
+        let mut builder = Session::builder()?.with_memory_pattern(self.memory_pattern)?;
+        if let Some((_, provider)) = &self.execution_provider {
+            let provider = match self.device_map.iter().find(|(p, _)| *p == part) {
+                Some((_, provider)) => provider,
+                None => provider,
+            };
+            builder = builder.with_execution_providers([provider.clone()])?;
+        }
+        if !self.memory_arena {
+            builder = builder.with_allocator(MemoryInfo::new(
+                AllocationDevice::CPU,
+                0,
+                AllocatorType::Device,
+                MemoryType::Default,
+            )?)?;
+        } else if let Some(config) = &self.arena_config {
+            config.register()?;
+            builder = builder.with_env_allocators()?;
+        }
+        if let Some(threads) = self.intra_threads {
+            builder = builder.with_intra_threads(threads)?;
+        }
+        if let Some(threads) = self.inter_threads {
+            builder = builder
+                .with_parallel_execution(true)?
+                .with_inter_threads(threads)?;
+        }
+        Ok(builder)
+    }
+}
+
+/// A loaded MusicGen model, which generates audio through [JobProcessor::process].
+pub struct MusicGenModels {
+    text_encoder: MusicGenTextEncoder,
+    decoder: Box<dyn MusicGenDecoder>,
+    audio_encodec: MusicGenAudioEncodec,
+    sampling_rate: u32,
+    decoder_profiler: Option<DecoderProfiler>,
+    device: &'static str,
+    cpu_fallback: Option<CpuFallback>,
+}
+
+/// The models loaded in the CPU once the device runs out of memory in a generation, which
+/// are used for that one and all the following ones.
+struct CpuFallback {
+    files: ModelFiles,
+    options: SessionOptions,
+    models: Mutex<Option<Arc<MusicGenModels>>>,
+}
+
+impl CpuFallback {
+    fn loaded(&self) -> Option<Arc<MusicGenModels>> {
+        self.models.lock().expect("poisoned lock").clone()
+    }
+
+    fn load(&self) -> ort::Result<Arc<MusicGenModels>> {
+        let mut models = self.models.lock().expect("poisoned lock");
+        if let Some(models) = &*models {
+            return Ok(models.clone());
+        }
+        let loaded =
+            MusicGenModels::load_in(&self.files, &self.options, &|_, _| {}).map_err(|err| {
+                ort::Error::new(format!("Could not load the models in the CPU: {err}"))
+            })?;
+        Ok(models.insert(Arc::new(loaded)).clone())
+    }
+}
+
+/// ORT only writes the profile of a session once it is dropped, so for having a trace per
+/// generation, each generation gets its own decoder with the profiler enabled.
+struct DecoderProfiler {
+    dir: PathBuf,
+    files: DecoderFiles,
+    fp16: bool,
+    options: SessionOptions,
+    config: MusicGenConfig,
+}
+
+impl DecoderProfiler {
+    fn decoder(&self) -> ort::Result<Box<dyn MusicGenDecoder>> {
+        let mut sessions = VecDeque::new();
+        for file in self.files.files() {
+            let builder = self
+                .options
+                .builder(ModelPart::Decoder)?
+                .with_profiling(self.dir.join("decoder"))?;
+            sessions.push_back(commit(builder, file)?);
+        }
+        let config = self.config.clone();
+        Ok(load_decoder(self.fp16, config, &mut sessions))
+    }
+}
+
+impl MusicGenModels {
+    pub fn encode_text(&self, text: &str) -> ort::Result<(DynValue, DynValue)> {
+        self.text_encoder.encode(text)
+    }
+
+    pub fn generate_tokens(
+        &self,
+        last_hidden_state: DynValue,
+        encoder_attention_mask: DynValue,
+        max_len: usize,
+        seed: Option<u64>,
+    ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
+        self.decoder
+            .generate_tokens(last_hidden_state, encoder_attention_mask, max_len, seed)
+    }
+
+    pub fn encode_audio(
+        &self,
+        tokens: impl IntoIterator<Item = [i64; 4]>,
+    ) -> ort::Result<VecDeque<f32>> {
+        self.audio_encodec.encode(tokens)
+    }
+
+    pub fn sampling_rate(&self) -> u32 {
+        self.sampling_rate
+    }
+
+    /// The device in which the models run.
+    pub fn device(&self) -> &'static str {
+        match self.cpu_fallback.as_ref().and_then(CpuFallback::loaded) {
+            Some(models) => models.device,
+            None => self.device,
+        }
+    }
+
+    /// Runs a tiny generation, as GPUs without enough memory for the model usually fail in
+    /// the first inference rather than while loading it.
+    fn warm_up(&self) -> ort::Result<()> {
+        let (lhs, am) = self.encode_text("warm up")?;
+        let mut tokens = vec![];
+        for token in self.generate_tokens(lhs, am, 4, None)?.iter() {
+            tokens.push(token?);
+        }
+        self.encode_audio(tokens)?;
+        Ok(())
+    }
+
+    /// Loads the model in `files` in the device of [SessionOptions::execution_provider],
+    /// falling back to the CPU if it does not work there and [SessionOptions::cpu_fallback]
+    /// is set.
+    pub fn load(files: ModelFiles, session_options: &SessionOptions) -> anyhow::Result<Self> {
+        Self::load_with_progress(files, session_options, &|_, _| {})
+    }
+
+    /// Like [MusicGenModels::load], calling `on_loaded` with the sessions built so far and
+    /// the total each time that one of the `.onnx` files is loaded.
+    pub fn load_with_progress(
+        files: ModelFiles,
+        session_options: &SessionOptions,
+        on_loaded: &(dyn Fn(usize, usize) + Sync),
+    ) -> anyhow::Result<Self> {
+        let Some((device, _)) = &session_options.execution_provider else {
+            return Self::load_in(&files, session_options, on_loaded);
+        };
+        let loaded = match Self::load_in(&files, session_options, on_loaded) {
+            Ok(models) => match models.warm_up() {
+                Ok(()) => Ok(models),
+                Err(err) => Err(anyhow!(err)),
+            },
+            Err(err) => Err(err),
+        };
+        let cpu_options = SessionOptions {
+            execution_provider: None,
+            device_map: vec![],
+            ..session_options.clone()
+        };
+        match loaded {
+            Ok(mut models) => {
+                models.cpu_fallback = session_options.cpu_fallback.then(|| CpuFallback {
+                    files,
+                    options: cpu_options,
+                    models: Mutex::default(),
+                });
+                Ok(models)
+            }
+            Err(err) if session_options.cpu_fallback => {
+                // The sessions in the device were already dropped, freeing its memory.
+                warn!("Could not run the models in {device}, running them in the CPU: {err}");
+                Self::load_in(&files, &cpu_options, on_loaded)
+            }
+            Err(err) => Err(anyhow!("Could not run the models in {device}: {err}")),
+        }
+    }
+
+    fn load_in(
+        files: &ModelFiles,
+        session_options: &SessionOptions,
+        on_loaded: &(dyn Fn(usize, usize) + Sync),
+    ) -> anyhow::Result<Self> {
+        let decoder_files = files.decoder.files();
+        let total = decoder_files.len() + 2;
+        let loaded = AtomicUsize::new(0);
+        let build = |part: ModelPart, file: &Path| -> ort::Result<Session> {
+            let session = commit(session_options.builder(part)?, file)?;
+            on_loaded(loaded.fetch_add(1, Ordering::Relaxed) + 1, total);
+            Ok(session)
+        };
+        // Building a session, which optimizes the graph of its file, takes most of the
+        // loading time and barely uses more than one core, so each file gets its own thread.
+        let (text_encoder, sessions, audio_encodec) = std::thread::scope(|s| {
+            let text_encoder = s.spawn(|| build(ModelPart::TextEncoder, &files.text_encoder));
+            let decoder: Vec<_> = decoder_files
+                .into_iter()
+                .map(|file| s.spawn(move || build(ModelPart::Decoder, file)))
+                .collect();
+            let audio_encodec = s.spawn(|| build(ModelPart::Encodec, &files.encodec));
+            (
+                join(text_encoder),
+                decoder
+                    .into_iter()
+                    .map(join)
+                    .collect::<Result<VecDeque<_>, _>>(),
+                join(audio_encodec),
+            )
+        });
+
+        let mut tokenizer = Tokenizer::from_file(&files.tokenizer)
+            .map_err(|err| anyhow!("Could not load the tokenizer: {err}"))?;
+        tokenizer
+            .with_padding(None)
+            .with_truncation(None)
+            .map_err(|err| anyhow!("Could not configure the tokenizer: {err}"))?;
+        let text_encoder = MusicGenTextEncoder {
+            tokenizer,
+            text_encoder: text_encoder?,
+        };
+
+        let config = std::fs::read_to_string(&files.config)?;
+        let config: MusicGenConfig = serde_json::from_str(&config)?;
+        let sampling_rate = config.audio_encoder.sampling_rate as u32;
+        let decoder_profiler =
+            session_options
+                .decoder_profile_dir
+                .as_ref()
+                .map(|dir| DecoderProfiler {
+                    dir: dir.clone(),
+                    files: files.decoder.clone(),
+                    fp16: files.fp16,
+                    options: session_options.clone(),
+                    config: config.clone(),
+                });
+        let decoder = load_decoder(files.fp16, config, &mut sessions?);
+        let audio_encodec = MusicGenAudioEncodec {
+            audio_encodec_decode: audio_encodec?,
+        };
+
+        Ok(MusicGenModels {
+            text_encoder,
+            decoder,
+            audio_encodec,
+            sampling_rate,
+            decoder_profiler,
+            device: match &session_options.execution_provider {
+                Some((device, _)) => device,
+                None => "Cpu",
+            },
+            cpu_fallback: None,
+        })
+    }
+}
+
+/// Builds the session of the `.onnx` in `file`, which is memory mapped instead of read into
+/// the heap, so that the pages of the big models are loaded as ORT parses them and can be
+/// reclaimed afterwards.
+fn commit(builder: SessionBuilder, file: &Path) -> ort::Result<Session> {
+    // Given the model in memory, ORT would not know where to look for its external data,
+    // which is next to its file.
+    if file.with_extension("onnx_data").exists() {
+        return builder.commit_from_file(file);
+    }
+    let map = || -> std::io::Result<Mmap> {
+        let file = File::open(file)?;
+        // Mapping is unsafe if the file changes meanwhile, and model files are never modified.
+        unsafe { Mmap::map(&file) }
+    };
+    let mmap =
+        map().map_err(|err| ort::Error::new(format!("Could not map {}: {err}", file.display())))?;
+    builder.commit_from_memory(&mmap)
+}
+
+/// Waits for a thread of the scope, resuming its panic if it panicked.
+fn join<T>(handle: ScopedJoinHandle<'_, T>) -> T {
+    handle
+        .join()
+        .unwrap_or_else(|err| std::panic::resume_unwind(err))
+}
+
+/// Builds the decoder from the next sessions, which are its two parts if it is split.
+fn load_decoder(
+    fp16: bool,
+    config: MusicGenConfig,
+    sessions: &mut VecDeque<Session>,
+) -> Box<dyn MusicGenDecoder> {
+    #[allow(clippy::collapsible_else_if)]
+    if sessions.len() == 2 {
+        macro_rules! load {
+            ($ty: ty) => {
+                Box::new(MusicGenSplitDecoder::<$ty> {
+                    decoder_model: sessions.pop_front().unwrap(),
+                    decoder_with_past_model: Arc::new(sessions.pop_front().unwrap()),
+                    config,
+                    _phantom_data: Default::default(),
+                })
+            };
+        }
+        if fp16 {
+            load!(f16)
+        } else {
+            load!(f32)
+        }
+    } else {
+        macro_rules! load {
+            ($ty: ty) => {
+                Box::new(MusicGenMergedDecoder::<$ty> {
+                    decoder_model_merged: Arc::new(sessions.pop_front().unwrap()),
+                    config,
+                    _phantom_data: Default::default(),
+                })
+            };
+        }
+        if fp16 {
+            load!(f16)
+        } else {
+            load!(f32)
+        }
+    }
+}
+
+/// Whether ORT failed because the device ran out of memory. Models that barely fit usually
+/// get through the warm-up and run out of memory with the first long generation.
+fn is_out_of_memory(err: &ort::Error) -> bool {
+    let msg = err.to_string().to_lowercase();
+    [
+        "out of memory",
+        "outofmemory",
+        "failed to allocate memory",
+        "smaller than requested bytes",
+    ]
+    .iter()
+    .any(|pattern| msg.contains(pattern))
+}
+
+impl MusicGenModels {
+    fn generate(
+        &self,
+        prompt: &str,
+        secs: usize,
+        params: &GenerationParams,
+        on_progress: &(dyn Fn(GenerationStage) -> bool + Sync + Send),
+        on_partial_audio: Option<&(dyn Fn(VecDeque<f32>) + Sync + Send)>,
+    ) -> ort::Result<(VecDeque<f32>, GenerationTimings)> {
+        if params.reference.is_some() {
+            // Conditioning needs the Encodec encoder, which is not exported to ONNX.
+            return Err(ort::Error::new(
+                "MusicGen models cannot be conditioned with audio, generate without a reference",
+            ));
+        }
+        let max_len = secs * INPUT_IDS_BATCH_PER_SECOND;
+        let mut timings = GenerationTimings::default();
+        let report = |stage| {
+            if on_progress(stage) {
+                return Err(ort::Error::new("Aborted"));
+            }
+            Ok(())
+        };
+
+        report(GenerationStage::TextEncoding)?;
+        let start = Instant::now();
+        let (lhs, am) = info_span!("text_encoding").in_scope(|| self.encode_text(prompt))?;
+        timings.text_encoding_secs = start.elapsed().as_secs_f32();
+
+        let generation_span = info_span!("token_generation", max_len).entered();
+        let start = Instant::now();
+        // Time spent decoding partial audios while the tokens are being generated.
+        let mut decoding = Duration::ZERO;
+        // Dropped once the generation finishes, which writes its profile.
+        let profiled_decoder = match &self.decoder_profiler {
+            Some(profiler) => Some(profiler.decoder()?),
+            None => None,
+        };
+        let token_stream = match &profiled_decoder {
+            Some(decoder) => decoder.generate_tokens(lhs, am, max_len, params.seed)?,
+            None => self.generate_tokens(lhs, am, max_len, params.seed)?,
+        };
+
+        let mut data = VecDeque::new();
+        let mut sent_samples = 0;
+        while let Ok(tokens) = token_stream.recv() {
+            data.push_back(tokens?);
+            report(GenerationStage::TokenGeneration {
+                done: data.len(),
+                total: max_len,
+            })?;
+            // Every second of generated tokens, the tokens generated so far are decoded
+            // for sending the new samples.
+            if let Some(on_partial_audio) = &on_partial_audio {
+                if data.len() % INPUT_IDS_BATCH_PER_SECOND == 0 && data.len() < max_len {
+                    let decoding_start = Instant::now();
+                    let mut samples = info_span!("audio_decoding", partial = true)
+                        .in_scope(|| self.encode_audio(data.iter().copied()))?;
+                    decoding += decoding_start.elapsed();
+                    let new_samples = samples.split_off(sent_samples.min(samples.len()));
+                    sent_samples += new_samples.len();
+                    on_partial_audio(new_samples);
+                }
+            }
+        }
+        drop(generation_span);
+        timings.token_generation_secs = start.elapsed().saturating_sub(decoding).as_secs_f32();
+
+        report(GenerationStage::EncodecDecoding { done: 0, total: 1 })?;
+        let decoding_start = Instant::now();
+        let samples = info_span!("audio_decoding").in_scope(|| self.encode_audio(data))?;
+        decoding += decoding_start.elapsed();
+        report(GenerationStage::EncodecDecoding { done: 1, total: 1 })?;
+        timings.audio_decoding_secs = decoding.as_secs_f32();
+        if let Some(on_partial_audio) = &on_partial_audio {
+            let new_samples = samples.iter().skip(sent_samples).copied().collect();
+            on_partial_audio(new_samples);
+        }
+        Ok((samples, timings))
+    }
+}
+
+impl JobProcessor for MusicGenModels {
+    fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        params: &GenerationParams,
+        on_progress: OnProgress,
+        on_partial_audio: Option<OnPartialAudio>,
+    ) -> ort::Result<(VecDeque<f32>, GenerationTimings)> {
+        if let Some(models) = self.cpu_fallback.as_ref().and_then(CpuFallback::loaded) {
+            return models.generate(
+                prompt,
+                secs,
+                params,
+                &on_progress,
+                on_partial_audio.as_deref(),
+            );
+        }
+        let sent_partial_audio = AtomicBool::new(false);
+        let on_partial_audio = on_partial_audio.map(|on_partial_audio| {
+            let sent_partial_audio = &sent_partial_audio;
+            move |samples| {
+                sent_partial_audio.store(true, Ordering::Relaxed);
+                on_partial_audio(samples)
+            }
+        });
+        let on_partial_audio = on_partial_audio
+            .as_ref()
+            .map(|f| f as &(dyn Fn(VecDeque<f32>) + Sync + Send));
+        match self.generate(prompt, secs, params, &on_progress, on_partial_audio) {
+            Err(err) if is_out_of_memory(&err) => {
+                let device = self.device;
+                let oom = format!(
+                    "{device} ran out of memory generating {secs}s of audio, try with fewer \
+                    seconds or a smaller model"
+                );
+                // Starting again in the CPU would repeat the partial audio already sent.
+                let fallback = match &self.cpu_fallback {
+                    Some(fallback) if !sent_partial_audio.load(Ordering::Relaxed) => fallback,
+                    _ => return Err(ort::Error::new(format!("{oom}: {err}"))),
+                };
+                warn!("{oom}, running the models in the CPU from now on: {err}");
+                let models = fallback.load()?;
+                models.generate(prompt, secs, params, &on_progress, on_partial_audio)
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_model_parts() {
+        for part in ModelPart::ALL {
+            assert_eq!(part.to_string().parse::<ModelPart>().unwrap(), part);
+        }
+        assert_eq!(
+            "Text-Encoder".parse::<ModelPart>().unwrap(),
+            ModelPart::TextEncoder
+        );
+        assert!("vocoder".parse::<ModelPart>().is_err());
+
+        let split = DecoderFiles::Split {
+            decoder: "decoder_model.onnx".into(),
+            decoder_with_past: "decoder_with_past_model.onnx".into(),
+        };
+        assert_eq!(
+            split.files(),
+            [
+                Path::new("decoder_model.onnx"),
+                Path::new("decoder_with_past_model.onnx")
+            ]
+        );
+    }
+    #[test]
+    fn detects_out_of_memory_errors() {
+        let cuda = ort::Error::new("CUDA failure 2: out of memory ; GPU=0 ; hostname=gpu");
+        assert!(is_out_of_memory(&cuda));
+        let arena =
+            ort::Error::new("Failed to allocate memory for requested buffer of size 1073741824");
+        assert!(is_out_of_memory(&arena));
+        assert!(!is_out_of_memory(&ort::Error::new("Aborted")));
+    }
+}
@@ -7,7 +7,7 @@ use num_traits::FloatConst;
 use ort::tensor::ArrayExtensions;
 use ort::value::DynValue;
 use rand::distributions::WeightedIndex;
-use rand::{thread_rng, Rng};
+use rand::Rng;
 
 pub struct Logits(Array2<f32>);
 
@@ -96,9 +96,10 @@ impl Logits {
     /// # Arguments
     ///
     /// * `k`: Take into account only top k logits in each batch
+    /// * `rng`: The source of randomness, seeded for reproducible samples
     ///
     /// returns: Vec<(i64, f32), Global> the per-batch sample
-    pub fn sample(&self, k: usize) -> Vec<(i64, f32)> {
+    pub fn sample(&self, k: usize, rng: &mut impl Rng) -> Vec<(i64, f32)> {
         let mut result = vec![];
         let softmax_logits = self.0.softmax(Axis(1));
         for batch in softmax_logits.axis_iter(Axis(0)) {
@@ -122,7 +123,7 @@ impl Logits {
             let distribution = WeightedIndex::new(softmax_logits_batch.iter().map(|e| e.1))
                 .expect("Could not create WeightedIndex distribution");
             // Sample a random index based on the softmax probabilities.
-            let (idx, softmax_prob) = softmax_logits_batch[thread_rng().sample(distribution)];
+            let (idx, softmax_prob) = softmax_logits_batch[rng.sample(distribution)];
             // based on JS implementation:
             //  Math.log(probabilities[sampledIndex])
             // In JS, Math.log uses euler's number base.
@@ -135,6 +136,8 @@ impl Logits {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     #[test]
     fn free_guidance() {
@@ -142,4 +145,17 @@ mod tests {
         let logits = logits.apply_free_guidance(3);
         assert_eq!(logits.shape(), &[1, 3]);
     }
+
+    #[test]
+    fn samples_the_same_with_the_same_seed() {
+        let logits = Logits::from(Array::from(vec![[1., 1., 1., 1.], [1., 2., 3., 4.]]).into_dyn());
+        let sample = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..10)
+                .map(|_| logits.sample(4, &mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sample(42), sample(42));
+        assert_ne!(sample(42), sample(43));
+    }
 }
@@ -9,5 +9,8 @@ mod music_gen_text_encoder;
 mod tensor_ops;
 
 pub use music_gen_audio_encodec::MusicGenAudioEncodec;
-pub use music_gen_decoder::{MusicGenDecoder, MusicGenMergedDecoder, MusicGenSplitDecoder};
+pub use music_gen_config::MusicGenConfig;
+pub use music_gen_decoder::{
+    MusicGenDecoder, MusicGenMergedDecoder, MusicGenSplitDecoder, MusicGenType,
+};
 pub use music_gen_text_encoder::MusicGenTextEncoder;
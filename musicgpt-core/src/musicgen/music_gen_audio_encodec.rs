@@ -0,0 +1,170 @@
+use std::collections::VecDeque;
+
+use half::f16;
+use ndarray::{Array, Axis};
+use ort::session::Session;
+use ort::value::DynValue;
+
+/// Frames of tokens decoded at once, 10 seconds of audio. Encodec's memory grows with the
+/// length of its input, so long audios are decoded in chunks of this size.
+const CHUNK_FRAMES: usize = 500;
+
+/// Frames before and after each chunk that are decoded along with it, for giving the model
+/// the context around the chunk.
+const CONTEXT_FRAMES: usize = 25;
+
+/// Frames at each side of the boundary between two chunks in which the audio of one is
+/// crossfaded into the other, so that chunks join without clicks. The rest of the audio
+/// of the context frames is discarded.
+const OVERLAP_FRAMES: usize = 5;
+
+pub struct MusicGenAudioEncodec {
+    pub audio_encodec_decode: Session,
+}
+
+impl MusicGenAudioEncodec {
+    pub fn encode(&self, tokens: impl IntoIterator<Item = [i64; 4]>) -> ort::Result<VecDeque<f32>> {
+        let frames: Vec<[i64; 4]> = tokens.into_iter().collect();
+        decode_chunked(
+            &frames,
+            CHUNK_FRAMES,
+            CONTEXT_FRAMES,
+            OVERLAP_FRAMES,
+            |frames| self.decode(frames),
+        )
+    }
+
+    fn decode(&self, frames: &[[i64; 4]]) -> ort::Result<Vec<f32>> {
+        let data = frames.iter().flatten().copied().collect();
+        let arr = Array::from_shape_vec((frames.len(), 4), data).expect("Programming error");
+        let arr = arr.t().insert_axis(Axis(0)).insert_axis(Axis(0));
+        let mut outputs = self.audio_encodec_decode.run(ort::inputs![arr]?)?;
+        let audio_values: DynValue = outputs
+            .remove("audio_values")
+            .expect("audio_values not found in output");
+
+        if let Ok((_, data)) = audio_values.try_extract_raw_tensor::<f32>() {
+            return Ok(data.to_vec());
+        }
+        if let Ok((_, data)) = audio_values.try_extract_raw_tensor::<f16>() {
+            return Ok(data.iter().map(|e| f32::from(*e)).collect());
+        }
+
+        Err(ort::error::Error::new(
+            "Token stream must be either f16 or f32",
+        ))
+    }
+}
+
+/// Decodes `frames` with `decode` in chunks of `chunk` frames, each one along with up to
+/// `context` frames at both sides. Consecutive chunks are overlap-added over `overlap`
+/// frames at each side of their boundary, and the rest of the context is discarded.
+fn decode_chunked(
+    frames: &[[i64; 4]],
+    chunk: usize,
+    context: usize,
+    overlap: usize,
+    mut decode: impl FnMut(&[[i64; 4]]) -> ort::Result<Vec<f32>>,
+) -> ort::Result<VecDeque<f32>> {
+    if frames.len() <= chunk {
+        return Ok(decode(frames)?.into());
+    }
+    let overlap = overlap.min(context).min(chunk);
+    let mut samples: VecDeque<f32> = VecDeque::new();
+    let mut start = 0;
+    while start < frames.len() {
+        let end = (start + chunk).min(frames.len());
+        let context_start = start.saturating_sub(context);
+        let context_end = (end + context).min(frames.len());
+        let decoded = decode(&frames[context_start..context_end])?;
+        let samples_per_frame = decoded.len() / (context_end - context_start);
+
+        let from = start.saturating_sub(overlap);
+        let to = (end + overlap).min(frames.len());
+        let mut decoded = decoded
+            .into_iter()
+            .skip((from - context_start) * samples_per_frame)
+            .take((to - from) * samples_per_frame);
+        // The samples from `from` on were already decoded by the previous chunk.
+        let fade_start = from * samples_per_frame;
+        let fade = samples.len() - fade_start;
+        for (i, sample) in decoded.by_ref().take(fade).enumerate() {
+            let weight = crossfade_weight(i, fade);
+            let previous = &mut samples[fade_start + i];
+            *previous = *previous * (1.0 - weight) + sample * weight;
+        }
+        samples.extend(decoded);
+        start = end;
+    }
+    Ok(samples)
+}
+
+/// The weight of the incoming chunk in the `i`th of `n` crossfaded samples, a raised cosine
+/// that goes from 0 to 1. The weights of both chunks always add up to 1.
+fn crossfade_weight(i: usize, n: usize) -> f32 {
+    let x = (i as f32 + 0.5) / n as f32;
+    0.5 - 0.5 * (std::f32::consts::PI * x).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes each frame into 4 samples with the value of its first token.
+    fn fake_decode(frames: &[[i64; 4]]) -> ort::Result<Vec<f32>> {
+        Ok(frames
+            .iter()
+            .flat_map(|frame| [frame[0] as f32; 4])
+            .collect())
+    }
+
+    #[test]
+    fn decodes_in_chunks() -> ort::Result<()> {
+        let frames: Vec<[i64; 4]> = (0..23).map(|i| [i, 0, 0, 0]).collect();
+        let full = decode_chunked(&frames, 100, 2, 1, fake_decode)?;
+        assert_eq!(full.len(), 23 * 4);
+
+        let mut calls = vec![];
+        let chunked = decode_chunked(&frames, 5, 2, 1, |frames| {
+            calls.push(frames.len());
+            fake_decode(frames)
+        })?;
+        assert_eq!(chunked.len(), full.len());
+        for (a, b) in chunked.iter().zip(&full) {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+        // No chunk is decoded with more than the chunk and its context.
+        assert_eq!(calls, vec![7, 9, 9, 9, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn crossfades_chunks() -> ort::Result<()> {
+        let frames = vec![[0; 4]; 20];
+        // Each chunk decodes into a different level, so the boundaries are clicks unless
+        // they are crossfaded.
+        let mut level = 0.0;
+        let samples = decode_chunked(&frames, 10, 2, 1, |frames| {
+            level += 1.0;
+            Ok(vec![level; frames.len() * 4])
+        })?;
+        assert_eq!(samples.len(), 20 * 4);
+        assert_eq!(samples[0], 1.0);
+        assert_eq!(samples[79], 2.0);
+        // The 8 samples of the frames at both sides of the boundary go from one to the other.
+        for (i, sample) in samples.iter().enumerate() {
+            let expected = if i < 36 {
+                1.0
+            } else if i < 44 {
+                1.0 + crossfade_weight(i - 36, 8)
+            } else {
+                2.0
+            };
+            assert!((sample - expected).abs() < 1e-6, "{i}: {sample}");
+        }
+        for (a, b) in samples.iter().zip(samples.iter().skip(1)) {
+            assert!(b >= a && b - a < 0.25, "{a} -> {b}");
+        }
+        Ok(())
+    }
+}
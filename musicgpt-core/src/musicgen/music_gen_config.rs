@@ -1,18 +1,18 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MusicGenConfig {
     pub audio_encoder: AudioEncoderConfig,
     pub decoder: DecoderConfig,
     pub text_encoder: TextEncoderConfig,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AudioEncoderConfig {
     pub sampling_rate: usize,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DecoderConfig {
     pub num_attention_heads: usize,
     pub num_hidden_layers: usize,
@@ -20,7 +20,7 @@ pub struct DecoderConfig {
     pub pad_token_id: i64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TextEncoderConfig {
     pub d_kv: usize,
 }
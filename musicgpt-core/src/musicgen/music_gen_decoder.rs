@@ -12,6 +12,8 @@ use num_traits::Zero;
 use ort::session::Session;
 use ort::tensor::PrimitiveTensorElementType;
 use ort::value::{DynValue, Tensor};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
 pub trait MusicGenType: PrimitiveTensorElementType + Debug + Clone + Zero {}
 
@@ -29,9 +31,18 @@ pub trait MusicGenDecoder: Send + Sync {
         last_hidden_state: DynValue,
         encoder_attention_mask: DynValue,
         max_len: usize,
+        seed: Option<u64>,
     ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>>;
 }
 
+/// The random generator for sampling the tokens, seeded with `seed` if provided.
+fn rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
 pub struct MusicGenMergedDecoder<T: MusicGenType> {
     pub decoder_model_merged: Arc<Session>,
     pub config: MusicGenConfig,
@@ -47,6 +58,7 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
         last_hidden_state: DynValue,
         encoder_attention_mask: DynValue,
         max_len: usize,
+        seed: Option<u64>,
     ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
         // Apparently, there's a setting in huggingface's transformers that says that
         // if `guidance_scale` > 1 then you should concatenate 0 along the first axis.
@@ -55,6 +67,7 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
             dupe_zeros_along_first_dim::<i64>(encoder_attention_mask.downcast()?)?;
 
         let mut delay_pattern_mask_ids = DelayedPatternMaskIds::<4>::new();
+        let mut rng = rng(seed);
 
         let decoder_model_merged = self.decoder_model_merged.clone();
 
@@ -93,7 +106,7 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenMergedDecoder<T> {
                         outputs
                             .take_logits()?
                             .apply_free_guidance(GUIDANCE_SCALE)
-                            .sample(top_k)
+                            .sample(top_k, &mut rng)
                             .iter()
                             .map(|e| e.0),
                     );
@@ -157,6 +170,7 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenSplitDecoder<T> {
         last_hidden_state: DynValue,
         encoder_attention_mask: DynValue,
         max_len: usize,
+        seed: Option<u64>,
     ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
         // Apparently, there's a setting in huggingface's transformers that says that
         // if `guidance_scale` > 1 then you should concatenate 0 along the first axis.
@@ -165,6 +179,7 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenSplitDecoder<T> {
             dupe_zeros_along_first_dim::<i64>(encoder_attention_mask.downcast()?)?;
 
         let mut delay_pattern_mask_ids = DelayedPatternMaskIds::<4>::new();
+        let mut rng = rng(seed);
 
         let num_hidden_layers = self.config.decoder.num_hidden_layers;
         let pad_token_id = self.config.decoder.pad_token_id;
@@ -182,7 +197,7 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenSplitDecoder<T> {
             outputs
                 .take_logits()?
                 .apply_free_guidance(GUIDANCE_SCALE)
-                .sample(top_k)
+                .sample(top_k, &mut rng)
                 .iter()
                 .map(|e| e.0),
         );
@@ -218,7 +233,7 @@ impl<T: MusicGenType + 'static> MusicGenDecoder for MusicGenSplitDecoder<T> {
                         outputs
                             .take_logits()?
                             .apply_free_guidance(GUIDANCE_SCALE)
-                            .sample(top_k)
+                            .sample(top_k, &mut rng)
                             .iter()
                             .map(|e| e.0),
                     );
@@ -88,7 +88,7 @@ impl MusicGenInputs {
         );
     }
 
-    pub fn ort(&self) -> SessionInputs {
+    pub fn ort(&self) -> SessionInputs<'_, '_> {
         SessionInputs::ValueMap(
             self.inputs
                 .iter()
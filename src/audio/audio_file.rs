@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::path::Path;
+
+use anyhow::anyhow;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// Decoded audio, downmixed to a single channel.
+pub struct AudioFile {
+    pub samples: VecDeque<f32>,
+    pub sampling_rate: u32,
+    /// The channels of the file, before being downmixed.
+    pub channels: usize,
+}
+
+impl AudioFile {
+    /// Decodes a WAV, MP3 or FLAC file from disk, averaging all its channels into one.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)?;
+        let ext = path.extension().and_then(|e| e.to_str());
+        Self::decode(Box::new(file), ext).map_err(|err| anyhow!("Could not decode {path:?}: {err}"))
+    }
+
+    /// Decodes an in memory WAV, MP3 or FLAC file, `ext` helps guessing its format.
+    pub fn from_bytes(bytes: Vec<u8>, ext: Option<&str>) -> anyhow::Result<Self> {
+        Self::decode(Box::new(std::io::Cursor::new(bytes)), ext)
+    }
+
+    fn decode(source: Box<dyn MediaSource>, ext: Option<&str>) -> anyhow::Result<Self> {
+        let mss = MediaSourceStream::new(source, Default::default());
+
+        let mut hint = Hint::new();
+        if let Some(ext) = ext {
+            hint.with_extension(ext);
+        }
+        let probed = symphonia::default::get_probe().format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?;
+        let mut format = probed.format;
+        let Some(track) = format.default_track() else {
+            return Err(anyhow!("No audio track found"));
+        };
+        let track_id = track.id;
+        let Some(sampling_rate) = track.codec_params.sample_rate else {
+            return Err(anyhow!("Unknown sampling rate"));
+        };
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())?;
+
+        let mut samples = VecDeque::new();
+        let mut channels = track.codec_params.channels.map(|v| v.count()).unwrap_or(1);
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+            let decoded = match decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // Malformed packets can be skipped, the rest of the file is still playable.
+                Err(Error::DecodeError(_)) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let spec = *decoded.spec();
+            let n_channels = spec.channels.count().max(1);
+            channels = n_channels;
+            let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            buffer.copy_interleaved_ref(decoded);
+            for frame in buffer.samples().chunks(n_channels) {
+                samples.push_back(frame.iter().sum::<f32>() / n_channels as f32)
+            }
+        }
+
+        Ok(Self {
+            samples,
+            sampling_rate,
+            channels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_wav_file() -> anyhow::Result<()> {
+        let wav_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test.wav");
+        let reader = hound::WavReader::open(wav_path)?;
+        let spec = reader.spec();
+        let expected = reader
+            .into_samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let audio = AudioFile::open(wav_path)?;
+        assert_eq!(audio.sampling_rate, spec.sample_rate);
+        assert_eq!(audio.channels, spec.channels as usize);
+        assert_eq!(audio.samples, VecDeque::from(expected.clone()));
+
+        let audio = AudioFile::from_bytes(std::fs::read(wav_path)?, None)?;
+        assert_eq!(audio.samples, VecDeque::from(expected));
+        Ok(())
+    }
+}
@@ -1,12 +1,16 @@
 use anyhow::anyhow;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{
-    ChannelCount, SampleFormat, SampleRate, Stream, SupportedBufferSize, SupportedStreamConfig,
+    ChannelCount, FromSample, Sample, SampleFormat, SampleRate, SizedSample, Stream,
+    SupportedBufferSize, SupportedStreamConfig,
 };
 use std::collections::VecDeque;
 use std::time::Duration;
 
-const DEFAULT_SAMPLING_RATE: u32 = 32000;
+use crate::audio::flac::encode_flac;
+use crate::audio::mp3::encode_mp3;
+use crate::audio::ogg::encode_ogg;
+use crate::audio::Playlist;
 
 pub struct AudioManager {
     host: cpal::Host,
@@ -15,19 +19,6 @@ pub struct AudioManager {
     n_channels: u16,
 }
 
-impl Default for AudioManager {
-    fn default() -> Self {
-        let host = cpal::default_host();
-
-        Self {
-            host,
-            sampling_rate: DEFAULT_SAMPLING_RATE,
-            sample_format: SampleFormat::F32,
-            n_channels: 1,
-        }
-    }
-}
-
 #[allow(dead_code)]
 pub struct AudioStream {
     pub stream: Stream,
@@ -38,8 +29,67 @@ unsafe impl Send for AudioStream {}
 unsafe impl Sync for AudioStream {}
 
 impl AudioManager {
-    pub fn play_from_queue(&self, mut v: VecDeque<f32>) -> anyhow::Result<AudioStream> {
-        let time = 1000 * v.len() / self.sampling_rate as usize;
+    /// Creates a new [AudioManager] that plays and encodes mono sample streams.
+    ///
+    /// # Arguments
+    ///
+    /// * `sampling_rate`: The sampling rate of the samples, usually dictated by the model.
+    /// * `n_channels`: The number of output channels, the mono signal is copied to all of them.
+    /// * `sample_format`: The format in which samples are sent to the device and written to disk.
+    ///
+    /// returns: AudioManager
+    pub fn new(sampling_rate: u32, n_channels: u16, sample_format: SampleFormat) -> Self {
+        Self {
+            host: cpal::default_host(),
+            sampling_rate,
+            sample_format,
+            n_channels,
+        }
+    }
+
+    /// Replaces the default audio host with the one identified by `name`, for example
+    /// "ALSA" or "JACK" on Linux, or "WASAPI" or "ASIO" on Windows. The name is matched
+    /// case-insensitively against the hosts available in this platform.
+    pub fn with_host(mut self, name: &str) -> anyhow::Result<Self> {
+        let available = cpal::available_hosts();
+        let Some(host_id) = available
+            .iter()
+            .find(|id| id.name().eq_ignore_ascii_case(name))
+        else {
+            let names = available.iter().map(|id| id.name()).collect::<Vec<_>>();
+            return Err(anyhow!(
+                "Audio host {name} is not available, available hosts are: {}",
+                names.join(", ")
+            ));
+        };
+        self.host = cpal::host_from_id(*host_id)?;
+        Ok(self)
+    }
+
+    /// Opens an output stream that stays alive for as long as the returned [AudioStream] is
+    /// referenced, playing the tracks in `playlist` one after the other, and playing
+    /// silence while there's nothing left to play.
+    pub fn play_playlist(&self, playlist: Playlist) -> anyhow::Result<AudioStream> {
+        let stream = match self.sample_format {
+            SampleFormat::F32 => self.build_output_stream::<f32>(move || playlist.next_sample())?,
+            SampleFormat::I16 => self.build_output_stream::<i16>(move || playlist.next_sample())?,
+            SampleFormat::I32 => self.build_output_stream::<i32>(move || playlist.next_sample())?,
+            unknown => return Err(anyhow!("unsupported sample format {unknown}")),
+        };
+
+        stream.play()?;
+        Ok(AudioStream {
+            stream,
+            duration: Duration::MAX,
+        })
+    }
+
+    /// Builds an output stream in the default output device that pulls mono samples from
+    /// `next_sample`, converting them to `T` and copying them to all the output channels.
+    fn build_output_stream<T: SizedSample + FromSample<f32>>(
+        &self,
+        mut next_sample: impl FnMut() -> Option<f32> + Send + 'static,
+    ) -> anyhow::Result<Stream> {
         let channels = self.n_channels;
 
         let config = SupportedStreamConfig::new(
@@ -55,54 +105,70 @@ impl AudioManager {
         };
         let stream = device.build_output_stream(
             &config.into(),
-            move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
                 for frame in output.chunks_mut(channels as usize) {
-                    for sample in frame.iter_mut() {
-                        *sample = v.pop_front().unwrap_or_default()
+                    let sample = T::from_sample(next_sample().unwrap_or_default());
+                    for out in frame.iter_mut() {
+                        *out = sample
                     }
                 }
             },
             |_err| {},
             None,
         )?;
+        Ok(stream)
+    }
 
-        stream.play()?;
-        Ok(AudioStream {
-            stream,
-            duration: Duration::from_millis(time as u64),
-        })
+    /// The name of the device in which the audios are played.
+    pub fn output_device_name(&self) -> anyhow::Result<String> {
+        match self.host.default_output_device() {
+            None => Err(anyhow!("No audio device")),
+            Some(device) => Ok(device.name()?),
+        }
+    }
+
+    pub fn sampling_rate(&self) -> u32 {
+        self.sampling_rate
+    }
+
+    pub fn sample_format(&self) -> SampleFormat {
+        self.sample_format
     }
 
     pub fn to_wav(&self, v: VecDeque<f32>) -> hound::Result<Vec<u8>> {
+        self.to_wav_as(v, self.sample_format)
+    }
+
+    /// Encodes the samples as a 16 bit .flac file, with the channels of the [AudioManager].
+    pub fn to_flac(&self, mut v: VecDeque<f32>) -> Vec<u8> {
+        encode_flac(v.make_contiguous(), self.sampling_rate, self.n_channels)
+    }
+
+    /// Encodes the samples as a 192 kbps .mp3 file, stereo if the [AudioManager] has more
+    /// than one channel.
+    pub fn to_mp3(&self, mut v: VecDeque<f32>) -> anyhow::Result<Vec<u8>> {
+        encode_mp3(v.make_contiguous(), self.sampling_rate, self.n_channels)
+    }
+
+    /// Encodes the samples as an .ogg Vorbis file, with the channels of the [AudioManager].
+    pub fn to_ogg(&self, mut v: VecDeque<f32>) -> anyhow::Result<Vec<u8>> {
+        encode_ogg(v.make_contiguous(), self.sampling_rate, self.n_channels)
+    }
+
+    /// Like [AudioManager::to_wav], but writing the samples in `format` instead of in the
+    /// format given when creating the [AudioManager].
+    pub fn to_wav_as(&self, v: VecDeque<f32>, format: SampleFormat) -> hound::Result<Vec<u8>> {
+        let (bits_per_sample, sample_format) = match format {
+            SampleFormat::I16 => (16, hound::SampleFormat::Int),
+            SampleFormat::I32 => (32, hound::SampleFormat::Int),
+            SampleFormat::F32 => (32, hound::SampleFormat::Float),
+            _ => return Err(hound::Error::Unsupported),
+        };
         let spec = hound::WavSpec {
             channels: self.n_channels,
             sample_rate: self.sampling_rate,
-            bits_per_sample: match self.sample_format {
-                SampleFormat::I8 => 8,
-                SampleFormat::I16 => 16,
-                SampleFormat::I32 => 32,
-                SampleFormat::I64 => 64,
-                SampleFormat::U8 => 8,
-                SampleFormat::U16 => 16,
-                SampleFormat::U32 => 32,
-                SampleFormat::U64 => 64,
-                SampleFormat::F32 => 32,
-                SampleFormat::F64 => 64,
-                unknown => panic!("unknown sample format {unknown}"),
-            },
-            sample_format: match self.sample_format {
-                SampleFormat::I8 => hound::SampleFormat::Int,
-                SampleFormat::I16 => hound::SampleFormat::Int,
-                SampleFormat::I32 => hound::SampleFormat::Int,
-                SampleFormat::I64 => hound::SampleFormat::Int,
-                SampleFormat::U8 => hound::SampleFormat::Int,
-                SampleFormat::U16 => hound::SampleFormat::Int,
-                SampleFormat::U32 => hound::SampleFormat::Int,
-                SampleFormat::U64 => hound::SampleFormat::Int,
-                SampleFormat::F32 => hound::SampleFormat::Float,
-                SampleFormat::F64 => hound::SampleFormat::Float,
-                unknown => panic!("unknown sample format {unknown}"),
-            },
+            bits_per_sample,
+            sample_format,
         };
 
         let mut buffer = vec![];
@@ -111,7 +177,13 @@ impl AudioManager {
         {
             let mut writer = hound::WavWriter::new(in_memory_file, spec)?;
             for sample in v {
-                writer.write_sample(sample)?;
+                for _ in 0..self.n_channels {
+                    match format {
+                        SampleFormat::I16 => writer.write_sample(sample.to_sample::<i16>())?,
+                        SampleFormat::I32 => writer.write_sample(sample.to_sample::<i32>())?,
+                        _ => writer.write_sample(sample)?,
+                    }
+                }
             }
             // <- we need writer to be dropped here.
         }
@@ -124,18 +196,40 @@ impl AudioManager {
 mod tests {
     use super::*;
 
-    #[test]
-    fn saves_to_wav() -> anyhow::Result<()> {
+    fn read_test_wav() -> anyhow::Result<VecDeque<f32>> {
         let wav_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test.wav");
-        let audio_manager = AudioManager::default();
         let reader = hound::WavReader::open(wav_path)?;
         let mut data = VecDeque::new();
         for sample in reader.into_samples::<f32>() {
             data.push_back(sample?)
         }
-        let buff = audio_manager.to_wav(data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn saves_to_wav() -> anyhow::Result<()> {
+        let wav_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test.wav");
+        let audio_manager = AudioManager::new(32000, 1, SampleFormat::F32);
+        let buff = audio_manager.to_wav(read_test_wav()?)?;
         let wav_path_content = std::fs::read(wav_path)?;
         assert_eq!(wav_path_content, buff);
         Ok(())
     }
+
+    #[test]
+    fn saves_to_wav_with_custom_format() -> anyhow::Result<()> {
+        let data = read_test_wav()?;
+        let n_samples = data.len();
+        let audio_manager = AudioManager::new(44100, 2, SampleFormat::I16);
+        let buff = audio_manager.to_wav(data)?;
+
+        let reader = hound::WavReader::new(std::io::Cursor::new(buff))?;
+        let spec = reader.spec();
+        assert_eq!(spec.channels, 2);
+        assert_eq!(spec.sample_rate, 44100);
+        assert_eq!(spec.bits_per_sample, 16);
+        assert_eq!(spec.sample_format, hound::SampleFormat::Int);
+        assert_eq!(reader.len() as usize, n_samples * 2);
+        Ok(())
+    }
 }
@@ -0,0 +1,90 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::SupportedStreamConfigRange;
+
+/// Describes the audio hosts available in this platform, and below each of them its output
+/// and input devices with the channels, sample formats and sample rates they support.
+pub fn describe_audio_devices() -> Vec<String> {
+    let mut lines = vec![];
+    for host_id in cpal::available_hosts() {
+        lines.push(host_id.name().to_string());
+        let host = match cpal::host_from_id(host_id) {
+            Ok(host) => host,
+            Err(err) => {
+                lines.push(format!("  unavailable: {err}"));
+                continue;
+            }
+        };
+        let default_output = host.default_output_device().and_then(|v| v.name().ok());
+        let default_input = host.default_input_device().and_then(|v| v.name().ok());
+        let devices = match host.devices() {
+            Ok(devices) => devices,
+            Err(err) => {
+                lines.push(format!("  cannot list devices: {err}"));
+                continue;
+            }
+        };
+        for device in devices {
+            let name = device.name().unwrap_or_else(|_| "Unknown".to_string());
+            let mut defaults = vec![];
+            if default_output.as_ref() == Some(&name) {
+                defaults.push("default output");
+            }
+            if default_input.as_ref() == Some(&name) {
+                defaults.push("default input");
+            }
+            if defaults.is_empty() {
+                lines.push(format!("  {name}"));
+            } else {
+                lines.push(format!("  {name} ({})", defaults.join(", ")));
+            }
+            if let Ok(configs) = device.supported_output_configs() {
+                lines.extend(configs.map(|v| describe_config("output", &v)));
+            }
+            if let Ok(configs) = device.supported_input_configs() {
+                lines.extend(configs.map(|v| describe_config("input", &v)));
+            }
+        }
+    }
+    lines
+}
+
+fn describe_config(kind: &str, config: &SupportedStreamConfigRange) -> String {
+    let (min, max) = (config.min_sample_rate().0, config.max_sample_rate().0);
+    let rates = if min == max {
+        format!("{min} Hz")
+    } else {
+        format!("{min}-{max} Hz")
+    };
+    format!(
+        "    {kind:<6} {}ch {} {rates}",
+        config.channels(),
+        config.sample_format()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use cpal::{SampleFormat, SampleRate, SupportedBufferSize};
+
+    use super::*;
+
+    #[test]
+    fn describes_configs() {
+        let config = SupportedStreamConfigRange::new(
+            2,
+            SampleRate(8000),
+            SampleRate(48000),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        assert_eq!(describe_config("output", &config), "    output 2ch f32 8000-48000 Hz");
+        let config = SupportedStreamConfigRange::new(
+            1,
+            SampleRate(32000),
+            SampleRate(32000),
+            SupportedBufferSize::Unknown,
+            SampleFormat::I16,
+        );
+        assert_eq!(describe_config("input", &config), "    input  1ch i16 32000 Hz");
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+
+/// Scales `samples` so that the loudest one is at `peak_db` dBFS, like -1.0. Silent audios
+/// are left as they are.
+pub fn normalize(samples: &mut VecDeque<f32>, peak_db: f32) {
+    let peak = samples.iter().fold(0.0f32, |max, v| max.max(v.abs()));
+    if peak == 0.0 {
+        return;
+    }
+    let gain = 10f32.powf(peak_db / 20.0) / peak;
+    for sample in samples.iter_mut() {
+        *sample *= gain;
+    }
+}
+
+/// Fades `samples` in during the first `in_secs` and out during the last `out_secs`.
+pub fn fade(samples: &mut VecDeque<f32>, sampling_rate: u32, in_secs: f32, out_secs: f32) {
+    let len = samples.len();
+    let fade_in = ((in_secs * sampling_rate as f32) as usize).min(len);
+    let fade_out = ((out_secs * sampling_rate as f32) as usize).min(len);
+    for (i, sample) in samples.iter_mut().take(fade_in).enumerate() {
+        *sample *= i as f32 / fade_in as f32;
+    }
+    for (i, sample) in samples.iter_mut().rev().take(fade_out).enumerate() {
+        *sample *= i as f32 / fade_out as f32;
+    }
+}
+
+/// The levels of an audio, in dBFS.
+#[derive(Debug, PartialEq)]
+pub struct Loudness {
+    /// The level of the loudest sample.
+    pub peak_db: f32,
+    /// The level of the root mean square of the samples, which is closer to how loud the
+    /// audio sounds.
+    pub rms_db: f32,
+}
+
+/// Measures the levels of `samples`. Silence is at minus infinity.
+pub fn loudness(samples: &VecDeque<f32>) -> Loudness {
+    let peak = samples.iter().fold(0.0f32, |max, v| max.max(v.abs()));
+    let sum_squares: f32 = samples.iter().map(|v| v * v).sum();
+    let rms = (sum_squares / samples.len().max(1) as f32).sqrt();
+    Loudness {
+        peak_db: 20.0 * peak.log10(),
+        rms_db: 20.0 * rms.log10(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_and_fades() {
+        let mut samples = VecDeque::from(vec![0.25, -0.5, 0.25, 0.5]);
+        normalize(&mut samples, 0.0);
+        assert_eq!(samples, VecDeque::from(vec![0.5, -1.0, 0.5, 1.0]));
+
+        let mut silence = VecDeque::from(vec![0.0; 4]);
+        normalize(&mut silence, -1.0);
+        assert_eq!(silence, VecDeque::from(vec![0.0; 4]));
+
+        let mut samples = VecDeque::from(vec![1.0; 6]);
+        fade(&mut samples, 2, 1.0, 0.5);
+        assert_eq!(samples, VecDeque::from(vec![0.0, 0.5, 1.0, 1.0, 1.0, 0.0]));
+    }
+
+    #[test]
+    fn measures_loudness() {
+        let levels = loudness(&VecDeque::from(vec![0.5, -0.5, 0.5, -0.5]));
+        assert!((levels.peak_db + 6.02).abs() < 0.01, "{levels:?}");
+        assert!((levels.rms_db + 6.02).abs() < 0.01, "{levels:?}");
+        assert_eq!(loudness(&VecDeque::new()).peak_db, f32::NEG_INFINITY);
+    }
+}
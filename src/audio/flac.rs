@@ -0,0 +1,155 @@
+use cpal::Sample;
+
+/// Samples in each frame of the encoded files.
+const BLOCK_SIZE: usize = 4096;
+
+/// Encodes mono `samples` as a 16 bit FLAC file with `n_channels` channels, copying the
+/// samples to all of them. Samples are stored verbatim, so files are lossless but not
+/// compressed, like .wav files that any FLAC player can read.
+pub fn encode_flac(samples: &[f32], sampling_rate: u32, n_channels: u16) -> Vec<u8> {
+    let mut out = b"fLaC".to_vec();
+
+    // The only metadata block is the STREAMINFO.
+    let mut info = BitWriter::default();
+    info.write(BLOCK_SIZE as u64, 16);
+    info.write(BLOCK_SIZE as u64, 16);
+    // The sizes of the frames and the MD5 of the samples are unknown.
+    info.write(0, 24);
+    info.write(0, 24);
+    info.write(sampling_rate as u64, 20);
+    info.write(n_channels as u64 - 1, 3);
+    info.write(15, 5);
+    info.write(samples.len() as u64, 36);
+    info.write(0, 64);
+    info.write(0, 64);
+    out.push(0x80); // Last metadata block, STREAMINFO.
+    out.extend_from_slice(&34u32.to_be_bytes()[1..]);
+    out.extend_from_slice(&info.bytes);
+
+    for (i, block) in samples.chunks(BLOCK_SIZE).enumerate() {
+        let mut frame = BitWriter::default();
+        frame.write(0b11111111111110, 14);
+        frame.write(0, 1);
+        // Fixed block size.
+        frame.write(0, 1);
+        // The block size minus one follows the frame number as 16 bits.
+        frame.write(0b0111, 4);
+        // The sampling rate is the one in the STREAMINFO.
+        frame.write(0, 4);
+        // Independent channels.
+        frame.write(n_channels as u64 - 1, 4);
+        // 16 bits per sample.
+        frame.write(0b100, 3);
+        frame.write(0, 1);
+        frame.write_utf8(i as u64);
+        frame.write(block.len() as u64 - 1, 16);
+        let crc = crc8(&frame.bytes);
+        frame.write(crc as u64, 8);
+        for _ in 0..n_channels {
+            // Zero padding bit, verbatim subframe and no wasted bits.
+            frame.write(0b00000010, 8);
+            for sample in block {
+                frame.write(sample.to_sample::<i16>() as u16 as u64, 16);
+            }
+        }
+        let crc = crc16(&frame.bytes);
+        frame.write(crc as u64, 16);
+        out.extend_from_slice(&frame.bytes);
+    }
+    out
+}
+
+/// Writes values with arbitrary amounts of bits, most significant first. Everything
+/// written by the encoder is byte aligned once complete.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, n: u32) {
+        for i in (0..n).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.bits % 8);
+            self.bits += 1;
+        }
+    }
+
+    /// Writes `value` coded like UTF-8 characters, extended to 36 bits.
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            return self.write(value, 8);
+        }
+        let mut n = 2;
+        while value >= 1 << (5 * n + 1) {
+            n += 1;
+        }
+        let prefix = (0xFF00u64 >> n) & 0xFF;
+        let first_bits = 7 - n as u32;
+        let rest = 6 * (n as u32 - 1);
+        self.write(prefix >> first_bits, 8 - first_bits);
+        self.write(value >> rest, first_bits);
+        for i in (0..n as u32 - 1).rev() {
+            self.write(0b10, 2);
+            self.write(value >> (6 * i), 6);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= *byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioFile;
+
+    #[test]
+    fn encodes_flac_files() -> anyhow::Result<()> {
+        let samples: Vec<f32> = (0..10000).map(|i| (i as f32 / 50.0).sin() * 0.5).collect();
+        let flac = encode_flac(&samples, 32000, 2);
+        let audio = AudioFile::from_bytes(flac, Some("flac"))?;
+        assert_eq!(audio.sampling_rate, 32000);
+        assert_eq!(audio.samples.len(), samples.len());
+        for (a, b) in audio.samples.iter().zip(&samples) {
+            assert!((a - b).abs() < 1e-3, "{a} != {b}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn codes_frame_numbers_like_utf8() {
+        for (value, expected) in [
+            (0x41, vec![0x41]),
+            (0xE9, vec![0xC3, 0xA9]),
+            (0x20AC, vec![0xE2, 0x82, 0xAC]),
+        ] {
+            let mut writer = BitWriter::default();
+            writer.write_utf8(value);
+            assert_eq!(writer.bytes, expected);
+        }
+    }
+}
@@ -1,3 +1,16 @@
+mod audio_file;
 mod audio_manager;
+mod devices;
+mod effects;
+mod flac;
+mod mp3;
+mod ogg;
+mod playlist;
+mod wav_chunk;
 
+pub use audio_file::AudioFile;
 pub use audio_manager::{AudioManager, AudioStream};
+pub use devices::describe_audio_devices;
+pub use effects::{fade, loudness, normalize};
+pub use playlist::Playlist;
+pub use wav_chunk::{append_chunk, read_chunk};
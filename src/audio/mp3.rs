@@ -0,0 +1,49 @@
+use anyhow::anyhow;
+use mp3lame_encoder::{
+    max_required_buffer_size, Bitrate, Builder, DualPcm, FlushNoGap, MonoPcm, Quality,
+};
+
+/// Encodes mono `samples` as a 192 kbps MP3 file. MP3 files cannot have more than two
+/// channels, so any `n_channels` above one writes a stereo file.
+pub fn encode_mp3(samples: &[f32], sampling_rate: u32, n_channels: u16) -> anyhow::Result<Vec<u8>> {
+    let mut builder = Builder::new().ok_or(anyhow!("Could not initialize the MP3 encoder"))?;
+    builder.set_num_channels(if n_channels > 1 { 2 } else { 1 })?;
+    builder.set_sample_rate(sampling_rate)?;
+    builder.set_brate(Bitrate::Kbps192)?;
+    builder.set_quality(Quality::Best)?;
+    let mut encoder = builder.build()?;
+
+    let mut out = Vec::with_capacity(max_required_buffer_size(samples.len()));
+    if n_channels > 1 {
+        let input = DualPcm {
+            left: samples,
+            right: samples,
+        };
+        encoder.encode_to_vec(input, &mut out)?;
+    } else {
+        encoder.encode_to_vec(MonoPcm(samples), &mut out)?;
+    }
+    // The last frame takes at most 7200 bytes.
+    out.reserve(7200);
+    encoder.flush_to_vec::<FlushNoGap>(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioFile;
+
+    #[test]
+    fn encodes_mp3_files() -> anyhow::Result<()> {
+        let samples: Vec<f32> = (0..32000).map(|i| (i as f32 / 50.0).sin() * 0.5).collect();
+        let mp3 = encode_mp3(&samples, 32000, 2)?;
+        let audio = AudioFile::from_bytes(mp3, Some("mp3"))?;
+        assert_eq!(audio.sampling_rate, 32000);
+        assert_eq!(audio.channels, 2);
+        // The encoder pads the audio up to whole frames.
+        assert!(audio.samples.len() >= samples.len());
+        assert!(audio.samples.len() < samples.len() + 4 * 1152);
+        Ok(())
+    }
+}
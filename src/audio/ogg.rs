@@ -0,0 +1,42 @@
+use std::num::{NonZeroU32, NonZeroU8};
+
+use anyhow::anyhow;
+use vorbis_rs::VorbisEncoderBuilder;
+
+/// Samples handed to the encoder at a time, libvorbis gets slow with bigger blocks.
+const BLOCK_SIZE: usize = 4096;
+
+/// Encodes mono `samples` as an OGG Vorbis file with `n_channels` channels, copying the
+/// samples to all of them.
+pub fn encode_ogg(samples: &[f32], sampling_rate: u32, n_channels: u16) -> anyhow::Result<Vec<u8>> {
+    let sampling_rate =
+        NonZeroU32::new(sampling_rate).ok_or(anyhow!("Invalid sampling rate {sampling_rate}"))?;
+    let channels = u8::try_from(n_channels)
+        .ok()
+        .and_then(NonZeroU8::new)
+        .ok_or(anyhow!("OGG files cannot have {n_channels} channels"))?;
+    let mut encoder = VorbisEncoderBuilder::new(sampling_rate, channels, vec![])?.build()?;
+    for block in samples.chunks(BLOCK_SIZE) {
+        encoder.encode_audio_block(vec![block; n_channels as usize])?;
+    }
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioFile;
+
+    #[test]
+    fn encodes_ogg_files() -> anyhow::Result<()> {
+        let samples: Vec<f32> = (0..32000).map(|i| (i as f32 / 50.0).sin() * 0.5).collect();
+        let ogg = encode_ogg(&samples, 32000, 2)?;
+        let audio = AudioFile::from_bytes(ogg, Some("ogg"))?;
+        assert_eq!(audio.sampling_rate, 32000);
+        assert_eq!(audio.channels, 2);
+        // The last packet may be decoded with some padding.
+        assert!(audio.samples.len() >= samples.len());
+        assert!(audio.samples.len() < samples.len() + BLOCK_SIZE);
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct PlaylistState {
+    tracks: Vec<(String, Vec<f32>)>,
+    current: usize,
+    position: usize,
+}
+
+/// An ordered list of tracks that are played back-to-back. The playlist is cheap to
+/// clone, and all the clones share the same state, so one clone can be handed to
+/// an audio stream while another one is used for queueing and navigating tracks.
+#[derive(Clone, Default)]
+pub struct Playlist {
+    state: Arc<Mutex<PlaylistState>>,
+}
+
+impl Playlist {
+    /// Appends a track to the end of the playlist. If the playlist had already finished
+    /// playing all of its tracks, the new track will start playing right away.
+    pub fn push(&self, name: impl Into<String>, samples: VecDeque<f32>) {
+        let mut state = self.state.lock().unwrap();
+        state.tracks.push((name.into(), samples.into()));
+    }
+
+    /// Skips to the beginning of the next track. Returns false if there is no next track.
+    pub fn next(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.current + 1 >= state.tracks.len() {
+            return false;
+        }
+        state.current += 1;
+        state.position = 0;
+        true
+    }
+
+    /// Goes back to the beginning of the previous track. Returns false if there is no
+    /// previous track.
+    pub fn prev(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.current == 0 || state.tracks.is_empty() {
+            return false;
+        }
+        state.current = (state.current - 1).min(state.tracks.len() - 1);
+        state.position = 0;
+        true
+    }
+
+    /// Returns the names of all the tracks, along with the index of the track currently
+    /// playing, which is None if all the tracks have already been played.
+    pub fn tracks(&self) -> (Vec<String>, Option<usize>) {
+        let state = self.state.lock().unwrap();
+        let names = state.tracks.iter().map(|(n, _)| n.clone()).collect();
+        let current = (state.current < state.tracks.len()).then_some(state.current);
+        (names, current)
+    }
+
+    /// Pulls the next sample to be played. As soon as a track ends, the first sample of
+    /// the following one is returned, so there are no gaps between tracks.
+    pub(crate) fn next_sample(&self) -> Option<f32> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let (current, position) = (state.current, state.position);
+            let (_, samples) = state.tracks.get(current)?;
+            if let Some(sample) = samples.get(position).copied() {
+                state.position += 1;
+                return Some(sample);
+            }
+            state.current += 1;
+            state.position = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(playlist: &Playlist, n: usize) -> Vec<Option<f32>> {
+        (0..n).map(|_| playlist.next_sample()).collect()
+    }
+
+    #[test]
+    fn plays_tracks_back_to_back() {
+        let playlist = Playlist::default();
+        playlist.push("a", VecDeque::from([1.0, 2.0]));
+        playlist.push("b", VecDeque::from([3.0]));
+        assert_eq!(
+            drain(&playlist, 4),
+            vec![Some(1.0), Some(2.0), Some(3.0), None]
+        );
+
+        // Tracks pushed after the playlist finished start playing immediately.
+        playlist.push("c", VecDeque::from([4.0]));
+        assert_eq!(drain(&playlist, 2), vec![Some(4.0), None]);
+        assert_eq!(
+            playlist.tracks(),
+            (vec!["a".into(), "b".into(), "c".into()], None)
+        );
+    }
+
+    #[test]
+    fn navigates_tracks() {
+        let playlist = Playlist::default();
+        playlist.push("a", VecDeque::from([1.0, 2.0]));
+        playlist.push("b", VecDeque::from([3.0, 4.0]));
+
+        assert!(!playlist.prev());
+        assert_eq!(playlist.next_sample(), Some(1.0));
+        assert!(playlist.next());
+        assert_eq!(playlist.next_sample(), Some(3.0));
+        assert!(!playlist.next());
+        assert!(playlist.prev());
+        assert_eq!(playlist.tracks().1, Some(0));
+        assert_eq!(drain(&playlist, 3), vec![Some(1.0), Some(2.0), Some(3.0)]);
+    }
+}
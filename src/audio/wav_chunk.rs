@@ -0,0 +1,77 @@
+use anyhow::anyhow;
+
+const HEADER_LEN: usize = 12;
+
+/// Appends a chunk with `id` and `data` to the end of the .wav file in `wav`. Players ignore
+/// the chunks they do not know, so they can hold information about the audio.
+pub fn append_chunk(mut wav: Vec<u8>, id: &[u8; 4], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if !is_wav(&wav) {
+        return Err(anyhow!("Not a .wav file"));
+    }
+    // Chunks start at even offsets.
+    if wav.len() % 2 == 1 {
+        wav.push(0);
+    }
+    wav.extend_from_slice(id);
+    wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        wav.push(0);
+    }
+    let riff_len = (wav.len() - 8) as u32;
+    wav[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Ok(wav)
+}
+
+/// Returns the data of the first chunk with `id` in the .wav file in `wav`, if any.
+pub fn read_chunk<'a>(wav: &'a [u8], id: &[u8; 4]) -> anyhow::Result<Option<&'a [u8]>> {
+    if !is_wav(wav) {
+        return Err(anyhow!("Not a .wav file"));
+    }
+    let mut offset = HEADER_LEN;
+    while offset + 8 <= wav.len() {
+        let len = u32::from_le_bytes(wav[offset + 4..offset + 8].try_into()?) as usize;
+        let start = offset + 8;
+        let end = start.saturating_add(len).min(wav.len());
+        if &wav[offset..offset + 4] == id {
+            return Ok(Some(&wav[start..end]));
+        }
+        offset = end + len % 2;
+    }
+    Ok(None)
+}
+
+fn is_wav(wav: &[u8]) -> bool {
+    wav.len() >= HEADER_LEN && &wav[0..4] == b"RIFF" && &wav[8..12] == b"WAVE"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{AudioFile, AudioManager};
+    use cpal::SampleFormat;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn appends_and_reads_chunks() -> anyhow::Result<()> {
+        let audio_manager = AudioManager::new(32000, 1, SampleFormat::I16);
+        let wav = audio_manager.to_wav(VecDeque::from(vec![0.0, 0.5, -0.5]))?;
+        assert_eq!(read_chunk(&wav, b"test")?, None);
+
+        let wav = append_chunk(wav, b"test", b"odd")?;
+        let wav = append_chunk(wav, b"next", b"even")?;
+        assert_eq!(wav.len() % 2, 0);
+        assert_eq!(
+            u32::from_le_bytes(wav[4..8].try_into()?) as usize,
+            wav.len() - 8
+        );
+        assert_eq!(read_chunk(&wav, b"test")?, Some(&b"odd"[..]));
+        assert_eq!(read_chunk(&wav, b"next")?, Some(&b"even"[..]));
+
+        // The audio is still readable.
+        let audio = AudioFile::from_bytes(wav, Some("wav"))?;
+        assert_eq!(audio.samples.len(), 3);
+        assert!(append_chunk(vec![0; 20], b"test", b"").is_err());
+        Ok(())
+    }
+}
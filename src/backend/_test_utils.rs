@@ -1,19 +1,34 @@
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::time::Duration;
 
 use async_trait::async_trait;
+use cpal::SampleFormat;
+use futures_util::{SinkExt, StreamExt};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_util::sync::CancellationToken;
 
+use crate::audio::AudioManager;
 use crate::backend::audio_generation_backend::{
-    AudioGenerationRequest, BackendOutboundMsg, JobProcessor,
+    AudioGenerationRequest, BackendOutboundMsg, GenerationParams, GenerationStage, GenerationStats,
+    GenerationTimings, JobProcessor, OnPartialAudio, OnProgress,
 };
 use crate::backend::audio_generation_fanout::{
     AudioGenerationError, AudioGenerationProgress, AudioGenerationResult, AudioGenerationStart,
-    GenerationMessage,
+    GenerationMessage, ProgressThrottle,
 };
-use crate::backend::music_gpt_chat::{Chat, ChatEntry};
+use crate::backend::generation_limits::GenerationLimits;
+use crate::backend::music_gpt_chat::{Chat, ChatEntry, ChatsPage};
 use crate::backend::music_gpt_ws_handler::{Info, OutboundMsg};
+use crate::backend::presence::Client;
+use crate::backend::server::{run_web_server, RunWebServerOptions};
+use crate::backend::ws_handler::DEFAULT_PING_INTERVAL;
 use crate::storage::AppFs;
 
 impl OutboundMsg {
@@ -25,6 +40,10 @@ impl OutboundMsg {
     }
 
     pub(crate) fn chats(self) -> Vec<Chat> {
+        self.chats_page().chats
+    }
+
+    pub(crate) fn chats_page(self) -> ChatsPage {
         match self {
             OutboundMsg::Chats(p) => p,
             _ => panic!("msg was not OutboundMsg::Chats, it was {self:?}"),
@@ -77,12 +96,14 @@ impl BackendOutboundMsg {
 
     pub(crate) fn unwrap_progress(self) -> (String, f32) {
         match self {
-            BackendOutboundMsg::Progress(p) => p,
+            BackendOutboundMsg::Progress((id, stage)) => (id, stage.progress()),
             _ => panic!("msg was not Progress, it was {self:?}"),
         }
     }
 
-    pub(crate) fn unwrap_response(self) -> (String, VecDeque<f32>) {
+    pub(crate) fn unwrap_response(
+        self,
+    ) -> (String, VecDeque<f32>, GenerationTimings, GenerationStats) {
         match self {
             BackendOutboundMsg::Response(p) => p,
             _ => panic!("msg was not Response, it was {self:?}"),
@@ -100,11 +121,21 @@ impl BackendOutboundMsg {
 #[derive(Default)]
 pub struct DummyJobProcessor {
     wait_scale: Duration,
+    reference: bool,
 }
 
 impl DummyJobProcessor {
     pub fn new(wait_scale: Duration) -> Self {
-        Self { wait_scale }
+        Self {
+            wait_scale,
+            reference: false,
+        }
+    }
+
+    /// Accepts generations conditioned on a reference audio.
+    pub fn with_reference(mut self) -> Self {
+        self.reference = true;
+        self
     }
 }
 
@@ -114,8 +145,13 @@ impl JobProcessor for DummyJobProcessor {
         &self,
         prompt: &str,
         secs: usize,
-        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>> {
+        params: &GenerationParams,
+        on_progress: OnProgress,
+        on_partial_audio: Option<OnPartialAudio>,
+    ) -> ort::Result<(VecDeque<f32>, GenerationTimings)> {
+        if params.reference.is_some() && !self.reference {
+            return Err(ort::Error::new("References are not supported"));
+        }
         let mut result = VecDeque::new();
         for i in 0..secs {
             if prompt == format!("fail at {i}") {
@@ -123,13 +159,27 @@ impl JobProcessor for DummyJobProcessor {
             }
             std::thread::sleep(self.wait_scale);
             result.push_back(i as f32);
-            let should_exit = on_progress(result.len() as f32, secs as f32);
+            if let Some(on_partial_audio) = &on_partial_audio {
+                on_partial_audio(VecDeque::from([i as f32]));
+            }
+            let should_exit = on_progress(GenerationStage::TokenGeneration {
+                done: result.len(),
+                total: secs,
+            });
             if should_exit {
                 return Err(ort::Error::new("Aborted"));
             }
         }
 
-        Ok(result)
+        let timings = GenerationTimings {
+            token_generation_secs: (self.wait_scale * secs as u32).as_secs_f32(),
+            ..GenerationTimings::default()
+        };
+        Ok((result, timings))
+    }
+
+    fn supports_reference(&self) -> bool {
+        self.reference
     }
 }
 
@@ -146,3 +196,125 @@ impl AppFs {
         Self::new(format!("/tmp/musicgpt-tests/{}", rand_string()))
     }
 }
+
+#[async_trait]
+pub trait TungsteniteMsg: Sized {
+    async fn to_ws(self, ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>)
+        -> anyhow::Result<()>;
+
+    async fn from_ws(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> anyhow::Result<Self>;
+}
+
+#[async_trait]
+impl<T: Serialize + DeserializeOwned + Send> TungsteniteMsg for T {
+    async fn to_ws(
+        self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ) -> anyhow::Result<()> {
+        let msg = serde_json::to_string(&self).expect("Could not serialize msg");
+        Ok(ws
+            .send(tokio_tungstenite::tungstenite::Message::Text(msg))
+            .await?)
+    }
+
+    async fn from_ws(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> anyhow::Result<Self> {
+        // Binary messages are partial audios and clients come and go at any time, both
+        // are tested separately.
+        let msg = loop {
+            match ws.next().await.unwrap()? {
+                Message::Binary(_) | Message::Ping(_) | Message::Pong(_) => continue,
+                Message::Text(text) if text.starts_with("{\"Clients\"") => continue,
+                msg => break msg,
+            }
+        };
+        Ok(serde_json::de::from_str(msg.to_text()?)?)
+    }
+}
+
+pub async fn next_clients(
+    ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+) -> anyhow::Result<Vec<Client>> {
+    loop {
+        if let Message::Text(text) = ws.next().await.unwrap()? {
+            if let Ok(OutboundMsg::Clients(clients)) = serde_json::from_str(&text) {
+                return Ok(clients);
+            }
+        }
+    }
+}
+
+static PORT: AtomicU16 = AtomicU16::new(8643);
+
+/// A port not used by any other test.
+pub fn next_port() -> u16 {
+    PORT.fetch_add(1, Ordering::SeqCst)
+}
+
+pub async fn spawn<P: JobProcessor + 'static>(
+    processor: P,
+) -> anyhow::Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, String)> {
+    spawn_with_storage(processor, AppFs::new_tmp()).await
+}
+
+pub async fn spawn_with_storage<P: JobProcessor + 'static>(
+    processor: P,
+    app_fs: AppFs,
+) -> anyhow::Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, String)> {
+    spawn_server(processor, app_fs, |_| {}).await
+}
+
+/// The options of the servers spawned by the tests, listening in `port`.
+pub fn test_options(port: usize) -> RunWebServerOptions {
+    RunWebServerOptions {
+        name: "Dummy".to_string(),
+        model_id: "dummy".to_string(),
+        device: "Cpu".to_string(),
+        port,
+        auto_open: false,
+        host: "127.0.0.1".to_string(),
+        cors_origins: vec!["http://allowed.example".to_string()],
+        audio_manager: AudioManager::new(32000, 1, SampleFormat::F32),
+        shutdown: CancellationToken::new(),
+        shutdown_grace: Duration::from_secs(1),
+        abort_on_ctrl_c: false,
+        stream_audio: true,
+        workers: 1,
+        dedupe: false,
+        web_dir: None,
+        limits: GenerationLimits::default(),
+        require_api_key: false,
+        socket: None,
+        idle_timeout: None,
+        progress_throttle: ProgressThrottle::default(),
+        ws_ping_interval: DEFAULT_PING_INTERVAL,
+        export: None,
+        prompt_enhancer: None,
+    }
+}
+
+pub async fn spawn_server<P: JobProcessor + 'static>(
+    processor: P,
+    app_fs: AppFs,
+    configure: impl FnOnce(&mut RunWebServerOptions),
+) -> anyhow::Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, String)> {
+    let port = next_port() as usize;
+    let mut run_options = test_options(port);
+    configure(&mut run_options);
+    tokio::spawn(run_web_server(
+        app_fs.root.clone(),
+        app_fs,
+        processor,
+        run_options,
+    ));
+    // The server needs some time for booting, so retry the connection a few times.
+    let mut retries = 0;
+    let ws_stream = loop {
+        match connect_async(&format!("ws://localhost:{port}/ws")).await {
+            Ok((ws_stream, _)) => break ws_stream,
+            Err(_) if retries < 50 => retries += 1,
+            Err(err) => return Err(err.into()),
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    };
+    Ok((ws_stream, format!("localhost:{port}")))
+}
@@ -0,0 +1,182 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::storage::Storage;
+
+const KEYS_DIR: &str = "keys";
+const KEY_PREFIX: &str = "mgpt-";
+
+/// How long [ApiKeys] trusts its hashes before reading the keys again, so that the keys
+/// created or revoked with the CLI while the server runs are picked up.
+const KEYS_TTL: Duration = Duration::from_secs(10);
+
+/// An API key for accessing the server programmatically. Only the hash of the key is
+/// stored, the key itself is shown once when created.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub hash: String,
+    pub created_at: u128,
+}
+
+fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+impl ApiKey {
+    /// Creates and stores a new API key, returning it along with the secret key that
+    /// clients will need to send in the `Authorization: Bearer <key>` header.
+    pub async fn create<S: Storage>(storage: &S, name: String) -> anyhow::Result<(Self, String)> {
+        let secret: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let secret = format!("{KEY_PREFIX}{secret}");
+        let key = Self {
+            id: Uuid::new_v4(),
+            name,
+            hash: hash(&secret),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+        };
+        let path = format!("{KEYS_DIR}/{}.json", key.id);
+        storage.write(&path, serde_json::to_vec(&key)?).await?;
+        Ok((key, secret))
+    }
+
+    /// Loads all the API keys, oldest first.
+    pub async fn load_all<S: Storage>(storage: &S) -> anyhow::Result<Vec<Self>> {
+        let mut result = vec![];
+        for file in storage.list(KEYS_DIR).await? {
+            let Some(content) = storage.read(&file).await? else {
+                continue;
+            };
+            match serde_json::from_slice::<Self>(&content) {
+                Ok(key) => result.push(key),
+                Err(_) => continue,
+            }
+        }
+        result.sort_by_key(|v| v.created_at);
+        Ok(result)
+    }
+
+    /// Revokes an API key, returning whether it existed.
+    pub async fn revoke<S: Storage>(storage: &S, id: Uuid) -> anyhow::Result<bool> {
+        Ok(storage.rm(&format!("{KEYS_DIR}/{id}.json")).await?)
+    }
+}
+
+/// The hashes of the API keys along with when they were read.
+type LoadedHashes = Option<(Instant, HashSet<String>)>;
+
+/// The hashes of the API keys that were not revoked, loaded once and shared by all the
+/// requests instead of reading every key for each of them.
+#[derive(Clone)]
+pub struct ApiKeys<S> {
+    storage: S,
+    hashes: Arc<RwLock<LoadedHashes>>,
+}
+
+impl<S: Storage> ApiKeys<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            hashes: Arc::default(),
+        }
+    }
+
+    /// Whether `secret` is one of the API keys that were not revoked.
+    pub async fn verify(&self, secret: &str) -> anyhow::Result<bool> {
+        let hash = hash(secret);
+        if let Some((loaded_at, hashes)) = &*self.hashes.read().unwrap() {
+            if loaded_at.elapsed() < KEYS_TTL {
+                return Ok(hashes.contains(&hash));
+            }
+        }
+        Ok(self.refresh().await?.contains(&hash))
+    }
+
+    /// See [ApiKey::create].
+    pub async fn create(&self, name: String) -> anyhow::Result<(ApiKey, String)> {
+        let result = ApiKey::create(&self.storage, name).await?;
+        self.refresh().await?;
+        Ok(result)
+    }
+
+    /// See [ApiKey::revoke].
+    pub async fn revoke(&self, id: Uuid) -> anyhow::Result<bool> {
+        let existed = ApiKey::revoke(&self.storage, id).await?;
+        self.refresh().await?;
+        Ok(existed)
+    }
+
+    async fn refresh(&self) -> anyhow::Result<HashSet<String>> {
+        let hashes: HashSet<String> = ApiKey::load_all(&self.storage)
+            .await?
+            .into_iter()
+            .map(|v| v.hash)
+            .collect();
+        *self.hashes.write().unwrap() = Some((Instant::now(), hashes.clone()));
+        Ok(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn manages_api_keys() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let keys = ApiKeys::new(storage.clone());
+        assert!(!keys.verify("mgpt-foo").await?);
+        let (first, first_secret) = keys.create("first".to_string()).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        let (second, second_secret) = keys.create("second".to_string()).await?;
+        assert_ne!(first.hash, first_secret);
+
+        assert_eq!(
+            ApiKey::load_all(&storage).await?,
+            vec![first.clone(), second]
+        );
+        assert!(keys.verify(&first_secret).await?);
+        assert!(!keys.verify("mgpt-foo").await?);
+
+        assert!(keys.revoke(first.id).await?);
+        assert!(!keys.revoke(first.id).await?);
+        assert!(!keys.verify(&first_secret).await?);
+        assert!(keys.verify(&second_secret).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn caches_api_keys() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let keys = ApiKeys::new(storage.clone());
+        let (key, secret) = keys.create("test".to_string()).await?;
+
+        // Revoking the key in another process is only noticed once the cache expires.
+        ApiKey::revoke(&storage, key.id).await?;
+        assert!(keys.verify(&secret).await?);
+        if let Some((loaded_at, _)) = &mut *keys.hashes.write().unwrap() {
+            *loaded_at -= KEYS_TTL;
+        }
+        assert!(!keys.verify(&secret).await?);
+        Ok(())
+    }
+}
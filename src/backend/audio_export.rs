@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+/// Longest part of the exported file names that comes from the prompt.
+const MAX_PROMPT_LEN: usize = 60;
+
+/// Copies every audio generated in the web app to a directory chosen by the user, as
+/// the audios in the data dir are named after their ids and are hard to find.
+#[derive(Clone, Debug)]
+pub struct AudioExport {
+    pub dir: PathBuf,
+    /// Name of the exported files, in which `{prompt}`, `{date}`, `{id}` and `{chat_id}`
+    /// are replaced by the ones of each audio.
+    pub file_name: String,
+    /// Also writes a .json file with the parameters of each audio next to it.
+    pub metadata: bool,
+}
+
+/// Turns a prompt into something that can be safely used in a file name.
+fn sanitize(prompt: &str) -> String {
+    let mut result = String::new();
+    for c in prompt.trim().chars() {
+        if c.is_alphanumeric() || c == '-' {
+            result.push(c)
+        } else if !result.ends_with('_') {
+            result.push('_')
+        }
+        if result.chars().count() >= MAX_PROMPT_LEN {
+            break;
+        }
+    }
+    let result = result.trim_matches('_');
+    if result.is_empty() {
+        "audio".to_string()
+    } else {
+        result.to_string()
+    }
+}
+
+impl AudioExport {
+    fn file_name(&self, id: Uuid, chat_id: Uuid, prompt: &str, date: &str) -> String {
+        let mut name = self
+            .file_name
+            .replace("{prompt}", &sanitize(prompt))
+            .replace("{date}", date)
+            .replace("{id}", &id.to_string())
+            .replace("{chat_id}", &chat_id.to_string());
+        if !name.ends_with(".wav") {
+            name += ".wav";
+        }
+        name
+    }
+
+    /// Writes the .wav `bytes` of a generated audio to the export dir, without overwriting
+    /// previous exports with the same name. Returns where it was written.
+    pub async fn export(
+        &self,
+        id: Uuid,
+        chat_id: Uuid,
+        prompt: &str,
+        bytes: &[u8],
+    ) -> anyhow::Result<PathBuf> {
+        let date_format =
+            time::format_description::parse("[year]-[month]-[day]_[hour]-[minute]-[second]")?;
+        let date = time::OffsetDateTime::now_utc().format(&date_format)?;
+        let name = self.file_name(id, chat_id, prompt, &date);
+        let mut path = self.dir.join(&name);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let stem = name.strip_suffix(".wav").unwrap_or(&name);
+        let mut n = 1;
+        while tokio::fs::try_exists(&path).await? {
+            path = self.dir.join(format!("{stem}_{n}.wav"));
+            n += 1;
+        }
+        tokio::fs::write(&path, bytes).await?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[test]
+    fn renders_file_names() {
+        let export = AudioExport {
+            dir: PathBuf::new(),
+            file_name: "{date} {prompt}".to_string(),
+            metadata: false,
+        };
+        let name = export.file_name(Uuid::nil(), Uuid::nil(), " Lo-fi beats, for 10/10 ", "d");
+        assert_eq!(name, "d Lo-fi_beats_for_10_10.wav");
+        assert_eq!(sanitize("..."), "audio");
+        assert_eq!(sanitize(&"a".repeat(100)).len(), MAX_PROMPT_LEN);
+
+        let export = AudioExport {
+            dir: PathBuf::new(),
+            file_name: "{chat_id}/{id}.wav".to_string(),
+            metadata: false,
+        };
+        let id = Uuid::new_v4();
+        let name = export.file_name(id, Uuid::nil(), "", "d");
+        assert_eq!(name, format!("{}/{id}.wav", Uuid::nil()));
+    }
+
+    #[tokio::test]
+    async fn exports_without_overwriting() -> anyhow::Result<()> {
+        let export = AudioExport {
+            dir: AppFs::new_tmp().root,
+            file_name: "{prompt}".to_string(),
+            metadata: false,
+        };
+        let first = export
+            .export(Uuid::new_v4(), Uuid::nil(), "Rock", b"1")
+            .await?;
+        let second = export
+            .export(Uuid::new_v4(), Uuid::nil(), "Rock", b"2")
+            .await?;
+        assert_eq!(first, export.dir.join("Rock.wav"));
+        assert_eq!(second, export.dir.join("Rock_1.wav"));
+        assert_eq!(tokio::fs::read(&first).await?, b"1");
+        assert_eq!(tokio::fs::read(&second).await?, b"2");
+        Ok(())
+    }
+}
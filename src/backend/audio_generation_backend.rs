@@ -1,29 +1,115 @@
-use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::sync::Notify;
 use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::backend::prompt_enhancer::PromptEnhancer;
+use crate::debug_bundle;
 
 #[derive(Clone, Debug)]
 pub struct AudioGenerationRequest {
     pub id: String,
     pub prompt: String,
     pub secs: usize,
+    /// The plugin that processes the job, none for the model MusicGPT was started with.
+    pub model: Option<String>,
+    /// Whether the request can be answered with the result of an identical one, if the
+    /// backend deduplicates requests.
+    pub dedupe: bool,
+    /// The seed for sampling the audio, a random one is chosen when the job starts if none.
+    pub seed: Option<u64>,
+    /// The audio the generation is conditioned on, shared by the clones of the request.
+    pub reference: Option<Arc<ReferenceSamples>>,
+}
+
+impl AudioGenerationRequest {
+    /// Identifies the parameters of the request, regardless of its id.
+    fn dedupe_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (&self.prompt, self.secs, &self.model, self.seed).hash(&mut hasher);
+        if let Some(reference) = &self.reference {
+            reference.sampling_rate.hash(&mut hasher);
+            for sample in &reference.samples {
+                sample.to_bits().hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum BackendInboundMsg {
     Request(AudioGenerationRequest),
     Abort(String),
+    /// Aborts the job being processed and removes all the others from the queue, which
+    /// fail as aborted.
+    AbortAll,
 }
 
 #[derive(Clone, Debug)]
 pub enum BackendOutboundMsg {
     Start(AudioGenerationRequest),
-    Response((String, VecDeque<f32>)),
+    /// The prompt of a job after being rewritten by the prompt enhancer, sent before it
+    /// starts.
+    PromptEnhanced((String, String)),
+    Response((String, VecDeque<f32>, GenerationTimings, GenerationStats)),
     Failure((String, String)),
-    Progress((String, f32)),
+    Progress((String, GenerationStage)),
+    /// Samples of a job that is still being processed, along with the offset of the
+    /// first one among all the samples of the job.
+    PartialAudio((String, usize, VecDeque<f32>)),
+    /// A job that is waiting in the queue, along with its position, starting at 1 for the
+    /// job that will be processed next, and an estimation of the seconds until it's finished.
+    Queued((AudioGenerationRequest, usize, Option<f32>)),
+}
+
+/// How a job was processed, besides the timings reported by its processor.
+#[derive(Clone, Debug, Default, PartialEq, Type, Serialize, Deserialize)]
+pub struct GenerationStats {
+    /// Seconds since the job was taken from the queue until it finished.
+    pub wall_secs: f32,
+    /// Tokens generated per second, if the processor reports the token generation.
+    pub tokens_per_sec: Option<f32>,
+    /// Peak resident memory of MusicGPT when the job finished, in bytes, if known.
+    pub peak_rss_bytes: Option<u64>,
+    /// Where the job was processed, like "Cpu", or the plugin that processed it.
+    pub device: String,
+    /// The seed the audio was sampled with, for generating it again.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+type JobResult = ort::Result<(VecDeque<f32>, GenerationTimings, GenerationStats)>;
+
+/// The peak resident memory of the process, in bytes. Only known in Linux.
+fn peak_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+/// How long the results of the jobs can answer identical requests.
+const DEDUPE_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// How many results are kept for answering identical requests, as they take memory.
+const MAX_RECENT_RESULTS: usize = 16;
+
+struct RecentResult {
+    key: u64,
+    finished_at: Instant,
+    samples: VecDeque<f32>,
+    timings: GenerationTimings,
+    stats: GenerationStats,
 }
 
 #[derive(Clone, Debug)]
@@ -41,74 +127,481 @@ impl Job {
     }
 }
 
-pub trait JobProcessor: Send + Sync {
-    fn process(
+pub use musicgpt_core::{
+    GenerationParams, GenerationStage, GenerationTimings, JobProcessor, OnPartialAudio, OnProgress,
+    ReferenceSamples,
+};
+
+/// Like [JobProcessor], but for processors that mostly wait on IO, like the ones that
+/// run the inference remotely, which do not need a thread of their own.
+#[async_trait]
+pub trait AsyncJobProcessor: Send + Sync {
+    /// See [JobProcessor::process].
+    async fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        params: &GenerationParams,
+        on_progress: OnProgress,
+        on_partial_audio: Option<OnPartialAudio>,
+    ) -> ort::Result<(VecDeque<f32>, GenerationTimings)>;
+
+    /// See [JobProcessor::supports_reference].
+    fn supports_reference(&self) -> bool {
+        false
+    }
+}
+
+/// Runs a blocking [JobProcessor] in tokio's blocking threads, so that it does not block
+/// the runtime.
+struct Blocking<T>(Arc<T>);
+
+#[async_trait]
+impl<T: JobProcessor + 'static> AsyncJobProcessor for Blocking<T> {
+    async fn process(
         &self,
         prompt: &str,
         secs: usize,
-        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>>;
+        params: &GenerationParams,
+        on_progress: OnProgress,
+        on_partial_audio: Option<OnPartialAudio>,
+    ) -> ort::Result<(VecDeque<f32>, GenerationTimings)> {
+        let processor = self.0.clone();
+        let prompt = prompt.to_string();
+        let params = params.clone();
+        tokio::task::spawn_blocking(move || {
+            processor.process(&prompt, secs, &params, on_progress, on_partial_audio)
+        })
+        .await
+        .map_err(|err| ort::Error::new(err.to_string()))?
+    }
+
+    fn supports_reference(&self) -> bool {
+        self.0.supports_reference()
+    }
 }
 
 #[derive(Clone)]
 pub struct AudioGenerationBackend {
-    processor: Arc<dyn JobProcessor>,
+    processor: Arc<dyn AsyncJobProcessor>,
+    /// Additional processors, like the ones provided by plugins, by the model jobs ask for.
+    processors: Arc<HashMap<String, Arc<dyn AsyncJobProcessor>>>,
     job_queue: Arc<RwLock<VecDeque<Job>>>,
+    /// Notified when jobs are added to the queue, for waking up the idle workers.
+    new_jobs: Arc<Notify>,
     abort_token: CancellationToken,
+    /// Progress of the jobs currently being processed, from 0 to 1, by id.
+    running_jobs: Arc<RwLock<HashMap<String, f32>>>,
+    /// Wall clock seconds that it takes to generate one second of audio, averaged over
+    /// recent jobs. None until the first job finishes.
+    secs_per_audio_sec: Arc<RwLock<Option<f32>>>,
+    /// How many jobs are processed at the same time.
+    workers: usize,
+    /// Once cancelled, no more jobs are taken from the queue.
+    draining: CancellationToken,
+    /// Whether to send the audio of the jobs as it gets generated.
+    partial_audio: bool,
+    prompt_enhancer: Option<Arc<PromptEnhancer>>,
+    /// Where the jobs that are not processed by plugins run, like "Cpu".
+    device: String,
+    /// Whether to answer requests with the result of an identical job.
+    dedupe: bool,
+    /// Requests that are answered with the result of an identical job in the queue, by
+    /// the id of that job.
+    duplicates: Arc<RwLock<HashMap<String, Vec<AudioGenerationRequest>>>>,
+    /// The results of the last jobs, most recent first.
+    recent_results: Arc<RwLock<VecDeque<RecentResult>>>,
 }
 
 impl AudioGenerationBackend {
     pub fn new<T: JobProcessor + 'static>(processor: T) -> Self {
+        Self::new_async(Blocking(Arc::new(processor)))
+    }
+
+    pub fn new_async<T: AsyncJobProcessor + 'static>(processor: T) -> Self {
         Self {
             processor: Arc::new(processor),
+            processors: Arc::new(HashMap::new()),
             job_queue: Arc::new(RwLock::new(VecDeque::new())),
+            new_jobs: Arc::new(Notify::new()),
             abort_token: CancellationToken::new(),
+            running_jobs: Arc::new(RwLock::new(HashMap::new())),
+            secs_per_audio_sec: Arc::new(RwLock::new(None)),
+            workers: 1,
+            draining: CancellationToken::new(),
+            partial_audio: false,
+            prompt_enhancer: None,
+            device: "Unknown".to_string(),
+            dedupe: false,
+            duplicates: Arc::new(RwLock::new(HashMap::new())),
+            recent_results: Arc::new(RwLock::new(VecDeque::new())),
+        }
+    }
+
+    /// Sends [BackendOutboundMsg::PartialAudio] messages as the audio of jobs is being
+    /// generated. Depending on the processor, this might make the generation slower.
+    pub fn with_partial_audio(mut self, enabled: bool) -> Self {
+        self.partial_audio = enabled;
+        self
+    }
+
+    /// Rewrites the prompt of the jobs with `prompt_enhancer` before processing them. If
+    /// it fails, the original prompt is used.
+    pub fn with_prompt_enhancer(mut self, prompt_enhancer: Option<PromptEnhancer>) -> Self {
+        self.prompt_enhancer = prompt_enhancer.map(Arc::new);
+        self
+    }
+
+    /// Processes up to `workers` jobs at the same time. The workers share the processors,
+    /// so the models are loaded once, but each running job needs its own memory.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Reports `device` in the stats of the jobs that are not processed by plugins.
+    pub fn with_device(mut self, device: &str) -> Self {
+        self.device = device.to_string();
+        self
+    }
+
+    /// Answers the requests that allow it with the result of an identical job, with the
+    /// same prompt, seconds, model and seed, if it's in the queue or it finished recently.
+    pub fn with_dedupe(mut self, enabled: bool) -> Self {
+        self.dedupe = enabled;
+        self
+    }
+
+    /// Processes the jobs that ask for `model` with `processor` instead of the default one.
+    pub fn with_processor<T>(mut self, model: &str, processor: T) -> Self
+    where
+        T: AsyncJobProcessor + 'static,
+    {
+        Arc::make_mut(&mut self.processors).insert(model.to_string(), Arc::new(processor));
+        self
+    }
+
+    /// Stops taking new jobs from the queue, letting the ones being processed finish.
+    /// Returns the ids of those jobs.
+    pub fn drain(&self) -> Vec<String> {
+        let running_jobs = self.running_jobs.read().unwrap();
+        self.draining.cancel();
+        running_jobs.keys().cloned().collect()
+    }
+
+    /// Whether the jobs that ask for `model` can be conditioned on a reference audio.
+    pub fn supports_reference(&self, model: Option<&str>) -> bool {
+        match model.and_then(|v| self.processors.get(v)) {
+            Some(processor) => processor.supports_reference(),
+            None => self.processor.supports_reference(),
+        }
+    }
+
+    /// Whether any of the models can be conditioned on a reference audio, as there's no
+    /// point in storing references otherwise.
+    pub fn supports_any_reference(&self) -> bool {
+        self.processor.supports_reference()
+            || self.processors.values().any(|v| v.supports_reference())
+    }
+
+    /// Number of jobs in the queue, including the ones being processed.
+    pub fn queue_len(&self) -> usize {
+        self.job_queue.read().unwrap().len()
+    }
+
+    /// The jobs in the queue, in processing order, along with whether they are being
+    /// processed.
+    pub fn jobs(&self) -> Vec<(AudioGenerationRequest, bool)> {
+        let queue = self.job_queue.read().unwrap();
+        let running_jobs = self.running_jobs.read().unwrap();
+        queue
+            .iter()
+            .map(|job| (job.req.clone(), running_jobs.contains_key(&job.req.id)))
+            .collect()
+    }
+
+    /// Aborts the jobs currently being processed, and stops processing any other job.
+    pub fn abort_all(&self) {
+        self.abort_token.cancel()
+    }
+
+    /// Informs about the position and ETA of all the jobs waiting in the queue.
+    fn send_queue_status(&self, outbound_tx: &Sender<BackendOutboundMsg>) {
+        let secs_per_audio_sec = *self.secs_per_audio_sec.read().unwrap();
+        let queue = self.job_queue.read().unwrap();
+        let running_jobs = self.running_jobs.read().unwrap();
+        let mut pending_audio_secs = 0.0;
+        for job in queue.iter() {
+            if let Some(progress) = running_jobs.get(&job.req.id) {
+                pending_audio_secs += job.req.secs as f32 * (1.0 - progress);
+            }
+        }
+        // The jobs that idle workers are about to take are not waiting.
+        let idle_workers = if self.draining.is_cancelled() {
+            0
+        } else {
+            self.workers.saturating_sub(running_jobs.len())
+        };
+        let waiting = queue
+            .iter()
+            .filter(|job| !running_jobs.contains_key(&job.req.id));
+        for (i, job) in waiting.enumerate() {
+            pending_audio_secs += job.req.secs as f32;
+            if i < idle_workers {
+                continue;
+            }
+            // The workers split the pending audio among them.
+            let eta = secs_per_audio_sec.map(|v| v * pending_audio_secs / self.workers as f32);
+            let msg = BackendOutboundMsg::Queued((job.req.clone(), i + 1 - idle_workers, eta));
+            let _ = outbound_tx.send(msg);
+        }
+    }
+
+    fn record_throughput(&self, elapsed: Duration, audio_secs: usize) {
+        if audio_secs == 0 {
+            return;
+        }
+        let sample = elapsed.as_secs_f32() / audio_secs as f32;
+        let mut secs_per_audio_sec = self.secs_per_audio_sec.write().unwrap();
+        // Exponential moving average, so that the estimation follows recent jobs.
+        *secs_per_audio_sec = Some(match *secs_per_audio_sec {
+            Some(prev) => prev * 0.5 + sample * 0.5,
+            None => sample,
+        });
+    }
+
+    /// Takes the first job in the queue that is not being processed by another worker.
+    /// Returns None if there's none, or if the backend is draining.
+    fn take_job(&self) -> Option<Job> {
+        let queue = self.job_queue.read().unwrap();
+        let mut running_jobs = self.running_jobs.write().unwrap();
+        // Checked while holding the lock, so that draining either happens before
+        // picking up the job, or reports it as a running one.
+        if self.draining.is_cancelled() {
+            return None;
         }
+        let job = queue
+            .iter()
+            .find(|job| !running_jobs.contains_key(&job.req.id))?;
+        running_jobs.insert(job.req.id.clone(), 0.0);
+        Some(job.clone())
     }
 
-    fn job_processing_loop(self, outbound_tx: Sender<BackendOutboundMsg>) {
+    async fn job_processing_loop(self, outbound_tx: Sender<BackendOutboundMsg>) {
         loop {
-            let front = {
-                // Immediately drop jq so that the lock is released.
-                let jq = self.job_queue.read().unwrap();
-                jq.front().cloned()
-            };
-            let Some(job) = front else {
-                if self.abort_token.is_cancelled() {
-                    return;
+            // Registered before looking at the queue, so that jobs added in between wake
+            // the worker up.
+            let new_jobs = self.new_jobs.notified();
+            tokio::pin!(new_jobs);
+            new_jobs.as_mut().enable();
+            let Some(job) = self.take_job() else {
+                tokio::select! {
+                    _ = new_jobs => continue,
+                    _ = self.abort_token.cancelled() => return,
+                    _ = self.draining.cancelled() => return,
                 }
-                std::thread::sleep(Duration::from_millis(10));
-                continue;
             };
+            // The job is no longer waiting, so the ones behind it move forward.
+            self.send_queue_status(&outbound_tx);
+            let taken_at = Instant::now();
+
+            let mut prompt = job.req.prompt.clone();
+            if let Some(prompt_enhancer) = &self.prompt_enhancer {
+                match prompt_enhancer.enhance(&prompt).await {
+                    Ok(enhanced) => {
+                        let msg = BackendOutboundMsg::PromptEnhanced((
+                            job.req.id.clone(),
+                            enhanced.clone(),
+                        ));
+                        let _ = outbound_tx.send(msg);
+                        prompt = enhanced;
+                    }
+                    Err(err) => warn!(id = job.req.id, "Could not enhance the prompt: {err}"),
+                }
+            }
 
             let _ = outbound_tx.send(BackendOutboundMsg::Start(job.req.clone()));
+            debug_bundle::set_last_request(&prompt, job.req.secs);
+            let start = Instant::now();
 
             let output_tx_clone = outbound_tx.clone();
             let abort_token = self.abort_token.clone();
+            let running_jobs = self.running_jobs.clone();
+            let duplicates = self.duplicates.clone();
+            let tokens = Arc::new(AtomicUsize::new(0));
+            let tokens_clone = tokens.clone();
             let job_id = job.req.id.clone();
-            let cbk = Box::new(move |elapsed, total| {
-                let msg = BackendOutboundMsg::Progress((job_id.clone(), elapsed / total));
+            let cbk: OnProgress = Box::new(move |stage: GenerationStage| {
+                if let Some(progress) = running_jobs.write().unwrap().get_mut(&job_id) {
+                    *progress = stage.progress();
+                }
+                if let GenerationStage::TokenGeneration { done, .. } = stage {
+                    tokens_clone.store(done, Ordering::SeqCst);
+                }
+                let msg = BackendOutboundMsg::Progress((job_id.clone(), stage));
                 let _ = output_tx_clone.send(msg);
+                for req in duplicates.read().unwrap().get(&job_id).into_iter().flatten() {
+                    let msg = BackendOutboundMsg::Progress((req.id.clone(), stage));
+                    let _ = output_tx_clone.send(msg);
+                }
                 abort_token.is_cancelled() || job.abort_token.is_cancelled()
             });
 
-            let msg = match self.processor.process(&job.req.prompt, job.req.secs, cbk) {
-                Ok(filepath) => BackendOutboundMsg::Response((job.req.id, filepath)),
-                Err(err) => BackendOutboundMsg::Failure((job.req.id, err.to_string())),
+            let on_partial_audio = self.partial_audio.then(|| {
+                let output_tx_clone = outbound_tx.clone();
+                let job_id = job.req.id.clone();
+                let offset = AtomicUsize::new(0);
+                Box::new(move |samples: VecDeque<f32>| {
+                    let offset = offset.fetch_add(samples.len(), Ordering::SeqCst);
+                    let msg = BackendOutboundMsg::PartialAudio((job_id.clone(), offset, samples));
+                    let _ = output_tx_clone.send(msg);
+                }) as OnPartialAudio
+            });
+
+            let (processor, device) = match &job.req.model {
+                Some(model) if self.processors.contains_key(model) => {
+                    (&self.processors[model], model)
+                }
+                _ => (&self.processor, &self.device),
             };
+            // Chosen here rather than by the processor, so that it's known afterward.
+            let seed = job.req.seed.unwrap_or_else(rand::random);
+            let params = GenerationParams {
+                seed: Some(seed),
+                reference: job.req.reference.as_deref().cloned(),
+            };
+            let result = processor
+                .process(&prompt, job.req.secs, &params, cbk, on_partial_audio)
+                .await
+                .map(|(samples, timings)| {
+                    let tokens = tokens.load(Ordering::SeqCst);
+                    let stats = GenerationStats {
+                        wall_secs: taken_at.elapsed().as_secs_f32(),
+                        tokens_per_sec: (tokens > 0 && timings.token_generation_secs > 0.0)
+                            .then_some(tokens as f32 / timings.token_generation_secs),
+                        peak_rss_bytes: peak_rss_bytes(),
+                        device: device.clone(),
+                        seed: Some(seed),
+                    };
+                    (samples, timings, stats)
+                });
+            if result.is_ok() {
+                self.record_throughput(start.elapsed(), job.req.secs);
+            }
+            for req in self.finish_job(&job.req, &result) {
+                let msg = match &result {
+                    Ok((samples, timings, stats)) => BackendOutboundMsg::Response((
+                        req.id,
+                        samples.clone(),
+                        *timings,
+                        stats.clone(),
+                    )),
+                    Err(err) => BackendOutboundMsg::Failure((req.id, err.to_string())),
+                };
+                let _ = outbound_tx.send(msg);
+            }
+            let msg = match result {
+                Ok((samples, timings, stats)) => {
+                    BackendOutboundMsg::Response((job.req.id.clone(), samples, timings, stats))
+                }
+                Err(err) => BackendOutboundMsg::Failure((job.req.id.clone(), err.to_string())),
+            };
+            let _ = outbound_tx.send(msg);
+            self.running_jobs.write().unwrap().remove(&job.req.id);
+            self.send_queue_status(&outbound_tx);
+        }
+    }
+
+    /// Removes the job of `req` from the queue, remembering its result for identical
+    /// requests, and returns the requests that were waiting for it.
+    fn finish_job(
+        &self,
+        req: &AudioGenerationRequest,
+        result: &JobResult,
+    ) -> Vec<AudioGenerationRequest> {
+        // Holding the queue's lock, so that identical requests either find the job in
+        // the queue or its result.
+        let mut queue = self.job_queue.write().unwrap();
+        queue.retain(|queued| queued.req.id != req.id);
+        if let (true, Ok((samples, timings, stats))) = (self.dedupe, result) {
+            let mut recent_results = self.recent_results.write().unwrap();
+            recent_results.push_front(RecentResult {
+                key: req.dedupe_key(),
+                finished_at: Instant::now(),
+                samples: samples.clone(),
+                timings: *timings,
+                stats: stats.clone(),
+            });
+            recent_results.truncate(MAX_RECENT_RESULTS);
+        }
+        let duplicates = self.duplicates.write().unwrap().remove(&req.id);
+        duplicates.unwrap_or_default()
+    }
+
+    /// Answers `req` with the result of an identical job, either one that finished
+    /// recently or one in `queue`, returning whether it did.
+    fn dedupe_request(
+        &self,
+        queue: &VecDeque<Job>,
+        req: &AudioGenerationRequest,
+        outbound_tx: &Sender<BackendOutboundMsg>,
+    ) -> bool {
+        if !self.dedupe || !req.dedupe {
+            return false;
+        }
+        let key = req.dedupe_key();
+        let recent_results = self.recent_results.read().unwrap();
+        let recent_result = recent_results
+            .iter()
+            .find(|v| v.key == key && v.finished_at.elapsed() < DEDUPE_WINDOW);
+        if let Some(result) = recent_result {
+            info!(id = req.id, "Answering with the result of an identical job");
+            let _ = outbound_tx.send(BackendOutboundMsg::Start(req.clone()));
+            let msg = BackendOutboundMsg::Response((
+                req.id.clone(),
+                result.samples.clone(),
+                result.timings,
+                result.stats.clone(),
+            ));
             let _ = outbound_tx.send(msg);
-            self.job_queue.write().unwrap().pop_front();
+            return true;
         }
+        let Some(job) = queue.iter().find(|job| job.req.dedupe_key() == key) else {
+            return false;
+        };
+        info!(id = req.id, "Waiting for the result of identical job {}", job.req.id);
+        let mut duplicates = self.duplicates.write().unwrap();
+        duplicates.entry(job.req.id.clone()).or_default().push(req.clone());
+        let _ = outbound_tx.send(BackendOutboundMsg::Start(req.clone()));
+        true
     }
 
-    fn msg_processing_loop(self, inbound_rx: Receiver<BackendInboundMsg>) {
+    fn msg_processing_loop(
+        self,
+        inbound_rx: Receiver<BackendInboundMsg>,
+        outbound_tx: Sender<BackendOutboundMsg>,
+    ) {
         while let Ok(msg) = inbound_rx.recv() {
             match msg {
                 BackendInboundMsg::Request(req) => {
-                    self.job_queue.write().unwrap().push_back(Job::new(req));
+                    let mut queue = self.job_queue.write().unwrap();
+                    if self.dedupe_request(&queue, &req, &outbound_tx) {
+                        continue;
+                    }
+                    queue.push_back(Job::new(req));
+                    self.new_jobs.notify_waiters();
                 }
                 BackendInboundMsg::Abort(id) => {
                     let mut queue = self.job_queue.write().unwrap();
+                    let mut duplicates = self.duplicates.write().unwrap();
+                    for requests in duplicates.values_mut() {
+                        if let Some(i) = requests.iter().position(|req| req.id == id) {
+                            // Nothing else answers it once it stops waiting for the job.
+                            requests.remove(i);
+                            let msg = BackendOutboundMsg::Failure((id.clone(), "Aborted".into()));
+                            let _ = outbound_tx.send(msg);
+                        }
+                    }
                     let mut to_remove = None;
                     for (i, job) in queue.iter().enumerate() {
                         if job.req.id == id {
@@ -119,23 +612,57 @@ impl AudioGenerationBackend {
                     }
                     if let Some(to_remove) = to_remove {
                         queue.remove(to_remove);
+                        // The requests waiting for the aborted job are still wanted, so the
+                        // first one takes its place.
+                        let mut requests = duplicates.remove(&id).unwrap_or_default();
+                        if !requests.is_empty() {
+                            let req = requests.remove(0);
+                            duplicates.insert(req.id.clone(), requests);
+                            queue.insert(to_remove, Job::new(req));
+                            self.new_jobs.notify_waiters();
+                        }
+                    }
+                }
+                BackendInboundMsg::AbortAll => {
+                    let mut queue = self.job_queue.write().unwrap();
+                    let running_jobs = self.running_jobs.read().unwrap();
+                    for job in queue.iter() {
+                        job.abort_token.cancel();
+                    }
+                    // The jobs being processed are removed from the queue by their worker
+                    // once it notices they were aborted.
+                    let (running, waiting): (VecDeque<_>, VecDeque<_>) = queue
+                        .drain(..)
+                        .partition(|job| running_jobs.contains_key(&job.req.id));
+                    *queue = running;
+                    let duplicates = self.duplicates.write().unwrap().drain().collect::<Vec<_>>();
+                    let duplicates = duplicates.into_iter().flat_map(|(_, requests)| requests);
+                    for req in waiting.into_iter().map(|job| job.req).chain(duplicates) {
+                        let msg = BackendOutboundMsg::Failure((req.id, "Aborted".to_string()));
+                        let _ = outbound_tx.send(msg);
                     }
                 }
             }
+            self.send_queue_status(&outbound_tx);
         }
         self.abort_token.cancel()
     }
 
+    /// Starts processing the jobs sent to the returned sender, which needs to be called
+    /// inside a tokio runtime.
     pub fn run(self) -> (Sender<BackendInboundMsg>, Receiver<BackendOutboundMsg>) {
         let (inbound_tx, inbound_rx) = channel::<BackendInboundMsg>();
         let (outbound_tx, outbound_rx) = channel::<BackendOutboundMsg>();
 
-        // Job processing loop.
-        let self_clone = self.clone();
-        std::thread::spawn(move || self_clone.job_processing_loop(outbound_tx));
+        // Job processing loops, one for each worker.
+        for _ in 0..self.workers {
+            let self_clone = self.clone();
+            let outbound_tx_clone = outbound_tx.clone();
+            tokio::spawn(self_clone.job_processing_loop(outbound_tx_clone));
+        }
 
         // Communications processing loop.
-        std::thread::spawn(move || self.msg_processing_loop(inbound_rx));
+        std::thread::spawn(move || self.msg_processing_loop(inbound_rx, outbound_tx));
 
         (inbound_tx, outbound_rx)
     }
@@ -149,8 +676,8 @@ mod tests {
 
     use super::*;
 
-    #[test]
-    fn processes_job() -> anyhow::Result<()> {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn processes_job() -> anyhow::Result<()> {
         let backend = AudioGenerationBackend::new(DummyJobProcessor::default());
 
         let (tx, rx) = backend.run();
@@ -160,6 +687,10 @@ mod tests {
             id: id.clone(),
             prompt: "".to_string(),
             secs: 4,
+            model: None,
+            dedupe: false,
+            seed: None,
+            reference: None,
         }))?;
 
         assert_eq!(rx.recv()?.unwrap_start().id, id);
@@ -175,8 +706,125 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn handles_job_failure() -> anyhow::Result<()> {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn passes_the_reference_to_the_processor() -> anyhow::Result<()> {
+        let request = AudioGenerationRequest {
+            id: Uuid::new_v4().to_string(),
+            prompt: "".to_string(),
+            secs: 1,
+            model: None,
+            dedupe: false,
+            seed: None,
+            reference: Some(Arc::new(ReferenceSamples {
+                samples: vec![0.5; 4],
+                sampling_rate: 16000,
+            })),
+        };
+
+        let backend = AudioGenerationBackend::new(DummyJobProcessor::default());
+        assert!(!backend.supports_reference(None));
+        assert!(!backend.supports_any_reference());
+        let (tx, rx) = backend.run();
+        tx.send(BackendInboundMsg::Request(request.clone()))?;
+        assert_eq!(rx.recv()?.unwrap_start().id, request.id);
+        assert_eq!(rx.recv()?.unwrap_err().1, "References are not supported");
+
+        let backend = AudioGenerationBackend::new(DummyJobProcessor::default().with_reference());
+        assert!(backend.supports_reference(None));
+        assert!(backend.supports_any_reference());
+        let (tx, rx) = backend.run();
+        tx.send(BackendInboundMsg::Request(request.clone()))?;
+        assert_eq!(rx.recv()?.unwrap_start().id, request.id);
+        assert_eq!(rx.recv()?.unwrap_progress().1, 1.0);
+        assert_eq!(rx.recv()?.unwrap_response().1, VecDeque::from([0.0]));
+
+        Ok(())
+    }
+
+    /// Waits on the runtime instead of blocking a thread, like processors doing IO.
+    struct SleepingJobProcessor;
+
+    #[async_trait]
+    impl AsyncJobProcessor for SleepingJobProcessor {
+        async fn process(
+            &self,
+            _prompt: &str,
+            secs: usize,
+            _params: &GenerationParams,
+            on_progress: OnProgress,
+            _on_partial_audio: Option<OnPartialAudio>,
+        ) -> ort::Result<(VecDeque<f32>, GenerationTimings)> {
+            for done in 1..=secs {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                on_progress(GenerationStage::TokenGeneration { done, total: secs });
+            }
+            Ok((VecDeque::from(vec![0.0; secs]), GenerationTimings::default()))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn processes_job_asynchronously() -> anyhow::Result<()> {
+        let backend = AudioGenerationBackend::new_async(SleepingJobProcessor);
+
+        let (tx, rx) = backend.run();
+
+        let id = Uuid::new_v4().to_string();
+        tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+            id: id.clone(),
+            prompt: "".to_string(),
+            secs: 2,
+            model: None,
+            dedupe: false,
+            seed: None,
+            reference: None,
+        }))?;
+
+        assert_eq!(rx.recv()?.unwrap_start().id, id);
+        assert_eq!(rx.recv()?.unwrap_progress().1, 0.5);
+        assert_eq!(rx.recv()?.unwrap_progress().1, 1.0);
+        assert_eq!(rx.recv()?.unwrap_response().1.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sends_partial_audio() -> anyhow::Result<()> {
+        let backend =
+            AudioGenerationBackend::new(DummyJobProcessor::default()).with_partial_audio(true);
+
+        let (tx, rx) = backend.run();
+
+        let id = Uuid::new_v4().to_string();
+        tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+            id: id.clone(),
+            prompt: "".to_string(),
+            secs: 3,
+            model: None,
+            dedupe: false,
+            seed: None,
+            reference: None,
+        }))?;
+
+        let mut partial_audio = vec![];
+        loop {
+            match rx.recv()? {
+                BackendOutboundMsg::PartialAudio((_, offset, samples)) => {
+                    assert_eq!(offset, partial_audio.len());
+                    partial_audio.extend(samples)
+                }
+                BackendOutboundMsg::Response((_, samples, ..)) => {
+                    assert_eq!(VecDeque::from(partial_audio), samples);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handles_job_failure() -> anyhow::Result<()> {
         let backend = AudioGenerationBackend::new(DummyJobProcessor::default());
 
         let (tx, rx) = backend.run();
@@ -186,6 +834,10 @@ mod tests {
             id: id.clone(),
             prompt: "fail at 2".to_string(),
             secs: 4,
+            model: None,
+            dedupe: false,
+            seed: None,
+            reference: None,
         }))?;
 
         assert_eq!(rx.recv()?.unwrap_start().id, id);
@@ -196,7 +848,116 @@ mod tests {
         Ok(())
     }
 
-    #[tokio::test]
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn enhances_prompts() -> anyhow::Result<()> {
+        let prompt_enhancer = "sh -c 'read p; echo \"fail at 1\"'".parse()?;
+        let backend = AudioGenerationBackend::new(DummyJobProcessor::default())
+            .with_prompt_enhancer(Some(prompt_enhancer));
+
+        let (tx, rx) = backend.run();
+
+        let id = Uuid::new_v4().to_string();
+        tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+            id: id.clone(),
+            prompt: "Rock".to_string(),
+            secs: 4,
+            model: None,
+            dedupe: false,
+            seed: None,
+            reference: None,
+        }))?;
+
+        let BackendOutboundMsg::PromptEnhanced((enhanced_id, prompt)) = rx.recv()? else {
+            panic!("the prompt was not enhanced")
+        };
+        assert_eq!(enhanced_id, id);
+        assert_eq!(prompt, "fail at 1");
+        // The request keeps the original prompt, but the enhanced one is processed.
+        assert_eq!(rx.recv()?.unwrap_start().prompt, "Rock");
+        assert_eq!(rx.recv()?.unwrap_progress().1, 0.25);
+        assert_eq!(rx.recv()?.unwrap_err().1, "Failed at 1");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn informs_about_queued_jobs() -> anyhow::Result<()> {
+        let backend =
+            AudioGenerationBackend::new(DummyJobProcessor::new(Duration::from_millis(10)));
+
+        let (tx, rx) = backend.run();
+
+        let ids = [0; 3].map(|_| Uuid::new_v4().to_string());
+        for id in &ids {
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.clone(),
+                prompt: "".to_string(),
+                secs: 2,
+                model: None,
+                dedupe: false,
+                seed: None,
+                reference: None,
+            }))?;
+        }
+
+        let mut queued = vec![];
+        let mut responses = 0;
+        while responses < ids.len() {
+            match rx.recv()? {
+                BackendOutboundMsg::Queued((req, position, eta)) => {
+                    queued.push((req.id, position, eta))
+                }
+                BackendOutboundMsg::Response(_) => responses += 1,
+                _ => {}
+            }
+        }
+
+        // The last job is at position 2 until the first job finishes, there's no ETA
+        // until then because there's no information about the throughput yet.
+        assert!(queued.contains(&(ids[2].clone(), 2, None)));
+        let (_, position, eta) = queued.last().unwrap();
+        assert_eq!(*position, 1);
+        assert!(eta.unwrap() > 0.0);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lists_jobs() -> anyhow::Result<()> {
+        let backend =
+            AudioGenerationBackend::new(DummyJobProcessor::new(Duration::from_millis(50)));
+        let (tx, rx) = backend.clone().run();
+
+        let ids = [0; 2].map(|_| Uuid::new_v4().to_string());
+        for id in &ids {
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.clone(),
+                prompt: "".to_string(),
+                secs: 2,
+                model: None,
+                dedupe: false,
+                seed: None,
+                reference: None,
+            }))?;
+        }
+        // Queue updates might come before the first job starts.
+        while !matches!(rx.recv()?, BackendOutboundMsg::Start(req) if req.id == ids[0]) {}
+        // The second job might still be on its way to the queue.
+        while backend.queue_len() < 2 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let jobs = backend
+            .jobs()
+            .into_iter()
+            .map(|(req, running)| (req.id, running))
+            .collect::<Vec<_>>();
+        assert_eq!(jobs, vec![(ids[0].clone(), true), (ids[1].clone(), false)]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
     // TODO: for some reason this test fails in CI with a timeout.
     #[cfg(not(target_os = "macos"))]
     async fn handles_job_cancellation() -> anyhow::Result<()> {
@@ -210,6 +971,10 @@ mod tests {
             id: id.clone(),
             prompt: "".to_string(),
             secs: 4,
+            model: None,
+            dedupe: false,
+            seed: None,
+            reference: None,
         }))?;
 
         tokio::time::sleep(Duration::from_millis(50)).await;
@@ -224,11 +989,218 @@ mod tests {
             id: id.clone(),
             prompt: "".to_string(),
             secs: 1,
+            model: None,
+            dedupe: false,
+            seed: None,
+            reference: None,
         }))?;
 
-        assert_eq!(rx.recv()?.unwrap_start().id, id);
+        // The job waits in the queue until the worker notices the abort.
+        while !matches!(rx.recv()?, BackendOutboundMsg::Start(req) if req.id == id) {}
         assert_eq!(rx.recv()?.unwrap_progress().1, 1.0);
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn aborts_all_jobs() -> anyhow::Result<()> {
+        let backend =
+            AudioGenerationBackend::new(DummyJobProcessor::new(Duration::from_millis(200)));
+        let (tx, rx) = backend.run();
+
+        let ids = [0; 3].map(|_| Uuid::new_v4().to_string());
+        for id in &ids {
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.clone(),
+                prompt: "".to_string(),
+                secs: 4,
+                model: None,
+                dedupe: false,
+                seed: None,
+                reference: None,
+            }))?;
+        }
+        while !matches!(rx.recv()?, BackendOutboundMsg::Start(req) if req.id == ids[0]) {}
+        tx.send(BackendInboundMsg::AbortAll)?;
+
+        let mut aborted = vec![];
+        while aborted.len() < ids.len() {
+            match rx.recv()? {
+                BackendOutboundMsg::Failure((id, err)) => {
+                    assert_eq!(err, "Aborted");
+                    aborted.push(id)
+                }
+                BackendOutboundMsg::Response(_) => panic!("a job was not aborted"),
+                _ => {}
+            }
+        }
+        aborted.sort();
+        let mut expected = ids.to_vec();
+        expected.sort();
+        assert_eq!(aborted, expected);
+
+        // The backend keeps processing new jobs.
+        let id = Uuid::new_v4().to_string();
+        tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+            id: id.clone(),
+            prompt: "".to_string(),
+            secs: 1,
+            model: None,
+            dedupe: false,
+            seed: None,
+            reference: None,
+        }))?;
+        while !matches!(rx.recv()?, BackendOutboundMsg::Response((res_id, ..)) if res_id == id) {}
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn processes_jobs_in_parallel() -> anyhow::Result<()> {
+        let backend =
+            AudioGenerationBackend::new(DummyJobProcessor::new(Duration::from_millis(100)))
+                .with_workers(2);
+        let (tx, rx) = backend.clone().run();
+
+        let ids = [0; 3].map(|_| Uuid::new_v4().to_string());
+        for id in &ids {
+            tx.send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: id.clone(),
+                prompt: "".to_string(),
+                secs: 4,
+                model: None,
+                dedupe: false,
+                seed: None,
+                reference: None,
+            }))?;
+        }
+
+        // The first two jobs start before any of them finishes, the third one waits.
+        let mut started = vec![];
+        while started.len() < 2 {
+            match rx.recv()? {
+                BackendOutboundMsg::Start(req) => started.push(req.id),
+                BackendOutboundMsg::Response(_) => panic!("a job finished too early"),
+                _ => {}
+            }
+        }
+        started.sort();
+        let mut expected = ids[..2].to_vec();
+        expected.sort();
+        assert_eq!(started, expected);
+        while backend.queue_len() < 3 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let running = backend
+            .jobs()
+            .into_iter()
+            .map(|(_, running)| running)
+            .collect::<Vec<_>>();
+        assert_eq!(running, vec![true, true, false]);
+
+        let mut responses = 0;
+        while responses < ids.len() {
+            if let BackendOutboundMsg::Response(_) = rx.recv()? {
+                responses += 1
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dedupes_identical_requests() -> anyhow::Result<()> {
+        let backend =
+            AudioGenerationBackend::new(DummyJobProcessor::new(Duration::from_millis(50)))
+                .with_dedupe(true);
+        let (tx, rx) = backend.clone().run();
+
+        let request = |prompt: &str, dedupe: bool| AudioGenerationRequest {
+            id: Uuid::new_v4().to_string(),
+            prompt: prompt.to_string(),
+            secs: 2,
+            model: None,
+            dedupe,
+            seed: None,
+            reference: None,
+        };
+        let reqs = [request("Rock", true), request("Rock", true), request("Rock", false)];
+        for req in &reqs {
+            tx.send(BackendInboundMsg::Request(req.clone()))?;
+        }
+        // The second request waits for the first job, the third one opted out.
+        while backend.queue_len() < 2 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let queued = backend
+            .jobs()
+            .into_iter()
+            .map(|(req, _)| req.id)
+            .collect::<Vec<_>>();
+        assert_eq!(queued, vec![reqs[0].id.clone(), reqs[2].id.clone()]);
+
+        let mut responses = HashMap::new();
+        while responses.len() < reqs.len() {
+            if let BackendOutboundMsg::Response((id, samples, ..)) = rx.recv()? {
+                responses.insert(id, samples);
+            }
+        }
+        assert_eq!(responses[&reqs[1].id], responses[&reqs[0].id]);
+
+        // Aborting a request that waits for an identical job fails only that request.
+        let (original, duplicate) = (request("Jazz", true), request("Jazz", true));
+        tx.send(BackendInboundMsg::Request(original.clone()))?;
+        tx.send(BackendInboundMsg::Request(duplicate.clone()))?;
+        tx.send(BackendInboundMsg::Abort(duplicate.id.clone()))?;
+        let mut failed = false;
+        loop {
+            match rx.recv()? {
+                BackendOutboundMsg::Failure((id, err)) => {
+                    assert_eq!((id, err.as_str()), (duplicate.id.clone(), "Aborted"));
+                    failed = true;
+                }
+                BackendOutboundMsg::Response((id, ..)) => {
+                    assert_eq!(id, original.id);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert!(failed);
+
+        // Identical requests are answered with the recent result right away.
+        let req = request("Rock", true);
+        tx.send(BackendInboundMsg::Request(req.clone()))?;
+        loop {
+            match rx.recv()? {
+                BackendOutboundMsg::Start(start) => assert_eq!(start.id, req.id),
+                BackendOutboundMsg::Response((id, samples, ..)) => {
+                    assert_eq!(id, req.id);
+                    assert_eq!(samples, VecDeque::from([0.0, 1.0]));
+                    break;
+                }
+                BackendOutboundMsg::Queued(_) => {}
+                msg => panic!("the request was not answered right away: {msg:?}"),
+            }
+        }
+
+        // The same request with another seed is generated again.
+        let req = AudioGenerationRequest {
+            seed: Some(1),
+            ..request("Rock", true)
+        };
+        tx.send(BackendInboundMsg::Request(req.clone()))?;
+        loop {
+            match rx.recv()? {
+                BackendOutboundMsg::Progress((id, _)) => {
+                    assert_eq!(id, req.id);
+                    break;
+                }
+                BackendOutboundMsg::Response(_) => panic!("the request was deduplicated"),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
 }
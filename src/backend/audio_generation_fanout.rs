@@ -1,12 +1,22 @@
+use std::collections::HashMap;
+use std::mem::discriminant;
+use std::time::{Duration, Instant};
+
 use serde::{Deserialize, Serialize};
 use specta::Type;
-use tracing::info;
+use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::audio::AudioManager;
-use crate::backend::audio_generation_backend::BackendOutboundMsg;
-use crate::backend::music_gpt_chat::ChatEntry;
+use crate::backend::audio_export::AudioExport;
+use crate::backend::audio_generation_backend::{
+    BackendOutboundMsg, GenerationStage, GenerationStats, GenerationTimings,
+};
+use crate::backend::generation_metadata::GenerationMetadata;
+use crate::backend::music_gpt_chat::{ChatEntry, UserChatEntry};
 use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::persisted_queue::PersistedJob;
+use crate::backend::presence::Client;
 use crate::storage::Storage;
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -14,14 +24,29 @@ pub struct AudioGenerationStart {
     pub id: Uuid,
     pub chat_id: Uuid,
     pub prompt: String,
+    /// The prompt rewritten by the prompt enhancer, from which the audio is generated.
+    pub enhanced_prompt: Option<String>,
     pub secs: usize,
+    pub requested_by: Option<Client>,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct AudioGenerationQueued {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub prompt: String,
+    pub secs: usize,
+    pub position: usize,
+    pub eta_secs: Option<f32>,
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub struct AudioGenerationProgress {
     pub id: Uuid,
     pub chat_id: Uuid,
+    /// The progress of the whole generation, from 0 to 1.
     pub progress: f32,
+    pub stage: GenerationStage,
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -36,51 +61,183 @@ pub struct AudioGenerationResult {
     pub id: Uuid,
     pub chat_id: Uuid,
     pub relpath: String,
+    pub requested_by: Option<Client>,
+    pub timings: GenerationTimings,
+    pub stats: GenerationStats,
+}
+
+/// Samples of an audio that is still being generated, sent to the web app in binary
+/// websocket messages so that it can start playing before the generation finishes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartialAudio {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub sampling_rate: u32,
+    /// Position of the first of these samples in the whole audio.
+    pub offset: u32,
+    pub samples: Vec<f32>,
+}
+
+impl PartialAudio {
+    /// Encodes the message as the 16 bytes of `id`, the 16 bytes of `chat_id`, and
+    /// `sampling_rate`, `offset` and all the `samples`, all in little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(40 + self.samples.len() * 4);
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes.extend_from_slice(self.chat_id.as_bytes());
+        bytes.extend_from_slice(&self.sampling_rate.to_le_bytes());
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        for sample in &self.samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        bytes
+    }
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub enum GenerationMessage {
     Start(AudioGenerationStart),
+    Queued(AudioGenerationQueued),
     Progress(AudioGenerationProgress),
     Error(AudioGenerationError),
     Result(AudioGenerationResult),
 }
 
+/// Bounds how often the progress of a job is broadcast, as the backend reports it on
+/// every generated token. The first and the last progress updates are always sent.
+#[derive(Clone, Debug, Default)]
+pub struct ProgressThrottle {
+    /// Minimum time between two progress updates of the same job.
+    pub min_interval: Duration,
+    /// Minimum increase of the progress, from 0 to 1, between two updates of the same job.
+    pub min_delta: f32,
+}
+
+impl ProgressThrottle {
+    /// Whether `progress` should be sent, given when the last update was sent and its value.
+    fn allows(&self, last: Option<&(Instant, f32)>, progress: f32) -> bool {
+        let Some((sent_at, last_progress)) = last else {
+            return true;
+        };
+        progress >= 1.0
+            || (sent_at.elapsed() >= self.min_interval
+                && progress - last_progress >= self.min_delta)
+    }
+}
+
 pub fn audio_generation_fanout<S: Storage + 'static>(
     ai_rx: std::sync::mpsc::Receiver<BackendOutboundMsg>,
     storage: S,
+    audio_manager: AudioManager,
+    partial_audio_tx: tokio::sync::broadcast::Sender<PartialAudio>,
+    progress_throttle: ProgressThrottle,
+    export: Option<AudioExport>,
+    model: String,
 ) -> tokio::sync::broadcast::Sender<GenerationMessage> {
     let (ai_broadcast_tx, _) = tokio::sync::broadcast::channel(1000); // Arbitrary number.
 
     let mut ai_rx = std_to_tokio_receiver(ai_rx);
     let ai_broadcast_tx_clone = ai_broadcast_tx.clone();
-    let audio_manager = AudioManager::default();
     tokio::spawn(async move {
+        // The last progress update sent for each of the jobs being processed.
+        let mut last_progress: HashMap<String, ((Instant, f32), GenerationStage)> =
+            HashMap::new();
+        // The prompts rewritten by the prompt enhancer of the jobs being processed.
+        let mut enhanced_prompts = HashMap::new();
         while let Some(msg) = ai_rx.recv().await {
+            let mut enhanced_prompt = None;
+            if let BackendOutboundMsg::Response((id, ..))
+            | BackendOutboundMsg::Failure((id, _)) = &msg
+            {
+                last_progress.remove(id);
+                enhanced_prompt = enhanced_prompts.remove(id);
+            }
             let outbound_msg = match msg {
+                BackendOutboundMsg::PromptEnhanced((id, prompt)) => {
+                    enhanced_prompts.insert(id, prompt);
+                    continue;
+                }
                 BackendOutboundMsg::Start(msg) => {
+                    let enhanced_prompt = enhanced_prompts.get(&msg.id).cloned();
                     let IdPair(chat_id, id) = msg.id.into();
-                    let entry = ChatEntry::new_user(chat_id, id, msg.prompt.clone());
-                    let _ = entry.save(&storage).await;
+                    info!(%id, %chat_id, secs = msg.secs, "Audio generation started");
+                    let requested_by = PersistedJob::requested_by(&storage, id).await;
+                    // Jobs recovered after a restart might have been started before, and
+                    // in that case their user entry was already saved.
+                    let started = PersistedJob::mark_started(&storage, id).await;
+                    if !started.unwrap_or_default() {
+                        let entry = ChatEntry::User(UserChatEntry {
+                            id,
+                            chat_id,
+                            text: msg.prompt.clone(),
+                            enhanced_prompt: enhanced_prompt.clone(),
+                        });
+                        let _ = entry.save(&storage).await;
+                    }
                     GenerationMessage::Start(AudioGenerationStart {
                         id,
                         chat_id,
                         prompt: msg.prompt,
+                        enhanced_prompt,
                         secs: msg.secs,
+                        requested_by,
                     })
                 }
-                BackendOutboundMsg::Response((id, queue)) => {
-                    info!("Audio generated successfully");
+                BackendOutboundMsg::Response((id, queue, timings, stats)) => {
                     let IdPair(chat_id, id) = id.into();
+                    info!(
+                        %id,
+                        %chat_id,
+                        text_encoding_secs = timings.text_encoding_secs,
+                        token_generation_secs = timings.token_generation_secs,
+                        audio_decoding_secs = timings.audio_decoding_secs,
+                        wall_secs = stats.wall_secs,
+                        device = stats.device,
+                        "Audio generated successfully"
+                    );
+                    let job = PersistedJob::load(&storage, id).await.ok().flatten();
+                    let requested_by = job.as_ref().and_then(|job| job.requested_by.clone());
+                    let compared_with = job.as_ref().and_then(|job| job.compared_with);
+                    let _ = PersistedJob::remove(&storage, id).await;
                     let relpath = format!("audios/{}.wav", id);
+                    let metadata = job.as_ref().map(|job| {
+                        let model = job.model.as_deref().unwrap_or(&model);
+                        GenerationMetadata {
+                            enhanced_prompt,
+                            seed: stats.seed,
+                            ..GenerationMetadata::new(&job.prompt, model, job.secs, timings)
+                        }
+                    });
                     let save_audio = || async {
-                        let bytes = audio_manager.to_wav(queue)?;
-                        storage.write(&relpath, bytes).await?;
+                        let mut bytes = audio_manager.to_wav(queue)?;
+                        if let Some(metadata) = &metadata {
+                            bytes = metadata.embed(bytes)?;
+                        }
+                        storage.write(&relpath, &bytes).await?;
+                        if let Some(export) = &export {
+                            let prompt = job.as_ref().map(|job| job.prompt.as_str());
+                            match export
+                                .export(id, chat_id, prompt.unwrap_or_default(), &bytes)
+                                .await
+                            {
+                                Ok(path) => {
+                                    info!(%id, "Audio exported to {}", path.display());
+                                    if let (true, Some(metadata)) = (export.metadata, &metadata) {
+                                        if let Err(err) = metadata.write_sidecar(&path).await {
+                                            warn!(%id, "Could not write audio metadata: {err}")
+                                        }
+                                    }
+                                }
+                                // The audio is still available in the web app.
+                                Err(err) => warn!(%id, "Could not export audio: {err}"),
+                            }
+                        }
                         Ok::<(), anyhow::Error>(())
                     };
                     // If audio failed to be saved, do not count as a success.
                     if let Err(err) = save_audio().await {
-                        let entry = ChatEntry::new_ai_err(chat_id, id, err.to_string());
+                        let entry = ChatEntry::new_ai_err(chat_id, id, err.to_string())
+                            .with_comparison(compared_with);
                         let _ = entry.save(&storage).await;
                         GenerationMessage::Error(AudioGenerationError {
                             id,
@@ -88,28 +245,77 @@ pub fn audio_generation_fanout<S: Storage + 'static>(
                             error: err.to_string(),
                         })
                     } else {
-                        let entry = ChatEntry::new_ai_success(chat_id, id, relpath.clone());
+                        let entry = ChatEntry::new_ai_success(chat_id, id, relpath.clone())
+                            .with_comparison(compared_with)
+                            .with_stats(stats.clone());
                         let _ = entry.save(&storage).await;
                         GenerationMessage::Result(AudioGenerationResult {
                             id,
                             chat_id,
                             relpath,
+                            requested_by,
+                            timings,
+                            stats,
                         })
                     }
                 }
                 BackendOutboundMsg::Failure((id, error)) => {
-                    info!("Error generating audio {error}");
                     let IdPair(chat_id, id) = id.into();
-                    let entry = ChatEntry::new_ai_err(chat_id, id, error.clone());
+                    info!(%id, %chat_id, error, "Error generating audio");
+                    let job = PersistedJob::load(&storage, id).await.ok().flatten();
+                    let _ = PersistedJob::remove(&storage, id).await;
+                    // Jobs aborted before starting, like the ones flushed from the queue,
+                    // have no user entry yet.
+                    if let Some(job) = job.as_ref().filter(|job| !job.started) {
+                        let entry = ChatEntry::new_user(chat_id, id, job.prompt.clone());
+                        let _ = entry.save(&storage).await;
+                    }
+                    let entry = ChatEntry::new_ai_err(chat_id, id, error.clone())
+                        .with_comparison(job.and_then(|job| job.compared_with));
                     let _ = entry.save(&storage).await;
                     GenerationMessage::Error(AudioGenerationError { id, chat_id, error })
                 }
-                BackendOutboundMsg::Progress((id, progress)) => {
+                BackendOutboundMsg::Queued((req, position, eta_secs)) => {
+                    let IdPair(chat_id, id) = req.id.into();
+                    GenerationMessage::Queued(AudioGenerationQueued {
+                        id,
+                        chat_id,
+                        prompt: req.prompt,
+                        secs: req.secs,
+                        position,
+                        eta_secs,
+                    })
+                }
+                BackendOutboundMsg::PartialAudio((id, offset, samples)) => {
+                    let IdPair(chat_id, id) = id.into();
+                    let _ = partial_audio_tx.send(PartialAudio {
+                        id,
+                        chat_id,
+                        sampling_rate: audio_manager.sampling_rate(),
+                        offset: offset as u32,
+                        samples: samples.into(),
+                    });
+                    continue;
+                }
+                BackendOutboundMsg::Progress((id, stage)) => {
+                    let progress = stage.progress();
+                    let last = last_progress.get(&id);
+                    // The web app shows the stage of the generation, so changes of stage
+                    // are always sent.
+                    let same_stage = last.is_some_and(|(_, last_stage)| {
+                        discriminant(last_stage) == discriminant(&stage)
+                    });
+                    let last = last.map(|(last, _)| last);
+                    if same_stage && !progress_throttle.allows(last, progress) {
+                        continue;
+                    }
+                    last_progress.insert(id.clone(), ((Instant::now(), progress), stage));
                     let IdPair(chat_id, id) = id.into();
                     GenerationMessage::Progress(AudioGenerationProgress {
                         id,
                         chat_id,
                         progress,
+                        stage,
                     })
                 }
             };
@@ -131,3 +337,49 @@ fn std_to_tokio_receiver<T: Send + 'static>(
     });
     tokio_rx
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_progress() {
+        let throttle = ProgressThrottle {
+            min_interval: Duration::from_secs(60),
+            min_delta: 0.1,
+        };
+        assert!(throttle.allows(None, 0.01));
+        assert!(!throttle.allows(Some(&(Instant::now(), 0.01)), 0.5));
+        assert!(throttle.allows(Some(&(Instant::now(), 0.9)), 1.0));
+
+        let throttle = ProgressThrottle {
+            min_interval: Duration::ZERO,
+            min_delta: 0.1,
+        };
+        assert!(!throttle.allows(Some(&(Instant::now(), 0.1)), 0.15));
+        assert!(throttle.allows(Some(&(Instant::now(), 0.1)), 0.25));
+
+        let throttle = ProgressThrottle::default();
+        assert!(throttle.allows(Some(&(Instant::now(), 0.1)), 0.1));
+    }
+
+    #[test]
+    fn encodes_partial_audio() {
+        let (id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let partial_audio = PartialAudio {
+            id,
+            chat_id,
+            sampling_rate: 32000,
+            offset: 3,
+            samples: vec![0.5, -1.0],
+        };
+        let bytes = partial_audio.to_bytes();
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(&bytes[..16], id.as_bytes());
+        assert_eq!(&bytes[16..32], chat_id.as_bytes());
+        assert_eq!(&bytes[32..36], &32000u32.to_le_bytes());
+        assert_eq!(&bytes[36..40], &3u32.to_le_bytes());
+        assert_eq!(&bytes[40..44], &0.5f32.to_le_bytes());
+        assert_eq!(&bytes[44..], &(-1.0f32).to_le_bytes());
+    }
+}
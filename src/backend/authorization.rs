@@ -0,0 +1,278 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use percent_encoding::percent_decode_str;
+use sha2::Sha256;
+
+use crate::backend::api_keys::ApiKeys;
+use crate::storage::Storage;
+
+/// Cookie in which browsers keep the API key, see [remember_api_key].
+const API_KEY_COOKIE: &str = "musicgpt_key";
+
+const API_KEY_COOKIE_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// How long the URLs signed by [UrlSigner] can be used.
+const SIGNED_URL_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Signs the paths of the server for the clients that cannot send an API key, like the DLNA
+/// renderers that audios are cast to. The secret is random for each run of the server, so
+/// restarting it invalidates all the signed URLs.
+#[derive(Clone)]
+pub struct UrlSigner(Arc<[u8; 32]>);
+
+impl Default for UrlSigner {
+    fn default() -> Self {
+        Self(Arc::new(rand::random()))
+    }
+}
+
+impl UrlSigner {
+    /// Adds to `path` the query params that let anyone access it for [SIGNED_URL_TTL].
+    pub fn sign(&self, path: &str) -> String {
+        let expires = unix_secs() + SIGNED_URL_TTL.as_secs();
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(path, expires).finalize().into_bytes());
+        let separator = if path.contains('?') { '&' } else { '?' };
+        format!("{path}{separator}expires={expires}&signature={signature}")
+    }
+
+    /// Whether `uri` was signed by [UrlSigner::sign] and has not expired yet.
+    fn verify(&self, uri: &Uri) -> bool {
+        let expires = query_param(uri, "expires").and_then(|v| v.parse::<u64>().ok());
+        let signature = query_param(uri, "signature").and_then(|v| URL_SAFE_NO_PAD.decode(v).ok());
+        let (Some(expires), Some(signature)) = (expires, signature) else {
+            return false;
+        };
+        expires >= unix_secs()
+            && self
+                .mac(uri.path(), expires)
+                .verify_slice(&signature)
+                .is_ok()
+    }
+
+    fn mac(&self, path: &str, expires: u64) -> Hmac<Sha256> {
+        // Only the path is signed, so the signature is the same with or without other params.
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(self.0.as_slice()).expect("HMAC takes keys of any size");
+        mac.update(format!("{path}\n{expires}").as_bytes());
+        mac
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Rejects requests with an invalid API key, or without one if `required` is set, unless
+/// their URL was signed by `signer`.
+pub async fn authorize<S: Storage>(
+    keys: ApiKeys<S>,
+    signer: UrlSigner,
+    required: bool,
+    req: Request,
+    next: Next,
+) -> Response {
+    let authorized = match request_api_key(&req) {
+        Some(key) => keys.verify(&key).await.unwrap_or_default(),
+        None => !required || signer.verify(req.uri()),
+    };
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, "Invalid or missing API key").into_response();
+    }
+    next.run(req).await
+}
+
+/// The API key of the request, from the `Authorization: Bearer <key>` header. Browsers
+/// cannot set headers in websockets nor in audio elements, so the key is also taken from
+/// a `key` query param or from the cookie set by [remember_api_key].
+fn request_api_key(req: &Request) -> Option<String> {
+    if let Some(auth) = req.headers().get(header::AUTHORIZATION) {
+        let key = auth.to_str().ok().and_then(|v| v.strip_prefix("Bearer "));
+        // Any other kind of authorization is an invalid key.
+        return Some(key.unwrap_or_default().trim().to_string());
+    }
+    if let Some(key) = query_param(req.uri(), "key") {
+        return Some(key);
+    }
+    req.headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|v| v.trim().strip_prefix(API_KEY_COOKIE)?.strip_prefix('='))
+        .map(|v| v.to_string())
+}
+
+fn query_param(uri: &Uri, name: &str) -> Option<String> {
+    uri.query()?.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=')?;
+        let v = percent_decode_str(v).decode_utf8().ok()?;
+        (k == name).then(|| v.to_string())
+    })
+}
+
+/// Stores the API key given in the `key` query param of any page in a cookie, so that
+/// opening the web app once with `/?key=<key>` gives the browser access to the server.
+pub async fn remember_api_key<S: Storage>(keys: ApiKeys<S>, req: Request, next: Next) -> Response {
+    let key = query_param(req.uri(), "key");
+    let mut res = next.run(req).await;
+    if let Some(key) = key {
+        if keys.verify(&key).await.unwrap_or_default() {
+            let cookie = format!(
+                "{API_KEY_COOKIE}={key}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+                API_KEY_COOKIE_MAX_AGE.as_secs()
+            );
+            if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+                res.headers_mut().append(header::SET_COOKIE, cookie);
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use std::time::Duration;
+    use tokio_tungstenite::connect_async;
+
+    use super::*;
+    use crate::backend::_test_utils::{next_port, test_options, DummyJobProcessor, TungsteniteMsg};
+    use crate::backend::api_keys::ApiKey;
+    use crate::backend::music_gpt_ws_handler::OutboundMsg;
+    use crate::backend::server::{run_web_server, RunWebServerOptions};
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn requires_api_keys() -> anyhow::Result<()> {
+        let app_fs = AppFs::new_tmp();
+        let (_, secret) = ApiKey::create(&app_fs, "test".to_string()).await?;
+        let port = next_port() as usize;
+        tokio::spawn(run_web_server(
+            app_fs.root.clone(),
+            app_fs,
+            DummyJobProcessor::default(),
+            RunWebServerOptions {
+                require_api_key: true,
+                ..test_options(port)
+            },
+        ));
+
+        // The server needs some time for booting, so retry a few times. The web app
+        // itself does not need an API key.
+        let client = reqwest::Client::new();
+        let url = format!("http://localhost:{port}");
+        let mut retries = 0;
+        let res = loop {
+            match client.get(&url).send().await {
+                Ok(res) => break res,
+                Err(_) if retries < 50 => retries += 1,
+                Err(err) => return Err(err.into()),
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let status = |auth: Option<String>| {
+            let mut req = client.get(format!("{url}/events"));
+            if let Some(auth) = auth {
+                req = req.header("authorization", auth);
+            }
+            async move { req.send().await.map(|res| res.status()) }
+        };
+        assert_eq!(status(None).await?, StatusCode::UNAUTHORIZED);
+        let invalid = Some("Bearer mgpt-foo".to_string());
+        assert_eq!(status(invalid).await?, StatusCode::UNAUTHORIZED);
+        let valid = Some(format!("Bearer {secret}"));
+        assert_eq!(status(valid).await?, StatusCode::OK);
+
+        // Browsers send the key in the query or in a cookie, set when opening the web app
+        // with it.
+        let res = client
+            .get(format!("{url}/events?key={secret}"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(format!("{url}/?key=mgpt-foo")).send().await?;
+        assert!(res.headers().get(header::SET_COOKIE).is_none());
+        let res = client.get(format!("{url}/?key={secret}")).send().await?;
+        let cookie = res.headers()[header::SET_COOKIE].to_str()?;
+        assert!(cookie.starts_with(&format!("musicgpt_key={secret};")));
+        let res = client
+            .get(format!("{url}/files/missing.wav"))
+            .header(header::COOKIE, format!("theme=dark; musicgpt_key={secret}"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        for res in [
+            client.get(format!("{url}/renderers")).send().await?,
+            client.post(format!("{url}/cast")).send().await?,
+        ] {
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+        let ws_url = format!("ws://localhost:{port}/ws");
+        assert!(connect_async(&ws_url).await.is_err());
+        let (mut ws, _) = connect_async(&format!("{ws_url}?key={secret}")).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accepts_signed_urls() -> anyhow::Result<()> {
+        let signer = UrlSigner::default();
+        let auth_signer = signer.clone();
+        let app = Router::new()
+            .route("/files/*path", get(|| async { "audio" }))
+            .route_layer(middleware::from_fn(move |req, next| {
+                authorize(
+                    ApiKeys::new(AppFs::new_tmp()),
+                    auth_signer.clone(),
+                    true,
+                    req,
+                    next,
+                )
+            }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let status = |path: String| {
+            let url = format!("{url}{path}");
+            async move { reqwest::get(url).await.map(|res| res.status()) }
+        };
+        let signed = signer.sign("/files/song.wav");
+        assert_eq!(status(signed.clone()).await?, StatusCode::OK);
+        assert_eq!(
+            status("/files/song.wav".into()).await?,
+            StatusCode::UNAUTHORIZED
+        );
+        let other_file = signed.replace("song", "other");
+        assert_eq!(status(other_file).await?, StatusCode::UNAUTHORIZED);
+        let other_signer = UrlSigner::default().sign("/files/song.wav");
+        assert_eq!(status(other_signer).await?, StatusCode::UNAUTHORIZED);
+
+        let expires = unix_secs() - 1;
+        let mac = signer
+            .mac("/files/song.wav", expires)
+            .finalize()
+            .into_bytes();
+        let signature = URL_SAFE_NO_PAD.encode(mac);
+        let expired = format!("/files/song.wav?expires={expires}&signature={signature}");
+        assert_eq!(status(expired).await?, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
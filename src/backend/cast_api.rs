@@ -0,0 +1,95 @@
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+
+use crate::backend::authorization::UrlSigner;
+use crate::backend::dlna::{DiscoveredRenderers, DISCOVERY_TIMEOUT};
+
+#[derive(Deserialize)]
+pub struct CastRequest {
+    /// The location or the name of one of the renderers listed in `/renderers`.
+    renderer: String,
+    /// The audio to play, either a path served by MusicGPT like `/files/...` or any URL.
+    url: String,
+    title: Option<String>,
+}
+
+/// Lists the DLNA renderers in the LAN.
+pub async fn renderers(renderers: DiscoveredRenderers) -> Response {
+    match renderers.discover(DISCOVERY_TIMEOUT).await {
+        Ok(renderers) => Json(renderers).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Casts an audio to a DLNA renderer. Paths served by MusicGPT are resolved against the
+/// address through which the client reached the server, which must also be reachable from
+/// the renderer. Renderers cannot send API keys, so if a `signer` is given because they
+/// are required, the paths are signed for them.
+pub async fn cast(
+    renderers: DiscoveredRenderers,
+    signer: Option<UrlSigner>,
+    headers: HeaderMap,
+    req: CastRequest,
+) -> Response {
+    let url = if req.url.starts_with('/') {
+        let Some(host) = headers.get(header::HOST).and_then(|v| v.to_str().ok()) else {
+            return (StatusCode::BAD_REQUEST, "Missing host header").into_response();
+        };
+        match &signer {
+            Some(signer) => format!("http://{host}{}", signer.sign(&req.url)),
+            None => format!("http://{host}{}", req.url),
+        }
+    } else {
+        req.url
+    };
+    let title = req.title.as_deref().unwrap_or("MusicGPT");
+    let renderer = match renderers.find(&req.renderer, DISCOVERY_TIMEOUT).await {
+        Ok(renderer) => renderer,
+        Err(err) => return (StatusCode::NOT_FOUND, err.to_string()).into_response(),
+    };
+    let result = renderer.play(&url, title).await;
+    match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => (StatusCode::BAD_GATEWAY, err.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum::Router;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use crate::backend::_test_utils::{spawn, DummyJobProcessor};
+
+    #[tokio::test]
+    async fn only_casts_to_discovered_renderers() -> anyhow::Result<()> {
+        let (_ws, host) = spawn(DummyJobProcessor::default()).await?;
+        let fetched = Arc::new(AtomicBool::new(false));
+        let fetched_clone = fetched.clone();
+        let app = Router::new().fallback(move || async move {
+            fetched_clone.store(true, Ordering::SeqCst);
+            StatusCode::NOT_FOUND
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let req = serde_json::json!({
+            "renderer": format!("http://{addr}/description.xml"),
+            "url": "/files/song.wav",
+        });
+        let res = reqwest::Client::new()
+            .post(format!("http://{host}/cast"))
+            .header("content-type", "application/json")
+            .body(req.to_string())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(!fetched.load(Ordering::SeqCst));
+        Ok(())
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::backend::music_gpt_chat::{AiChatEntry, Chat, ChatEntry, ChatsCursor};
+use crate::storage::Storage;
+
+/// Lives in the chats dir, so that it counts as chats in the disk usage and the garbage
+/// collector leaves it alone.
+const DB_FILE: &str = "chats/chats.sqlite";
+/// Bumped on each schema change. Databases with an older version are migrated when opened,
+/// starting with the chats that older MusicGPT versions stored as JSON files.
+const SCHEMA_VERSION: i64 = 1;
+const METADATA_FILE: &str = ".metadata.json";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS chats (
+    chat_id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS chats_by_creation ON chats (created_at DESC, chat_id DESC);
+CREATE TABLE IF NOT EXISTS entries (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    is_ai INTEGER NOT NULL,
+    favorite INTEGER NOT NULL DEFAULT 0,
+    entry TEXT NOT NULL,
+    UNIQUE (chat_id, id, is_ai)
+);
+CREATE INDEX IF NOT EXISTS entries_by_chat ON entries (chat_id, seq);
+";
+
+/// The databases opened so far, by path, so that everything using the same storage shares
+/// a connection.
+static OPENED: LazyLock<tokio::sync::Mutex<HashMap<PathBuf, ChatDb>>> =
+    LazyLock::new(Default::default);
+
+/// The chats and their entries stored in an SQLite database, so that listing and paginating
+/// them does not need to read one file per chat. Audios are still stored as files.
+#[derive(Clone)]
+pub struct ChatDb {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl ChatDb {
+    /// The database of the storage, opened and migrated on first use. None for storages that
+    /// are not in the local disk or that are read only, which keep the chats as JSON files.
+    pub async fn of<S: Storage>(storage: &S) -> anyhow::Result<Option<Self>> {
+        if !storage.is_local() || storage.is_read_only() {
+            return Ok(None);
+        }
+        let path = storage.path_buf(DB_FILE);
+        let mut opened = OPENED.lock().await;
+        if let Some(db) = opened.get(&path) {
+            return Ok(Some(db.clone()));
+        }
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let conn = Connection::open(&path)?;
+        conn.execute_batch(SCHEMA)?;
+        let db = Self {
+            conn: Arc::new(Mutex::new(conn)),
+        };
+        db.migrate(storage).await?;
+        opened.insert(path, db.clone());
+        Ok(Some(db))
+    }
+
+    /// Runs the queries in a blocking thread, SQLite does not play well with async.
+    async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let conn = self.conn.clone();
+        let result = tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await?;
+        Ok(result?)
+    }
+
+    /// Moves the chats that older versions stored as `chats/{chat_id}/*.json` files into the
+    /// database, removing the files once they are committed.
+    async fn migrate<S: Storage>(&self, storage: &S) -> anyhow::Result<()> {
+        let version = self
+            .call(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0)))
+            .await?;
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        let mut dirs = vec![];
+        let mut chats = vec![];
+        for dir in storage.list("chats").await? {
+            let chat_id = dir.strip_prefix("chats/").unwrap_or(&dir);
+            let Ok(chat_id) = Uuid::parse_str(chat_id) else {
+                continue;
+            };
+            chats.push(load_json_chat(storage, chat_id).await?);
+            dirs.push(dir);
+        }
+
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            for (chat, entries) in &chats {
+                upsert_chat(&tx, chat)?;
+                for entry in entries {
+                    upsert_entry(&tx, entry)?;
+                }
+            }
+            tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+            tx.commit()
+        })
+        .await?;
+
+        for dir in &dirs {
+            storage.rm_rf(dir).await?;
+        }
+        if !dirs.is_empty() {
+            info!("Migrated {} chats into {DB_FILE}", dirs.len());
+        }
+        Ok(())
+    }
+
+    pub async fn exists(&self, chat_id: Uuid) -> anyhow::Result<bool> {
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM chats WHERE chat_id = ?1)",
+                [chat_id.to_string()],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    /// Loads the chat, creating it if it does not exist.
+    pub async fn load_or_create(&self, chat_id: Uuid) -> anyhow::Result<Chat> {
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO chats (chat_id, name, created_at) VALUES (?1, '', ?2)",
+                params![chat_id.to_string(), now_millis()],
+            )?;
+            conn.query_row(
+                "SELECT chat_id, name, created_at FROM chats WHERE chat_id = ?1",
+                [chat_id.to_string()],
+                chat_from_row,
+            )
+        })
+        .await
+    }
+
+    /// Loads up to `limit` chats, newest first, after the one `cursor` points to.
+    pub async fn load_chats(
+        &self,
+        cursor: Option<ChatsCursor>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Chat>> {
+        self.call(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT chat_id, name, created_at FROM chats
+                 WHERE ?1 IS NULL OR (created_at, chat_id) < (?1, ?2)
+                 ORDER BY created_at DESC, chat_id DESC LIMIT ?3",
+            )?;
+            let created_at = cursor.map(|v| v.created_at as i64);
+            let chat_id = cursor.map(|v| v.chat_id.to_string());
+            let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+            let rows = stmt.query_map(params![created_at, chat_id, limit], chat_from_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    pub async fn save_chat(&self, chat: Chat) -> anyhow::Result<()> {
+        self.call(move |conn| upsert_chat(conn, &chat)).await
+    }
+
+    pub async fn delete_chat(&self, chat_id: Uuid) -> anyhow::Result<()> {
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM entries WHERE chat_id = ?1",
+                [chat_id.to_string()],
+            )?;
+            tx.execute(
+                "DELETE FROM chats WHERE chat_id = ?1",
+                [chat_id.to_string()],
+            )?;
+            tx.commit()
+        })
+        .await
+    }
+
+    /// Saves the entry, replacing the previous version of it if any. Its chat is created if
+    /// it does not exist, the same as with chats stored in files.
+    pub async fn save_entry(&self, entry: ChatEntry) -> anyhow::Result<()> {
+        let serial = serde_json::to_string(&entry)?;
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR IGNORE INTO chats (chat_id, name, created_at) VALUES (?1, '', ?2)",
+                params![entry_key(&entry).0.to_string(), now_millis()],
+            )?;
+            upsert_serialized_entry(&tx, &entry, &serial)?;
+            tx.commit()
+        })
+        .await
+    }
+
+    /// Loads the entries of the chat, in the order they were first saved.
+    pub async fn load_entries(&self, chat_id: Uuid) -> anyhow::Result<Vec<ChatEntry>> {
+        let serials = self
+            .call(move |conn| {
+                let mut stmt = conn
+                    .prepare_cached("SELECT entry FROM entries WHERE chat_id = ?1 ORDER BY seq")?;
+                let rows = stmt.query_map([chat_id.to_string()], |row| row.get::<_, String>(0))?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+        Ok(serials
+            .iter()
+            .filter_map(|v| serde_json::from_str(v).ok())
+            .collect())
+    }
+
+    pub async fn load_ai_entry(
+        &self,
+        chat_id: Uuid,
+        id: Uuid,
+    ) -> anyhow::Result<Option<AiChatEntry>> {
+        let serial = self
+            .call(move |conn| {
+                conn.query_row(
+                    "SELECT entry FROM entries WHERE chat_id = ?1 AND id = ?2 AND is_ai = 1",
+                    [chat_id.to_string(), id.to_string()],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+            })
+            .await?;
+        match serial.map(|v| serde_json::from_str(&v)).transpose()? {
+            Some(ChatEntry::Ai(entry)) => Ok(Some(entry)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Deletes both the generation and the prompt that originated it.
+    pub async fn delete_entry(&self, chat_id: Uuid, id: Uuid) -> anyhow::Result<()> {
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM entries WHERE chat_id = ?1 AND id = ?2",
+                [chat_id.to_string(), id.to_string()],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Loads the entries marked as favorite across all chats, newest chats first.
+    pub async fn load_favorites(&self) -> anyhow::Result<Vec<AiChatEntry>> {
+        let serials = self
+            .call(|conn| {
+                let mut stmt = conn.prepare_cached(
+                    "SELECT e.entry FROM entries e JOIN chats c ON c.chat_id = e.chat_id
+                     WHERE e.is_ai = 1 AND e.favorite = 1
+                     ORDER BY c.created_at DESC, c.chat_id DESC, e.seq",
+                )?;
+                let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+        Ok(serials
+            .iter()
+            .filter_map(|v| match serde_json::from_str(v) {
+                Ok(ChatEntry::Ai(entry)) => Some(entry),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+/// Reads a chat stored as JSON files, without writing anything.
+async fn load_json_chat<S: Storage>(
+    storage: &S,
+    chat_id: Uuid,
+) -> anyhow::Result<(Chat, Vec<ChatEntry>)> {
+    let metadata = storage
+        .read(&format!("chats/{chat_id}/{METADATA_FILE}"))
+        .await?;
+    let chat = metadata
+        .and_then(|v| serde_json::from_slice::<Chat>(&v).ok())
+        .unwrap_or_else(|| Chat {
+            chat_id,
+            name: "".to_string(),
+            created_at: now_millis() as u128,
+        });
+
+    let mut entries = vec![];
+    // Entry files are named after their creation time, and listed sorted.
+    for file in storage.list(&format!("chats/{chat_id}")).await? {
+        if file.ends_with(METADATA_FILE) {
+            continue;
+        }
+        if let Some(content) = storage.read(&file).await? {
+            if let Ok(entry) = serde_json::from_slice::<ChatEntry>(&content) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok((chat, entries))
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+fn chat_from_row(row: &rusqlite::Row) -> rusqlite::Result<Chat> {
+    let chat_id = row.get::<_, String>(0)?;
+    Ok(Chat {
+        chat_id: Uuid::parse_str(&chat_id).map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, err.into())
+        })?,
+        name: row.get(1)?,
+        created_at: row.get::<_, i64>(2)? as u128,
+    })
+}
+
+fn upsert_chat(conn: &Connection, chat: &Chat) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO chats (chat_id, name, created_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT (chat_id) DO UPDATE SET name = excluded.name, created_at = excluded.created_at",
+        params![chat.chat_id.to_string(), chat.name, chat.created_at as i64],
+    )?;
+    Ok(())
+}
+
+/// The chat, the id, whether it's the generation or the prompt, and whether it's a favorite.
+fn entry_key(entry: &ChatEntry) -> (Uuid, Uuid, bool, bool) {
+    match entry {
+        ChatEntry::User(v) => (v.chat_id, v.id, false, false),
+        ChatEntry::Ai(v) => (v.chat_id, v.id, true, v.favorite),
+    }
+}
+
+fn upsert_entry(tx: &Transaction, entry: &ChatEntry) -> rusqlite::Result<()> {
+    let serial = serde_json::to_string(entry)
+        .map_err(|err| rusqlite::Error::ToSqlConversionFailure(err.into()))?;
+    upsert_serialized_entry(tx, entry, &serial)
+}
+
+fn upsert_serialized_entry(
+    conn: &Connection,
+    entry: &ChatEntry,
+    serial: &str,
+) -> rusqlite::Result<()> {
+    let (chat_id, id, is_ai, favorite) = entry_key(entry);
+    conn.execute(
+        "INSERT INTO entries (chat_id, id, is_ai, favorite, entry) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (chat_id, id, is_ai) DO UPDATE
+         SET favorite = excluded.favorite, entry = excluded.entry",
+        params![chat_id.to_string(), id.to_string(), is_ai, favorite, serial],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::backend::chat_db::ChatDb;
+    use crate::backend::music_gpt_chat::{AiChatEntry, Chat, ChatEntry};
+    use crate::storage::{AppFs, Storage};
+
+    #[tokio::test]
+    async fn migrates_chats_stored_as_json_files() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let chat_id = Uuid::new_v4();
+        let id = Uuid::new_v4();
+        let chat = Chat {
+            chat_id,
+            name: "old".to_string(),
+            created_at: 1000,
+        };
+        let user = ChatEntry::new_user(chat_id, id, "user_1".to_string());
+        let mut ai = ChatEntry::new_ai_success(chat_id, id, "ai_1".to_string());
+        if let ChatEntry::Ai(entry) = &mut ai {
+            entry.favorite = true;
+        }
+        let dir = format!("chats/{chat_id}");
+        storage
+            .write(&format!("{dir}/.metadata.json"), serde_json::to_vec(&chat)?)
+            .await?;
+        storage
+            .write(
+                &format!("{dir}/2024-01-01 00_00_00_000000_{id}_0.json"),
+                serde_json::to_vec(&user)?,
+            )
+            .await?;
+        storage
+            .write(
+                &format!("{dir}/2024-01-01 00_00_01_000000_{id}_1.json"),
+                serde_json::to_vec(&ai)?,
+            )
+            .await?;
+
+        assert!(ChatDb::of(&storage).await?.is_some());
+        assert!(!storage.exists(&dir).await?);
+        assert_eq!(Chat::load_all(&storage).await?, vec![chat]);
+        assert_eq!(
+            Chat::load_entries(&storage, chat_id).await?,
+            vec![user, ai.clone()]
+        );
+        let ChatEntry::Ai(ai) = ai else {
+            unreachable!()
+        };
+        assert_eq!(AiChatEntry::load_favorites(&storage).await?, vec![ai]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_only_storages_keep_the_json_files() -> anyhow::Result<()> {
+        let storage = AppFs::read_only(AppFs::new_tmp().root);
+        assert!(ChatDb::of(&storage).await?.is_none());
+        Ok(())
+    }
+}
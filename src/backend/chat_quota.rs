@@ -0,0 +1,129 @@
+use anyhow::anyhow;
+use clap::ValueEnum;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::backend::music_gpt_chat::{AiChatEntry, Chat, ChatEntry};
+use crate::disk_usage::format_bytes;
+use crate::storage::Storage;
+
+/// What happens to new generations in a chat whose audios already take its whole quota.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum QuotaPolicy {
+    /// The generation is rejected.
+    #[default]
+    Reject,
+    /// The oldest generations that are not marked as favorite are deleted to make room.
+    Evict,
+}
+
+/// Maximum bytes that the audios of a single chat can take.
+#[derive(Clone, Debug)]
+pub struct ChatQuota {
+    pub max_bytes: u64,
+    pub policy: QuotaPolicy,
+}
+
+impl ChatQuota {
+    /// Makes sure that there's room for a new generation in the chat, either by deleting
+    /// old generations or by failing, depending on the policy. Returns the deleted ones.
+    pub async fn enforce<S: Storage>(
+        &self,
+        storage: &S,
+        chat_id: Uuid,
+    ) -> anyhow::Result<Vec<Uuid>> {
+        // Entries are listed from oldest to newest.
+        let mut entries = vec![];
+        let mut used = 0;
+        for entry in Chat::load_entries(storage, chat_id).await? {
+            let ChatEntry::Ai(entry) = entry else {
+                continue;
+            };
+            if entry.relpath.is_empty() {
+                continue;
+            }
+            let size = storage.len(&entry.relpath).await?.unwrap_or_default();
+            used += size;
+            entries.push((entry, size));
+        }
+
+        // Nothing is deleted if the quota cannot be met anyway.
+        let mut to_evict = vec![];
+        if self.policy == QuotaPolicy::Evict {
+            let mut remaining = used;
+            for (entry, size) in entries {
+                if remaining < self.max_bytes {
+                    break;
+                }
+                if !entry.favorite {
+                    remaining -= size;
+                    to_evict.push(entry.id);
+                }
+            }
+            if remaining < self.max_bytes {
+                used = remaining;
+            }
+        }
+        if used >= self.max_bytes {
+            return Err(anyhow!(
+                "The audios of this chat take {}, which reaches its quota of {}. \
+                 Delete some generations or start a new chat",
+                format_bytes(used),
+                format_bytes(self.max_bytes)
+            ));
+        }
+        let mut evicted = vec![];
+        for id in to_evict {
+            info!(%id, %chat_id, "Deleting generation to stay within the quota");
+            AiChatEntry::delete(storage, chat_id, id).await?;
+            evicted.push(id);
+        }
+        Ok(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    async fn generation(storage: &AppFs, chat_id: Uuid, size: usize) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        let relpath = format!("audios/{id}.wav");
+        storage.write(&relpath, vec![0; size]).await?;
+        ChatEntry::new_ai_success(chat_id, id, relpath)
+            .save(storage)
+            .await?;
+        Ok(id)
+    }
+
+    #[tokio::test]
+    async fn enforces_chat_quotas() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let chat_id = Uuid::new_v4();
+        let favorite = generation(&storage, chat_id, 100).await?;
+        AiChatEntry::rate(&storage, chat_id, favorite, Some(true), None).await?;
+        let oldest = generation(&storage, chat_id, 100).await?;
+        let newest = generation(&storage, chat_id, 100).await?;
+
+        let mut quota = ChatQuota {
+            max_bytes: 301,
+            policy: QuotaPolicy::Reject,
+        };
+        assert!(quota.enforce(&storage, chat_id).await?.is_empty());
+        quota.max_bytes = 300;
+        assert!(quota.enforce(&storage, chat_id).await.is_err());
+
+        quota.policy = QuotaPolicy::Evict;
+        assert_eq!(quota.enforce(&storage, chat_id).await?, vec![oldest]);
+        assert!(!storage.exists(&format!("audios/{oldest}.wav")).await?);
+        assert!(storage.exists(&format!("audios/{newest}.wav")).await?);
+
+        // Favorites are never deleted, so this quota cannot be met.
+        quota.max_bytes = 50;
+        assert!(quota.enforce(&storage, chat_id).await.is_err());
+        assert!(storage.exists(&format!("audios/{favorite}.wav")).await?);
+        assert!(storage.exists(&format!("audios/{newest}.wav")).await?);
+        Ok(())
+    }
+}
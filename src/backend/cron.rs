@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use time::OffsetDateTime;
+
+/// Upper bound for searching the next run, a valid expression that does not match any
+/// minute in 5 years (for example, "0 0 31 2 *") never runs.
+const MAX_SEARCHED_MINUTES: i64 = 5 * 366 * MINUTES_PER_DAY;
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// A standard cron expression with five fields: minute, hour, day of month, month and
+/// day of week, evaluated in UTC. Each field accepts `*`, numbers, ranges like `1-5`,
+/// steps like `*/15` or `0-30/10`, and comma separated lists of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u8, max: u8) -> anyhow::Result<Vec<bool>> {
+    let mut result = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u8>()?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(anyhow!("Invalid step in {part}"));
+        }
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse()?, end.parse()?),
+            None => {
+                let v = range.parse()?;
+                // A single value with a step, like 5/15, goes until the end.
+                (v, if part.contains('/') { max } else { v })
+            }
+        };
+        if start < min || end > max || start > end {
+            return Err(anyhow!("{part} is out of the range {min}-{max}"));
+        }
+        for v in (start..=end).step_by(step as usize) {
+            result[v as usize] = true;
+        }
+    }
+    Ok(result)
+}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(anyhow!(
+                "Cron expressions must have 5 fields: minute, hour, day of month, month and day of week"
+            ));
+        };
+        let parse = |field, min, max| {
+            parse_field(field, min, max).map_err(|err| anyhow!("Invalid cron field {field}: {err}"))
+        };
+        let mut weekdays_parsed = parse(weekdays, 0, 7)?;
+        // Both 0 and 7 are Sunday.
+        weekdays_parsed[0] |= weekdays_parsed[7];
+        Ok(Self {
+            minutes: parse(minutes, 0, 59)?,
+            hours: parse(hours, 0, 23)?,
+            days: parse(days, 1, 31)?,
+            months: parse(months, 1, 12)?,
+            weekdays: weekdays_parsed,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl Cron {
+    fn day_matches(&self, date: OffsetDateTime) -> bool {
+        let day = self.days[date.day() as usize];
+        let weekday = self.weekdays[date.weekday().number_days_from_sunday() as usize];
+        // Like in standard cron, if both days of month and week are restricted, matching
+        // any of them is enough.
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        self.months[u8::from(date.month()) as usize] && day_matches
+    }
+
+    /// The next time, in milliseconds since the epoch, strictly after `after_ms` in
+    /// which the expression matches, if any.
+    pub fn next_after(&self, after_ms: u128) -> Option<u128> {
+        let after_minute = (after_ms / 60_000) as i64;
+        let mut minute = after_minute + 1;
+        while minute <= after_minute + MAX_SEARCHED_MINUTES {
+            let date = OffsetDateTime::from_unix_timestamp(minute * 60).ok()?;
+            if !self.day_matches(date) {
+                // Skip to the start of the next day.
+                minute = (minute / MINUTES_PER_DAY + 1) * MINUTES_PER_DAY;
+                continue;
+            }
+            if self.minutes[date.minute() as usize] && self.hours[date.hour() as usize] {
+                return Some(minute as u128 * 60_000);
+            }
+            minute += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Date, Month};
+
+    fn ms(day: u8, hour: u8, minute: u8, second: u8) -> anyhow::Result<u128> {
+        let date = Date::from_calendar_date(2024, Month::May, day)?;
+        let date = date.with_hms(hour, minute, second)?.assume_utc();
+        Ok(date.unix_timestamp() as u128 * 1000)
+    }
+
+    #[test]
+    fn computes_next_runs() -> anyhow::Result<()> {
+        let now = ms(10, 13, 27, 30)?;
+
+        let cron: Cron = "*/15 * * * *".parse()?;
+        assert_eq!(cron.next_after(now), Some(ms(10, 13, 30, 0)?));
+
+        let cron: Cron = "0 3 * * *".parse()?;
+        assert_eq!(cron.next_after(now), Some(ms(11, 3, 0, 0)?));
+
+        // 2024-05-10 is a Friday.
+        let cron: Cron = "30 8 * * 1-5".parse()?;
+        assert_eq!(cron.next_after(now), Some(ms(13, 8, 30, 0)?));
+
+        let cron: Cron = "0 0 1,15 * *".parse()?;
+        assert_eq!(cron.next_after(now), Some(ms(15, 0, 0, 0)?));
+
+        let cron: Cron = "0 0 31 2 *".parse()?;
+        assert_eq!(cron.next_after(now), None);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+        assert!("5-1 * * * *".parse::<Cron>().is_err());
+        assert!("foo * * * *".parse::<Cron>().is_err());
+    }
+}
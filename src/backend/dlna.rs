@@ -0,0 +1,315 @@
+use std::net::{IpAddr, UdpSocket as StdUdpSocket};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use axum::Router;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::net::{TcpListener, UdpSocket};
+use tower_http::services::ServeFile;
+use tracing::warn;
+
+/// Where UPnP devices listen for discovery requests.
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+
+/// The UPnP service of the renderers that can play media from a URL.
+const AV_TRANSPORT: &str = "urn:schemas-upnp-org:service:AVTransport:1";
+
+/// How long renderers have for answering a discovery request.
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A DLNA media renderer in the LAN, like a smart TV or a networked speaker, that can be
+/// told to play the audio in a URL.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, Type)]
+pub struct Renderer {
+    pub name: String,
+    /// The URL of its UPnP description, which identifies it.
+    pub location: String,
+    #[serde(skip)]
+    control_url: String,
+}
+
+impl Renderer {
+    /// Reads the renderer described in `location`.
+    pub async fn from_location(location: &str) -> anyhow::Result<Self> {
+        let description = reqwest::get(location)
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        parse_description(&description, location)
+    }
+
+    /// Plays the audio in `url`, which must be reachable from the renderer.
+    pub async fn play(&self, url: &str, title: &str) -> anyhow::Result<()> {
+        let metadata = didl_lite(url, title);
+        self.action(
+            "SetAVTransportURI",
+            &[("InstanceID", "0"), ("CurrentURI", url), ("CurrentURIMetaData", &metadata)],
+        )
+        .await?;
+        self.action("Play", &[("InstanceID", "0"), ("Speed", "1")])
+            .await
+    }
+
+    pub async fn stop(&self) -> anyhow::Result<()> {
+        self.action("Stop", &[("InstanceID", "0")]).await
+    }
+
+    /// The address of this machine in the network through which the renderer is reached.
+    pub fn local_ip(&self) -> anyhow::Result<IpAddr> {
+        let url = reqwest::Url::parse(&self.location)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("Renderer {} has no host", self.name))?;
+        let socket = StdUdpSocket::bind("0.0.0.0:0")?;
+        socket.connect((host, url.port_or_known_default().unwrap_or(80)))?;
+        Ok(socket.local_addr()?.ip())
+    }
+
+    async fn action(&self, action: &str, args: &[(&str, &str)]) -> anyhow::Result<()> {
+        let res = reqwest::Client::new()
+            .post(&self.control_url)
+            .header("content-type", "text/xml; charset=\"utf-8\"")
+            .header("soapaction", format!("\"{AV_TRANSPORT}#{action}\""))
+            .body(soap_envelope(action, args))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(anyhow!("{} rejected {action} with {}", self.name, res.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Finds the DLNA renderers in the LAN, waiting `timeout` for their answers.
+pub async fn discover(timeout: Duration) -> anyhow::Result<Vec<Renderer>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {AV_TRANSPORT}\r\n\r\n",
+        timeout.as_secs().max(1)
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDR).await?;
+
+    let mut locations = vec![];
+    let mut buf = [0; 2048];
+    let deadline = tokio::time::Instant::now() + timeout;
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, _) = res?;
+        let Some(location) = ssdp_location(&String::from_utf8_lossy(&buf[..len])) else {
+            continue;
+        };
+        if !locations.contains(&location) {
+            locations.push(location);
+        }
+    }
+
+    let mut renderers = vec![];
+    for location in locations {
+        match Renderer::from_location(&location).await {
+            Ok(renderer) => renderers.push(renderer),
+            Err(err) => warn!("Skipping renderer in {location}: {err}"),
+        }
+    }
+    renderers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(renderers)
+}
+
+/// The renderers found by the last discovery. The server only casts to these, so that
+/// clients cannot make it send requests to arbitrary URLs.
+#[derive(Clone, Default)]
+pub struct DiscoveredRenderers(Arc<RwLock<Vec<Renderer>>>);
+
+impl DiscoveredRenderers {
+    /// Discovers the renderers in the LAN, replacing the previous ones.
+    pub async fn discover(&self, timeout: Duration) -> anyhow::Result<Vec<Renderer>> {
+        let renderers = discover(timeout).await?;
+        *self.0.write().unwrap() = renderers.clone();
+        Ok(renderers)
+    }
+
+    /// Finds a renderer by its location or by its name, discovering them again if it was
+    /// not found in the last discovery.
+    pub async fn find(&self, id: &str, timeout: Duration) -> anyhow::Result<Renderer> {
+        if let Some(renderer) = self.get(id) {
+            return Ok(renderer);
+        }
+        self.discover(timeout).await?;
+        self.get(id)
+            .ok_or_else(|| anyhow!("Renderer {id:?} was not found in the LAN"))
+    }
+
+    fn get(&self, id: &str) -> Option<Renderer> {
+        let renderers = self.0.read().unwrap();
+        renderers
+            .iter()
+            .find(|v| v.location == id || v.name == id)
+            .cloned()
+    }
+}
+
+/// Casts the audio file in `path` to `renderer`, serving it through HTTP until Ctrl+C is
+/// pressed, after which the renderer is stopped.
+pub async fn cast_file(renderer: &Renderer, path: &Path) -> anyhow::Result<()> {
+    let listener = TcpListener::bind((renderer.local_ip()?, 0)).await?;
+    let addr = listener.local_addr()?;
+    let ext = path.extension().and_then(|v| v.to_str()).unwrap_or("wav");
+    let app = Router::new().fallback_service(ServeFile::new(path));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let title = path.file_stem().and_then(|v| v.to_str()).unwrap_or("MusicGPT");
+    renderer.play(&format!("http://{addr}/audio.{ext}"), title).await?;
+    println!("Casting {} to {}, press Ctrl+C to stop", path.display(), renderer.name);
+    tokio::signal::ctrl_c().await?;
+    renderer.stop().await
+}
+
+/// The URL of the description of the device that answered a discovery request.
+fn ssdp_location(response: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case("location")
+            .then(|| value.trim().to_string())
+    })
+}
+
+fn tag_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(xml[start..end].trim())
+}
+
+/// Reads the name and the AVTransport control URL of the renderer in a UPnP description.
+fn parse_description(xml: &str, location: &str) -> anyhow::Result<Renderer> {
+    let name = tag_text(xml, "friendlyName").unwrap_or("Unknown renderer");
+    let control_url = xml
+        .split("<service>")
+        .find(|service| tag_text(service, "serviceType") == Some(AV_TRANSPORT))
+        .and_then(|service| tag_text(service, "controlURL"))
+        .ok_or_else(|| anyhow!("{name} is not a media renderer"))?;
+    let control_url = reqwest::Url::parse(location)?.join(control_url)?;
+    Ok(Renderer {
+        name: xml_unescape(name),
+        location: location.to_string(),
+        control_url: control_url.to_string(),
+    })
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+fn soap_envelope(action: &str, args: &[(&str, &str)]) -> String {
+    let args: String = args
+        .iter()
+        .map(|(k, v)| format!("<{k}>{}</{k}>", xml_escape(v)))
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:{action} xmlns:u="{AV_TRANSPORT}">{args}</u:{action}></s:Body></s:Envelope>"#
+    )
+}
+
+/// The metadata of the audio, which some renderers need for playing it.
+fn didl_lite(url: &str, title: &str) -> String {
+    format!(
+        r#"<DIDL-Lite xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/"><item id="0" parentID="-1" restricted="1"><dc:title>{}</dc:title><upnp:class>object.item.audioItem.musicTrack</upnp:class><res protocolInfo="http-get:*:audio/wav:*">{}</res></item></DIDL-Lite>"#,
+        xml_escape(title),
+        xml_escape(url)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::HeaderMap;
+    use axum::routing::{get, post};
+
+    use super::*;
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <friendlyName>Living room &amp; kitchen</friendlyName>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:RenderingControl:1</serviceType>
+        <controlURL>/rendering</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:AVTransport:1</serviceType>
+        <controlURL>/transport</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>"#;
+
+    #[test]
+    fn parses_renderers() -> anyhow::Result<()> {
+        let response = "HTTP/1.1 200 OK\r\nCache-Control: max-age=1800\r\nLOCATION: http://192.168.1.20:49152/description.xml\r\n\r\n";
+        assert_eq!(
+            ssdp_location(response),
+            Some("http://192.168.1.20:49152/description.xml".to_string())
+        );
+        assert_eq!(ssdp_location("HTTP/1.1 200 OK\r\n\r\n"), None);
+
+        let renderer = parse_description(DESCRIPTION, "http://192.168.1.20:49152/description.xml")?;
+        assert_eq!(renderer.name, "Living room & kitchen");
+        assert_eq!(renderer.control_url, "http://192.168.1.20:49152/transport");
+        let media_server = DESCRIPTION.replace("AVTransport", "ContentDirectory");
+        assert!(parse_description(&media_server, "http://192.168.1.20/").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn casts_to_renderers() -> anyhow::Result<()> {
+        let actions = Arc::new(Mutex::new(vec![]));
+        let actions_clone = actions.clone();
+        let app = Router::new()
+            .route("/description.xml", get(|| async { DESCRIPTION }))
+            .route(
+                "/transport",
+                post(move |headers: HeaderMap, body: String| async move {
+                    let action = headers["soapaction"].to_str().unwrap().to_string();
+                    actions_clone.lock().unwrap().push((action, body));
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let renderer = Renderer::from_location(&format!("http://{addr}/description.xml")).await?;
+        renderer.play("http://127.0.0.1/files/a&b.wav", "Lo-fi").await?;
+        let actions = actions.lock().unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].0, format!("\"{AV_TRANSPORT}#SetAVTransportURI\""));
+        assert!(actions[0].1.contains("<CurrentURI>http://127.0.0.1/files/a&amp;b.wav</CurrentURI>"));
+        assert_eq!(actions[1].0, format!("\"{AV_TRANSPORT}#Play\""));
+        assert_eq!(renderer.local_ip()?.to_string(), "127.0.0.1");
+        Ok(())
+    }
+
+    #[test]
+    fn only_finds_discovered_renderers() -> anyhow::Result<()> {
+        let location = "http://192.168.1.20:49152/description.xml";
+        let renderers = DiscoveredRenderers::default();
+        *renderers.0.write().unwrap() = vec![parse_description(DESCRIPTION, location)?];
+        assert_eq!(renderers.get(location).unwrap().name, "Living room & kitchen");
+        assert_eq!(renderers.get("Living room & kitchen").unwrap().location, location);
+        assert_eq!(renderers.get("http://169.254.169.254/latest/meta-data"), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use std::convert::Infallible;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::Stream;
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::audio_generation_fanout::GenerationMessage;
+
+/// Streams the same generation messages that are sent through the websocket as
+/// Server-Sent Events, for clients that just want to observe generations.
+pub fn generation_events(
+    ai_broadcast_tx: &tokio::sync::broadcast::Sender<GenerationMessage>,
+    close: CancellationToken,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let mut rx = ai_broadcast_tx.subscribe();
+    let stream = async_stream::stream! {
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = close.cancelled() => break,
+            };
+            match msg {
+                Ok(msg) => match Event::default().json_data(&msg) {
+                    Ok(event) => yield Ok(event),
+                    Err(_) => continue,
+                },
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+    };
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use uuid::Uuid;
+
+    use crate::backend::_test_utils::{spawn, DummyJobProcessor, TungsteniteMsg};
+    use crate::backend::audio_generation_fanout::GenerationMessage;
+    use crate::backend::music_gpt_ws_handler::{GenerateAudioRequest, InboundMsg};
+
+    #[tokio::test]
+    async fn streams_generation_events() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::default()).await?;
+        let res = reqwest::get(format!("http://{host}/events")).await?;
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+        let mut events = res.bytes_stream();
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: 1,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        let mut received = String::new();
+        while !received.contains("\"Result\"") {
+            received += std::str::from_utf8(&events.next().await.unwrap()?)?;
+        }
+        let mut data = received.lines().filter_map(|l| l.strip_prefix("data: "));
+        let msg: GenerationMessage = serde_json::from_str(data.next().unwrap())?;
+        assert!(matches!(msg, GenerationMessage::Start(v) if v.id == id));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use percent_encoding::percent_decode_str;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::backend::music_gpt_chat::Chat;
+use crate::backend::reference_audio::ReferenceAudio;
+use crate::storage::Storage;
+
+pub async fn chat_zip<S: Storage>(storage: S, chat_id: Uuid) -> Response {
+    match Chat::to_zip(&storage, chat_id).await {
+        Ok(Some(stream)) => {
+            let disposition = format!("attachment; filename=\"musicgpt-chat-{chat_id}.zip\"");
+            (
+                [
+                    (header::CONTENT_TYPE, "application/zip".to_string()),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                Body::from_stream(stream),
+            )
+                .into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, format!("Chat {chat_id} not found")).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Serves the files of storages that are not in the local disk, which cannot be served
+/// with [tower_http::services::ServeDir]. Like it, it serves single ranges of bytes, so that
+/// browsers can seek in long audios without downloading them whole.
+pub async fn serve_file<S: Storage>(storage: S, uri: Uri, headers: HeaderMap) -> Response {
+    let path = percent_decode_str(uri.path()).decode_utf8_lossy();
+    let path = path.trim_start_matches('/');
+    if path.is_empty() || path.split('/').any(|v| v == "..") {
+        return (StatusCode::BAD_REQUEST, format!("Invalid file {path}")).into_response();
+    }
+    let content_type = match path.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("wav") => "audio/wav",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    };
+    let not_found = || (StatusCode::NOT_FOUND, format!("File {path} not found")).into_response();
+    let len = match storage.len(path).await {
+        Ok(Some(len)) => len,
+        Ok(None) => return not_found(),
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+    let open_err =
+        |err: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+
+    let Some(range) = headers.get(header::RANGE) else {
+        let reader = match storage.read_stream(path).await {
+            Ok(Some(reader)) => reader,
+            Ok(None) => return not_found(),
+            Err(err) => return open_err(err),
+        };
+        let headers = [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::CONTENT_LENGTH, len.to_string()),
+        ];
+        return (headers, Body::from_stream(ReaderStream::new(reader))).into_response();
+    };
+    let Some((start, end)) = range.to_str().ok().and_then(|v| byte_range(v, len)) else {
+        let headers = [(header::CONTENT_RANGE, format!("bytes */{len}"))];
+        return (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response();
+    };
+    let reader = match storage.read_range(path, start, end).await {
+        Ok(Some(reader)) => reader,
+        Ok(None) => return not_found(),
+        Err(err) => return open_err(err),
+    };
+    let headers = [
+        (header::CONTENT_TYPE, content_type.to_string()),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        (header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}")),
+        (header::CONTENT_LENGTH, (end - start + 1).to_string()),
+    ];
+    let body = Body::from_stream(ReaderStream::new(reader.take(end - start + 1)));
+    (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+}
+
+/// Parses a `Range` header with a single range of bytes, like `bytes=0-99`, `bytes=100-` or
+/// `bytes=-100`, into its first and last byte. None if a file of `len` bytes cannot satisfy
+/// it, or if it has several ranges.
+fn byte_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let last = len.checked_sub(1)?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last bytes of the file.
+        ("", suffix) => match suffix.parse::<u64>().ok()? {
+            0 => return None,
+            suffix => (len.saturating_sub(suffix), last),
+        },
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(last)),
+    };
+    (start <= end).then_some((start, end))
+}
+
+/// Stores the reference audio in the body, whose format is given by its content type.
+/// References are refused if none of the models can be conditioned on them.
+pub async fn upload_reference<S: Storage>(
+    storage: S,
+    supported: bool,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !supported {
+        let msg = "None of the models can be conditioned with audio";
+        return (StatusCode::NOT_IMPLEMENTED, msg).into_response();
+    }
+    let mime = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let Some(format) = ReferenceAudio::format_from_mime(mime) else {
+        let msg = format!("Unsupported content type {mime:?}, use a wav, mp3 or flac file");
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg).into_response();
+    };
+    match ReferenceAudio::save(&storage, body.to_vec(), format).await {
+        Ok(reference) => Json(reference).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use base64::Engine;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::backend::_test_utils::{
+        spawn, spawn_with_storage, DummyJobProcessor, TungsteniteMsg,
+    };
+    use crate::backend::music_gpt_ws_handler::{
+        ChatRequest, GenerateAudioRequest, InboundMsg, OutboundMsg, UploadReferenceRequest,
+    };
+    use crate::storage::AppFs;
+    use axum::body::to_bytes;
+
+    #[tokio::test]
+    async fn uploads_reference_audios() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::default().with_reference()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        let wav = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test.wav"))?;
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{host}/references");
+        let res = client
+            .post(&url)
+            .header("content-type", "audio/wav")
+            .body(wav.clone())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let reference: ReferenceAudio = serde_json::from_slice(&res.bytes().await?)?;
+        let res = reqwest::get(format!("http://{host}/files/{}", reference.relpath)).await?;
+        assert_eq!(res.bytes().await?, wav);
+
+        let res = client
+            .post(&url)
+            .header("content-type", "audio/ogg")
+            .body(wav.clone())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let res = client
+            .post(&url)
+            .header("content-type", "audio/wav")
+            .body("not an audio")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        InboundMsg::UploadReference(UploadReferenceRequest {
+            format: "wav".to_string(),
+            data: base64::engine::general_purpose::STANDARD.encode(&wav),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let msg = OutboundMsg::from_ws(&mut ws).await?;
+        assert!(matches!(msg, OutboundMsg::ReferenceUploaded(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_references_if_no_model_supports_them() -> anyhow::Result<()> {
+        let app_fs = AppFs::new_tmp();
+        let wav = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test.wav"))?;
+        let reference = ReferenceAudio::save(&app_fs, wav.clone(), "wav").await?;
+        let (mut ws, host) = spawn_with_storage(DummyJobProcessor::default(), app_fs).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let res = reqwest::Client::new()
+            .post(format!("http://{host}/references"))
+            .header("content-type", "audio/wav")
+            .body(wav.clone())
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_IMPLEMENTED);
+
+        InboundMsg::UploadReference(UploadReferenceRequest {
+            format: "wav".to_string(),
+            data: base64::engine::general_purpose::STANDARD.encode(&wav),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let msg = OutboundMsg::from_ws(&mut ws).await?;
+        assert!(matches!(msg, OutboundMsg::Error(v) if v.contains("conditioned with audio")));
+
+        // References stored before cannot be used either.
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
+            prompt: "Continue this song".to_string(),
+            secs: 1,
+            reference_id: Some(reference.id),
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let msg = OutboundMsg::from_ws(&mut ws).await?;
+        assert!(matches!(msg, OutboundMsg::Error(v) if v.contains("audio conditioning")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exports_a_chat_as_zip() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::default()).await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GenerateAudioNewChat(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "foo".to_string(),
+            secs: 1,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        OutboundMsg::from_ws(&mut ws).await?.start();
+        OutboundMsg::from_ws(&mut ws).await?.progress();
+        OutboundMsg::from_ws(&mut ws).await?.result();
+
+        InboundMsg::ExportChat(ChatRequest { chat_id })
+            .to_ws(&mut ws)
+            .await?;
+        let OutboundMsg::ChatExport(export) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("msg was not OutboundMsg::ChatExport")
+        };
+        assert_eq!(export.chat_id, chat_id);
+
+        let res = reqwest::get(format!("http://{host}{}", export.url)).await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "application/zip"
+        );
+        let archive = zip::ZipArchive::new(std::io::Cursor::new(res.bytes().await?))?;
+        assert!(archive
+            .file_names()
+            .any(|v| v == format!("audios/{id}.wav")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_audio_files_in_ranges() -> anyhow::Result<()> {
+        let app_fs = AppFs::new_tmp();
+        let wav = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test.wav"))?;
+        app_fs.write("audios/test.wav", &wav).await?;
+        let (_ws, host) = spawn_with_storage(DummyJobProcessor::default(), app_fs).await?;
+        let url = format!("http://{host}/files/audios/test.wav");
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.headers().get("accept-ranges").unwrap(), "bytes");
+
+        let res = client
+            .get(&url)
+            .header("range", "bytes=10-19")
+            .send()
+            .await?;
+        assert_eq!(res.status(), 206);
+        let content_range = format!("bytes 10-19/{}", wav.len());
+        assert_eq!(res.headers().get("content-range").unwrap(), &content_range);
+        assert_eq!(res.bytes().await?.to_vec(), wav[10..20]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_files_of_remote_storages_in_ranges() -> anyhow::Result<()> {
+        let app_fs = AppFs::new_tmp();
+        app_fs.write("audios/test.wav", "0123456789").await?;
+        let uri = Uri::from_static("/audios/test.wav");
+        let range = |v: &'static str| HeaderMap::from_iter([(header::RANGE, v.parse().unwrap())]);
+
+        let res = serve_file(app_fs.clone(), uri.clone(), HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(to_bytes(res.into_body(), usize::MAX).await?, "0123456789");
+
+        for (value, content_range, content) in [
+            ("bytes=2-4", "bytes 2-4/10", "234"),
+            ("bytes=7-", "bytes 7-9/10", "789"),
+            ("bytes=-2", "bytes 8-9/10", "89"),
+            ("bytes=8-20", "bytes 8-9/10", "89"),
+        ] {
+            let res = serve_file(app_fs.clone(), uri.clone(), range(value)).await;
+            assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(res.headers()[header::CONTENT_RANGE], content_range);
+            assert_eq!(to_bytes(res.into_body(), usize::MAX).await?, content);
+        }
+
+        for value in ["bytes=10-", "bytes=5-2", "bytes=-0", "bytes=0-1,4-5"] {
+            let res = serve_file(app_fs.clone(), uri.clone(), range(value)).await;
+            assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */10");
+        }
+
+        let uri = Uri::from_static("/audios/missing.wav");
+        let res = serve_file(app_fs, uri, range("bytes=0-1")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+}
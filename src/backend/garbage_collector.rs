@@ -0,0 +1,156 @@
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::backend::music_gpt_chat::{Chat, ChatEntry};
+use crate::disk_usage::format_bytes;
+use crate::storage::Storage;
+
+/// Interrupted downloads are resumed, so their temporary files are only removed once
+/// they have not been touched in this long.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Audios are saved right before their chat entry, so recent audios are never removed
+/// even if no entry references them yet.
+const ORPHAN_AUDIO_AGE: Duration = Duration::from_secs(60 * 60);
+const COLLECT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GarbageCollection {
+    pub temp_files: usize,
+    pub audios: usize,
+    pub freed: u64,
+}
+
+fn older_than(modified: SystemTime, age: Duration, now: SystemTime) -> bool {
+    now.duration_since(modified).unwrap_or_default() > age
+}
+
+/// Removes the temporary files of downloads that were abandoned and the audios that no
+/// chat entry references anymore, like the ones of deleted chats.
+pub async fn collect_garbage<S: Storage>(
+    storage: &S,
+    now: SystemTime,
+) -> anyhow::Result<GarbageCollection> {
+    let mut result = GarbageCollection::default();
+    if storage.is_read_only() {
+        return Ok(result);
+    }
+
+    let mut pending = vec![];
+    for path in storage.list("").await? {
+        // Audios are not downloaded, and chats might be large.
+        if path != "audios" && path != "chats" {
+            pending.push(path)
+        }
+    }
+    while let Some(path) = pending.pop() {
+        let Some(metadata) = storage.metadata(&path).await? else {
+            continue;
+        };
+        if metadata.is_dir {
+            pending.extend(storage.list(&path).await?);
+        } else if path.ends_with(".temp") && older_than(metadata.modified, STALE_TEMP_AGE, now) {
+            info!("Removing stale temporary file {path}");
+            storage.rm(&path).await?;
+            result.temp_files += 1;
+            result.freed += metadata.size;
+        }
+    }
+
+    let mut referenced = HashSet::new();
+    for chat in Chat::load_all(storage).await? {
+        for entry in Chat::load_entries(storage, chat.chat_id).await? {
+            if let ChatEntry::Ai(entry) = entry {
+                referenced.insert(entry.relpath);
+            }
+        }
+    }
+    for path in storage.list("audios").await? {
+        if referenced.contains(&path) {
+            continue;
+        }
+        let Some(metadata) = storage.metadata(&path).await? else {
+            continue;
+        };
+        if !metadata.is_dir && older_than(metadata.modified, ORPHAN_AUDIO_AGE, now) {
+            info!("Removing orphaned audio {path}");
+            storage.rm(&path).await?;
+            result.audios += 1;
+            result.freed += metadata.size;
+        }
+    }
+
+    if result.freed > 0 {
+        info!(
+            "Removed {} stale temporary files and {} orphaned audios, freeing {}",
+            result.temp_files,
+            result.audios,
+            format_bytes(result.freed)
+        );
+    }
+    Ok(result)
+}
+
+/// Periodically collects garbage, until `shutdown` is cancelled. The first collection
+/// happens after the first interval, as MusicGPT already collects garbage on startup.
+pub async fn run_garbage_collector<S: Storage>(storage: S, shutdown: CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(COLLECT_INTERVAL) => {}
+            _ = shutdown.cancelled() => return,
+        }
+        if let Err(err) = collect_garbage(&storage, SystemTime::now()).await {
+            error!(%err, "Error collecting garbage");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn removes_stale_temp_files_and_orphaned_audios() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let (chat_id, id, orphan) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        storage.write("v1/small/model.onnx.temp", [0; 100]).await?;
+        storage.write("v1/small/config.json", [0; 10]).await?;
+        storage.write(&format!("audios/{id}.wav"), [0; 20]).await?;
+        storage
+            .write(&format!("audios/{orphan}.wav"), [0; 30])
+            .await?;
+        Chat::load(&storage, chat_id).await?;
+        ChatEntry::new_ai_success(chat_id, id, format!("audios/{id}.wav"))
+            .save(&storage)
+            .await?;
+
+        // Nothing is old enough yet.
+        let now = SystemTime::now();
+        let result = collect_garbage(&storage, now).await?;
+        assert_eq!(result, GarbageCollection::default());
+
+        let result = collect_garbage(&storage, now + ORPHAN_AUDIO_AGE * 2).await?;
+        assert_eq!(result.audios, 1);
+        assert_eq!(result.temp_files, 0);
+        assert!(!storage.exists(&format!("audios/{orphan}.wav")).await?);
+        assert!(storage.exists(&format!("audios/{id}.wav")).await?);
+
+        let result = collect_garbage(&storage, now + STALE_TEMP_AGE * 2).await?;
+        assert_eq!(
+            result,
+            GarbageCollection {
+                temp_files: 1,
+                audios: 0,
+                freed: 100
+            }
+        );
+        assert!(!storage.exists("v1/small/model.onnx.temp").await?);
+        assert!(storage.exists("v1/small/config.json").await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::anyhow;
+
+use crate::backend::chat_quota::ChatQuota;
+
+/// Limits on the generation requests that clients can send, enforced on every request
+/// so that exposing the server does not allow anyone to monopolize it.
+#[derive(Clone, Debug)]
+pub struct GenerationLimits {
+    /// Maximum seconds of audio that a single request can ask for.
+    pub max_secs: usize,
+    /// Maximum jobs in the queue, including the one being processed. New requests are
+    /// rejected once reached.
+    pub max_queued_jobs: Option<usize>,
+    /// Models that requests can refer to, any model is allowed if empty.
+    pub allowed_models: Vec<String>,
+    /// Maximum bytes of audio in each chat, checked before generating in an existing chat.
+    pub chat_quota: Option<ChatQuota>,
+}
+
+impl Default for GenerationLimits {
+    fn default() -> Self {
+        Self {
+            max_secs: 30,
+            max_queued_jobs: None,
+            allowed_models: vec![],
+            chat_quota: None,
+        }
+    }
+}
+
+impl GenerationLimits {
+    pub fn check_secs(&self, secs: usize) -> anyhow::Result<()> {
+        if !(1..=self.max_secs).contains(&secs) {
+            return Err(anyhow!("secs must be between 1 and {}", self.max_secs));
+        }
+        Ok(())
+    }
+
+    pub fn check_model(&self, model: &str) -> anyhow::Result<()> {
+        if !self.allowed_models.is_empty() && !self.allowed_models.iter().any(|v| v == model) {
+            return Err(anyhow!("Model {model} is not allowed in this server"));
+        }
+        Ok(())
+    }
+
+    /// Checks that `model` is allowed and that it's the one loaded by the server, referred
+    /// either by its id, like "small", or by its name, like "MusicGen Small".
+    pub fn check_model_loaded(&self, model: &str, id: &str, name: &str) -> anyhow::Result<()> {
+        if model != id && model != name {
+            return Err(anyhow!(
+                "Model {model} is not available, this server runs {id} ({name})"
+            ));
+        }
+        self.check_model(id)
+    }
+
+    /// Like [GenerationLimits::check_model_loaded], but also accepts the models provided by
+    /// plugins. Returns the plugin that must process the request, if any.
+    pub fn check_model_available(
+        &self,
+        model: &str,
+        id: &str,
+        name: &str,
+        plugins: &[String],
+    ) -> anyhow::Result<Option<String>> {
+        if plugins.iter().any(|v| v == model) {
+            self.check_model(model)?;
+            return Ok(Some(model.to_string()));
+        }
+        self.check_model_loaded(model, id, name).map(|_| None)
+    }
+
+    pub fn check_queue(&self, queued_jobs: usize) -> anyhow::Result<()> {
+        match self.max_queued_jobs {
+            Some(max) if queued_jobs >= max => Err(anyhow!(
+                "There are already {queued_jobs} jobs queued, try again later"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enforces_limits() -> anyhow::Result<()> {
+        let limits = GenerationLimits {
+            max_secs: 10,
+            max_queued_jobs: Some(2),
+            allowed_models: vec!["small".to_string()],
+            chat_quota: None,
+        };
+        assert!(limits.check_secs(10).is_ok());
+        assert!(limits.check_secs(11).is_err());
+        assert!(limits.check_secs(0).is_err());
+        assert!(limits.check_model("small").is_ok());
+        assert!(limits.check_model("large").is_err());
+        assert!(limits.check_queue(1).is_ok());
+        assert!(limits.check_queue(2).is_err());
+
+        assert!(limits
+            .check_model_loaded("MusicGen Small", "small", "MusicGen Small")
+            .is_ok());
+        assert!(limits
+            .check_model_loaded("large", "small", "MusicGen Small")
+            .is_err());
+        assert!(limits
+            .check_model_loaded("large", "large", "MusicGen Large")
+            .is_err());
+
+        let plugins = vec!["remote".to_string()];
+        assert_eq!(
+            limits.check_model_available("small", "small", "MusicGen Small", &plugins)?,
+            None
+        );
+        assert!(limits
+            .check_model_available("remote", "small", "MusicGen Small", &plugins)
+            .is_err());
+
+        let limits = GenerationLimits::default();
+        assert_eq!(
+            limits.check_model_available("remote", "small", "MusicGen Small", &plugins)?,
+            Some("remote".to_string())
+        );
+        assert!(limits.check_model("large").is_ok());
+        assert!(limits.check_queue(100).is_ok());
+        Ok(())
+    }
+}
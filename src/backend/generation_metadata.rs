@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{append_chunk, read_chunk};
+use crate::backend::audio_generation_backend::GenerationTimings;
+
+/// The id of the .wav chunk in which the metadata is embedded.
+const WAV_CHUNK_ID: &[u8; 4] = b"mgpt";
+
+/// The parameters a generated audio was made with, embedded in its .wav file and written next
+/// to it with `--metadata`, so that it can be reproduced or compared with others later.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GenerationMetadata {
+    pub prompt: String,
+    /// The prompt the audio was generated from, if `prompt` was rewritten by the prompt
+    /// enhancer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enhanced_prompt: Option<String>,
+    /// The model, as given to --model.
+    pub model: String,
+    pub secs: usize,
+    /// The seed the audio tokens were sampled with, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// When the audio finished generating, in RFC 3339.
+    pub created_at: String,
+    pub timings: GenerationTimings,
+}
+
+impl GenerationMetadata {
+    pub fn new(prompt: &str, model: &str, secs: usize, timings: GenerationTimings) -> Self {
+        let created_at = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        Self {
+            prompt: prompt.to_string(),
+            enhanced_prompt: None,
+            model: model.to_string(),
+            secs,
+            seed: None,
+            created_at,
+            timings,
+        }
+    }
+
+    /// Embeds the metadata in the .wav file in `wav`.
+    pub fn embed(&self, wav: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        append_chunk(wav, WAV_CHUNK_ID, &serde_json::to_vec(self)?)
+    }
+
+    /// Reads the metadata embedded in the .wav file in `wav`, none if it was not generated by
+    /// MusicGPT.
+    pub fn from_wav(wav: &[u8]) -> anyhow::Result<Option<Self>> {
+        match read_chunk(wav, WAV_CHUNK_ID)? {
+            Some(data) => Ok(Some(serde_json::from_slice(data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The command that generates the audio again, with the enhanced prompt if any.
+    pub fn command(&self) -> String {
+        let prompt = self.enhanced_prompt.as_ref().unwrap_or(&self.prompt);
+        let mut command = format!(
+            "musicgpt {} --model {} --secs {}",
+            shell_words::quote(prompt),
+            self.model,
+            self.secs
+        );
+        if let Some(seed) = self.seed {
+            command += &format!(" --seed {seed}");
+        }
+        command + " --no-interactive"
+    }
+
+    /// Where the metadata of the audio in `audio_path` is written, `song.wav` -> `song.json`.
+    pub fn sidecar_path(audio_path: &Path) -> PathBuf {
+        audio_path.with_extension("json")
+    }
+
+    /// Writes the metadata next to the audio in `audio_path`, returning where it was written.
+    pub async fn write_sidecar(&self, audio_path: &Path) -> anyhow::Result<PathBuf> {
+        let path = Self::sidecar_path(audio_path);
+        tokio::fs::write(&path, serde_json::to_vec_pretty(self)?).await?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioManager;
+    use crate::storage::AppFs;
+    use cpal::SampleFormat;
+    use std::collections::VecDeque;
+
+    #[tokio::test]
+    async fn writes_sidecars() -> anyhow::Result<()> {
+        let dir = AppFs::new_tmp().root;
+        tokio::fs::create_dir_all(&dir).await?;
+        let timings = GenerationTimings {
+            text_encoding_secs: 0.5,
+            token_generation_secs: 10.0,
+            audio_decoding_secs: 1.0,
+        };
+        let metadata = GenerationMetadata {
+            seed: Some(42),
+            ..GenerationMetadata::new("Lo-fi beats", "small", 10, timings)
+        };
+        let path = metadata.write_sidecar(&dir.join("song.wav")).await?;
+        assert_eq!(path, dir.join("song.json"));
+
+        let read: GenerationMetadata = serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+        assert_eq!(read, metadata);
+        assert!(!read.created_at.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn embeds_in_wavs() -> anyhow::Result<()> {
+        let audio_manager = AudioManager::new(32000, 1, SampleFormat::F32);
+        let wav = audio_manager.to_wav(VecDeque::from(vec![0.0; 32]))?;
+        assert_eq!(GenerationMetadata::from_wav(&wav)?, None);
+
+        let metadata = GenerationMetadata::new("Rock", "small", 4, Default::default());
+        let wav = metadata.embed(wav)?;
+        assert_eq!(GenerationMetadata::from_wav(&wav)?, Some(metadata.clone()));
+        assert_eq!(
+            metadata.command(),
+            "musicgpt Rock --model small --secs 4 --no-interactive"
+        );
+
+        let metadata = GenerationMetadata {
+            seed: Some(7),
+            ..metadata
+        };
+        assert_eq!(
+            metadata.command(),
+            "musicgpt Rock --model small --secs 4 --seed 7 --no-interactive"
+        );
+        Ok(())
+    }
+}
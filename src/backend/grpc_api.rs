@@ -0,0 +1,362 @@
+use std::pin::Pin;
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::Stream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tonic::{Request, Response, Status};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::{AudioGenerationBackend, BackendInboundMsg};
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::generation_limits::GenerationLimits;
+use crate::backend::music_gpt_chat::Chat;
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::persisted_queue::PersistedJob;
+use crate::backend::GenerationStage;
+use crate::storage::Storage;
+
+pub mod proto {
+    tonic::include_proto!("musicgpt.v1");
+}
+
+use proto::generation_event::Event;
+use proto::generation_stage::Stage;
+use proto::music_gpt_server::MusicGpt;
+pub use proto::music_gpt_server::MusicGptServer;
+
+/// The gRPC service described in proto/musicgpt.proto, built on the same backend as the
+/// websocket and the OpenAI compatible APIs.
+#[derive(Clone)]
+pub struct GrpcApi<S: Storage> {
+    pub storage: S,
+    pub ai_broadcast_tx: tokio::sync::broadcast::Sender<GenerationMessage>,
+    pub ai_tx: Sender<BackendInboundMsg>,
+    pub backend: AudioGenerationBackend,
+    pub limits: GenerationLimits,
+    pub model: String,
+    pub model_id: String,
+    /// Models provided by plugins, which requests can also ask for.
+    pub plugins: Vec<String>,
+    /// Cancelled when the server starts shutting down, after that no new jobs are accepted.
+    pub shutdown: CancellationToken,
+    /// Cancelled once the server stopped processing jobs, which ends the progress streams.
+    pub close: CancellationToken,
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::GenerationEvent, Status>> + Send>>;
+
+impl<S: Storage> GrpcApi<S> {
+    /// Checks the request like the websocket does, returning the plugin that processes it.
+    fn check_generation(&self, req: &proto::GenerateRequest) -> Result<Option<String>, Status> {
+        if self.shutdown.is_cancelled() {
+            let msg = "MusicGPT is shutting down, no new jobs are accepted";
+            return Err(Status::unavailable(msg));
+        }
+        if req.prompt.is_empty() {
+            return Err(Status::invalid_argument("prompt must not be empty"));
+        }
+        if !(1..=self.limits.max_secs).contains(&(req.secs as usize)) {
+            let msg = format!("secs must be between 1 and {}", self.limits.max_secs);
+            return Err(Status::invalid_argument(msg));
+        }
+        let plugin = match &req.model {
+            Some(model) => self
+                .limits
+                .check_model_available(model, &self.model_id, &self.model, &self.plugins)
+                .map_err(|err| Status::invalid_argument(err.to_string()))?,
+            None => None,
+        };
+        if let Err(err) = self.limits.check_queue(self.backend.queue_len()) {
+            return Err(Status::resource_exhausted(err.to_string()));
+        }
+        Ok(plugin)
+    }
+
+    /// Queues the generation as a new chat, so that it also shows up in the web app and
+    /// it's resumed if MusicGPT is restarted.
+    async fn queue(
+        &self,
+        req: proto::GenerateRequest,
+        model: Option<String>,
+    ) -> anyhow::Result<proto::GenerationStart> {
+        info!("Generating audio from the gRPC API");
+        let chat = Chat {
+            chat_id: Uuid::new_v4(),
+            name: req.prompt.clone(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+        };
+        chat.save(&self.storage).await?;
+        let secs = req.secs as usize;
+        let job = PersistedJob {
+            model,
+            dedupe: req.dedupe.unwrap_or(true),
+            seed: req.seed,
+            ..PersistedJob::new(chat.chat_id, Uuid::new_v4(), req.prompt, secs)
+        };
+        job.save(&self.storage).await?;
+        self.ai_tx.send(BackendInboundMsg::Request(
+            job.request(&self.storage).await?,
+        ))?;
+        Ok(proto::GenerationStart {
+            id: job.id.to_string(),
+            chat_id: job.chat_id.to_string(),
+            prompt: job.prompt,
+            secs: req.secs,
+            enhanced_prompt: None,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl<S: Storage + 'static> MusicGpt for GrpcApi<S> {
+    async fn generate(
+        &self,
+        request: Request<proto::GenerateRequest>,
+    ) -> Result<Response<proto::GenerationStart>, Status> {
+        let req = request.into_inner();
+        let plugin = self.check_generation(&req)?;
+        match self.queue(req, plugin).await {
+            Ok(start) => Ok(Response::new(start)),
+            Err(err) => Err(Status::internal(err.to_string())),
+        }
+    }
+
+    type StreamProgressStream = EventStream;
+
+    async fn stream_progress(
+        &self,
+        request: Request<proto::StreamProgressRequest>,
+    ) -> Result<Response<Self::StreamProgressStream>, Status> {
+        let id = match request.into_inner().id {
+            Some(id) => match id.parse::<Uuid>() {
+                Ok(id) => Some(id),
+                Err(err) => return Err(Status::invalid_argument(format!("Invalid id: {err}"))),
+            },
+            None => None,
+        };
+        let mut rx = self.ai_broadcast_tx.subscribe();
+        let close = self.close.clone();
+        let stream = async_stream::stream! {
+            loop {
+                let msg = tokio::select! {
+                    msg = rx.recv() => msg,
+                    _ = close.cancelled() => break,
+                };
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                };
+                if id.is_some_and(|id| id != msg_id(&msg)) {
+                    continue;
+                }
+                let finished =
+                    matches!(msg, GenerationMessage::Result(_) | GenerationMessage::Error(_));
+                yield Ok(to_event(msg));
+                // The stream of a single generation ends with it.
+                if finished && id.is_some() {
+                    break;
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn list_jobs(
+        &self,
+        _: Request<proto::ListJobsRequest>,
+    ) -> Result<Response<proto::ListJobsResponse>, Status> {
+        let jobs = self
+            .backend
+            .jobs()
+            .into_iter()
+            .map(|(req, running)| {
+                let IdPair(chat_id, id) = req.id.into();
+                proto::QueuedJob {
+                    id: id.to_string(),
+                    chat_id: chat_id.to_string(),
+                    prompt: req.prompt,
+                    secs: req.secs as u32,
+                    state: if running {
+                        proto::JobState::Running
+                    } else {
+                        proto::JobState::Pending
+                    } as i32,
+                }
+            })
+            .collect();
+        Ok(Response::new(proto::ListJobsResponse { jobs }))
+    }
+}
+
+fn msg_id(msg: &GenerationMessage) -> Uuid {
+    match msg {
+        GenerationMessage::Start(v) => v.id,
+        GenerationMessage::Queued(v) => v.id,
+        GenerationMessage::Progress(v) => v.id,
+        GenerationMessage::Error(v) => v.id,
+        GenerationMessage::Result(v) => v.id,
+    }
+}
+
+fn to_event(msg: GenerationMessage) -> proto::GenerationEvent {
+    let event = match msg {
+        GenerationMessage::Start(v) => Event::Start(proto::GenerationStart {
+            id: v.id.to_string(),
+            chat_id: v.chat_id.to_string(),
+            prompt: v.prompt,
+            secs: v.secs as u32,
+            enhanced_prompt: v.enhanced_prompt,
+        }),
+        GenerationMessage::Queued(v) => Event::Queued(proto::GenerationQueued {
+            id: v.id.to_string(),
+            chat_id: v.chat_id.to_string(),
+            prompt: v.prompt,
+            secs: v.secs as u32,
+            position: v.position as u32,
+            eta_secs: v.eta_secs,
+        }),
+        GenerationMessage::Progress(v) => Event::Progress(proto::GenerationProgress {
+            id: v.id.to_string(),
+            chat_id: v.chat_id.to_string(),
+            progress: v.progress,
+            stage: Some(to_stage(v.stage)),
+        }),
+        GenerationMessage::Error(v) => Event::Error(proto::GenerationError {
+            id: v.id.to_string(),
+            chat_id: v.chat_id.to_string(),
+            error: v.error,
+        }),
+        GenerationMessage::Result(v) => Event::Result(proto::GenerationResult {
+            id: v.id.to_string(),
+            chat_id: v.chat_id.to_string(),
+            relpath: v.relpath,
+        }),
+    };
+    proto::GenerationEvent { event: Some(event) }
+}
+
+fn to_stage(stage: GenerationStage) -> proto::GenerationStage {
+    let progress = |done: usize, total: usize| proto::StageProgress {
+        done: done as u32,
+        total: total as u32,
+    };
+    let stage = match stage {
+        GenerationStage::TextEncoding => Stage::TextEncoding(proto::TextEncoding {}),
+        GenerationStage::TokenGeneration { done, total } => {
+            Stage::TokenGeneration(progress(done, total))
+        }
+        GenerationStage::EncodecDecoding { done, total } => {
+            Stage::EncodecDecoding(progress(done, total))
+        }
+    };
+    proto::GenerationStage { stage: Some(stage) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::proto::generation_event::Event;
+    use super::proto::generation_stage::Stage;
+    use super::proto::music_gpt_client::MusicGptClient;
+    use super::proto::{self, GenerateRequest, StreamProgressRequest};
+    use crate::backend::_test_utils::{spawn, DummyJobProcessor};
+
+    #[tokio::test]
+    async fn generates_audio_through_grpc() -> anyhow::Result<()> {
+        let processor = DummyJobProcessor::new(Duration::from_millis(100));
+        let (_ws, host) = spawn(processor).await?;
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{host}"))?
+            .connect()
+            .await?;
+        let mut client = MusicGptClient::new(channel);
+
+        let mut all = client
+            .stream_progress(StreamProgressRequest { id: None })
+            .await?
+            .into_inner();
+        let req = GenerateRequest {
+            prompt: "Create a cool song".to_string(),
+            secs: 4,
+            model: Some("dummy".to_string()),
+            dedupe: None,
+            seed: Some(42),
+        };
+        let start = client.generate(req.clone()).await?.into_inner();
+        assert_eq!(start.prompt, "Create a cool song");
+        assert_eq!(start.secs, 4);
+        // The backend takes the job from its inbox asynchronously.
+        let jobs = loop {
+            let res = client.list_jobs(proto::ListJobsRequest {}).await?;
+            let jobs = res.into_inner().jobs;
+            if !jobs.is_empty() {
+                break jobs;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id, start.id);
+
+        let mut stages = vec![];
+        let relpath = loop {
+            let event = all.message().await?.expect("the stream ended");
+            match event.event.expect("empty event") {
+                Event::Progress(p) if p.id == start.id => stages.push(p.stage.unwrap().stage),
+                Event::Result(r) if r.id == start.id => break r.relpath,
+                Event::Error(err) => panic!("generation failed: {}", err.error),
+                _ => {}
+            }
+        };
+        assert!(relpath.ends_with(".wav"));
+        assert_eq!(
+            stages.last(),
+            Some(&Some(Stage::TokenGeneration(proto::StageProgress {
+                done: 4,
+                total: 4
+            })))
+        );
+        assert!(client
+            .list_jobs(proto::ListJobsRequest {})
+            .await?
+            .into_inner()
+            .jobs
+            .is_empty());
+
+        // The stream of a single generation ends with it.
+        let req = GenerateRequest {
+            prompt: "fail at 2".to_string(),
+            ..req
+        };
+        let start = client.generate(req).await?.into_inner();
+        let mut events = client
+            .stream_progress(StreamProgressRequest {
+                id: Some(start.id.clone()),
+            })
+            .await?
+            .into_inner();
+        let mut last = None;
+        while let Some(event) = events.message().await? {
+            last = event.event;
+        }
+        let Some(Event::Error(err)) = last else {
+            panic!("expected an error, got {last:?}")
+        };
+        assert_eq!(err.error, "Failed at 2");
+
+        let req = GenerateRequest {
+            secs: 0,
+            ..GenerateRequest::default()
+        };
+        let err = client.generate(req).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        Ok(())
+    }
+}
@@ -1,22 +1,65 @@
-pub use audio_generation_backend::JobProcessor;
+pub use api_keys::{ApiKey, ApiKeys};
+pub use audio_export::AudioExport;
+pub use audio_generation_backend::{
+    GenerationParams, GenerationStage, GenerationTimings, JobProcessor,
+};
+pub use audio_generation_fanout::ProgressThrottle;
+pub use chat_quota::{ChatQuota, QuotaPolicy};
+pub use dlna::{cast_file, discover, Renderer, DISCOVERY_TIMEOUT};
+pub use generation_metadata::GenerationMetadata;
+pub use garbage_collector::collect_garbage;
+pub use generation_limits::GenerationLimits;
+pub use prompt_enhancer::PromptEnhancer;
 pub use server::*;
+pub use shutdown::shutdown_on_signal;
+pub use ws_handler::DEFAULT_PING_INTERVAL;
 
 #[cfg(test)]
 mod _test_utils;
+mod api_keys;
+mod audio_export;
 mod audio_generation_backend;
 mod audio_generation_fanout;
+mod authorization;
+mod cast_api;
+mod chat_db;
+mod chat_quota;
+mod cron;
+mod dlna;
+mod events_api;
+mod files_api;
+mod garbage_collector;
+mod generation_limits;
+mod generation_metadata;
+mod grpc_api;
+mod msgpack;
 mod music_gpt_chat;
 mod music_gpt_ws_handler;
+mod openai_api;
+mod persisted_queue;
+mod plugins;
+mod presence;
+mod prompt_enhancer;
+mod reference_audio;
+mod replay_buffer;
+mod scheduler;
 mod server;
+mod shutdown;
+mod ws_api;
 mod ws_handler;
 
 #[cfg(test)]
 mod tests {
+    use cpal::SampleFormat;
     use specta::ts::{BigIntExportBehavior, ExportConfiguration};
     use std::path::{Path, PathBuf};
     use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
 
-    use crate::backend::RunWebServerOptions;
+    use crate::audio::AudioManager;
+    use crate::backend::{
+        GenerationLimits, ProgressThrottle, RunWebServerOptions, DEFAULT_PING_INTERVAL,
+    };
     use crate::backend::_test_utils::DummyJobProcessor;
     use crate::backend::server::run_web_server;
     use crate::storage::AppFs;
@@ -29,9 +72,27 @@ mod tests {
         let options = RunWebServerOptions {
             device: "Cpu".to_string(),
             name: "Dummy".to_string(),
+            model_id: "dummy".to_string(),
             port: 8642,
             auto_open: false,
-            expose: false,
+            host: "127.0.0.1".to_string(),
+            cors_origins: vec![],
+            audio_manager: AudioManager::new(32000, 1, SampleFormat::F32),
+            shutdown: CancellationToken::new(),
+            shutdown_grace: Duration::from_secs(30),
+            abort_on_ctrl_c: false,
+            stream_audio: true,
+            workers: 1,
+            dedupe: false,
+            web_dir: None,
+            limits: GenerationLimits::default(),
+            require_api_key: false,
+            socket: None,
+            idle_timeout: None,
+            progress_throttle: ProgressThrottle::default(),
+            ws_ping_interval: DEFAULT_PING_INTERVAL,
+            export: None,
+            prompt_enhancer: None,
         };
         run_web_server(storage.root.clone(), storage, processor, options).await
     }
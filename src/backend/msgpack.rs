@@ -0,0 +1,289 @@
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+
+/// Arrays and maps nested deeper than this are rejected, so that clients cannot overflow
+/// the stack with a few bytes.
+const MAX_DEPTH: usize = 64;
+
+/// Serializes `value` as MessagePack, with the same shape that it would have in JSON.
+pub fn to_vec(value: &impl Serialize) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![];
+    encode(&mut buf, &serde_json::to_value(value)?);
+    Ok(buf)
+}
+
+/// Deserializes a MessagePack value that has the same shape that `T` has in JSON.
+pub fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> anyhow::Result<T> {
+    let mut reader = Reader {
+        bytes,
+        pos: 0,
+        depth: 0,
+    };
+    let value = reader.decode()?;
+    if reader.pos != bytes.len() {
+        return Err(anyhow!("Trailing bytes after MessagePack value"));
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Encodes raw data as a MessagePack bin value.
+pub fn bin(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 5);
+    match data.len() {
+        len if len <= u8::MAX as usize => buf.extend([0xc4, len as u8]),
+        len if len <= u16::MAX as usize => {
+            buf.push(0xc5);
+            buf.extend((len as u16).to_be_bytes())
+        }
+        len => {
+            buf.push(0xc6);
+            buf.extend((len as u32).to_be_bytes())
+        }
+    }
+    buf.extend_from_slice(data);
+    buf
+}
+
+fn encode_len(buf: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, markers: [u8; 3]) {
+    match len {
+        len if len <= fix_max => buf.push(fix | len as u8),
+        len if len <= u8::MAX as usize && markers[0] != 0 => buf.extend([markers[0], len as u8]),
+        len if len <= u16::MAX as usize => {
+            buf.push(markers[1]);
+            buf.extend((len as u16).to_be_bytes())
+        }
+        len => {
+            buf.push(markers[2]);
+            buf.extend((len as u32).to_be_bytes())
+        }
+    }
+}
+
+fn encode_str(buf: &mut Vec<u8>, s: &str) {
+    encode_len(buf, s.len(), 0xa0, 31, [0xd9, 0xda, 0xdb]);
+    buf.extend_from_slice(s.as_bytes())
+}
+
+fn encode(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => buf.push(0xc0),
+        Value::Bool(b) => buf.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                match n {
+                    0..=0x7f => buf.push(n as u8),
+                    _ if n <= u8::MAX as u64 => buf.extend([0xcc, n as u8]),
+                    _ if n <= u16::MAX as u64 => {
+                        buf.push(0xcd);
+                        buf.extend((n as u16).to_be_bytes())
+                    }
+                    _ if n <= u32::MAX as u64 => {
+                        buf.push(0xce);
+                        buf.extend((n as u32).to_be_bytes())
+                    }
+                    _ => {
+                        buf.push(0xcf);
+                        buf.extend(n.to_be_bytes())
+                    }
+                }
+            } else if let Some(n) = n.as_i64() {
+                // Only negative numbers get here.
+                match n {
+                    -32..=-1 => buf.push(n as u8),
+                    _ if n >= i8::MIN as i64 => buf.extend([0xd0, n as u8]),
+                    _ if n >= i16::MIN as i64 => {
+                        buf.push(0xd1);
+                        buf.extend((n as i16).to_be_bytes())
+                    }
+                    _ if n >= i32::MIN as i64 => {
+                        buf.push(0xd2);
+                        buf.extend((n as i32).to_be_bytes())
+                    }
+                    _ => {
+                        buf.push(0xd3);
+                        buf.extend(n.to_be_bytes())
+                    }
+                }
+            } else {
+                buf.push(0xcb);
+                buf.extend(n.as_f64().unwrap_or_default().to_be_bytes())
+            }
+        }
+        Value::String(s) => encode_str(buf, s),
+        Value::Array(values) => {
+            encode_len(buf, values.len(), 0x90, 15, [0, 0xdc, 0xdd]);
+            for value in values {
+                encode(buf, value)
+            }
+        }
+        Value::Object(map) => {
+            encode_len(buf, map.len(), 0x80, 15, [0, 0xde, 0xdf]);
+            for (key, value) in map {
+                encode_str(buf, key);
+                encode(buf, value)
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&[u8]> {
+        let end = self.pos + n;
+        if end > self.bytes.len() {
+            return Err(anyhow!("Unexpected end of MessagePack value"));
+        }
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn len(&mut self, size: usize) -> anyhow::Result<usize> {
+        Ok(match size {
+            1 => self.take_array::<1>()?[0] as usize,
+            2 => u16::from_be_bytes(self.take_array()?) as usize,
+            _ => u32::from_be_bytes(self.take_array()?) as usize,
+        })
+    }
+
+    fn string(&mut self, len: usize) -> anyhow::Result<Value> {
+        Ok(Value::String(String::from_utf8(self.take(len)?.to_vec())?))
+    }
+
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        if self.depth == MAX_DEPTH {
+            return Err(anyhow!(
+                "MessagePack values cannot be nested more than {MAX_DEPTH} levels"
+            ));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn array(&mut self, len: usize) -> anyhow::Result<Value> {
+        self.nested(|reader| {
+            let mut values = Vec::with_capacity(len.min(1024));
+            for _ in 0..len {
+                values.push(reader.decode()?)
+            }
+            Ok(Value::Array(values))
+        })
+    }
+
+    fn map(&mut self, len: usize) -> anyhow::Result<Value> {
+        self.nested(|reader| {
+            let mut map = Map::new();
+            for _ in 0..len {
+                let Value::String(key) = reader.decode()? else {
+                    return Err(anyhow!("MessagePack map keys must be strings"));
+                };
+                map.insert(key, reader.decode()?);
+            }
+            Ok(Value::Object(map))
+        })
+    }
+
+    fn float(n: f64) -> anyhow::Result<Value> {
+        let n = Number::from_f64(n).ok_or_else(|| anyhow!("Unsupported float {n}"))?;
+        Ok(Value::Number(n))
+    }
+
+    fn decode(&mut self) -> anyhow::Result<Value> {
+        let marker = self.take_array::<1>()?[0];
+        match marker {
+            0x00..=0x7f => Ok(Value::from(marker)),
+            0x80..=0x8f => self.map((marker & 0x0f) as usize),
+            0x90..=0x9f => self.array((marker & 0x0f) as usize),
+            0xa0..=0xbf => self.string((marker & 0x1f) as usize),
+            0xc0 => Ok(Value::Null),
+            0xc2 => Ok(Value::Bool(false)),
+            0xc3 => Ok(Value::Bool(true)),
+            // Raw data is deserialized like a Vec<u8> in JSON.
+            0xc4..=0xc6 => {
+                let len = self.len(1 << (marker - 0xc4))?;
+                Ok(Value::from(self.take(len)?.to_vec()))
+            }
+            0xca => Self::float(f32::from_be_bytes(self.take_array()?) as f64),
+            0xcb => Self::float(f64::from_be_bytes(self.take_array()?)),
+            0xcc => Ok(Value::from(self.take_array::<1>()?[0])),
+            0xcd => Ok(Value::from(u16::from_be_bytes(self.take_array()?))),
+            0xce => Ok(Value::from(u32::from_be_bytes(self.take_array()?))),
+            0xcf => Ok(Value::from(u64::from_be_bytes(self.take_array()?))),
+            0xd0 => Ok(Value::from(i8::from_be_bytes(self.take_array()?))),
+            0xd1 => Ok(Value::from(i16::from_be_bytes(self.take_array()?))),
+            0xd2 => Ok(Value::from(i32::from_be_bytes(self.take_array()?))),
+            0xd3 => Ok(Value::from(i64::from_be_bytes(self.take_array()?))),
+            0xd9..=0xdb => {
+                let len = self.len(1 << (marker - 0xd9))?;
+                self.string(len)
+            }
+            0xdc | 0xdd => {
+                let len = self.len(2 << (marker - 0xdc))?;
+                self.array(len)
+            }
+            0xde | 0xdf => {
+                let len = self.len(2 << (marker - 0xde))?;
+                self.map(len)
+            }
+            0xe0..=0xff => Ok(Value::from(marker as i8)),
+            _ => Err(anyhow!("Unsupported MessagePack type {marker:#x}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_values() -> anyhow::Result<()> {
+        let value = json!({
+            "GenerateAudio": {
+                "id": "8f9b5c1e-3b7a-4c67-9d3a-0d5c1e7b2a41",
+                "prompt": "a".repeat(300),
+                "secs": 10,
+                "numbers": [0, 127, 255, 65_535, 1_u64 << 40, -1, -33, -200, -40_000, -(1_i64 << 40)],
+                "progress": 0.25,
+                "model": null,
+                "favorite": true,
+            }
+        });
+        let bytes = to_vec(&value)?;
+        assert_eq!(from_slice::<Value>(&bytes)?, value);
+        assert!(from_slice::<Value>(&bytes[..bytes.len() - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn encodes_raw_data_as_bin() -> anyhow::Result<()> {
+        assert_eq!(bin(&[1, 2]), vec![0xc4, 2, 1, 2]);
+        assert_eq!(from_slice::<Vec<u8>>(&bin(&[7; 300]))?, vec![7; 300]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_deeply_nested_values() -> anyhow::Result<()> {
+        let mut nested = vec![0x91; MAX_DEPTH - 1];
+        nested.push(0xc0);
+        from_slice::<Value>(&nested)?;
+
+        let err = from_slice::<Value>(&[0x91; 100_000]).unwrap_err();
+        assert!(err.to_string().contains("nested"), "{err}");
+        Ok(())
+    }
+}
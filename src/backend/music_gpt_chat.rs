@@ -1,15 +1,33 @@
+use crate::backend::audio_generation_backend::GenerationStats;
+use crate::backend::chat_db::ChatDb;
 use crate::storage::Storage;
 
+use anyhow::anyhow;
+use async_stream::stream;
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use specta::Type;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::DuplexStream;
+use tokio_util::compat::FuturesAsyncWriteCompatExt;
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
+/// Bytes of the zip archive of a chat buffered ahead of the client downloading it.
+const ZIP_BUFFER_SIZE: usize = 64 * 1024;
+
 #[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
 pub struct UserChatEntry {
     pub id: Uuid,
     pub chat_id: Uuid,
     pub text: String,
+    /// The prompt the audio was generated from, if `text` was rewritten by the prompt
+    /// enhancer.
+    #[serde(default)]
+    pub enhanced_prompt: Option<String>,
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
@@ -18,6 +36,22 @@ pub struct AiChatEntry {
     pub chat_id: Uuid,
     pub relpath: String,
     pub error: String,
+    #[serde(default)]
+    pub favorite: bool,
+    /// The user's rating of the generation, from 1 to 5.
+    #[serde(default)]
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub note: Option<String>,
+    /// The other generation of the A/B comparison this one is part of, if any.
+    #[serde(default)]
+    pub compared_with: Option<Uuid>,
+    /// How long the generation took and where it ran, none for failed generations and
+    /// the ones from older versions.
+    #[serde(default)]
+    pub stats: Option<GenerationStats>,
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
@@ -33,6 +67,12 @@ impl ChatEntry {
             chat_id,
             relpath,
             error: "".to_string(),
+            favorite: false,
+            rating: None,
+            title: None,
+            note: None,
+            compared_with: None,
+            stats: None,
         })
     }
 
@@ -42,14 +82,44 @@ impl ChatEntry {
             chat_id,
             relpath: "".to_string(),
             error,
+            favorite: false,
+            rating: None,
+            title: None,
+            note: None,
+            compared_with: None,
+            stats: None,
         })
     }
 
+    /// Links the generation to the other one of the A/B comparison it's part of.
+    pub fn with_comparison(mut self, compared_with: Option<Uuid>) -> Self {
+        if let Self::Ai(entry) = &mut self {
+            entry.compared_with = compared_with;
+        }
+        self
+    }
+
+    /// Attaches how the generation was processed.
+    pub fn with_stats(mut self, stats: GenerationStats) -> Self {
+        if let Self::Ai(entry) = &mut self {
+            entry.stats = Some(stats);
+        }
+        self
+    }
+
     pub fn new_user(chat_id: Uuid, id: Uuid, text: String) -> Self {
-        Self::User(UserChatEntry { id, chat_id, text })
+        Self::User(UserChatEntry {
+            id,
+            chat_id,
+            text,
+            enhanced_prompt: None,
+        })
     }
 
     pub async fn save<S: Storage>(&self, storage: &S) -> anyhow::Result<()> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            return db.save_entry(self.clone()).await;
+        }
         let (chat_id, id, is_ai) = match self {
             ChatEntry::User(v) => (v.chat_id, v.id, 0),
             ChatEntry::Ai(v) => (v.chat_id, v.id, 1),
@@ -64,6 +134,176 @@ impl ChatEntry {
     }
 }
 
+impl AiChatEntry {
+    /// Finds the file in which the entry with the given id is stored. Entries are stored
+    /// in files named after their creation time, so the chat's directory is scanned.
+    async fn find<S: Storage>(
+        storage: &S,
+        chat_id: Uuid,
+        id: Uuid,
+    ) -> anyhow::Result<(String, Self)> {
+        let suffix = format!("_{id}_1.json");
+        for file in storage.list(&format!("chats/{chat_id}")).await? {
+            if !file.ends_with(&suffix) {
+                continue;
+            }
+            let Some(content) = storage.read(&file).await? else {
+                continue;
+            };
+            if let ChatEntry::Ai(entry) = serde_json::from_slice(&content)? {
+                return Ok((file, entry));
+            }
+        }
+        Err(anyhow!("Generation {id} does not exist in chat {chat_id}"))
+    }
+
+    /// Loads the entry with the given id.
+    pub async fn load<S: Storage>(storage: &S, chat_id: Uuid, id: Uuid) -> anyhow::Result<Self> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            return db
+                .load_ai_entry(chat_id, id)
+                .await?
+                .ok_or_else(|| anyhow!("Generation {id} does not exist in chat {chat_id}"));
+        }
+        Ok(Self::find(storage, chat_id, id).await?.1)
+    }
+
+    async fn update<S: Storage>(
+        storage: &S,
+        chat_id: Uuid,
+        id: Uuid,
+        f: impl FnOnce(&mut Self),
+    ) -> anyhow::Result<Self> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            let mut entry = Self::load(storage, chat_id, id).await?;
+            f(&mut entry);
+            db.save_entry(ChatEntry::Ai(entry.clone())).await?;
+            return Ok(entry);
+        }
+        let (file, mut entry) = Self::find(storage, chat_id, id).await?;
+        f(&mut entry);
+        let serial = serde_json::to_vec(&ChatEntry::Ai(entry.clone()))?;
+        storage.write(&file, serial).await?;
+        Ok(entry)
+    }
+
+    /// Marks the entry as favorite and/or rates it, leaving unchanged the fields that are none.
+    pub async fn rate<S: Storage>(
+        storage: &S,
+        chat_id: Uuid,
+        id: Uuid,
+        favorite: Option<bool>,
+        rating: Option<u8>,
+    ) -> anyhow::Result<Self> {
+        if let Some(rating) = rating {
+            if !(1..=5).contains(&rating) {
+                return Err(anyhow!("Ratings must be between 1 and 5, got {rating}"));
+            }
+        }
+        Self::update(storage, chat_id, id, |entry| {
+            if let Some(favorite) = favorite {
+                entry.favorite = favorite;
+            }
+            if rating.is_some() {
+                entry.rating = rating;
+            }
+        })
+        .await
+    }
+
+    /// Sets the user-defined title and/or note of the entry, leaving unchanged the fields
+    /// that are none.
+    pub async fn update_metadata<S: Storage>(
+        storage: &S,
+        chat_id: Uuid,
+        id: Uuid,
+        title: Option<String>,
+        note: Option<String>,
+    ) -> anyhow::Result<Self> {
+        Self::update(storage, chat_id, id, |entry| {
+            if title.is_some() {
+                entry.title = title;
+            }
+            if note.is_some() {
+                entry.note = note;
+            }
+        })
+        .await
+    }
+
+    /// Deletes the generation along with its audio file and the prompt that originated it.
+    pub async fn delete<S: Storage>(storage: &S, chat_id: Uuid, id: Uuid) -> anyhow::Result<()> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            let entry = Self::load(storage, chat_id, id).await?;
+            if !entry.relpath.is_empty() {
+                storage.rm(&entry.relpath).await?;
+            }
+            return db.delete_entry(chat_id, id).await;
+        }
+        let (file, entry) = Self::find(storage, chat_id, id).await?;
+        if !entry.relpath.is_empty() {
+            storage.rm(&entry.relpath).await?;
+        }
+        storage.rm(&file).await?;
+        let user_suffix = format!("_{id}_0.json");
+        for file in storage.list(&format!("chats/{chat_id}")).await? {
+            if file.ends_with(&user_suffix) {
+                storage.rm(&file).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the entries marked as favorite across all chats, newest chats first.
+    pub async fn load_favorites<S: Storage>(storage: &S) -> anyhow::Result<Vec<Self>> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            return db.load_favorites().await;
+        }
+        let mut result = vec![];
+        for chat in Chat::load_all(storage).await? {
+            for entry in Chat::load_entries(storage, chat.chat_id).await? {
+                match entry {
+                    ChatEntry::Ai(entry) if entry.favorite => result.push(entry),
+                    _ => continue,
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// A page of chats, newest first.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ChatsPage {
+    pub chats: Vec<Chat>,
+    /// The cursor used for requesting this page, none for the first one.
+    pub cursor: Option<ChatsCursor>,
+    /// The cursor for requesting the next page, none if this is the last one.
+    pub next_cursor: Option<ChatsCursor>,
+}
+
+/// Points to the last chat of a page. Chats are sorted by creation time and then by id, so
+/// that chats created in the same millisecond are neither skipped nor repeated.
+#[derive(Clone, Copy, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ChatsCursor {
+    pub created_at: u128,
+    pub chat_id: Uuid,
+}
+
+impl ChatsCursor {
+    fn of(chat: &Chat) -> Self {
+        Self {
+            created_at: chat.created_at,
+            chat_id: chat.chat_id,
+        }
+    }
+
+    /// Whether the chat goes after the one the cursor points to.
+    fn precedes(&self, chat: &Chat) -> bool {
+        (chat.created_at, chat.chat_id) < (self.created_at, self.chat_id)
+    }
+}
+
 #[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
 pub struct Chat {
     pub chat_id: Uuid,
@@ -73,8 +313,13 @@ pub struct Chat {
 
 const METADATA_FILE: &str = ".metadata.json";
 
+/// Chats are stored in a [ChatDb] for local storages, and as a `chats/{chat_id}` directory
+/// with one JSON file per entry for the rest.
 impl Chat {
     pub async fn load<S: Storage>(storage: &S, chat_id: Uuid) -> anyhow::Result<Self> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            return db.load_or_create(chat_id).await;
+        }
         let metadata_file = format!("chats/{chat_id}/{METADATA_FILE}");
 
         if let Some(this_serial) = storage.read(&metadata_file).await? {
@@ -97,6 +342,9 @@ impl Chat {
     }
 
     pub async fn load_all<S: Storage>(storage: &S) -> anyhow::Result<Vec<Self>> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            return db.load_chats(None, usize::MAX).await;
+        }
         let mut result = vec![];
 
         for dir in storage.list("chats").await? {
@@ -111,13 +359,46 @@ impl Chat {
             };
             result.push(chat)
         }
-        result.sort_by_key(|v| v.created_at);
+        result.sort_by_key(|v| (v.created_at, v.chat_id));
         result.reverse();
 
         Ok(result)
     }
 
+    /// Loads up to `limit` chats, newest first, starting after the one that `cursor` points to.
+    pub async fn load_page<S: Storage>(
+        storage: &S,
+        cursor: Option<ChatsCursor>,
+        limit: usize,
+    ) -> anyhow::Result<ChatsPage> {
+        let limit = limit.max(1);
+        // One more than needed, for knowing whether there is a next page.
+        let mut chats = match ChatDb::of(storage).await? {
+            Some(db) => db.load_chats(cursor, limit + 1).await?,
+            None => {
+                let mut chats = Self::load_all(storage).await?;
+                if let Some(cursor) = cursor {
+                    chats.retain(|v| cursor.precedes(v));
+                }
+                chats
+            }
+        };
+        let next_cursor = match chats.len() > limit {
+            true => Some(ChatsCursor::of(&chats[limit - 1])),
+            false => None,
+        };
+        chats.truncate(limit);
+        Ok(ChatsPage {
+            chats,
+            cursor,
+            next_cursor,
+        })
+    }
+
     pub async fn save<S: Storage>(&self, storage: &S) -> anyhow::Result<()> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            return db.save_chat(self.clone()).await;
+        }
         let this_serial = serde_json::to_string(self)?;
         storage
             .write(
@@ -136,20 +417,16 @@ impl Chat {
         if let Some(name) = name {
             self.name = name;
         }
-        let this_serial = serde_json::to_string(self)?;
-        storage
-            .write(
-                &format!("chats/{}/{METADATA_FILE}", self.chat_id),
-                this_serial,
-            )
-            .await?;
-        Ok(())
+        self.save(storage).await
     }
 
     pub async fn load_entries<S: Storage>(
         storage: &S,
         chat_id: Uuid,
     ) -> anyhow::Result<Vec<ChatEntry>> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            return db.load_entries(chat_id).await;
+        }
         let mut result = vec![];
         for file in storage.list(&format!("chats/{chat_id}")).await? {
             if file.ends_with(METADATA_FILE) {
@@ -165,16 +442,91 @@ impl Chat {
         Ok(result)
     }
 
+    /// Streams a zip archive with the chat's metadata, its entries and all its generated
+    /// audios, which are read from the storage as the archive is consumed. Returns None if
+    /// the chat does not exist.
+    pub async fn to_zip<S: Storage>(
+        storage: &S,
+        chat_id: Uuid,
+    ) -> anyhow::Result<Option<impl Stream<Item = std::io::Result<Bytes>>>> {
+        let exists = match ChatDb::of(storage).await? {
+            Some(db) => db.exists(chat_id).await?,
+            None => storage.exists(&format!("chats/{chat_id}")).await?,
+        };
+        if !exists {
+            return Ok(None);
+        }
+        let chat = Chat::load(storage, chat_id).await?;
+        let entries = Chat::load_entries(storage, chat_id).await?;
+
+        let (reader, writer) = tokio::io::duplex(ZIP_BUFFER_SIZE);
+        let task = tokio::spawn(write_zip(storage.clone(), chat, entries, writer));
+        Ok(Some(stream! {
+            let mut chunks = ReaderStream::new(reader);
+            while let Some(chunk) = chunks.next().await {
+                yield chunk;
+            }
+            // Failing halfway through aborts the download, instead of finishing it with a
+            // truncated archive.
+            match task.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => yield Err(std::io::Error::other(err)),
+                Err(err) => yield Err(std::io::Error::other(err)),
+            }
+        }))
+    }
+
     pub async fn delete<S: Storage>(self, storage: &S) -> anyhow::Result<()> {
+        if let Some(db) = ChatDb::of(storage).await? {
+            return db.delete_chat(self.chat_id).await;
+        }
         storage.rm_rf(&format!("chats/{}", self.chat_id)).await?;
         Ok(())
     }
 }
 
+async fn write_zip<S: Storage>(
+    storage: S,
+    chat: Chat,
+    entries: Vec<ChatEntry>,
+    writer: DuplexStream,
+) -> anyhow::Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    // Audios are already uncompressed wav files, with no much to gain by deflating them.
+    let zip_entry = |name: &str| ZipEntryBuilder::new(name.into(), Compression::Stored);
+
+    let metadata = serde_json::to_vec_pretty(&chat)?;
+    zip.write_entry_whole(zip_entry("metadata.json"), &metadata)
+        .await?;
+    let entries_json = serde_json::to_vec_pretty(&entries)?;
+    zip.write_entry_whole(zip_entry("entries.json"), &entries_json)
+        .await?;
+    for entry in &entries {
+        let ChatEntry::Ai(entry) = entry else {
+            continue;
+        };
+        if entry.relpath.is_empty() {
+            continue;
+        }
+        let Some(mut reader) = storage.read_stream(&entry.relpath).await? else {
+            continue;
+        };
+        let mut file = zip
+            .write_entry_stream(zip_entry(&entry.relpath))
+            .await?
+            .compat_write();
+        tokio::io::copy(&mut reader, &mut file).await?;
+        file.into_inner().close().await?;
+    }
+    zip.close().await?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::backend::music_gpt_chat::{Chat, ChatEntry};
-    use crate::storage::AppFs;
+    use crate::backend::music_gpt_chat::{AiChatEntry, Chat, ChatEntry};
+    use crate::storage::{AppFs, Storage};
+    use futures_util::TryStreamExt;
     use std::time::Duration;
     use uuid::Uuid;
 
@@ -218,6 +570,60 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn lists_chats_in_pages() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let mut chats = vec![];
+        for _ in 0..5 {
+            let chat_id = Uuid::new_v4();
+            let chat = Chat::load(&storage, chat_id).await?;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            chats.push(chat)
+        }
+        chats.reverse();
+
+        let first = Chat::load_page(&storage, None, 2).await?;
+        assert_eq!(first.chats, chats[..2]);
+        let second = Chat::load_page(&storage, first.next_cursor, 2).await?;
+        assert_eq!(second.chats, chats[2..4]);
+        let third = Chat::load_page(&storage, second.next_cursor, 2).await?;
+        assert_eq!(third.chats, chats[4..]);
+        assert_eq!(third.next_cursor, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lists_chats_created_in_the_same_millisecond_in_pages() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let mut chats = vec![];
+        for i in 0..5 {
+            let chat = Chat {
+                chat_id: Uuid::new_v4(),
+                name: format!("chat {i}"),
+                created_at: 1000,
+            };
+            chat.save(&storage).await?;
+            chats.push(chat)
+        }
+        chats.sort_by_key(|v| v.chat_id);
+        chats.reverse();
+
+        let mut listed = vec![];
+        let mut cursor = None;
+        loop {
+            let page = Chat::load_page(&storage, cursor, 2).await?;
+            listed.extend(page.chats);
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(listed, chats);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn list_messages_in_non_existing_chat_returns_empty_vec() -> anyhow::Result<()> {
         let storage = AppFs::new_tmp();
@@ -251,6 +657,79 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn rates_entries_and_lists_favorites() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let chat_id = Uuid::new_v4();
+        let id = Uuid::new_v4();
+
+        ChatEntry::new_user(chat_id, id, "user_1".to_string())
+            .save(&storage)
+            .await?;
+        ChatEntry::new_ai_success(chat_id, id, "ai_1".to_string())
+            .save(&storage)
+            .await?;
+        ChatEntry::new_ai_success(chat_id, Uuid::new_v4(), "ai_2".to_string())
+            .save(&storage)
+            .await?;
+        assert_eq!(AiChatEntry::load_favorites(&storage).await?, vec![]);
+
+        let entry = AiChatEntry::rate(&storage, chat_id, id, Some(true), Some(4)).await?;
+        assert!(entry.favorite);
+        assert_eq!(entry.rating, Some(4));
+        let entry = AiChatEntry::rate(&storage, chat_id, id, None, Some(5)).await?;
+        assert!(entry.favorite);
+        assert_eq!(entry.rating, Some(5));
+        assert_eq!(AiChatEntry::load_favorites(&storage).await?, vec![entry]);
+        assert_eq!(Chat::load_entries(&storage, chat_id).await?.len(), 3);
+
+        assert!(AiChatEntry::rate(&storage, chat_id, id, None, Some(6))
+            .await
+            .is_err());
+        assert!(
+            AiChatEntry::rate(&storage, chat_id, Uuid::new_v4(), Some(true), None)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn updates_and_deletes_entries() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let chat_id = Uuid::new_v4();
+        let id = Uuid::new_v4();
+        let relpath = format!("audios/{id}.wav");
+        storage.write(&relpath, "wav content").await?;
+
+        ChatEntry::new_user(chat_id, id, "user_1".to_string())
+            .save(&storage)
+            .await?;
+        ChatEntry::new_ai_success(chat_id, id, relpath.clone())
+            .save(&storage)
+            .await?;
+        let other = ChatEntry::new_ai_err(chat_id, Uuid::new_v4(), "error".to_string());
+        other.save(&storage).await?;
+
+        let entry = AiChatEntry::update_metadata(
+            &storage,
+            chat_id,
+            id,
+            Some("title".to_string()),
+            Some("note".to_string()),
+        )
+        .await?;
+        let entry = AiChatEntry::update_metadata(&storage, chat_id, entry.id, None, None).await?;
+        assert_eq!(entry.title, Some("title".to_string()));
+        assert_eq!(entry.note, Some("note".to_string()));
+
+        AiChatEntry::delete(&storage, chat_id, id).await?;
+        assert_eq!(Chat::load_entries(&storage, chat_id).await?, vec![other]);
+        assert!(!storage.exists(&relpath).await?);
+        assert!(AiChatEntry::delete(&storage, chat_id, id).await.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn deletes_chat() -> anyhow::Result<()> {
         let storage = AppFs::new_tmp();
@@ -272,4 +751,47 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn zips_a_chat() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let chat_id = Uuid::new_v4();
+        let id = Uuid::new_v4();
+
+        ChatEntry::new_user(chat_id, id, "user_1".to_string())
+            .save(&storage)
+            .await?;
+        let relpath = format!("audios/{id}.wav");
+        storage.write(&relpath, "wav content").await?;
+        ChatEntry::new_ai_success(chat_id, id, relpath.clone())
+            .save(&storage)
+            .await?;
+        ChatEntry::new_ai_err(chat_id, Uuid::new_v4(), "error".to_string())
+            .save(&storage)
+            .await?;
+
+        assert!(Chat::to_zip(&storage, Uuid::new_v4()).await?.is_none());
+        let zip = Chat::to_zip(&storage, chat_id)
+            .await?
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await?
+            .concat();
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zip))?;
+        let mut names = archive.file_names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![relpath.as_str(), "entries.json", "metadata.json"]
+        );
+
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut archive.by_name(&relpath)?, &mut content)?;
+        assert_eq!(content, "wav content");
+
+        let entries: Vec<ChatEntry> = serde_json::from_reader(archive.by_name("entries.json")?)?;
+        assert_eq!(entries.len(), 3);
+
+        Ok(())
+    }
 }
@@ -1,18 +1,32 @@
 use std::fmt::{Display, Formatter};
 use std::sync::mpsc::Sender;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use anyhow::anyhow;
 use async_trait::async_trait;
+use base64::Engine;
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use specta::Type;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 use uuid::Uuid;
 
-use crate::backend::audio_generation_backend::{AudioGenerationRequest, BackendInboundMsg};
-use crate::backend::audio_generation_fanout::GenerationMessage;
-use crate::backend::music_gpt_chat::{Chat, ChatEntry};
-use crate::backend::ws_handler::WsHandler;
+use crate::backend::audio_generation_backend::{AudioGenerationBackend, BackendInboundMsg};
+use crate::backend::audio_generation_fanout::{
+    AudioGenerationStart, GenerationMessage, PartialAudio,
+};
+use crate::backend::generation_limits::GenerationLimits;
+use crate::backend::generation_metadata::GenerationMetadata;
+use crate::backend::music_gpt_chat::{AiChatEntry, Chat, ChatEntry, ChatsCursor, ChatsPage};
+use crate::backend::persisted_queue::PersistedJob;
+use crate::backend::presence::{Client, Presence};
+use crate::backend::reference_audio::ReferenceAudio;
+use crate::backend::replay_buffer::ReplayBuffer;
+use crate::backend::scheduler::{Schedule, ScheduledJob};
+use crate::backend::ws_handler::{WsEncoding, WsHandler};
+use crate::reveal::reveal;
 use crate::storage::Storage;
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -20,12 +34,115 @@ pub struct ChatRequest {
     pub chat_id: Uuid,
 }
 
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct ChatsRequest {
+    /// The `next_cursor` of the previous page, none for the first page.
+    #[serde(default)]
+    pub cursor: Option<ChatsCursor>,
+    /// Maximum number of chats in the page, defaults to 50.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub struct GenerateAudioRequest {
     pub id: Uuid,
     pub chat_id: Uuid,
     pub prompt: String,
     pub secs: usize,
+    /// An uploaded reference audio for continuing it or for conditioning the generation.
+    #[serde(default)]
+    pub reference_id: Option<Uuid>,
+    /// Must be the model the server runs, by id or by name, or one of the models provided
+    /// by plugins, if provided.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Set to false for generating the audio even if the server deduplicates requests and
+    /// an identical one is queued or finished recently.
+    #[serde(default)]
+    pub dedupe: Option<bool>,
+    /// The seed for sampling the audio, generating the same prompt with the same seed gives
+    /// the same audio. A random one is used if none.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+/// Generates a new variation of an earlier generation of the chat, with the same prompt
+/// and parameters unless overridden. The new generation is appended to the chat.
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct RegenerateRequest {
+    /// The id of the new generation.
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    /// The id of the generation to regenerate.
+    pub entry_id: Uuid,
+    /// Overrides the seconds of the original generation. Required for generations whose
+    /// audio does not exist, like failed ones.
+    #[serde(default)]
+    pub secs: Option<usize>,
+    /// The seed of the new generation, a random one if none. The seed of the original
+    /// generation would give the same audio again, unless the seconds are overridden.
+    #[serde(default)]
+    pub new_seed: Option<u64>,
+}
+
+/// The parameters of one of the generations of an A/B comparison.
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct ComparisonSide {
+    pub id: Uuid,
+    pub secs: usize,
+    /// Like in [GenerateAudioRequest], the loaded model if none.
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+/// Generates the same prompt with two sets of parameters, and links both generations in
+/// the chat so that the settings can be compared.
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct GenerateComparisonRequest {
+    pub chat_id: Uuid,
+    /// Creates the chat, like [InboundMsg::GenerateAudioNewChat].
+    #[serde(default)]
+    pub new_chat: bool,
+    pub prompt: String,
+    pub a: ComparisonSide,
+    pub b: ComparisonSide,
+}
+
+impl GenerateComparisonRequest {
+    fn requests(&self) -> [GenerateAudioRequest; 2] {
+        [&self.a, &self.b].map(|side| GenerateAudioRequest {
+            id: side.id,
+            chat_id: self.chat_id,
+            prompt: self.prompt.clone(),
+            secs: side.secs,
+            reference_id: None,
+            model: side.model.clone(),
+            // Identical sides are meant to be compared.
+            dedupe: Some(false),
+            seed: None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct UploadReferenceRequest {
+    /// The extension of the audio format, wav, mp3 or flac.
+    pub format: String,
+    /// The base64 encoded audio file.
+    pub data: String,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct ScheduleJobRequest {
+    pub prompt: String,
+    pub secs: usize,
+    pub schedule: Schedule,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct ScheduledJobRequest {
+    pub id: Uuid,
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -37,7 +154,23 @@ pub struct AbortGenerationRequest {
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub struct Info {
     pub model: String,
+    /// The id of the model, like "small", that generation requests can ask for.
+    pub model_id: String,
     pub device: String,
+    /// The version of the websocket protocol spoken by the server.
+    pub protocol_version: u32,
+    /// Models provided by plugins, which generation requests can also ask for.
+    pub plugins: Vec<String>,
+    /// Identifies the connection, reconnecting with `/ws?session=<id>` replays the
+    /// generation messages missed while disconnected.
+    pub session_id: Uuid,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct ChatExport {
+    pub chat_id: Uuid,
+    /// URL, relative to the server, from which the chat's zip archive can be downloaded.
+    pub url: String,
 }
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
@@ -46,16 +179,74 @@ pub struct SetChatMetadataRequest {
     pub name: Option<String>,
 }
 
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct RateEntryRequest {
+    pub chat_id: Uuid,
+    pub id: Uuid,
+    #[serde(default)]
+    pub favorite: Option<bool>,
+    /// From 1 to 5.
+    #[serde(default)]
+    pub rating: Option<u8>,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct EntryRequest {
+    pub chat_id: Uuid,
+    pub id: Uuid,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize)]
+pub struct SetEntryMetadataRequest {
+    pub chat_id: Uuid,
+    pub id: Uuid,
+    pub title: Option<String>,
+    pub note: Option<String>,
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub enum JobState {
+    Pending,
+    Running,
+}
+
+/// A job in the backend's queue, in processing order.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct QueuedJob {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub prompt: String,
+    pub secs: usize,
+    pub state: JobState,
+    pub requested_by: Option<Client>,
+}
+
 // === Inbound ===
 
 #[derive(Clone, Debug, Type, Serialize, Deserialize)]
 pub enum InboundMsg {
     GenerateAudioNewChat(GenerateAudioRequest),
     GenerateAudio(GenerateAudioRequest),
+    Regenerate(RegenerateRequest),
+    GenerateComparison(GenerateComparisonRequest),
     AbortGeneration(AbortGenerationRequest),
+    AbortAll,
     GetChat(ChatRequest),
+    GetChats(ChatsRequest),
     SetChatMetadata(SetChatMetadataRequest),
     DelChat(ChatRequest),
+    ExportChat(ChatRequest),
+    UploadReference(UploadReferenceRequest),
+    ScheduleJob(ScheduleJobRequest),
+    GetScheduledJobs,
+    CancelScheduledJob(ScheduledJobRequest),
+    RateEntry(RateEntryRequest),
+    GetFavorites,
+    SetEntryMetadata(SetEntryMetadataRequest),
+    DelEntry(EntryRequest),
+    /// Shows the audio of a generation in the file manager of the machine running MusicGPT.
+    RevealEntry(EntryRequest),
+    GetQueue,
 }
 
 // === Outbound ===
@@ -65,16 +256,201 @@ pub enum OutboundMsg {
     Generation(GenerationMessage),
     Info(Info),
     Chat((Chat, Vec<ChatEntry>)),
-    Chats(Vec<Chat>),
+    Chats(ChatsPage),
+    /// Jobs that were pending when MusicGPT was stopped, and that were queued again on boot.
+    RecoveredJobs(Vec<AudioGenerationStart>),
+    ChatExport(ChatExport),
+    ReferenceUploaded(ReferenceAudio),
+    ScheduledJobs(Vec<ScheduledJob>),
+    Favorites(Vec<AiChatEntry>),
+    Queue(Vec<QueuedJob>),
+    /// The clients connected to the server, sent on connect and every time it changes.
+    Clients(Vec<Client>),
     Error(String),
 }
 
+/// Version of the websocket protocol, bumped on every breaking change to the messages.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version that the server still knows how to talk to.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Checks that the protocol version declared by a client on connect is supported.
+/// Clients that do not declare any are assumed to speak the current one.
+pub fn check_protocol_version(version: Option<u32>) -> anyhow::Result<()> {
+    match version {
+        Some(v) if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&v) => Err(anyhow!(
+            "Unsupported protocol version {v}, supported versions are {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+        )),
+        _ => Ok(()),
+    }
+}
+
+const DEFAULT_CHATS_LIMIT: usize = 50;
+const MAX_CHATS_LIMIT: usize = 200;
+
 #[derive(Clone)]
 pub struct MusicGptWsHandler<S: Storage> {
     pub storage: S,
-    pub ai_broadcast_tx: tokio::sync::broadcast::Sender<GenerationMessage>,
+    pub partial_audio_tx: tokio::sync::broadcast::Sender<PartialAudio>,
+    pub replay: ReplayBuffer,
     pub ai_tx: Sender<BackendInboundMsg>,
+    pub backend: AudioGenerationBackend,
+    pub limits: GenerationLimits,
     pub info: Info,
+    pub recovered_jobs: Vec<AudioGenerationStart>,
+    /// Cancelled when the server starts shutting down, after that no new jobs are accepted.
+    pub shutdown: CancellationToken,
+    pub ping_interval: Duration,
+    pub presence: Presence,
+    /// Set for every connection.
+    pub encoding: WsEncoding,
+    /// Set for every connection.
+    pub client_name: Option<String>,
+}
+
+impl<S: Storage> MusicGptWsHandler<S> {
+    async fn first_chats_page(&self) -> anyhow::Result<ChatsPage> {
+        Chat::load_page(&self.storage, None, DEFAULT_CHATS_LIMIT).await
+    }
+
+    /// The client of this connection.
+    pub fn client(&self) -> Client {
+        Client {
+            session_id: self.info.session_id,
+            name: self.client_name.clone(),
+        }
+    }
+
+    fn new_job(&self, req: GenerateAudioRequest) -> PersistedJob {
+        let model = req.model.filter(|v| self.info.plugins.contains(v));
+        PersistedJob {
+            requested_by: Some(self.client()),
+            model,
+            dedupe: req.dedupe.unwrap_or(true),
+            seed: req.seed,
+            reference_id: req.reference_id,
+            ..PersistedJob::new(req.chat_id, req.id, req.prompt, req.secs)
+        }
+    }
+
+    /// The request for regenerating the entry `req.entry_id`. The prompt is the one in the
+    /// chat, and the rest of parameters are the ones embedded in its audio.
+    async fn regenerate_request(
+        &self,
+        req: RegenerateRequest,
+    ) -> anyhow::Result<GenerateAudioRequest> {
+        let (mut prompt, mut relpath) = (None, None);
+        for entry in Chat::load_entries(&self.storage, req.chat_id).await? {
+            match entry {
+                ChatEntry::User(v) if v.id == req.entry_id => prompt = Some(v.text),
+                ChatEntry::Ai(v) if v.id == req.entry_id => relpath = Some(v.relpath),
+                _ => {}
+            }
+        }
+        let Some(prompt) = prompt else {
+            let (id, chat_id) = (req.entry_id, req.chat_id);
+            return Err(anyhow!("Generation {id} does not exist in chat {chat_id}"));
+        };
+        let mut metadata = None;
+        if let Some(relpath) = relpath.filter(|v| !v.is_empty()) {
+            if let Some(wav) = self.storage.read(&relpath).await? {
+                metadata = GenerationMetadata::from_wav(&wav)?;
+            }
+        }
+        let secs = match (req.secs, &metadata) {
+            (Some(secs), _) => secs,
+            (None, Some(metadata)) => metadata.secs,
+            (None, None) => {
+                return Err(anyhow!(
+                    "The parameters of generation {} are unknown, regenerate it with secs",
+                    req.entry_id
+                ))
+            }
+        };
+        // Generations from plugins are regenerated with the same plugin.
+        let model = metadata
+            .map(|v| v.model)
+            .filter(|v| self.info.plugins.contains(v));
+        Ok(GenerateAudioRequest {
+            id: req.id,
+            chat_id: req.chat_id,
+            prompt,
+            secs,
+            reference_id: None,
+            model,
+            // Asking for a variation is asking for a new audio.
+            dedupe: Some(false),
+            seed: req.new_seed,
+        })
+    }
+
+    /// Checks that the generation can be queued.
+    async fn check_generation(&self, req: &GenerateAudioRequest) -> anyhow::Result<()> {
+        self.limits.check_secs(req.secs)?;
+        self.limits.check_queue(self.backend.queue_len())?;
+        if let Some(model) = &req.model {
+            let (id, name) = (&self.info.model_id, &self.info.model);
+            let plugins = &self.info.plugins;
+            self.limits.check_model_available(model, id, name, plugins)?;
+        }
+        if let Some(reference_id) = req.reference_id {
+            if ReferenceAudio::find(&self.storage, reference_id)
+                .await?
+                .is_none()
+            {
+                return Err(anyhow!("Reference audio {reference_id} does not exist"));
+            }
+            if !self.backend.supports_reference(req.model.as_deref()) {
+                return Err(anyhow!(
+                    "The model does not support audio conditioning, generate without a reference audio"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a generation in an existing chat, returning the chat if some of its generations
+    /// were deleted for making room for the new one.
+    async fn generate_in_chat(
+        &self,
+        req: GenerateAudioRequest,
+    ) -> anyhow::Result<Option<OutboundMsg>> {
+        self.check_generation(&req).await?;
+        let chat_id = req.chat_id;
+        let evicted = match &self.limits.chat_quota {
+            Some(quota) => quota.enforce(&self.storage, chat_id).await?,
+            None => vec![],
+        };
+        let job = self.new_job(req);
+        let request = job.request(&self.storage).await?;
+        job.save(&self.storage).await?;
+        self.ai_tx.send(BackendInboundMsg::Request(request))?;
+        // The deleted generations disappear from the chat.
+        if evicted.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(self.chat_msg(chat_id).await?))
+        }
+    }
+
+    /// Creates a chat named after the prompt of its first generation.
+    async fn new_chat(&self, chat_id: Uuid, prompt: &str) -> anyhow::Result<()> {
+        let chat = Chat {
+            chat_id,
+            name: prompt.to_string(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+        };
+        chat.save(&self.storage).await
+    }
+
+    async fn chat_msg(&self, chat_id: Uuid) -> anyhow::Result<OutboundMsg> {
+        let chat = Chat::load(&self.storage, chat_id).await?;
+        let history = Chat::load_entries(&self.storage, chat_id).await?;
+        Ok(OutboundMsg::Chat((chat, history)))
+    }
 }
 
 #[async_trait]
@@ -83,56 +459,109 @@ impl<S: Storage> WsHandler for MusicGptWsHandler<S> {
     type Outbound = OutboundMsg;
 
     async fn handle_init(&self) -> Vec<OutboundMsg> {
-        let chats = Chat::load_all(&self.storage).await.unwrap_or_default();
-        vec![
+        let chats = self.first_chats_page().await.unwrap_or(ChatsPage {
+            chats: vec![],
+            cursor: None,
+            next_cursor: None,
+        });
+        let mut msgs = vec![
             OutboundMsg::Info(self.info.clone()),
             OutboundMsg::Chats(chats),
-        ]
+            OutboundMsg::Clients(self.presence.clients()),
+        ];
+        // Only inform about the recovered jobs that are still pending.
+        let mut recovered_jobs = vec![];
+        for job in &self.recovered_jobs {
+            if let Ok(Some(_)) = PersistedJob::load(&self.storage, job.id).await {
+                recovered_jobs.push(job.clone())
+            }
+        }
+        if !recovered_jobs.is_empty() {
+            msgs.push(OutboundMsg::RecoveredJobs(recovered_jobs))
+        }
+        msgs
     }
 
     async fn handle_inbound_msg(&self, msg: InboundMsg) -> Option<OutboundMsg> {
         async move {
             let res = match msg {
+                InboundMsg::GenerateAudioNewChat(_)
+                | InboundMsg::GenerateAudio(_)
+                | InboundMsg::Regenerate(_)
+                | InboundMsg::GenerateComparison(_)
+                    if self.shutdown.is_cancelled() =>
+                {
+                    return Err(anyhow!(
+                        "MusicGPT is shutting down, no new jobs are accepted"
+                    ));
+                }
                 InboundMsg::GenerateAudioNewChat(req) => {
                     info!("Generating audio for new chat");
-                    let chat = Chat {
-                        chat_id: req.chat_id,
-                        name: req.prompt.clone(),
-                        created_at: SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_millis(),
-                    };
-                    chat.save(&self.storage).await?;
-                    self.ai_tx
-                        .send(BackendInboundMsg::Request(AudioGenerationRequest {
-                            id: IdPair(req.chat_id, req.id).to_string(),
-                            prompt: req.prompt.clone(),
-                            secs: req.secs,
-                        }))?;
-                    let chats = Chat::load_all(&self.storage).await?;
-                    Some(OutboundMsg::Chats(chats))
+                    self.check_generation(&req).await?;
+                    self.new_chat(req.chat_id, &req.prompt).await?;
+                    let job = self.new_job(req);
+                    let request = job.request(&self.storage).await?;
+                    job.save(&self.storage).await?;
+                    self.ai_tx.send(BackendInboundMsg::Request(request))?;
+                    Some(OutboundMsg::Chats(self.first_chats_page().await?))
                 }
                 InboundMsg::GenerateAudio(req) => {
                     info!("Generating audio for existing chat");
-                    self.ai_tx
-                        .send(BackendInboundMsg::Request(AudioGenerationRequest {
-                            id: IdPair(req.chat_id, req.id).to_string(),
-                            prompt: req.prompt.clone(),
-                            secs: req.secs,
-                        }))?;
-                    None
+                    self.generate_in_chat(req).await?
+                }
+                InboundMsg::Regenerate(req) => {
+                    info!("Regenerating audio");
+                    let req = self.regenerate_request(req).await?;
+                    self.generate_in_chat(req).await?
+                }
+                InboundMsg::GenerateComparison(req) => {
+                    info!("Generating comparison");
+                    let [a, b] = req.requests();
+                    self.check_generation(&a).await?;
+                    self.check_generation(&b).await?;
+                    // Both jobs need to fit in the queue.
+                    self.limits.check_queue(self.backend.queue_len() + 1)?;
+                    if req.new_chat {
+                        self.new_chat(req.chat_id, &req.prompt).await?;
+                    } else if let Some(quota) = &self.limits.chat_quota {
+                        quota.enforce(&self.storage, req.chat_id).await?;
+                    }
+                    let (a_id, b_id) = (a.id, b.id);
+                    for (side, compared_with) in [(a, b_id), (b, a_id)] {
+                        let job = PersistedJob {
+                            compared_with: Some(compared_with),
+                            ..self.new_job(side)
+                        };
+                        let request = job.request(&self.storage).await?;
+                        job.save(&self.storage).await?;
+                        self.ai_tx.send(BackendInboundMsg::Request(request))?;
+                    }
+                    if req.new_chat {
+                        Some(OutboundMsg::Chats(self.first_chats_page().await?))
+                    } else {
+                        Some(self.chat_msg(req.chat_id).await?)
+                    }
                 }
                 InboundMsg::AbortGeneration(req) => {
                     info!("Aborting audio generation");
+                    PersistedJob::remove(&self.storage, req.id).await?;
                     let id = IdPair(req.chat_id, req.id).to_string();
                     self.ai_tx.send(BackendInboundMsg::Abort(id))?;
                     None
                 }
-                InboundMsg::GetChat(req) => {
-                    let chat = Chat::load(&self.storage, req.chat_id).await?;
-                    let history = Chat::load_entries(&self.storage, req.chat_id).await?;
-                    Some(OutboundMsg::Chat((chat, history)))
+                InboundMsg::AbortAll => {
+                    info!("Aborting all the audio generations");
+                    self.ai_tx.send(BackendInboundMsg::AbortAll)?;
+                    None
+                }
+                InboundMsg::GetChat(req) => Some(self.chat_msg(req.chat_id).await?),
+                InboundMsg::GetChats(req) => {
+                    let limit = req
+                        .limit
+                        .unwrap_or(DEFAULT_CHATS_LIMIT)
+                        .min(MAX_CHATS_LIMIT);
+                    let page = Chat::load_page(&self.storage, req.cursor, limit).await?;
+                    Some(OutboundMsg::Chats(page))
                 }
                 InboundMsg::SetChatMetadata(req) => {
                     info!("Modifying the chat's metadata");
@@ -144,9 +573,93 @@ impl<S: Storage> WsHandler for MusicGptWsHandler<S> {
                     info!("Deleting chat");
                     let chat = Chat::load(&self.storage, req.chat_id).await?;
                     chat.delete(&self.storage).await?;
-                    let chats = Chat::load_all(&self.storage).await?;
-                    Some(OutboundMsg::Chats(chats))
+                    Some(OutboundMsg::Chats(self.first_chats_page().await?))
+                }
+                InboundMsg::UploadReference(req) => {
+                    info!("Uploading reference audio");
+                    if !self.backend.supports_any_reference() {
+                        return Err(anyhow!("None of the models can be conditioned with audio"));
+                    }
+                    let bytes = base64::engine::general_purpose::STANDARD.decode(req.data)?;
+                    let reference = ReferenceAudio::save(&self.storage, bytes, &req.format).await?;
+                    Some(OutboundMsg::ReferenceUploaded(reference))
                 }
+                InboundMsg::ScheduleJob(req) => {
+                    info!("Scheduling job");
+                    self.limits.check_secs(req.secs)?;
+                    let job = ScheduledJob::new(req.prompt, req.secs, req.schedule)?;
+                    job.save(&self.storage).await?;
+                    let jobs = ScheduledJob::load_all(&self.storage).await?;
+                    Some(OutboundMsg::ScheduledJobs(jobs))
+                }
+                InboundMsg::GetScheduledJobs => {
+                    let jobs = ScheduledJob::load_all(&self.storage).await?;
+                    Some(OutboundMsg::ScheduledJobs(jobs))
+                }
+                InboundMsg::CancelScheduledJob(req) => {
+                    info!("Cancelling scheduled job");
+                    ScheduledJob::remove(&self.storage, req.id).await?;
+                    let jobs = ScheduledJob::load_all(&self.storage).await?;
+                    Some(OutboundMsg::ScheduledJobs(jobs))
+                }
+                InboundMsg::RateEntry(req) => {
+                    info!("Rating generation");
+                    AiChatEntry::rate(&self.storage, req.chat_id, req.id, req.favorite, req.rating)
+                        .await?;
+                    Some(self.chat_msg(req.chat_id).await?)
+                }
+                InboundMsg::GetFavorites => {
+                    let favorites = AiChatEntry::load_favorites(&self.storage).await?;
+                    Some(OutboundMsg::Favorites(favorites))
+                }
+                InboundMsg::SetEntryMetadata(req) => {
+                    info!("Modifying the generation's metadata");
+                    AiChatEntry::update_metadata(
+                        &self.storage,
+                        req.chat_id,
+                        req.id,
+                        req.title,
+                        req.note,
+                    )
+                    .await?;
+                    Some(self.chat_msg(req.chat_id).await?)
+                }
+                InboundMsg::DelEntry(req) => {
+                    info!("Deleting generation");
+                    AiChatEntry::delete(&self.storage, req.chat_id, req.id).await?;
+                    Some(self.chat_msg(req.chat_id).await?)
+                }
+                InboundMsg::RevealEntry(req) => {
+                    if !self.storage.is_local() {
+                        return Err(anyhow!("The audios are not stored in this machine"));
+                    }
+                    let entry = AiChatEntry::load(&self.storage, req.chat_id, req.id).await?;
+                    reveal(&self.storage.path_buf(&entry.relpath))?;
+                    None
+                }
+                InboundMsg::GetQueue => {
+                    let mut jobs = vec![];
+                    for (req, running) in self.backend.jobs() {
+                        let IdPair(chat_id, id) = req.id.into();
+                        jobs.push(QueuedJob {
+                            id,
+                            chat_id,
+                            prompt: req.prompt,
+                            secs: req.secs,
+                            state: if running {
+                                JobState::Running
+                            } else {
+                                JobState::Pending
+                            },
+                            requested_by: PersistedJob::requested_by(&self.storage, id).await,
+                        })
+                    }
+                    Some(OutboundMsg::Queue(jobs))
+                }
+                InboundMsg::ExportChat(req) => Some(OutboundMsg::ChatExport(ChatExport {
+                    chat_id: req.chat_id,
+                    url: format!("/chats/{}/zip", req.chat_id),
+                })),
             };
             Ok::<Option<OutboundMsg>, anyhow::Error>(res)
         }
@@ -159,17 +672,82 @@ impl<S: Storage> WsHandler for MusicGptWsHandler<S> {
     }
 
     fn handle_subscription(&self) -> impl StreamExt<Item = OutboundMsg> + Send + 'static {
-        let mut rx = self.ai_broadcast_tx.subscribe();
+        // Subscribe before resuming the session, so that no message is lost in between.
+        // The ones that are both replayed and received are skipped.
+        let mut rx = self.replay.subscribe();
+        let missed = self.replay.resume(self.info.session_id);
+        let (replay, session_id) = (self.replay.clone(), self.info.session_id);
+        let mut clients_rx = self.presence.subscribe();
+        clients_rx.mark_unchanged();
         async_stream::stream! {
-            while let Ok(msg) = rx.recv().await {
+            let mut last_replayed = None;
+            for (seq, msg) in missed {
+                last_replayed = Some(seq);
+                replay.ack(session_id, seq);
+                yield OutboundMsg::Generation(msg)
+            }
+            let mut presence_open = true;
+            loop {
+                let (seq, msg) = tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Ok(msg) => msg,
+                        // The buffer still keeps the messages this session fell behind on.
+                        Err(RecvError::Lagged(_)) => {
+                            for (seq, msg) in replay.resume(session_id) {
+                                last_replayed = Some(seq);
+                                yield OutboundMsg::Generation(msg)
+                            }
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    changed = clients_rx.changed(), if presence_open => match changed {
+                        Ok(()) => {
+                            let clients = clients_rx.borrow_and_update().clone();
+                            yield OutboundMsg::Clients(clients);
+                            continue;
+                        }
+                        // Generation messages keep flowing without presence updates.
+                        Err(_) => {
+                            presence_open = false;
+                            continue;
+                        }
+                    },
+                };
+                if last_replayed.is_some_and(|last| seq <= last) {
+                    continue;
+                }
+                replay.ack(session_id, seq);
                 yield OutboundMsg::Generation(msg)
             }
         }
     }
 
+    fn handle_binary_subscription(&self) -> impl StreamExt<Item = Vec<u8>> + Send + 'static {
+        let mut rx = self.partial_audio_tx.subscribe();
+        async_stream::stream! {
+            loop {
+                match rx.recv().await {
+                    Ok(msg) => yield msg.to_bytes(),
+                    // Partial audios are just a preview, missing some is not a problem.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+
     async fn handle_error(&self, err: impl Display + Send) -> Option<OutboundMsg> {
         Some(OutboundMsg::Error(err.to_string()))
     }
+
+    fn ping_interval(&self) -> Duration {
+        self.ping_interval
+    }
+
+    fn encoding(&self) -> WsEncoding {
+        self.encoding
+    }
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
@@ -0,0 +1,316 @@
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::{
+    AudioGenerationBackend, AudioGenerationRequest, BackendInboundMsg,
+};
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::generation_limits::GenerationLimits;
+use crate::backend::music_gpt_chat::Chat;
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::storage::Storage;
+
+const DEFAULT_SECS: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// A JSON body with the base64 encoded .wav file.
+    #[default]
+    B64Json,
+    /// The raw .wav file.
+    Wav,
+}
+
+/// Request body of `POST /v1/audio/generations`, shaped like the ones of the OpenAI audio
+/// and image generation APIs so that existing clients can be pointed to MusicGPT.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpenAiAudioGenerationRequest {
+    /// If provided, it must be the model MusicGPT was started with, either by its id or by
+    /// its name, or one of the models provided by plugins, and one of the allowed models if
+    /// the server restricts them.
+    #[serde(default)]
+    pub model: Option<String>,
+    pub prompt: String,
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+    /// The seconds of audio to generate.
+    #[serde(default)]
+    pub duration: Option<usize>,
+    /// Set to false for generating the audio even if the server deduplicates requests and
+    /// an identical one is queued or finished recently.
+    #[serde(default)]
+    pub dedupe: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpenAiAudioData {
+    pub b64_json: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpenAiAudioGenerationResponse {
+    pub created: u64,
+    pub model: String,
+    pub data: Vec<OpenAiAudioData>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpenAiErrorDetail {
+    pub message: String,
+    pub r#type: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpenAiError {
+    pub error: OpenAiErrorDetail,
+}
+
+impl OpenAiError {
+    fn response(status: StatusCode, r#type: &str, message: impl Into<String>) -> Response {
+        let error = OpenAiErrorDetail {
+            message: message.into(),
+            r#type: r#type.to_string(),
+        };
+        (status, Json(OpenAiError { error })).into_response()
+    }
+}
+
+#[derive(Clone)]
+pub struct OpenAiApi<S: Storage> {
+    pub storage: S,
+    pub ai_broadcast_tx: tokio::sync::broadcast::Sender<GenerationMessage>,
+    pub ai_tx: Sender<BackendInboundMsg>,
+    pub backend: AudioGenerationBackend,
+    pub limits: GenerationLimits,
+    pub model: String,
+    pub model_id: String,
+    /// Models provided by plugins, which requests can also ask for.
+    pub plugins: Vec<String>,
+    /// Cancelled when the server starts shutting down, after that no new jobs are accepted.
+    pub shutdown: CancellationToken,
+    /// Cancelled once the server stopped processing jobs, requests still waiting for
+    /// their job are answered with an error.
+    pub close: CancellationToken,
+}
+
+/// Aborts the generation if the request is dropped before it finished, for example,
+/// because the client closed the connection.
+struct AbortOnDrop {
+    ai_tx: Sender<BackendInboundMsg>,
+    id: Option<String>,
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            let _ = self.ai_tx.send(BackendInboundMsg::Abort(id));
+        }
+    }
+}
+
+impl<S: Storage> OpenAiApi<S> {
+    pub async fn generate(&self, req: OpenAiAudioGenerationRequest) -> Response {
+        let secs = req.duration.unwrap_or(DEFAULT_SECS);
+        if self.shutdown.is_cancelled() {
+            let msg = "MusicGPT is shutting down, no new jobs are accepted";
+            return OpenAiError::response(StatusCode::SERVICE_UNAVAILABLE, "server_error", msg);
+        }
+        if req.prompt.is_empty() {
+            let msg = "prompt must not be empty";
+            return OpenAiError::response(StatusCode::BAD_REQUEST, "invalid_request_error", msg);
+        }
+        if !(1..=self.limits.max_secs).contains(&secs) {
+            let msg = format!("duration must be between 1 and {}", self.limits.max_secs);
+            return OpenAiError::response(StatusCode::BAD_REQUEST, "invalid_request_error", msg);
+        }
+        let check_model = |v| {
+            self.limits
+                .check_model_available(v, &self.model_id, &self.model, &self.plugins)
+        };
+        let plugin = match req.model.as_deref().map(check_model) {
+            Some(Ok(plugin)) => plugin,
+            Some(Err(err)) => {
+                let msg = err.to_string();
+                return OpenAiError::response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    msg,
+                );
+            }
+            None => None,
+        };
+        if let Err(err) = self.limits.check_queue(self.backend.queue_len()) {
+            let msg = err.to_string();
+            return OpenAiError::response(StatusCode::TOO_MANY_REQUESTS, "rate_limit_error", msg);
+        }
+
+        let dedupe = req.dedupe.unwrap_or(true);
+        match self.generate_wav(&req.prompt, secs, plugin.clone(), dedupe).await {
+            Ok(Ok(bytes)) => match req.response_format {
+                ResponseFormat::Wav => {
+                    ([(header::CONTENT_TYPE, "audio/wav")], bytes).into_response()
+                }
+                ResponseFormat::B64Json => Json(OpenAiAudioGenerationResponse {
+                    created: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                    model: plugin.unwrap_or_else(|| self.model.clone()),
+                    data: vec![OpenAiAudioData {
+                        b64_json: base64::engine::general_purpose::STANDARD.encode(bytes),
+                    }],
+                })
+                .into_response(),
+            },
+            Ok(Err(err)) => {
+                OpenAiError::response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", err)
+            }
+            Err(err) => OpenAiError::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "server_error",
+                err.to_string(),
+            ),
+        }
+    }
+
+    /// Queues the generation as a new chat, so that it also shows up in the web app, and
+    /// waits for it to finish. The outer error is for failures in MusicGPT itself, the
+    /// inner one is for generation errors reported by the backend.
+    async fn generate_wav(
+        &self,
+        prompt: &str,
+        secs: usize,
+        model: Option<String>,
+        dedupe: bool,
+    ) -> anyhow::Result<Result<Vec<u8>, String>> {
+        info!("Generating audio from the OpenAI compatible API");
+        let (chat_id, id) = (Uuid::new_v4(), Uuid::new_v4());
+        let chat = Chat {
+            chat_id,
+            name: prompt.to_string(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+        };
+        chat.save(&self.storage).await?;
+
+        // Subscribe before sending the request so that no message is missed.
+        let mut rx = self.ai_broadcast_tx.subscribe();
+        let backend_id = IdPair(chat_id, id).to_string();
+        self.ai_tx
+            .send(BackendInboundMsg::Request(AudioGenerationRequest {
+                id: backend_id.clone(),
+                prompt: prompt.to_string(),
+                secs,
+                model,
+                dedupe,
+                seed: None,
+                reference: None,
+            }))?;
+        let mut abort_on_drop = AbortOnDrop {
+            ai_tx: self.ai_tx.clone(),
+            id: Some(backend_id),
+        };
+
+        let relpath = loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = self.close.cancelled() => {
+                    return Ok(Err("MusicGPT shut down before processing the job".to_string()))
+                }
+            };
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            match msg {
+                GenerationMessage::Result(res) if res.id == id => break res.relpath,
+                GenerationMessage::Error(err) if err.id == id => {
+                    abort_on_drop.id = None;
+                    return Ok(Err(err.error));
+                }
+                _ => continue,
+            }
+        };
+        abort_on_drop.id = None;
+
+        match self.storage.read(&relpath).await? {
+            Some(bytes) => Ok(Ok(bytes)),
+            None => Err(anyhow::anyhow!("Generated audio {relpath} not found")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+
+    use super::*;
+    use crate::backend::_test_utils::{spawn, DummyJobProcessor};
+
+    #[tokio::test]
+    async fn generates_audio_through_openai_api() -> anyhow::Result<()> {
+        let (_ws, host) = spawn(DummyJobProcessor::default()).await?;
+        let url = format!("http://{host}/v1/audio/generations");
+        let client = reqwest::Client::new();
+
+        let req = OpenAiAudioGenerationRequest {
+            model: Some("dummy".to_string()),
+            prompt: "Create a cool song".to_string(),
+            response_format: ResponseFormat::B64Json,
+            duration: Some(4),
+            dedupe: None,
+        };
+        let res = client
+            .post(&url)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        let res: OpenAiAudioGenerationResponse = serde_json::from_slice(&res.bytes().await?)?;
+        let b64_wav = base64::engine::general_purpose::STANDARD.decode(&res.data[0].b64_json)?;
+
+        let req = OpenAiAudioGenerationRequest {
+            response_format: ResponseFormat::Wav,
+            ..req
+        };
+        let res = client
+            .post(&url)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.bytes().await?.to_vec(), b64_wav);
+
+        let req = OpenAiAudioGenerationRequest {
+            prompt: "fail at 2".to_string(),
+            ..req
+        };
+        let res = client
+            .post(&url)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&req)?)
+            .send()
+            .await?;
+        assert_eq!(res.status(), 500);
+        let res: OpenAiError = serde_json::from_slice(&res.bytes().await?)?;
+        assert_eq!(res.error.message, "Failed at 2");
+
+        Ok(())
+    }
+}
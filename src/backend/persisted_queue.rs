@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::AudioGenerationRequest;
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::presence::Client;
+use crate::backend::reference_audio::ReferenceAudio;
+use crate::storage::Storage;
+
+const QUEUE_DIR: &str = "queue";
+
+/// A generation request that has not finished yet, stored on disk so that it can be
+/// resumed if MusicGPT is restarted or crashes before processing it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PersistedJob {
+    pub chat_id: Uuid,
+    pub id: Uuid,
+    pub prompt: String,
+    pub secs: usize,
+    pub created_at: u128,
+    /// Whether the job had already started processing, in which case its user chat entry
+    /// was already saved.
+    pub started: bool,
+    /// The web app that queued the job, none if it was scheduled or queued through the API.
+    #[serde(default)]
+    pub requested_by: Option<Client>,
+    /// The plugin that processes the job, none for the model MusicGPT was started with.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// The other job of the A/B comparison this one is part of, if any.
+    #[serde(default)]
+    pub compared_with: Option<Uuid>,
+    /// Whether the job can be answered with the result of an identical one.
+    #[serde(default)]
+    pub dedupe: bool,
+    /// The seed the audio is sampled with, a random one if none.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// The uploaded reference audio the generation is conditioned on, if any.
+    #[serde(default)]
+    pub reference_id: Option<Uuid>,
+}
+
+impl PersistedJob {
+    pub fn new(chat_id: Uuid, id: Uuid, prompt: String, secs: usize) -> Self {
+        Self {
+            chat_id,
+            id,
+            prompt,
+            secs,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            started: false,
+            requested_by: None,
+            model: None,
+            compared_with: None,
+            dedupe: false,
+            seed: None,
+            reference_id: None,
+        }
+    }
+
+    /// The request for the backend, with the samples of the reference audio if any.
+    pub async fn request<S: Storage>(&self, storage: &S) -> anyhow::Result<AudioGenerationRequest> {
+        let reference = match self.reference_id {
+            Some(id) => Some(Arc::new(ReferenceAudio::load_samples(storage, id).await?)),
+            None => None,
+        };
+        Ok(AudioGenerationRequest {
+            id: IdPair(self.chat_id, self.id).to_string(),
+            prompt: self.prompt.clone(),
+            secs: self.secs,
+            model: self.model.clone(),
+            dedupe: self.dedupe,
+            seed: self.seed,
+            reference,
+        })
+    }
+
+    pub async fn save<S: Storage>(&self, storage: &S) -> anyhow::Result<()> {
+        let path = format!("{QUEUE_DIR}/{}.json", self.id);
+        Ok(storage.write(&path, serde_json::to_vec(self)?).await?)
+    }
+
+    pub async fn load<S: Storage>(storage: &S, id: Uuid) -> anyhow::Result<Option<Self>> {
+        let path = format!("{QUEUE_DIR}/{id}.json");
+        match storage.read(&path).await? {
+            Some(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads all the jobs that were left unfinished, in the order they were queued.
+    pub async fn load_all<S: Storage>(storage: &S) -> anyhow::Result<Vec<Self>> {
+        let mut result = vec![];
+        for file in storage.list(QUEUE_DIR).await? {
+            let Some(content) = storage.read(&file).await? else {
+                continue;
+            };
+            match serde_json::from_slice::<Self>(&content) {
+                Ok(job) => result.push(job),
+                Err(_) => continue,
+            }
+        }
+        result.sort_by_key(|v| v.created_at);
+        Ok(result)
+    }
+
+    /// Marks the job as started, returning whether it was already started before.
+    pub async fn mark_started<S: Storage>(storage: &S, id: Uuid) -> anyhow::Result<bool> {
+        let Some(mut job) = Self::load(storage, id).await? else {
+            return Ok(false);
+        };
+        if job.started {
+            return Ok(true);
+        }
+        job.started = true;
+        job.save(storage).await?;
+        Ok(false)
+    }
+
+    /// The client that queued the job, if it is still pending.
+    pub async fn requested_by<S: Storage>(storage: &S, id: Uuid) -> Option<Client> {
+        Self::load(storage, id).await.ok().flatten()?.requested_by
+    }
+
+    pub async fn remove<S: Storage>(storage: &S, id: Uuid) -> anyhow::Result<()> {
+        storage.rm(&format!("{QUEUE_DIR}/{id}.json")).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn persists_jobs() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let first = PersistedJob::new(Uuid::new_v4(), Uuid::new_v4(), "foo".to_string(), 1);
+        first.save(&storage).await?;
+        let mut second = PersistedJob::new(Uuid::new_v4(), Uuid::new_v4(), "bar".to_string(), 2);
+        second.created_at += 1;
+        second.save(&storage).await?;
+
+        assert!(!PersistedJob::mark_started(&storage, second.id).await?);
+        assert!(PersistedJob::mark_started(&storage, second.id).await?);
+        second.started = true;
+        assert_eq!(
+            PersistedJob::load_all(&storage).await?,
+            vec![first.clone(), second.clone()]
+        );
+
+        PersistedJob::remove(&storage, first.id).await?;
+        assert_eq!(PersistedJob::load_all(&storage).await?, vec![second]);
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::backend::audio_generation_backend::{
+    AsyncJobProcessor, GenerationParams, GenerationStage, GenerationTimings, OnPartialAudio,
+    OnProgress,
+};
+use crate::storage::{AppFs, Storage};
+
+const PLUGINS_DIR: &str = "plugins";
+
+/// Describes a plugin, read from `plugins/<id>.json` in the data dir. The id is the model
+/// that generation requests ask for in order to be processed by the plugin.
+///
+/// Manifests are only read from the local data dir, never from a remote storage like a
+/// WebDAV share, as whoever can write them can run any command in this machine.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PluginManifest {
+    /// Human-readable name of the model the plugin provides.
+    pub name: String,
+    /// The program and its arguments, spawned once for each job.
+    pub command: Vec<String>,
+    /// Whether the plugin conditions its generations on the reference audio of the requests.
+    /// Requests with a reference audio are rejected otherwise.
+    #[serde(default)]
+    pub reference: bool,
+}
+
+/// What a plugin is asked to generate, written as a single JSON line to its stdin.
+#[derive(Serialize)]
+struct PluginRequest<'a> {
+    prompt: &'a str,
+    secs: usize,
+    /// The samples the plugin outputs must be mono and at this rate.
+    sample_rate: u32,
+    #[serde(flatten)]
+    params: &'a GenerationParams,
+}
+
+/// The JSON lines a plugin writes to its stdout while generating. The generation succeeds
+/// if the plugin exits successfully without sending an error.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PluginMsg {
+    Progress(GenerationStage),
+    Samples(Vec<f32>),
+    Timings(GenerationTimings),
+    Error(String),
+}
+
+/// A [AsyncJobProcessor] provided by a third party program, for running other models or
+/// remote backends without forking MusicGPT.
+pub struct Plugin {
+    pub id: String,
+    pub manifest: PluginManifest,
+    sample_rate: u32,
+}
+
+impl Plugin {
+    pub fn new(id: &str, manifest: PluginManifest, sample_rate: u32) -> anyhow::Result<Self> {
+        if manifest.command.is_empty() {
+            return Err(anyhow!("The command of plugin {id} is empty"));
+        }
+        Ok(Self {
+            id: id.to_string(),
+            manifest,
+            sample_rate,
+        })
+    }
+
+    async fn run(
+        &self,
+        prompt: &str,
+        secs: usize,
+        params: &GenerationParams,
+        on_progress: OnProgress,
+        on_partial_audio: Option<OnPartialAudio>,
+    ) -> anyhow::Result<(VecDeque<f32>, GenerationTimings)> {
+        let mut child = Command::new(&self.manifest.command[0])
+            .args(&self.manifest.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| anyhow!("Could not start plugin {}: {err}", self.id))?;
+
+        let req = PluginRequest {
+            prompt,
+            secs,
+            sample_rate: self.sample_rate,
+            params,
+        };
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        stdin.write_all(&serde_json::to_vec(&req)?).await?;
+        stdin.write_all(b"\n").await?;
+        drop(stdin);
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut lines = BufReader::new(stdout).lines();
+        let mut samples = VecDeque::new();
+        let mut timings = GenerationTimings::default();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let msg = serde_json::from_str::<PluginMsg>(&line)
+                .map_err(|err| anyhow!("Plugin {} sent an invalid message: {err}", self.id))?;
+            match msg {
+                PluginMsg::Progress(stage) => {
+                    if on_progress(stage) {
+                        // Dropping the child kills it.
+                        return Err(anyhow!("Aborted"));
+                    }
+                }
+                PluginMsg::Samples(new) => {
+                    if let Some(on_partial_audio) = &on_partial_audio {
+                        on_partial_audio(VecDeque::from(new.clone()))
+                    }
+                    samples.extend(new);
+                }
+                PluginMsg::Timings(v) => timings = v,
+                PluginMsg::Error(err) => return Err(anyhow!(err)),
+            }
+        }
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(anyhow!("Plugin {} exited with {status}", self.id));
+        }
+        Ok((samples, timings))
+    }
+}
+
+#[async_trait]
+impl AsyncJobProcessor for Plugin {
+    async fn process(
+        &self,
+        prompt: &str,
+        secs: usize,
+        params: &GenerationParams,
+        on_progress: OnProgress,
+        on_partial_audio: Option<OnPartialAudio>,
+    ) -> ort::Result<(VecDeque<f32>, GenerationTimings)> {
+        self.run(prompt, secs, params, on_progress, on_partial_audio)
+            .await
+            .map_err(|err| ort::Error::new(err.to_string()))
+    }
+
+    fn supports_reference(&self) -> bool {
+        self.manifest.reference
+    }
+}
+
+/// Loads the plugins described in the `plugins` dir of the local data dir. Plugins with an
+/// invalid manifest are skipped with a warning.
+pub async fn discover_plugins(storage: &AppFs, sample_rate: u32) -> anyhow::Result<Vec<Plugin>> {
+    let mut plugins = vec![];
+    for file in storage.list(PLUGINS_DIR).await? {
+        let path = Path::new(&file);
+        if path.extension().and_then(|v| v.to_str()) != Some("json") {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|v| v.to_str()) else {
+            continue;
+        };
+        let Some(content) = storage.read(&file).await? else {
+            continue;
+        };
+        let plugin = serde_json::from_slice::<PluginManifest>(&content)
+            .map_err(anyhow::Error::from)
+            .and_then(|manifest| Plugin::new(id, manifest, sample_rate));
+        match plugin {
+            Ok(plugin) => {
+                info!("Loaded plugin {id} ({})", plugin.manifest.name);
+                plugins.push(plugin)
+            }
+            Err(err) => warn!("Skipping plugin {file}: {err}"),
+        }
+    }
+    plugins.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(plugins)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::backend::audio_generation_backend::ReferenceSamples;
+
+    fn sh_plugin(script: &str) -> Plugin {
+        let manifest = PluginManifest {
+            name: "Shell".to_string(),
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            reference: true,
+        };
+        Plugin::new("shell", manifest, 32000).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn processes_jobs_in_plugins() -> anyhow::Result<()> {
+        let plugin = sh_plugin(
+            r#"read req
+            echo '{"progress":"TextEncoding"}'
+            echo '{"samples":[0.1,0.2]}'
+            echo '{"progress":{"TokenGeneration":{"done":1,"total":1}}}'
+            echo '{"samples":[0.3]}'"#,
+        );
+        let stages = Arc::new(Mutex::new(vec![]));
+        let partial = Arc::new(Mutex::new(vec![]));
+        let (stages_clone, partial_clone) = (stages.clone(), partial.clone());
+        let (samples, _) = plugin
+            .process(
+                "Rock",
+                1,
+                &GenerationParams::default(),
+                Box::new(move |stage| {
+                    stages_clone.lock().unwrap().push(stage);
+                    false
+                }),
+                Some(Box::new(move |samples| {
+                    partial_clone.lock().unwrap().push(samples.len())
+                })),
+            )
+            .await?;
+        assert_eq!(samples, VecDeque::from(vec![0.1, 0.2, 0.3]));
+        assert_eq!(*partial.lock().unwrap(), vec![2, 1]);
+        assert_eq!(stages.lock().unwrap().len(), 2);
+
+        let plugin = sh_plugin(r#"read req; echo '{"error":"out of credits"}'"#);
+        let err = plugin
+            .process(
+                "Rock",
+                1,
+                &GenerationParams::default(),
+                Box::new(|_| false),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "out of credits");
+
+        let plugin = sh_plugin(r#"read req; echo '{"progress":"TextEncoding"}'; sleep 10"#);
+        let err = plugin
+            .process(
+                "Rock",
+                1,
+                &GenerationParams::default(),
+                Box::new(|_| true),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Aborted");
+
+        let err = sh_plugin("read req; exit 3")
+            .process(
+                "Rock",
+                1,
+                &GenerationParams::default(),
+                Box::new(|_| false),
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("exited"), "{err}");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sends_the_reference_to_plugins() -> anyhow::Result<()> {
+        let plugin = sh_plugin(
+            r#"read req
+            case "$req" in
+              *'"reference":{"samples":[0.5],"sampling_rate":16000}'*) echo '{"samples":[1.0]}';;
+              *) echo '{"error":"no reference"}';;
+            esac"#,
+        );
+        assert!(plugin.supports_reference());
+        let params = GenerationParams {
+            seed: None,
+            reference: Some(ReferenceSamples {
+                samples: vec![0.5],
+                sampling_rate: 16000,
+            }),
+        };
+        let (samples, _) = plugin
+            .process("Rock", 1, &params, Box::new(|_| false), None)
+            .await?;
+        assert_eq!(samples, VecDeque::from(vec![1.0]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discovers_plugins() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        assert!(discover_plugins(&storage, 32000).await?.is_empty());
+
+        let manifest = r#"{ "name": "Remote", "command": ["remote-musicgen", "--fast"] }"#;
+        storage.write("plugins/remote.json", manifest).await?;
+        storage.write("plugins/broken.json", "{}").await?;
+        storage.write("plugins/empty.json", r#"{ "name": "Empty", "command": [] }"#).await?;
+        storage.write("plugins/README.md", "Not a plugin").await?;
+
+        let plugins = discover_plugins(&storage, 32000).await?;
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(plugins[0].id, "remote");
+        assert_eq!(plugins[0].manifest.command, vec!["remote-musicgen", "--fast"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// A web app connected to the server, identified by its session.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct Client {
+    pub session_id: Uuid,
+    /// The name the client chose to be shown to others, connecting with `/ws?name=<name>`.
+    pub name: Option<String>,
+}
+
+/// Keeps track of the clients connected to the server, so that when several of them share
+/// it they can see each other.
+#[derive(Clone)]
+pub struct Presence {
+    next_id: Arc<AtomicU64>,
+    connections: Arc<Mutex<Vec<(u64, Client)>>>,
+    tx: watch::Sender<Vec<Client>>,
+}
+
+/// Removes the connection from the connected clients when dropped.
+pub struct PresenceGuard {
+    presence: Presence,
+    id: u64,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        self.presence
+            .update(|connections| connections.retain(|(id, _)| *id != self.id));
+    }
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            next_id: Arc::default(),
+            connections: Arc::default(),
+            tx: watch::channel(vec![]).0,
+        }
+    }
+}
+
+impl Presence {
+    fn update(&self, f: impl FnOnce(&mut Vec<(u64, Client)>)) {
+        let mut connections = self.connections.lock().unwrap();
+        f(&mut connections);
+        // The same session might be connected more than once while reconnecting.
+        let mut clients: Vec<Client> = vec![];
+        for (_, client) in connections.iter() {
+            if !clients.iter().any(|v| v.session_id == client.session_id) {
+                clients.push(client.clone())
+            }
+        }
+        self.tx.send_replace(clients);
+    }
+
+    /// Registers a new connection of `client`, until the returned guard is dropped.
+    pub fn join(&self, client: Client) -> PresenceGuard {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.update(|connections| connections.push((id, client)));
+        PresenceGuard {
+            presence: self.clone(),
+            id,
+        }
+    }
+
+    /// The connected clients, in the order they connected.
+    pub fn clients(&self) -> Vec<Client> {
+        self.tx.borrow().clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tx.borrow().is_empty()
+    }
+
+    /// Notifies every time a client connects or disconnects.
+    pub fn subscribe(&self) -> watch::Receiver<Vec<Client>> {
+        self.tx.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(name: &str) -> Client {
+        Client {
+            session_id: Uuid::new_v4(),
+            name: Some(name.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn tracks_connected_clients() -> anyhow::Result<()> {
+        let presence = Presence::default();
+        let mut rx = presence.subscribe();
+        let (alice, bob) = (client("alice"), client("bob"));
+
+        let alice_guard = presence.join(alice.clone());
+        let bob_guard = presence.join(bob.clone());
+        let _reconnecting_bob_guard = presence.join(bob.clone());
+        rx.changed().await?;
+        assert_eq!(*rx.borrow_and_update(), vec![alice.clone(), bob.clone()]);
+
+        drop(alice_guard);
+        rx.changed().await?;
+        assert_eq!(*rx.borrow_and_update(), vec![bob.clone()]);
+
+        drop(bob_guard);
+        assert_eq!(presence.clients(), vec![bob]);
+        assert!(!presence.is_empty());
+        Ok(())
+    }
+}
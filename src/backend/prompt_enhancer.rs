@@ -0,0 +1,132 @@
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// How long the prompt enhancer has for rewriting a prompt, after that the original one
+/// is used.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Rewrites the prompts before they are encoded, for example with a local LLM that expands
+/// them into richer descriptions.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PromptEnhancer {
+    /// A program and its arguments, which receives the prompt as a line in its stdin and
+    /// writes the enhanced one to its stdout.
+    Command(Vec<String>),
+    /// An HTTP endpoint, which receives a `{"prompt": "..."}` JSON POST and answers with
+    /// the enhanced prompt in the same shape.
+    Http(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct EnhancerBody {
+    prompt: String,
+}
+
+impl FromStr for PromptEnhancer {
+    type Err = anyhow::Error;
+
+    /// URLs are parsed as [PromptEnhancer::Http], anything else as a command that is split
+    /// like in a shell.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(Self::Http(s.to_string()));
+        }
+        let command = shell_words::split(s)?;
+        if command.is_empty() {
+            return Err(anyhow!("The prompt enhancer command is empty"));
+        }
+        Ok(Self::Command(command))
+    }
+}
+
+impl PromptEnhancer {
+    /// Returns the enhanced version of `prompt`.
+    pub async fn enhance(&self, prompt: &str) -> anyhow::Result<String> {
+        let enhanced = tokio::time::timeout(TIMEOUT, self.run(prompt))
+            .await
+            .map_err(|_| anyhow!("The prompt enhancer timed out"))??;
+        let enhanced = enhanced.trim();
+        if enhanced.is_empty() {
+            return Err(anyhow!("The prompt enhancer returned an empty prompt"));
+        }
+        Ok(enhanced.to_string())
+    }
+
+    async fn run(&self, prompt: &str) -> anyhow::Result<String> {
+        match self {
+            Self::Command(command) => {
+                let mut child = Command::new(&command[0])
+                    .args(&command[1..])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()?;
+                let mut stdin = child.stdin.take().expect("stdin is piped");
+                stdin.write_all(format!("{prompt}\n").as_bytes()).await?;
+                drop(stdin);
+                let output = child.wait_with_output().await?;
+                if !output.status.success() {
+                    return Err(anyhow!("The prompt enhancer exited with {}", output.status));
+                }
+                Ok(String::from_utf8(output.stdout)?)
+            }
+            Self::Http(url) => {
+                let body = EnhancerBody {
+                    prompt: prompt.to_string(),
+                };
+                let res = reqwest::Client::new()
+                    .post(url)
+                    .header("content-type", "application/json")
+                    .body(serde_json::to_vec(&body)?)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let body: EnhancerBody = serde_json::from_slice(&res.bytes().await?)?;
+                Ok(body.prompt)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_prompt_enhancers() -> anyhow::Result<()> {
+        assert_eq!(
+            "http://localhost:11434/enhance".parse::<PromptEnhancer>()?,
+            PromptEnhancer::Http("http://localhost:11434/enhance".to_string())
+        );
+        assert_eq!(
+            "llm --template 'music prompt'".parse::<PromptEnhancer>()?,
+            PromptEnhancer::Command(vec![
+                "llm".to_string(),
+                "--template".to_string(),
+                "music prompt".to_string(),
+            ])
+        );
+        assert!("".parse::<PromptEnhancer>().is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn enhances_prompts_with_commands() -> anyhow::Result<()> {
+        let enhancer: PromptEnhancer = r#"sh -c 'read p; echo "$p, with warm strings"'"#.parse()?;
+        let enhanced = enhancer.enhance("Lo-fi beat").await?;
+        assert_eq!(enhanced, "Lo-fi beat, with warm strings");
+
+        let enhancer: PromptEnhancer = "sh -c 'read p; echo'".parse()?;
+        assert!(enhancer.enhance("Lo-fi beat").await.is_err());
+        let enhancer: PromptEnhancer = "sh -c 'exit 1'".parse()?;
+        assert!(enhancer.enhance("Lo-fi beat").await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use uuid::Uuid;
+
+use crate::audio::AudioFile;
+use crate::backend::audio_generation_backend::ReferenceSamples;
+use crate::storage::Storage;
+
+const REFERENCES_DIR: &str = "references";
+/// Maximum size of uploaded reference audios, in bytes.
+pub const MAX_REFERENCE_BYTES: usize = 10 * 1024 * 1024;
+const MAX_REFERENCE_SECS: f32 = 60.0;
+const FORMATS: [&str; 3] = ["wav", "mp3", "flac"];
+
+/// An audio uploaded by the user, which generation requests can refer to for
+/// continuing it or for conditioning the generation with its melody.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ReferenceAudio {
+    pub id: Uuid,
+    pub relpath: String,
+    pub secs: f32,
+}
+
+impl ReferenceAudio {
+    /// Maps a mime type to the extension of one of the supported formats.
+    pub fn format_from_mime(mime: &str) -> Option<&'static str> {
+        match mime {
+            "audio/wav" | "audio/x-wav" | "audio/wave" => Some("wav"),
+            "audio/mpeg" | "audio/mp3" => Some("mp3"),
+            "audio/flac" | "audio/x-flac" => Some("flac"),
+            _ => None,
+        }
+    }
+
+    /// Validates that `bytes` is a supported audio file and stores it.
+    pub async fn save<S: Storage>(
+        storage: &S,
+        bytes: Vec<u8>,
+        format: &str,
+    ) -> anyhow::Result<Self> {
+        if !FORMATS.contains(&format) {
+            return Err(anyhow!(
+                "Unsupported audio format {format}, use one of {}",
+                FORMATS.join(", ")
+            ));
+        }
+        if bytes.len() > MAX_REFERENCE_BYTES {
+            return Err(anyhow!(
+                "Reference audios cannot be bigger than {} MB",
+                MAX_REFERENCE_BYTES / 1024 / 1024
+            ));
+        }
+        let audio = AudioFile::from_bytes(bytes.clone(), Some(format))
+            .map_err(|err| anyhow!("Invalid {format} file: {err}"))?;
+        let secs = audio.samples.len() as f32 / audio.sampling_rate as f32;
+        if secs > MAX_REFERENCE_SECS {
+            return Err(anyhow!(
+                "Reference audios cannot be longer than {MAX_REFERENCE_SECS} seconds"
+            ));
+        }
+
+        let id = Uuid::new_v4();
+        let relpath = format!("{REFERENCES_DIR}/{id}.{format}");
+        storage.write(&relpath, bytes).await?;
+        Ok(Self { id, relpath, secs })
+    }
+
+    /// Finds the reference audio with the given id, whatever its format.
+    pub async fn find<S: Storage>(storage: &S, id: Uuid) -> anyhow::Result<Option<String>> {
+        for format in FORMATS {
+            let relpath = format!("{REFERENCES_DIR}/{id}.{format}");
+            if storage.exists(&relpath).await? {
+                return Ok(Some(relpath));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decodes the reference audio with the given id, for conditioning a generation on it.
+    pub async fn load_samples<S: Storage>(
+        storage: &S,
+        id: Uuid,
+    ) -> anyhow::Result<ReferenceSamples> {
+        let Some(relpath) = Self::find(storage, id).await? else {
+            return Err(anyhow!("Reference audio {id} does not exist"));
+        };
+        let Some(bytes) = storage.read(&relpath).await? else {
+            return Err(anyhow!("Reference audio {id} does not exist"));
+        };
+        let format = relpath.rsplit('.').next();
+        let audio = AudioFile::from_bytes(bytes, format)?;
+        Ok(ReferenceSamples {
+            samples: audio.samples.into(),
+            sampling_rate: audio.sampling_rate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn validates_and_saves_references() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let wav = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test.wav"))?;
+
+        let reference = ReferenceAudio::save(&storage, wav.clone(), "wav").await?;
+        assert!(reference.secs > 0.0);
+        assert_eq!(
+            ReferenceAudio::find(&storage, reference.id).await?,
+            Some(reference.relpath)
+        );
+        assert_eq!(ReferenceAudio::find(&storage, Uuid::new_v4()).await?, None);
+        let samples = ReferenceAudio::load_samples(&storage, reference.id).await?;
+        assert_eq!(
+            samples.samples.len() as f32 / samples.sampling_rate as f32,
+            reference.secs
+        );
+        assert!(ReferenceAudio::load_samples(&storage, Uuid::new_v4())
+            .await
+            .is_err());
+
+        assert!(ReferenceAudio::save(&storage, wav, "ogg").await.is_err());
+        let not_audio = b"not an audio".to_vec();
+        assert!(ReferenceAudio::save(&storage, not_audio, "wav")
+            .await
+            .is_err());
+        let too_big = vec![0; MAX_REFERENCE_BYTES + 1];
+        assert!(ReferenceAudio::save(&storage, too_big, "wav")
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::backend::audio_generation_fanout::GenerationMessage;
+
+/// How many of the most recent messages are kept for replaying them.
+const REPLAY_CAPACITY: usize = 1000;
+
+#[derive(Default)]
+struct Inner {
+    next_seq: u64,
+    messages: VecDeque<(u64, GenerationMessage)>,
+    /// The sequence number of the next message that each session has not received yet.
+    sessions: HashMap<Uuid, u64>,
+}
+
+/// Keeps the most recent generation messages along with a sequence number, so that clients
+/// that reconnect with the same session after a network blip receive the ones they missed.
+#[derive(Clone)]
+pub struct ReplayBuffer {
+    inner: Arc<Mutex<Inner>>,
+    tx: broadcast::Sender<(u64, GenerationMessage)>,
+}
+
+impl ReplayBuffer {
+    /// Records all the messages broadcast in `ai_broadcast_tx`.
+    pub fn new(ai_broadcast_tx: &broadcast::Sender<GenerationMessage>) -> Self {
+        let (tx, _) = broadcast::channel(1000); // Arbitrary number.
+        let this = Self {
+            inner: Arc::default(),
+            tx,
+        };
+        let mut rx = ai_broadcast_tx.subscribe();
+        let this_clone = this.clone();
+        tokio::spawn(async move {
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("Replay buffer skipped {n} generation messages");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let seq = this_clone.push(msg.clone());
+                let _ = this_clone.tx.send((seq, msg));
+            }
+        });
+        this
+    }
+
+    /// Records a message, returning its sequence number.
+    fn push(&self, msg: GenerationMessage) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.messages.push_back((seq, msg));
+        if inner.messages.len() > REPLAY_CAPACITY {
+            inner.messages.pop_front();
+            // Sessions that missed messages that are no longer kept cannot be resumed.
+            let oldest = inner.messages.front().map(|(seq, _)| *seq).unwrap_or(seq);
+            inner.sessions.retain(|_, next| *next >= oldest);
+        }
+        seq
+    }
+
+    /// Subscribes to the recorded messages along with their sequence numbers.
+    pub fn subscribe(&self) -> broadcast::Receiver<(u64, GenerationMessage)> {
+        self.tx.subscribe()
+    }
+
+    /// Returns the messages that `session` missed since it last received one, registering
+    /// it if it's a new session.
+    pub fn resume(&self, session: Uuid) -> Vec<(u64, GenerationMessage)> {
+        let mut inner = self.inner.lock().unwrap();
+        let next_seq = inner.next_seq;
+        let Some(next) = inner.sessions.insert(session, next_seq) else {
+            return vec![];
+        };
+        inner
+            .messages
+            .iter()
+            .filter(|(seq, _)| *seq >= next)
+            .cloned()
+            .collect()
+    }
+
+    /// Records that `session` received the message with the sequence number `seq`.
+    pub fn ack(&self, session: Uuid, seq: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let next = inner.sessions.entry(session).or_default();
+        *next = (*next).max(seq + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::backend::audio_generation_backend::GenerationStage;
+    use crate::backend::audio_generation_fanout::AudioGenerationProgress;
+
+    fn progress(progress: f32) -> GenerationMessage {
+        GenerationMessage::Progress(AudioGenerationProgress {
+            id: Uuid::nil(),
+            chat_id: Uuid::nil(),
+            progress,
+            stage: GenerationStage::TokenGeneration {
+                done: (progress * 100.0) as usize,
+                total: 100,
+            },
+        })
+    }
+
+    fn progresses(msgs: Vec<(u64, GenerationMessage)>) -> Vec<f32> {
+        msgs.into_iter()
+            .map(|(_, msg)| match msg {
+                GenerationMessage::Progress(p) => p.progress,
+                _ => panic!("msg was not GenerationMessage::Progress"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replays_missed_messages() {
+        let (ai_broadcast_tx, _) = broadcast::channel(10);
+        let buffer = ReplayBuffer::new(&ai_broadcast_tx);
+        let session = Uuid::new_v4();
+
+        buffer.push(progress(0.1));
+        assert!(buffer.resume(session).is_empty());
+        let seq = buffer.push(progress(0.2));
+        buffer.ack(session, seq);
+        buffer.push(progress(0.3));
+        buffer.push(progress(0.4));
+        assert_eq!(progresses(buffer.resume(session)), vec![0.3, 0.4]);
+        assert!(buffer.resume(session).is_empty());
+
+        // Sessions that fall too far behind are forgotten.
+        for _ in 0..=REPLAY_CAPACITY {
+            buffer.push(progress(0.5));
+        }
+        assert!(buffer.resume(session).is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_recording_after_lagging_behind() -> anyhow::Result<()> {
+        let (ai_broadcast_tx, _) = broadcast::channel(1);
+        let buffer = ReplayBuffer::new(&ai_broadcast_tx);
+        let mut rx = buffer.subscribe();
+
+        // The recorder task does not get to run until this one yields, so it lags.
+        for i in 0..5 {
+            ai_broadcast_tx.send(progress(i as f32 / 10.0))?;
+        }
+        tokio::task::yield_now().await;
+        ai_broadcast_tx.send(progress(0.9))?;
+
+        let mut received = vec![];
+        while received.last() != Some(&0.9) {
+            let msg = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await??;
+            received.extend(progresses(vec![msg]));
+        }
+        assert_eq!(received, vec![0.4, 0.9]);
+        Ok(())
+    }
+}
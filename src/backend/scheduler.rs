@@ -0,0 +1,205 @@
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::BackendInboundMsg;
+use crate::backend::cron::Cron;
+use crate::backend::music_gpt_chat::Chat;
+use crate::backend::persisted_queue::PersistedJob;
+use crate::storage::Storage;
+
+const SCHEDULES_DIR: &str = "schedules";
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub enum Schedule {
+    /// Runs once, at the given milliseconds since the epoch.
+    Once(u128),
+    /// Runs every time the cron expression matches, in UTC.
+    Cron(String),
+}
+
+/// A generation job that is queued in the future, once or repeatedly. Every run
+/// creates a new chat with the generated audio.
+#[derive(Clone, Debug, Type, Serialize, Deserialize, PartialEq)]
+pub struct ScheduledJob {
+    pub id: Uuid,
+    pub prompt: String,
+    pub secs: usize,
+    pub schedule: Schedule,
+    /// When the job will run next, in milliseconds since the epoch.
+    pub next_run: u128,
+}
+
+impl ScheduledJob {
+    pub fn new(prompt: String, secs: usize, schedule: Schedule) -> anyhow::Result<Self> {
+        let next_run = match &schedule {
+            Schedule::Once(run_at) => *run_at,
+            Schedule::Cron(expression) => {
+                let cron: Cron = expression.parse()?;
+                let Some(next_run) = cron.next_after(now_ms()) else {
+                    return Err(anyhow!("The cron expression {expression} never runs"));
+                };
+                next_run
+            }
+        };
+        Ok(Self {
+            id: Uuid::new_v4(),
+            prompt,
+            secs,
+            schedule,
+            next_run,
+        })
+    }
+
+    pub async fn save<S: Storage>(&self, storage: &S) -> anyhow::Result<()> {
+        let path = format!("{SCHEDULES_DIR}/{}.json", self.id);
+        Ok(storage.write(&path, serde_json::to_vec(self)?).await?)
+    }
+
+    /// Loads all the scheduled jobs, sorted by their next run.
+    pub async fn load_all<S: Storage>(storage: &S) -> anyhow::Result<Vec<Self>> {
+        let mut result = vec![];
+        for file in storage.list(SCHEDULES_DIR).await? {
+            let Some(content) = storage.read(&file).await? else {
+                continue;
+            };
+            match serde_json::from_slice::<Self>(&content) {
+                Ok(job) => result.push(job),
+                Err(_) => continue,
+            }
+        }
+        result.sort_by_key(|v| v.next_run);
+        Ok(result)
+    }
+
+    /// Removes a scheduled job, returning whether it existed.
+    pub async fn remove<S: Storage>(storage: &S, id: Uuid) -> anyhow::Result<bool> {
+        Ok(storage.rm(&format!("{SCHEDULES_DIR}/{id}.json")).await?)
+    }
+
+    /// Queues the job in a new chat, and either reschedules it or removes it if it
+    /// does not run again.
+    async fn run<S: Storage>(
+        mut self,
+        storage: &S,
+        ai_tx: &Sender<BackendInboundMsg>,
+        now: u128,
+    ) -> anyhow::Result<()> {
+        info!(id = %self.id, "Running scheduled job");
+        let chat = Chat {
+            chat_id: Uuid::new_v4(),
+            name: self.prompt.clone(),
+            created_at: now,
+        };
+        chat.save(storage).await?;
+        let job = PersistedJob::new(chat.chat_id, Uuid::new_v4(), self.prompt.clone(), self.secs);
+        job.save(storage).await?;
+        ai_tx.send(BackendInboundMsg::Request(job.request(storage).await?))?;
+
+        let next_run = match &self.schedule {
+            Schedule::Once(_) => None,
+            Schedule::Cron(expression) => expression.parse::<Cron>()?.next_after(now),
+        };
+        match next_run {
+            Some(next_run) => {
+                self.next_run = next_run;
+                self.save(storage).await
+            }
+            None => Self::remove(storage, self.id).await.map(|_| ()),
+        }
+    }
+}
+
+/// Runs all the scheduled jobs that are due at `now`.
+pub async fn run_due_jobs<S: Storage>(
+    storage: &S,
+    ai_tx: &Sender<BackendInboundMsg>,
+    now: u128,
+) -> anyhow::Result<()> {
+    for job in ScheduledJob::load_all(storage).await? {
+        if job.next_run > now {
+            break;
+        }
+        let id = job.id;
+        if let Err(err) = job.run(storage, ai_tx, now).await {
+            error!(%id, %err, "Error running scheduled job");
+        }
+    }
+    Ok(())
+}
+
+/// Periodically runs the scheduled jobs that are due, until `shutdown` is cancelled.
+pub async fn run_scheduler<S: Storage>(
+    storage: S,
+    ai_tx: Sender<BackendInboundMsg>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        if let Err(err) = run_due_jobs(&storage, &ai_tx, now_ms()).await {
+            error!(%err, "Error checking scheduled jobs");
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn runs_due_jobs() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let (ai_tx, ai_rx) = std::sync::mpsc::channel();
+        let now = now_ms();
+
+        let once = ScheduledJob::new("once".to_string(), 1, Schedule::Once(now))?;
+        once.save(&storage).await?;
+        let cron = ScheduledJob::new(
+            "cron".to_string(),
+            2,
+            Schedule::Cron("0 * * * *".to_string()),
+        )?;
+        cron.save(&storage).await?;
+        let later = ScheduledJob::new("later".to_string(), 3, Schedule::Once(now + 1))?;
+        later.save(&storage).await?;
+
+        run_due_jobs(&storage, &ai_tx, now).await?;
+        let BackendInboundMsg::Request(req) = ai_rx.try_recv()? else {
+            panic!("msg was not BackendInboundMsg::Request")
+        };
+        assert_eq!(req.prompt, "once");
+        assert!(ai_rx.try_recv().is_err());
+        assert_eq!(Chat::load_all(&storage).await?.len(), 1);
+        assert_eq!(PersistedJob::load_all(&storage).await?.len(), 1);
+
+        // The cron job is rescheduled after running, and the one-off job is removed.
+        run_due_jobs(&storage, &ai_tx, cron.next_run).await?;
+        let mut prompts = vec![];
+        while let Ok(BackendInboundMsg::Request(req)) = ai_rx.try_recv() {
+            prompts.push(req.prompt)
+        }
+        assert_eq!(prompts, vec!["later", "cron"]);
+        let jobs = ScheduledJob::load_all(&storage).await?;
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].next_run, cron.next_run + 60 * 60_000);
+        Ok(())
+    }
+}
@@ -1,23 +1,95 @@
-use axum::extract::WebSocketUpgrade;
+use anyhow::anyhow;
+use axum::extract::{DefaultBodyLimit, Path as UrlPath, Query, Request, WebSocketUpgrade};
+use axum::http::{HeaderMap, Uri};
+use axum::middleware;
 use axum::response::Html;
-use axum::routing::get;
-use axum::Router;
-use std::path::Path;
-use tower_http::services::ServeDir;
-use tracing::info;
-
-use crate::backend::audio_generation_backend::{AudioGenerationBackend, JobProcessor};
-use crate::backend::audio_generation_fanout::audio_generation_fanout;
-use crate::backend::music_gpt_ws_handler::{Info, MusicGptWsHandler};
-use crate::backend::ws_handler::WsHandler;
-use crate::storage::Storage;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tower_http::services::{ServeDir, ServeFile};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tower_http::LatencyUnit;
+use tracing::{error, info, warn, Level};
+use uuid::Uuid;
+
+use crate::audio::AudioManager;
+use crate::backend::api_keys::ApiKeys;
+use crate::backend::audio_export::AudioExport;
+use crate::backend::audio_generation_backend::{
+    AudioGenerationBackend, BackendInboundMsg, JobProcessor,
+};
+use crate::backend::audio_generation_fanout::{
+    audio_generation_fanout, AudioGenerationStart, ProgressThrottle,
+};
+use crate::backend::authorization::{authorize, remember_api_key, UrlSigner};
+use crate::backend::cast_api::{cast, renderers, CastRequest};
+use crate::backend::dlna::DiscoveredRenderers;
+use crate::backend::events_api::generation_events;
+use crate::backend::files_api::{chat_zip, serve_file, upload_reference};
+use crate::backend::garbage_collector::run_garbage_collector;
+use crate::backend::generation_limits::GenerationLimits;
+use crate::backend::grpc_api::{GrpcApi, MusicGptServer};
+use crate::backend::music_gpt_ws_handler::{Info, MusicGptWsHandler, PROTOCOL_VERSION};
+use crate::backend::openai_api::{OpenAiApi, OpenAiAudioGenerationRequest};
+use crate::backend::persisted_queue::PersistedJob;
+use crate::backend::plugins::discover_plugins;
+use crate::backend::presence::Presence;
+use crate::backend::prompt_enhancer::PromptEnhancer;
+use crate::backend::reference_audio::MAX_REFERENCE_BYTES;
+use crate::backend::replay_buffer::ReplayBuffer;
+use crate::backend::scheduler::run_scheduler;
+use crate::backend::shutdown::{abort_on_ctrl_c, drain_backend, shutdown_when_idle};
+use crate::backend::ws_api::{self, WsParams};
+use crate::backend::ws_handler::WsEncoding;
+use crate::storage::{AppFs, Storage};
 
 pub struct RunWebServerOptions {
     pub name: String,
+    /// Id of the model, like "small", by which clients can ask for it.
+    pub model_id: String,
     pub device: String,
     pub port: usize,
     pub auto_open: bool,
-    pub expose: bool,
+    pub host: String,
+    /// Origins allowed to call the server from a browser, "*" allows any origin. If
+    /// empty, only the bundled web app can use the server from a browser.
+    pub cors_origins: Vec<String>,
+    pub audio_manager: AudioManager,
+    /// Once cancelled, the server stops accepting new jobs, waits for the one being
+    /// processed to finish, closes all the connections and exits.
+    pub shutdown: CancellationToken,
+    /// How long to wait for the job being processed before aborting it on shutdown.
+    pub shutdown_grace: Duration,
+    /// Ctrl+C aborts all the jobs if there are any, and cancels `shutdown` otherwise.
+    pub abort_on_ctrl_c: bool,
+    /// Stream the audios to the web app while they are being generated.
+    pub stream_audio: bool,
+    /// How many jobs are processed at the same time.
+    pub workers: usize,
+    /// Answer requests with the result of an identical one if it's queued or finished
+    /// recently, unless they opt out.
+    pub dedupe: bool,
+    /// Directory with a web app build to serve instead of the bundled one.
+    pub web_dir: Option<PathBuf>,
+    pub limits: GenerationLimits,
+    /// Reject requests without a valid API key. Requests with an invalid one are always
+    /// rejected.
+    pub require_api_key: bool,
+    /// Listen in this Unix domain socket instead of in `host` and `port`.
+    pub socket: Option<PathBuf>,
+    /// Shut down after having no websocket clients and no jobs for this long.
+    pub idle_timeout: Option<Duration>,
+    pub progress_throttle: ProgressThrottle,
+    /// How often websocket clients are pinged for detecting dead connections.
+    pub ws_ping_interval: Duration,
+    /// Also write the generated audios outside the data dir.
+    pub export: Option<AudioExport>,
+    /// Rewrites the prompts before generating them.
+    pub prompt_enhancer: Option<PromptEnhancer>,
 }
 
 pub async fn run_web_server<T, S, P>(
@@ -31,49 +103,347 @@ where
     S: Storage + 'static,
     P: AsRef<Path>,
 {
-    let (ai_tx, ai_rx) = AudioGenerationBackend::new(processor).run();
-    let ai_broadcast_tx = audio_generation_fanout(ai_rx, storage.clone());
+    let mut backend = AudioGenerationBackend::new(processor)
+        .with_partial_audio(opts.stream_audio)
+        .with_workers(opts.workers)
+        .with_device(&opts.device)
+        .with_dedupe(opts.dedupe)
+        .with_prompt_enhancer(opts.prompt_enhancer);
+    let sampling_rate = opts.audio_manager.sampling_rate();
+    let mut plugins = vec![];
+    // Plugins run commands, so they are never read from a remote storage.
+    let local_fs = AppFs::new(root.as_ref());
+    for plugin in discover_plugins(&local_fs, sampling_rate).await? {
+        let id = plugin.id.clone();
+        backend = backend.with_processor(&id, plugin);
+        plugins.push(id);
+    }
+    let (ai_tx, ai_rx) = backend.clone().run();
+    let (partial_audio_tx, _) = tokio::sync::broadcast::channel(1000); // Arbitrary number.
+    let ai_broadcast_tx = audio_generation_fanout(
+        ai_rx,
+        storage.clone(),
+        opts.audio_manager,
+        partial_audio_tx.clone(),
+        opts.progress_throttle,
+        opts.export,
+        opts.model_id.clone(),
+    );
+
+    let replay = ReplayBuffer::new(&ai_broadcast_tx);
+
+    // Cancelled once the backend is drained, for closing all the connections.
+    let close = CancellationToken::new();
+    tokio::spawn({
+        let (shutdown, close) = (opts.shutdown.clone(), close.clone());
+        let (backend, replay) = (backend.clone(), replay.clone());
+        let grace = opts.shutdown_grace;
+        async move {
+            shutdown.cancelled().await;
+            drain_backend(&backend, &replay, grace).await;
+            close.cancel();
+        }
+    });
+
+    // Resume the jobs that were left pending the last time MusicGPT ran.
+    let mut recovered_jobs = vec![];
+    for job in PersistedJob::load_all(&storage).await? {
+        info!("Recovering pending job {}", job.id);
+        let request = match job.request(&storage).await {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("Could not recover pending job {}: {err}", job.id);
+                PersistedJob::remove(&storage, job.id).await?;
+                continue;
+            }
+        };
+        ai_tx.send(BackendInboundMsg::Request(request))?;
+        recovered_jobs.push(AudioGenerationStart {
+            id: job.id,
+            chat_id: job.chat_id,
+            prompt: job.prompt,
+            enhanced_prompt: None,
+            secs: job.secs,
+            requested_by: job.requested_by,
+        })
+    }
+
+    tokio::spawn(run_scheduler(
+        storage.clone(),
+        ai_tx.clone(),
+        opts.shutdown.clone(),
+    ));
+    tokio::spawn(run_garbage_collector(
+        storage.clone(),
+        opts.shutdown.clone(),
+    ));
+
+    if opts.abort_on_ctrl_c {
+        tokio::spawn(abort_on_ctrl_c(
+            backend.clone(),
+            ai_tx.clone(),
+            opts.shutdown.clone(),
+        ));
+    }
+
+    let presence = Presence::default();
+    if let Some(idle_timeout) = opts.idle_timeout {
+        tokio::spawn(shutdown_when_idle(
+            storage.clone(),
+            backend.clone(),
+            presence.clone(),
+            idle_timeout,
+            opts.shutdown.clone(),
+        ));
+    }
+
+    let openai_api = OpenAiApi {
+        storage: storage.clone(),
+        ai_broadcast_tx: ai_broadcast_tx.clone(),
+        ai_tx: ai_tx.clone(),
+        backend: backend.clone(),
+        limits: opts.limits.clone(),
+        model: opts.name.clone(),
+        model_id: opts.model_id.clone(),
+        plugins: plugins.clone(),
+        shutdown: opts.shutdown.clone(),
+        close: close.clone(),
+    };
+
+    let grpc_api = GrpcApi {
+        storage: storage.clone(),
+        ai_broadcast_tx: ai_broadcast_tx.clone(),
+        ai_tx: ai_tx.clone(),
+        backend: backend.clone(),
+        limits: opts.limits.clone(),
+        model: opts.name.clone(),
+        model_id: opts.model_id.clone(),
+        plugins: plugins.clone(),
+        shutdown: opts.shutdown.clone(),
+        close: close.clone(),
+    };
+
+    let files_storage = storage.clone();
+    let zip_storage = storage.clone();
+    let api_keys = ApiKeys::new(storage.clone());
+    let cookie_keys = api_keys.clone();
+    let discovered_renderers = DiscoveredRenderers::default();
+    let cast_renderers = discovered_renderers.clone();
+    let url_signer = UrlSigner::default();
+    let cast_signer = opts.require_api_key.then(|| url_signer.clone());
+    let references_storage = storage.clone();
+    let references_supported = backend.supports_any_reference();
+    let events_tx = ai_broadcast_tx.clone();
+    let (ws_close, events_close) = (close.clone(), close.clone());
 
     let ws_handler = MusicGptWsHandler {
         ai_tx,
+        backend,
+        limits: opts.limits,
         storage,
         info: Info {
             model: opts.name,
+            model_id: opts.model_id,
             device: opts.device,
+            protocol_version: PROTOCOL_VERSION,
+            plugins,
+            // Set for every connection.
+            session_id: Uuid::nil(),
         },
-        ai_broadcast_tx,
+        partial_audio_tx,
+        replay,
+        recovered_jobs,
+        shutdown: opts.shutdown,
+        ping_interval: opts.ws_ping_interval,
+        presence,
+        encoding: WsEncoding::Json,
+        client_name: None,
+    };
+
+    let files = if files_storage.is_local() {
+        Router::new().nest_service("/files", ServeDir::new(root))
+    } else {
+        Router::new().nest_service(
+            "/files",
+            get(move |uri: Uri, headers: HeaderMap| {
+                serve_file(files_storage.clone(), uri, headers)
+            }),
+        )
     };
 
-    let app = Router::new()
-        .fallback(get(web_app))
-        .nest_service("/files", ServeDir::new(root))
+    let mut app = files
         .route(
             "/ws",
-            get(|ws: WebSocketUpgrade| async move {
-                let ws_handler = ws_handler.clone();
-                ws.on_upgrade(move |ws| ws_handler.handle(ws))
+            get(
+                |ws: WebSocketUpgrade, Query(params): Query<WsParams>| async move {
+                    ws_api::upgrade(ws_handler.clone(), ws_close.clone(), ws, params)
+                },
+            ),
+        )
+        .route(
+            "/events",
+            get(|| async move { generation_events(&events_tx, events_close.clone()) }),
+        )
+        .route(
+            "/chats/:chat_id/zip",
+            get(move |UrlPath(chat_id)| chat_zip(zip_storage.clone(), chat_id)),
+        )
+        .route(
+            "/references",
+            post(move |headers, body| {
+                upload_reference(references_storage.clone(), references_supported, headers, body)
+            })
+                .layer(DefaultBodyLimit::max(MAX_REFERENCE_BYTES)),
+        )
+        .route(
+            "/renderers",
+            get(move || renderers(discovered_renderers.clone())),
+        )
+        .route(
+            "/cast",
+            post(move |headers: HeaderMap, Json(req): Json<CastRequest>| {
+                cast(cast_renderers.clone(), cast_signer.clone(), headers, req)
             }),
-        );
+        )
+        .route(
+            "/v1/audio/generations",
+            post(|Json(req): Json<OpenAiAudioGenerationRequest>| async move {
+                openai_api.generate(req).await
+            }),
+        )
+        // gRPC requests are served in the same port, through HTTP/2.
+        .route_service("/musicgpt.v1.MusicGpt/*rpc", MusicGptServer::new(grpc_api));
+    // Only the routes require authorization, the web app itself is always served.
+    let require_api_key = opts.require_api_key;
+    app = app.route_layer(middleware::from_fn(move |req, next| {
+        authorize(api_keys.clone(), url_signer.clone(), require_api_key, req, next)
+    }));
+    app = match opts.web_dir {
+        Some(web_dir) => {
+            let index = web_dir.join("index.html");
+            if !index.is_file() {
+                return Err(anyhow!("{web_dir:?} does not contain an index.html file"));
+            }
+            // Unknown paths serve the index.html, so that client side routing works.
+            app.fallback_service(ServeDir::new(web_dir).fallback(ServeFile::new(index)))
+        }
+        None => app.fallback(get(web_app)),
+    };
+    app = app.layer(middleware::from_fn(move |req, next| {
+        remember_api_key(cookie_keys.clone(), req, next)
+    }));
+    // Log every request along with its status and latency. The query is left out, as it
+    // may contain an API key.
+    app = app.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(|req: &Request| {
+                let (method, path) = (req.method(), req.uri().path());
+                tracing::info_span!("request", %method, %path)
+            })
+            .on_response(
+                DefaultOnResponse::new()
+                    .level(Level::INFO)
+                    .latency_unit(LatencyUnit::Millis),
+            ),
+    );
+    if !opts.cors_origins.is_empty() {
+        app = app.layer(cors_layer(&opts.cors_origins)?);
+    }
+
+    if let Some(socket) = opts.socket {
+        info!("MusicGPT running at {socket:?}");
+        return serve_unix(&socket, app, close).await;
+    }
 
     let port = opts.port;
-    let host = if opts.expose { "0.0.0.0" } else { "127.0.0.1" };
-    let advertised = if opts.expose {
-        hostname::get()
+    let host = opts.host;
+    let advertised = match host.as_str() {
+        "0.0.0.0" | "::" => hostname::get()
             .unwrap_or_default()
             .to_str()
             .unwrap_or("localhost")
-            .to_string()
-    } else {
-        "localhost".to_string()
+            .to_string(),
+        "127.0.0.1" | "::1" => "localhost".to_string(),
+        // IPv6 addresses need to be wrapped in brackets for being used in URLs.
+        _ if host.contains(':') => format!("[{host}]"),
+        _ => host.clone(),
     };
-    let listener = tokio::net::TcpListener::bind(format!("{host}:{port}")).await?;
+    let listener = tokio::net::TcpListener::bind((host.as_str(), port as u16)).await?;
     let addr = format!("http://{advertised}:{port}");
     info!("MusicGPT running at {addr}");
     if opts.auto_open {
         let _ = open::that(addr);
     }
 
-    Ok(axum::serve(listener, app).await?)
+    Ok(axum::serve(listener, app)
+        .with_graceful_shutdown(close.cancelled_owned())
+        .await?)
+}
+
+/// Serves the app in a Unix domain socket until `close` is cancelled. axum can only
+/// serve TCP listeners, so connections are handed to hyper manually.
+#[cfg(unix)]
+async fn serve_unix(path: &Path, app: Router, close: CancellationToken) -> anyhow::Result<()> {
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto;
+    use hyper_util::service::TowerToHyperService;
+
+    // A socket left behind by a previous run would make binding fail.
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    let tracker = TaskTracker::new();
+    loop {
+        let stream = tokio::select! {
+            conn = listener.accept() => conn?.0,
+            _ = close.cancelled() => break,
+        };
+        let service = TowerToHyperService::new(app.clone());
+        let close = close.clone();
+        tracker.spawn(async move {
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(conn);
+            let res = tokio::select! {
+                res = conn.as_mut() => res,
+                _ = close.cancelled() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(err) = res {
+                error!(%err, "Error serving connection");
+            }
+        });
+    }
+    tracker.close();
+    tracker.wait().await;
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_: &Path, _: Router, _: CancellationToken) -> anyhow::Result<()> {
+    Err(anyhow!(
+        "Unix domain sockets are not supported in this platform"
+    ))
+}
+
+fn cors_layer(origins: &[String]) -> anyhow::Result<CorsLayer> {
+    let allow_origin = if origins.iter().any(|v| v == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|v| v.parse())
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(Any)
+        .allow_headers(Any))
 }
 
 async fn web_app() -> Html<&'static str> {
@@ -85,254 +455,183 @@ async fn web_app() -> Html<&'static str> {
 
 #[cfg(test)]
 mod tests {
-    use async_trait::async_trait;
-    use futures_util::{SinkExt, StreamExt};
-    use serde::de::DeserializeOwned;
-    use serde::Serialize;
-    use std::sync::atomic::{AtomicU16, Ordering};
-    use tokio::net::TcpStream;
-    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
-    use uuid::Uuid;
-
-    #[cfg(not(target_os = "macos"))]
-    use crate::backend::music_gpt_ws_handler::AbortGenerationRequest;
-    #[cfg(not(target_os = "macos"))]
     use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+    use uuid::Uuid;
 
     use super::*;
-    use crate::backend::_test_utils::DummyJobProcessor;
-    use crate::backend::music_gpt_chat::{AiChatEntry, ChatEntry, UserChatEntry};
-    use crate::backend::music_gpt_ws_handler::{
-        ChatRequest, GenerateAudioRequest, InboundMsg, OutboundMsg,
+    use crate::backend::_test_utils::{
+        spawn, spawn_server, spawn_with_storage, test_options, DummyJobProcessor, TungsteniteMsg,
     };
+    use crate::backend::audio_generation_fanout::GenerationMessage;
+    use crate::backend::generation_metadata::GenerationMetadata;
+    use crate::backend::music_gpt_ws_handler::{GenerateAudioRequest, InboundMsg, OutboundMsg};
     use crate::storage::AppFs;
 
     #[tokio::test]
-    async fn sending_a_job_processes_it() -> anyhow::Result<()> {
-        let (mut ws, host) = spawn(DummyJobProcessor::default()).await?;
+    async fn exports_generated_audios() -> anyhow::Result<()> {
+        let export_dir = AppFs::new_tmp().root;
+        let export = AudioExport {
+            dir: export_dir.clone(),
+            file_name: "{prompt}".to_string(),
+            metadata: true,
+        };
+        let (mut ws, _) = spawn_server(DummyJobProcessor::default(), AppFs::new_tmp(), |opts| {
+            opts.export = Some(export)
+        })
+        .await?;
 
-        let id = Uuid::new_v4();
-        let chat_id = Uuid::new_v4();
         InboundMsg::GenerateAudio(GenerateAudioRequest {
-            id,
-            chat_id,
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
             prompt: "Create a cool song".to_string(),
             secs: 4,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
         })
         .to_ws(&mut ws)
         .await?;
-
-        OutboundMsg::from_ws(&mut ws).await?.info();
-        OutboundMsg::from_ws(&mut ws).await?.chats();
-        OutboundMsg::from_ws(&mut ws).await?.start();
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
-        assert_eq!(p.id, id);
-        assert_eq!(p.chat_id, chat_id);
-        assert_eq!(p.progress, 0.25);
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
-        assert_eq!(p.progress, 0.5);
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
-        assert_eq!(p.progress, 0.75);
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
-        assert_eq!(p.progress, 1.0);
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.result();
-        assert_eq!(p.id, id);
-        assert_eq!(p.chat_id, chat_id);
-        assert_eq!(p.relpath, format!("audios/{id}.wav"));
-
-        let res = reqwest::get(format!("http://{host}/files/audios/{id}.wav")).await?;
-        assert_eq!(res.status(), 200);
-
+        loop {
+            if let OutboundMsg::Generation(GenerationMessage::Result(_)) =
+                OutboundMsg::from_ws(&mut ws).await?
+            {
+                break;
+            }
+        }
+        let exported = tokio::fs::read(export_dir.join("Create_a_cool_song.wav")).await?;
+        assert!(exported.starts_with(b"RIFF"));
+        let metadata = tokio::fs::read(export_dir.join("Create_a_cool_song.json")).await?;
+        let metadata: GenerationMetadata = serde_json::from_slice(&metadata)?;
+        assert_eq!(metadata.prompt, "Create a cool song");
+        assert_eq!(metadata.model, "dummy");
+        assert_eq!(metadata.secs, 4);
         Ok(())
     }
 
     // TODO: for some reason this test fails in CI with a timeout.
-    #[cfg(not(target_os = "macos"))]
+    #[cfg(unix)]
     #[tokio::test]
-    async fn can_abort_a_job() -> anyhow::Result<()> {
-        let (mut ws, _) = spawn(DummyJobProcessor::new(Duration::from_millis(200))).await?;
+    async fn serves_in_unix_socket() -> anyhow::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-        let id = Uuid::new_v4();
-        let chat_id = Uuid::new_v4();
-        InboundMsg::GenerateAudio(GenerateAudioRequest {
-            id,
-            chat_id,
-            prompt: "Create a cool song".to_string(),
-            secs: 4,
-        })
-        .to_ws(&mut ws)
-        .await?;
-
-        tokio::time::sleep(Duration::from_millis(50)).await;
+        let app_fs = AppFs::new_tmp();
+        std::fs::create_dir_all(&app_fs.root)?;
+        let socket = app_fs.root.join("musicgpt.sock");
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(run_web_server(
+            app_fs.root.clone(),
+            app_fs,
+            DummyJobProcessor::default(),
+            RunWebServerOptions {
+                shutdown: shutdown.clone(),
+                socket: Some(socket.clone()),
+                ..test_options(0)
+            },
+        ));
 
-        InboundMsg::AbortGeneration(AbortGenerationRequest { id, chat_id })
-            .to_ws(&mut ws)
+        // The server needs some time for booting, so retry the connection a few times.
+        let mut retries = 0;
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&socket).await {
+                Ok(stream) => break stream,
+                Err(_) if retries < 50 => retries += 1,
+                Err(err) => return Err(err.into()),
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
             .await?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await?;
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
 
-        OutboundMsg::from_ws(&mut ws).await?.info();
-        OutboundMsg::from_ws(&mut ws).await?.chats();
-        OutboundMsg::from_ws(&mut ws).await?.start();
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
-        assert_eq!(p.id, id);
-        assert_eq!(p.chat_id, chat_id);
-        assert_eq!(p.progress, 0.25);
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.error();
-        assert_eq!(p.id, id);
-        assert_eq!(p.chat_id, chat_id);
-        assert_eq!(p.error, "Aborted");
+        shutdown.cancel();
+        server.await??;
+        assert!(!socket.exists());
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn handles_job_failures() -> anyhow::Result<()> {
-        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
-
-        let id = Uuid::new_v4();
-        let chat_id = Uuid::new_v4();
-        InboundMsg::GenerateAudio(GenerateAudioRequest {
-            id,
-            chat_id,
-            prompt: "fail at 2".to_string(),
-            secs: 4,
+    async fn serves_web_app_from_disk() -> anyhow::Result<()> {
+        let web_dir = AppFs::new_tmp().root;
+        std::fs::create_dir_all(&web_dir)?;
+        std::fs::write(web_dir.join("index.html"), "<html>custom</html>")?;
+        std::fs::write(web_dir.join("app.js"), "console.log('custom')")?;
+        let web_dir_clone = web_dir.clone();
+        let (_, host) = spawn_server(DummyJobProcessor::default(), AppFs::new_tmp(), |opts| {
+            opts.web_dir = Some(web_dir_clone)
         })
-        .to_ws(&mut ws)
         .await?;
 
-        OutboundMsg::from_ws(&mut ws).await?.info();
-        OutboundMsg::from_ws(&mut ws).await?.chats();
-        OutboundMsg::from_ws(&mut ws).await?.start();
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
-        assert_eq!(p.id, id);
-        assert_eq!(p.chat_id, chat_id);
-        assert_eq!(p.progress, 0.25);
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
-        assert_eq!(p.progress, 0.5);
-
-        let p = OutboundMsg::from_ws(&mut ws).await?.error();
-        assert_eq!(p.id, id);
-        assert_eq!(p.chat_id, chat_id);
-        assert_eq!(p.error, "Failed at 2");
+        let res = reqwest::get(format!("http://{host}/app.js")).await?;
+        assert_eq!(res.text().await?, "console.log('custom')");
+        // Unknown paths are handled by the web app's router.
+        let res = reqwest::get(format!("http://{host}/chats/some-chat")).await?;
+        assert_eq!(res.text().await?, "<html>custom</html>");
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn handles_chats() -> anyhow::Result<()> {
-        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
-
-        OutboundMsg::from_ws(&mut ws).await?.info();
-        OutboundMsg::from_ws(&mut ws).await?.chats();
-
-        let id = Uuid::new_v4();
-        let chat_id = Uuid::new_v4();
-        InboundMsg::GenerateAudioNewChat(GenerateAudioRequest {
-            id,
-            chat_id,
-            prompt: "foo".to_string(),
-            secs: 1,
-        })
-        .to_ws(&mut ws)
-        .await?;
-        OutboundMsg::from_ws(&mut ws).await?.chats();
-
-        OutboundMsg::from_ws(&mut ws).await?.start();
-        OutboundMsg::from_ws(&mut ws).await?.progress();
-        OutboundMsg::from_ws(&mut ws).await?.result();
-
-        InboundMsg::GetChat(ChatRequest { chat_id })
-            .to_ws(&mut ws)
+    async fn allows_configured_cors_origins() -> anyhow::Result<()> {
+        let (_ws, host) = spawn(DummyJobProcessor::default()).await?;
+        let client = reqwest::Client::new();
+        let url = format!("http://{host}/v1/audio/generations");
+
+        let res = client
+            .request(reqwest::Method::OPTIONS, &url)
+            .header("origin", "http://allowed.example")
+            .header("access-control-request-method", "POST")
+            .send()
             .await?;
-
-        let (chat, entries) = OutboundMsg::from_ws(&mut ws).await?.chat();
-        assert_eq!(chat.chat_id, chat_id);
-        assert_eq!(chat.name, "foo");
-        assert_eq!(entries.len(), 2);
-
-        assert_eq!(
-            entries[0],
-            ChatEntry::User(UserChatEntry {
-                id,
-                chat_id,
-                text: "foo".to_string(),
-            })
-        );
-
-        assert_eq!(
-            entries[1],
-            ChatEntry::Ai(AiChatEntry {
-                id,
-                chat_id,
-                relpath: format!("audios/{id}.wav"),
-                error: "".to_string(),
-            })
-        );
+        let allowed = res.headers().get("access-control-allow-origin");
+        assert_eq!(allowed.unwrap(), "http://allowed.example");
+
+        let res = client
+            .request(reqwest::Method::OPTIONS, &url)
+            .header("origin", "http://other.example")
+            .header("access-control-request-method", "POST")
+            .send()
+            .await?;
+        assert!(res.headers().get("access-control-allow-origin").is_none());
 
         Ok(())
     }
 
-    #[async_trait]
-    trait TungsteniteMsg: Sized {
-        async fn to_ws(
-            self,
-            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-        ) -> anyhow::Result<()>;
-
-        async fn from_ws(
-            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-        ) -> anyhow::Result<Self>;
-    }
-
-    #[async_trait]
-    impl<T: Serialize + DeserializeOwned + Send> TungsteniteMsg for T {
-        async fn to_ws(
-            self,
-            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-        ) -> anyhow::Result<()> {
-            let msg = serde_json::to_string(&self).expect("Could not serialize msg");
-            Ok(ws
-                .send(tokio_tungstenite::tungstenite::Message::Text(msg))
-                .await?)
-        }
-
-        async fn from_ws(
-            ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-        ) -> anyhow::Result<Self> {
-            let msg = ws.next().await.unwrap()?;
-            Ok(serde_json::de::from_str(msg.to_text()?)?)
-        }
-    }
+    #[tokio::test]
+    async fn recovers_pending_jobs() -> anyhow::Result<()> {
+        let app_fs = AppFs::new_tmp();
+        let (id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        PersistedJob::new(chat_id, id, "foo".to_string(), 1)
+            .save(&app_fs)
+            .await?;
 
-    static PORT: AtomicU16 = AtomicU16::new(8643);
+        let processor = DummyJobProcessor::new(std::time::Duration::from_millis(200));
+        let (mut ws, _) = spawn_with_storage(processor, app_fs.clone()).await?;
 
-    async fn spawn<P: JobProcessor + 'static>(
-        processor: P,
-    ) -> anyhow::Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, String)> {
-        let app_fs = AppFs::new_tmp();
-        let port = PORT.fetch_add(1, Ordering::SeqCst) as usize;
-        let run_options = RunWebServerOptions {
-            name: "Dummy".to_string(),
-            device: "Cpu".to_string(),
-            port,
-            auto_open: false,
-            expose: false,
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        let OutboundMsg::RecoveredJobs(jobs) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("msg was not OutboundMsg::RecoveredJobs")
         };
-        tokio::spawn(run_web_server(
-            app_fs.root.clone(),
-            app_fs,
-            processor,
-            run_options,
-        ));
-        let (ws_stream, _) = connect_async(&format!("ws://localhost:{port}/ws")).await?;
-        Ok((ws_stream, format!("localhost:{port}")))
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id, id);
+
+        let p = loop {
+            if let OutboundMsg::Generation(GenerationMessage::Result(p)) =
+                OutboundMsg::from_ws(&mut ws).await?
+            {
+                break p;
+            }
+        };
+        assert_eq!(p.id, id);
+        assert_eq!(p.chat_id, chat_id);
+        assert!(PersistedJob::load_all(&app_fs).await?.is_empty());
+
+        Ok(())
     }
 }
@@ -0,0 +1,224 @@
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::backend::audio_generation_backend::{AudioGenerationBackend, BackendInboundMsg};
+use crate::backend::audio_generation_fanout::GenerationMessage;
+use crate::backend::music_gpt_ws_handler::IdPair;
+use crate::backend::presence::Presence;
+use crate::backend::replay_buffer::ReplayBuffer;
+use crate::backend::scheduler::ScheduledJob;
+use crate::storage::Storage;
+
+/// Cancels `shutdown` once there have been no websocket clients, no queued jobs and no
+/// scheduled jobs for `timeout`, so that the models stop taking memory while unused.
+pub async fn shutdown_when_idle<S: Storage>(
+    storage: S,
+    backend: AudioGenerationBackend,
+    presence: Presence,
+    timeout: Duration,
+    shutdown: CancellationToken,
+) {
+    let interval = (timeout / 4).min(Duration::from_secs(10));
+    let mut idle_since = Instant::now();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown.cancelled() => return,
+        }
+        let no_scheduled_jobs = match ScheduledJob::load_all(&storage).await {
+            Ok(jobs) => jobs.is_empty(),
+            Err(_) => false,
+        };
+        let idle = presence.is_empty() && backend.queue_len() == 0 && no_scheduled_jobs;
+        if !idle {
+            idle_since = Instant::now();
+        } else if idle_since.elapsed() >= timeout {
+            info!("MusicGPT has been idle for {timeout:?}, shutting down");
+            shutdown.cancel();
+            return;
+        }
+    }
+}
+
+/// Aborts all the jobs on Ctrl+C, or cancels `shutdown` if there are none, so that pressing
+/// it twice always shuts down.
+pub async fn abort_on_ctrl_c(
+    backend: AudioGenerationBackend,
+    ai_tx: Sender<BackendInboundMsg>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => if res.is_err() {
+                return;
+            },
+            _ = shutdown.cancelled() => return,
+        }
+        if backend.queue_len() == 0 {
+            info!("Shutting down MusicGPT");
+            shutdown.cancel();
+            return;
+        }
+        info!("Aborting all the jobs, press Ctrl+C again for shutting down");
+        let _ = ai_tx.send(BackendInboundMsg::AbortAll);
+    }
+}
+
+/// Stops the backend from taking new jobs, and waits for the ones being processed to
+/// finish and to be saved, aborting them if they take longer than `grace`.
+pub async fn drain_backend(
+    backend: &AudioGenerationBackend,
+    replay: &ReplayBuffer,
+    grace: Duration,
+) {
+    // Subscribe before draining, so that the jobs' end is not missed. Messages are
+    // awaited in the same channel the websocket clients listen to, so that they also
+    // receive the jobs' end before the connections are closed.
+    let mut rx = replay.subscribe();
+    let mut ids = backend
+        .drain()
+        .into_iter()
+        .map(|id| IdPair::from(id).1)
+        .collect::<Vec<_>>();
+    if ids.is_empty() {
+        return;
+    }
+    info!("Shutting down, waiting for {} job(s) to finish", ids.len());
+    if tokio::time::timeout(grace, wait_for_jobs(&mut rx, &mut ids))
+        .await
+        .is_err()
+    {
+        info!("{} job(s) did not finish in time, aborting them", ids.len());
+        backend.abort_all();
+        let _ = tokio::time::timeout(grace, wait_for_jobs(&mut rx, &mut ids)).await;
+    }
+}
+
+/// Waits until all the jobs in `ids` finished, removing them from it as they do.
+async fn wait_for_jobs(
+    rx: &mut tokio::sync::broadcast::Receiver<(u64, GenerationMessage)>,
+    ids: &mut Vec<Uuid>,
+) {
+    while !ids.is_empty() {
+        let id = match rx.recv().await {
+            Ok((_, GenerationMessage::Result(res))) => res.id,
+            Ok((_, GenerationMessage::Error(err))) => err.id,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+            _ => continue,
+        };
+        ids.retain(|v| *v != id);
+    }
+}
+
+/// Returns a token that gets cancelled when the process receives SIGTERM. Ctrl+C is
+/// handled by the server, see [crate::backend::RunWebServerOptions::abort_on_ctrl_c].
+pub fn shutdown_on_signal() -> CancellationToken {
+    let token = CancellationToken::new();
+    #[cfg(unix)]
+    {
+        let token = token.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen to SIGTERM");
+            sigterm.recv().await;
+            info!("Shutting down MusicGPT");
+            token.cancel();
+        });
+    }
+    token
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_util::sync::CancellationToken;
+    use uuid::Uuid;
+
+    use crate::backend::_test_utils::{spawn_server, DummyJobProcessor, TungsteniteMsg};
+    use crate::backend::audio_generation_fanout::GenerationMessage;
+    use crate::backend::music_gpt_ws_handler::{GenerateAudioRequest, InboundMsg, OutboundMsg};
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn drains_the_active_job_on_shutdown() -> anyhow::Result<()> {
+        let processor = DummyJobProcessor::new(Duration::from_millis(50));
+        let shutdown = CancellationToken::new();
+        let (mut ws, _) = spawn_server(processor, AppFs::new_tmp(), |opts| {
+            opts.shutdown = shutdown.clone()
+        })
+        .await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        let req = GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: 4,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        };
+        InboundMsg::GenerateAudio(req.clone())
+            .to_ws(&mut ws)
+            .await?;
+        OutboundMsg::from_ws(&mut ws).await?.start();
+
+        shutdown.cancel();
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            ..req
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        let mut rejected = false;
+        let result = loop {
+            match OutboundMsg::from_ws(&mut ws).await? {
+                OutboundMsg::Error(_) => rejected = true,
+                OutboundMsg::Generation(GenerationMessage::Result(p)) => break p,
+                _ => continue,
+            }
+        };
+        assert!(rejected);
+        assert_eq!(result.id, id);
+
+        let Some(Ok(Message::Close(Some(frame)))) = ws.next().await else {
+            panic!("websocket was not closed with a close frame")
+        };
+        assert_eq!(frame.code, CloseCode::Away);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shuts_down_when_idle() -> anyhow::Result<()> {
+        let shutdown = CancellationToken::new();
+        let (mut ws, _) = spawn_server(DummyJobProcessor::default(), AppFs::new_tmp(), |opts| {
+            opts.shutdown = shutdown.clone();
+            opts.idle_timeout = Some(Duration::from_millis(200));
+        })
+        .await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+
+        // Connected clients keep the server alive.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!shutdown.is_cancelled());
+
+        ws.close(None).await?;
+        tokio::time::timeout(Duration::from_secs(3), shutdown.cancelled()).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1040 @@
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use axum::extract::WebSocketUpgrade;
+use axum::response::Response;
+use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::backend::music_gpt_ws_handler::{
+    check_protocol_version, MusicGptWsHandler, OutboundMsg,
+};
+use crate::backend::ws_handler::{WsEncoding, WsHandler};
+use crate::storage::Storage;
+
+#[derive(Deserialize)]
+pub struct WsParams {
+    /// The protocol version spoken by the client.
+    protocol: Option<u32>,
+    /// The session to resume, or to start with this id if it's unknown.
+    session: Option<Uuid>,
+    /// How messages are serialized, JSON by default.
+    #[serde(default)]
+    encoding: WsEncoding,
+    /// The name shown to other clients, like in the jobs requested by this one.
+    name: Option<String>,
+}
+
+/// Tells the client why it cannot connect, and closes the websocket.
+async fn reject_ws(mut ws: WebSocket, error: String) {
+    let msg = serde_json::to_string(&OutboundMsg::Error(error)).expect("Could not serialize msg");
+    let _ = ws.send(Message::Text(msg)).await;
+    let close = CloseFrame {
+        code: close_code::PROTOCOL,
+        reason: "Unsupported protocol version".into(),
+    };
+    let _ = ws.send(Message::Close(Some(close))).await;
+}
+
+/// Upgrades the connection to a websocket served by `ws_handler`, or rejects it if the
+/// client speaks an unsupported protocol version.
+pub fn upgrade<S: Storage + 'static>(
+    mut ws_handler: MusicGptWsHandler<S>,
+    close: CancellationToken,
+    ws: WebSocketUpgrade,
+    params: WsParams,
+) -> Response {
+    ws_handler.info.session_id = params.session.unwrap_or_else(Uuid::new_v4);
+    ws_handler.encoding = params.encoding;
+    ws_handler.client_name = params.name;
+    match check_protocol_version(params.protocol) {
+        Ok(()) => ws.on_upgrade(move |ws| async move {
+            let _presence = ws_handler.presence.join(ws_handler.client());
+            ws_handler.handle(ws, close).await;
+        }),
+        Err(err) => ws.on_upgrade(move |ws| reject_ws(ws, err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use base64::Engine;
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::Duration;
+    use tokio_tungstenite::connect_async;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+    use uuid::Uuid;
+
+    #[cfg(not(target_os = "macos"))]
+    use crate::backend::music_gpt_ws_handler::AbortGenerationRequest;
+
+    use crate::backend::_test_utils::{
+        next_clients, spawn, spawn_server, spawn_with_storage, DummyJobProcessor, TungsteniteMsg,
+    };
+    use crate::backend::audio_generation_fanout::GenerationMessage;
+    use crate::backend::generation_limits::GenerationLimits;
+    use crate::backend::msgpack;
+    use crate::backend::music_gpt_chat::{AiChatEntry, Chat, ChatEntry, UserChatEntry};
+    use crate::backend::music_gpt_ws_handler::{
+        ChatRequest, ChatsRequest, ComparisonSide, EntryRequest, GenerateAudioRequest,
+        GenerateComparisonRequest, InboundMsg, JobState, OutboundMsg, RateEntryRequest,
+        RegenerateRequest, ScheduleJobRequest, ScheduledJobRequest, SetEntryMetadataRequest,
+        UploadReferenceRequest, PROTOCOL_VERSION,
+    };
+    use crate::backend::presence::Client;
+    use crate::backend::scheduler::Schedule;
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn sending_a_job_processes_it() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::default()).await?;
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: 4,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        OutboundMsg::from_ws(&mut ws).await?.start();
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
+        assert_eq!(p.id, id);
+        assert_eq!(p.chat_id, chat_id);
+        assert_eq!(p.progress, 0.25);
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
+        assert_eq!(p.progress, 0.5);
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
+        assert_eq!(p.progress, 0.75);
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
+        assert_eq!(p.progress, 1.0);
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.result();
+        assert_eq!(p.id, id);
+        assert_eq!(p.chat_id, chat_id);
+        assert_eq!(p.relpath, format!("audios/{id}.wav"));
+
+        let res = reqwest::get(format!("http://{host}/files/audios/{id}.wav")).await?;
+        assert_eq!(res.status(), 200);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn attributes_jobs_to_clients() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let (mut alice, _) = connect_async(&format!("ws://{host}/ws?name=alice")).await?;
+        let info = OutboundMsg::from_ws(&mut alice).await?.info();
+        let alice_client = Client {
+            session_id: info.session_id,
+            name: Some("alice".to_string()),
+        };
+        // The connection opened by spawn() may not have been dropped yet.
+        while next_clients(&mut alice).await? != vec![alice_client.clone()] {}
+
+        let (mut bob, _) = connect_async(&format!("ws://{host}/ws?name=bob")).await?;
+        let clients = next_clients(&mut alice).await?;
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[1].name.as_deref(), Some("bob"));
+
+        let id = Uuid::new_v4();
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id: Uuid::new_v4(),
+            prompt: "Create a cool song".to_string(),
+            secs: 4,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut alice)
+        .await?;
+        let start = loop {
+            if let OutboundMsg::Generation(GenerationMessage::Start(start)) =
+                OutboundMsg::from_ws(&mut bob).await?
+            {
+                break start;
+            }
+        };
+        assert_eq!(start.requested_by, Some(alice_client.clone()));
+        let result = loop {
+            if let OutboundMsg::Generation(GenerationMessage::Result(result)) =
+                OutboundMsg::from_ws(&mut bob).await?
+            {
+                break result;
+            }
+        };
+        assert_eq!(result.id, id);
+        assert_eq!(result.requested_by, Some(alice_client.clone()));
+
+        bob.close(None).await?;
+        assert_eq!(next_clients(&mut alice).await?, vec![alice_client]);
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[tokio::test]
+    async fn can_abort_a_job() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::new(Duration::from_millis(200))).await?;
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: 4,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        InboundMsg::AbortGeneration(AbortGenerationRequest { id, chat_id })
+            .to_ws(&mut ws)
+            .await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        OutboundMsg::from_ws(&mut ws).await?.start();
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
+        assert_eq!(p.id, id);
+        assert_eq!(p.chat_id, chat_id);
+        assert_eq!(p.progress, 0.25);
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.error();
+        assert_eq!(p.id, id);
+        assert_eq!(p.chat_id, chat_id);
+        assert_eq!(p.error, "Aborted");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_abort_all_jobs() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::new(Duration::from_millis(200))).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let chat_id = Uuid::new_v4();
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for id in ids {
+            InboundMsg::GenerateAudio(GenerateAudioRequest {
+                id,
+                chat_id,
+                prompt: "Create a cool song".to_string(),
+                secs: 4,
+                reference_id: None,
+                model: None,
+                dedupe: None,
+                seed: None,
+            })
+            .to_ws(&mut ws)
+            .await?;
+        }
+        InboundMsg::AbortAll.to_ws(&mut ws).await?;
+
+        let mut aborted = vec![];
+        while aborted.len() < ids.len() {
+            match OutboundMsg::from_ws(&mut ws).await? {
+                OutboundMsg::Generation(GenerationMessage::Error(err)) => {
+                    assert_eq!(err.error, "Aborted");
+                    aborted.push(err.id)
+                }
+                OutboundMsg::Generation(GenerationMessage::Result(_)) => {
+                    panic!("a job was not aborted")
+                }
+                _ => {}
+            }
+        }
+        assert!(ids.iter().all(|id| aborted.contains(id)));
+
+        // Both prompts are kept in the chat, even the one that never started.
+        InboundMsg::GetChat(ChatRequest { chat_id })
+            .to_ws(&mut ws)
+            .await?;
+        let entries = loop {
+            if let OutboundMsg::Chat((_, entries)) = OutboundMsg::from_ws(&mut ws).await? {
+                break entries;
+            }
+        };
+        let prompts = entries
+            .iter()
+            .filter(|entry| matches!(entry, ChatEntry::User(_)))
+            .count();
+        assert_eq!(prompts, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn negotiates_protocol_version() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::default()).await?;
+        let info = OutboundMsg::from_ws(&mut ws).await?.info();
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+
+        let url = format!("ws://{host}/ws?protocol={PROTOCOL_VERSION}");
+        let (mut ws, _) = connect_async(&url).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+
+        let (mut ws, _) = connect_async(&format!("ws://{host}/ws?protocol=999")).await?;
+        let OutboundMsg::Error(err) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("msg was not OutboundMsg::Error")
+        };
+        assert!(err.contains("Unsupported protocol version 999"));
+        let Some(Ok(Message::Close(Some(frame)))) = ws.next().await else {
+            panic!("websocket was not closed with a close frame")
+        };
+        assert_eq!(frame.code, CloseCode::Protocol);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn speaks_msgpack() -> anyhow::Result<()> {
+        let (_, host) = spawn(DummyJobProcessor::default()).await?;
+        let url = format!("ws://{host}/ws?encoding=msgpack");
+        let (mut ws, _) = connect_async(&url).await?;
+        let Some(Ok(Message::Binary(bytes))) = ws.next().await else {
+            panic!("msg was not binary")
+        };
+        let info = msgpack::from_slice::<OutboundMsg>(&bytes)?.info();
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+
+        let msg = msgpack::to_vec(&InboundMsg::GetQueue)?;
+        ws.send(Message::Binary(msg)).await?;
+        let jobs = loop {
+            let Some(Ok(Message::Binary(bytes))) = ws.next().await else {
+                panic!("msg was not binary")
+            };
+            if let OutboundMsg::Queue(jobs) = msgpack::from_slice(&bytes)? {
+                break jobs;
+            }
+        };
+        assert!(jobs.is_empty());
+
+        // JSON text messages are still accepted.
+        InboundMsg::GetQueue.to_ws(&mut ws).await?;
+        let Some(Ok(Message::Binary(_))) = ws.next().await else {
+            panic!("msg was not binary")
+        };
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handles_job_failures() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "fail at 2".to_string(),
+            secs: 4,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        OutboundMsg::from_ws(&mut ws).await?.start();
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
+        assert_eq!(p.id, id);
+        assert_eq!(p.chat_id, chat_id);
+        assert_eq!(p.progress, 0.25);
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.progress();
+        assert_eq!(p.progress, 0.5);
+
+        let p = OutboundMsg::from_ws(&mut ws).await?.error();
+        assert_eq!(p.id, id);
+        assert_eq!(p.chat_id, chat_id);
+        assert_eq!(p.error, "Failed at 2");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn regenerates_generations() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let (id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: 3,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: Some(7),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        loop {
+            if let OutboundMsg::Generation(GenerationMessage::Result(result)) =
+                OutboundMsg::from_ws(&mut ws).await?
+            {
+                assert_eq!(result.stats.seed, Some(7));
+                break;
+            }
+        }
+
+        let new_id = Uuid::new_v4();
+        InboundMsg::Regenerate(RegenerateRequest {
+            id: new_id,
+            chat_id,
+            entry_id: id,
+            secs: None,
+            new_seed: Some(8),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let start = OutboundMsg::from_ws(&mut ws).await?.start();
+        assert_eq!((start.id, start.chat_id), (new_id, chat_id));
+        assert_eq!(start.prompt, "Create a cool song");
+        assert_eq!(start.secs, 3);
+        loop {
+            if let OutboundMsg::Generation(GenerationMessage::Result(result)) =
+                OutboundMsg::from_ws(&mut ws).await?
+            {
+                assert_eq!(result.id, new_id);
+                assert_eq!(result.stats.seed, Some(8));
+                break;
+            }
+        }
+
+        InboundMsg::Regenerate(RegenerateRequest {
+            id: Uuid::new_v4(),
+            chat_id,
+            entry_id: Uuid::new_v4(),
+            secs: Some(2),
+            new_seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        loop {
+            if let OutboundMsg::Error(err) = OutboundMsg::from_ws(&mut ws).await? {
+                assert!(err.contains("does not exist"), "{err}");
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn generates_comparisons() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let (a, b, chat_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        InboundMsg::GenerateComparison(GenerateComparisonRequest {
+            chat_id,
+            new_chat: true,
+            prompt: "Create a cool song".to_string(),
+            a: ComparisonSide {
+                id: a,
+                secs: 2,
+                model: None,
+            },
+            b: ComparisonSide {
+                id: b,
+                secs: 3,
+                model: Some("dummy".to_string()),
+            },
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let mut results = vec![];
+        while results.len() < 2 {
+            match OutboundMsg::from_ws(&mut ws).await? {
+                OutboundMsg::Chats(page) => assert_eq!(page.chats[0].name, "Create a cool song"),
+                OutboundMsg::Generation(GenerationMessage::Result(result)) => {
+                    results.push(result.id)
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(results, vec![a, b]);
+
+        InboundMsg::GetChat(ChatRequest { chat_id })
+            .to_ws(&mut ws)
+            .await?;
+        let (_, entries) = OutboundMsg::from_ws(&mut ws).await?.chat();
+        let mut links = entries
+            .into_iter()
+            .filter_map(|entry| match entry {
+                ChatEntry::Ai(entry) => Some((entry.id, entry.compared_with)),
+                ChatEntry::User(_) => None,
+            })
+            .collect::<Vec<_>>();
+        links.sort();
+        let mut expected = vec![(a, Some(b)), (b, Some(a))];
+        expected.sort();
+        assert_eq!(links, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn enforces_generation_limits() -> anyhow::Result<()> {
+        let processor = DummyJobProcessor::new(Duration::from_millis(200));
+        let (mut ws, host) = spawn_server(processor, AppFs::new_tmp(), |opts| {
+            opts.limits = GenerationLimits {
+                max_secs: 2,
+                max_queued_jobs: Some(1),
+                allowed_models: vec!["small".to_string()],
+                chat_quota: None,
+            }
+        })
+        .await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let req = |secs| GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
+            prompt: "foo".to_string(),
+            secs,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        };
+        InboundMsg::GenerateAudio(req(3)).to_ws(&mut ws).await?;
+        let msg = OutboundMsg::from_ws(&mut ws).await?;
+        assert!(matches!(msg, OutboundMsg::Error(v) if v == "secs must be between 1 and 2"));
+
+        InboundMsg::GenerateAudio(req(2)).to_ws(&mut ws).await?;
+        OutboundMsg::from_ws(&mut ws).await?.start();
+        InboundMsg::GenerateAudio(req(1)).to_ws(&mut ws).await?;
+        let msg = OutboundMsg::from_ws(&mut ws).await?;
+        assert!(matches!(msg, OutboundMsg::Error(v) if v.contains("jobs queued")));
+
+        let res = reqwest::Client::new()
+            .post(format!("http://{host}/v1/audio/generations"))
+            .header("content-type", "application/json")
+            .body(r#"{ "prompt": "foo", "model": "large" }"#)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn validates_the_requested_model() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::default()).await?;
+        let info = OutboundMsg::from_ws(&mut ws).await?.info();
+        assert_eq!(info.model_id, "dummy");
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let req = |model: &str| GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
+            prompt: "foo".to_string(),
+            secs: 1,
+            reference_id: None,
+            model: Some(model.to_string()),
+            dedupe: None,
+            seed: None,
+        };
+        InboundMsg::GenerateAudio(req("large"))
+            .to_ws(&mut ws)
+            .await?;
+        let msg = OutboundMsg::from_ws(&mut ws).await?;
+        assert!(matches!(msg, OutboundMsg::Error(v) if v.contains("is not available")));
+        InboundMsg::GenerateAudio(req("dummy"))
+            .to_ws(&mut ws)
+            .await?;
+        OutboundMsg::from_ws(&mut ws).await?.start();
+
+        let res = reqwest::Client::new()
+            .post(format!("http://{host}/v1/audio/generations"))
+            .header("content-type", "application/json")
+            .body(r#"{ "prompt": "foo", "model": "Dummy", "duration": 1 }"#)
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conditions_generations_on_reference_audios() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default().with_reference()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        let wav = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test.wav"))?;
+
+        InboundMsg::UploadReference(UploadReferenceRequest {
+            format: "wav".to_string(),
+            data: base64::engine::general_purpose::STANDARD.encode(&wav),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let OutboundMsg::ReferenceUploaded(reference) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("msg was not OutboundMsg::ReferenceUploaded")
+        };
+
+        let req = GenerateAudioRequest {
+            id: Uuid::new_v4(),
+            chat_id: Uuid::new_v4(),
+            prompt: "Continue this song".to_string(),
+            secs: 1,
+            reference_id: Some(Uuid::new_v4()),
+            model: None,
+            dedupe: None,
+            seed: None,
+        };
+        InboundMsg::GenerateAudioNewChat(req.clone())
+            .to_ws(&mut ws)
+            .await?;
+        let msg = OutboundMsg::from_ws(&mut ws).await?;
+        assert!(matches!(msg, OutboundMsg::Error(v) if v.contains("does not exist")));
+
+        let req = GenerateAudioRequest {
+            reference_id: Some(reference.id),
+            ..req
+        };
+        InboundMsg::GenerateAudioNewChat(req.clone())
+            .to_ws(&mut ws)
+            .await?;
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        loop {
+            match OutboundMsg::from_ws(&mut ws).await? {
+                OutboundMsg::Generation(GenerationMessage::Result(result)) => {
+                    assert_eq!(result.id, req.id);
+                    break;
+                }
+                OutboundMsg::Generation(GenerationMessage::Error(err)) => {
+                    panic!("the generation failed: {}", err.error)
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn schedules_jobs() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        InboundMsg::ScheduleJob(ScheduleJobRequest {
+            prompt: "Nightly song".to_string(),
+            secs: 1,
+            schedule: Schedule::Cron("0 3 * * *".to_string()),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let OutboundMsg::ScheduledJobs(jobs) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("msg was not OutboundMsg::ScheduledJobs")
+        };
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].prompt, "Nightly song");
+
+        InboundMsg::ScheduleJob(ScheduleJobRequest {
+            prompt: "Broken".to_string(),
+            secs: 1,
+            schedule: Schedule::Cron("0 3 * *".to_string()),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let msg = OutboundMsg::from_ws(&mut ws).await?;
+        assert!(matches!(msg, OutboundMsg::Error(_)));
+
+        InboundMsg::CancelScheduledJob(ScheduledJobRequest { id: jobs[0].id })
+            .to_ws(&mut ws)
+            .await?;
+        let OutboundMsg::ScheduledJobs(jobs) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("msg was not OutboundMsg::ScheduledJobs")
+        };
+        assert_eq!(jobs, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handles_chats() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GenerateAudioNewChat(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "foo".to_string(),
+            secs: 1,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        OutboundMsg::from_ws(&mut ws).await?.start();
+        OutboundMsg::from_ws(&mut ws).await?.progress();
+        OutboundMsg::from_ws(&mut ws).await?.result();
+
+        InboundMsg::GetChat(ChatRequest { chat_id })
+            .to_ws(&mut ws)
+            .await?;
+
+        let (chat, mut entries) = OutboundMsg::from_ws(&mut ws).await?.chat();
+        assert_eq!(chat.chat_id, chat_id);
+        assert_eq!(chat.name, "foo");
+        assert_eq!(entries.len(), 2);
+        if let ChatEntry::Ai(entry) = &mut entries[1] {
+            let stats = entry.stats.take().expect("the generation has no stats");
+            assert_eq!(stats.device, "Cpu");
+            assert!(stats.wall_secs > 0.0);
+        }
+
+        assert_eq!(
+            entries[0],
+            ChatEntry::User(UserChatEntry {
+                id,
+                chat_id,
+                text: "foo".to_string(),
+                enhanced_prompt: None,
+            })
+        );
+
+        assert_eq!(
+            entries[1],
+            ChatEntry::Ai(AiChatEntry {
+                id,
+                chat_id,
+                relpath: format!("audios/{id}.wav"),
+                error: "".to_string(),
+                favorite: false,
+                rating: None,
+                title: None,
+                note: None,
+                compared_with: None,
+                stats: None,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn updates_generations() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GenerateAudioNewChat(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "foo".to_string(),
+            secs: 1,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+        OutboundMsg::from_ws(&mut ws).await?.start();
+        OutboundMsg::from_ws(&mut ws).await?.progress();
+        OutboundMsg::from_ws(&mut ws).await?.result();
+
+        InboundMsg::RateEntry(RateEntryRequest {
+            chat_id,
+            id,
+            favorite: Some(true),
+            rating: Some(3),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let (_, entries) = OutboundMsg::from_ws(&mut ws).await?.chat();
+        let ChatEntry::Ai(entry) = &entries[1] else {
+            panic!("entry was not ChatEntry::Ai")
+        };
+        assert!(entry.favorite);
+        assert_eq!(entry.rating, Some(3));
+
+        InboundMsg::RateEntry(RateEntryRequest {
+            chat_id,
+            id,
+            favorite: None,
+            rating: Some(0),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let msg = OutboundMsg::from_ws(&mut ws).await?;
+        assert!(matches!(msg, OutboundMsg::Error(_)));
+
+        InboundMsg::GetFavorites.to_ws(&mut ws).await?;
+        let OutboundMsg::Favorites(favorites) = OutboundMsg::from_ws(&mut ws).await? else {
+            panic!("msg was not OutboundMsg::Favorites")
+        };
+        assert_eq!(favorites, vec![entry.clone()]);
+
+        InboundMsg::SetEntryMetadata(SetEntryMetadataRequest {
+            chat_id,
+            id,
+            title: Some("bar".to_string()),
+            note: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let (_, entries) = OutboundMsg::from_ws(&mut ws).await?.chat();
+        let ChatEntry::Ai(entry) = &entries[1] else {
+            panic!("entry was not ChatEntry::Ai")
+        };
+        assert_eq!(entry.title, Some("bar".to_string()));
+
+        InboundMsg::DelEntry(EntryRequest { chat_id, id })
+            .to_ws(&mut ws)
+            .await?;
+        let (_, entries) = OutboundMsg::from_ws(&mut ws).await?.chat();
+        assert_eq!(entries, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streams_partial_audio() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::default()).await?;
+
+        let id = Uuid::new_v4();
+        let chat_id = Uuid::new_v4();
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id,
+            prompt: "Create a cool song".to_string(),
+            secs: 2,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+
+        let mut samples = vec![];
+        while samples.len() < 2 {
+            let Message::Binary(bytes) = ws.next().await.unwrap()? else {
+                continue;
+            };
+            assert_eq!(&bytes[..16], id.as_bytes());
+            assert_eq!(&bytes[16..32], chat_id.as_bytes());
+            assert_eq!(&bytes[32..36], &32000u32.to_le_bytes());
+            assert_eq!(&bytes[36..40], &(samples.len() as u32).to_le_bytes());
+            for sample in bytes[40..].chunks(4) {
+                samples.push(f32::from_le_bytes(sample.try_into()?))
+            }
+        }
+        assert_eq!(samples, vec![0.0, 1.0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumes_sessions() -> anyhow::Result<()> {
+        let (mut ws, host) = spawn(DummyJobProcessor::default()).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let session = Uuid::new_v4();
+        let url = format!("ws://{host}/ws?session={session}");
+        let (mut resumed, _) = connect_async(&url).await?;
+        assert_eq!(
+            OutboundMsg::from_ws(&mut resumed).await?.info().session_id,
+            session
+        );
+        OutboundMsg::from_ws(&mut resumed).await?.chats();
+        // Wait for the server to close the connection on its side.
+        resumed.close(None).await?;
+        while resumed.next().await.is_some() {}
+
+        let id = Uuid::new_v4();
+        InboundMsg::GenerateAudio(GenerateAudioRequest {
+            id,
+            chat_id: Uuid::new_v4(),
+            prompt: "Create a cool song".to_string(),
+            secs: 1,
+            reference_id: None,
+            model: None,
+            dedupe: None,
+            seed: None,
+        })
+        .to_ws(&mut ws)
+        .await?;
+        loop {
+            if let OutboundMsg::Generation(GenerationMessage::Result(_)) =
+                OutboundMsg::from_ws(&mut ws).await?
+            {
+                break;
+            }
+        }
+
+        // The messages sent while disconnected are replayed after reconnecting.
+        let (mut resumed, _) = connect_async(&url).await?;
+        OutboundMsg::from_ws(&mut resumed).await?.info();
+        OutboundMsg::from_ws(&mut resumed).await?.chats();
+        assert_eq!(OutboundMsg::from_ws(&mut resumed).await?.start().id, id);
+        assert_eq!(OutboundMsg::from_ws(&mut resumed).await?.progress().id, id);
+        assert_eq!(OutboundMsg::from_ws(&mut resumed).await?.result().id, id);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closes_unresponsive_websockets() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn_server(DummyJobProcessor::default(), AppFs::new_tmp(), |opts| {
+            opts.ws_ping_interval = Duration::from_millis(50)
+        })
+        .await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        // Reading the pings answers them, which keeps the connection alive.
+        let mut pings = 0;
+        while pings < 3 {
+            if let Some(Ok(Message::Ping(_))) = ws.next().await {
+                pings += 1
+            }
+        }
+
+        // Not reading makes the client stop answering, so the server closes the connection.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let res = tokio::time::timeout(Duration::from_secs(1), async {
+            while let Some(Ok(_)) = ws.next().await {}
+        })
+        .await;
+        assert!(res.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lists_the_queue() -> anyhow::Result<()> {
+        let (mut ws, _) = spawn(DummyJobProcessor::new(Duration::from_millis(200))).await?;
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        OutboundMsg::from_ws(&mut ws).await?.chats();
+
+        let chat_id = Uuid::new_v4();
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for id in ids {
+            InboundMsg::GenerateAudio(GenerateAudioRequest {
+                id,
+                chat_id,
+                prompt: "Create a cool song".to_string(),
+                secs: 2,
+                reference_id: None,
+                model: None,
+                dedupe: None,
+                seed: None,
+            })
+            .to_ws(&mut ws)
+            .await?;
+        }
+        OutboundMsg::from_ws(&mut ws).await?.start();
+
+        InboundMsg::GetQueue.to_ws(&mut ws).await?;
+        let jobs = loop {
+            if let OutboundMsg::Queue(jobs) = OutboundMsg::from_ws(&mut ws).await? {
+                break jobs;
+            }
+        };
+        let states = jobs
+            .iter()
+            .map(|v| (v.id, v.state.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            vec![(ids[0], JobState::Running), (ids[1], JobState::Pending)]
+        );
+        assert_eq!(jobs[0].chat_id, chat_id);
+        assert_eq!(jobs[0].prompt, "Create a cool song");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paginates_chats() -> anyhow::Result<()> {
+        let app_fs = AppFs::new_tmp();
+        for i in 0..3 {
+            let chat = Chat {
+                chat_id: Uuid::new_v4(),
+                name: format!("chat {i}"),
+                created_at: i,
+            };
+            chat.save(&app_fs).await?;
+        }
+        let (mut ws, _) = spawn_with_storage(DummyJobProcessor::default(), app_fs).await?;
+
+        OutboundMsg::from_ws(&mut ws).await?.info();
+        let first = OutboundMsg::from_ws(&mut ws).await?.chats_page();
+        assert_eq!(first.chats.len(), 3);
+        assert_eq!(first.next_cursor, None);
+
+        InboundMsg::GetChats(ChatsRequest {
+            cursor: None,
+            limit: Some(2),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let page = OutboundMsg::from_ws(&mut ws).await?.chats_page();
+        let names: Vec<_> = page.chats.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, vec!["chat 2", "chat 1"]);
+        assert_eq!(page.next_cursor.map(|v| v.created_at), Some(1));
+
+        InboundMsg::GetChats(ChatsRequest {
+            cursor: page.next_cursor,
+            limit: Some(2),
+        })
+        .to_ws(&mut ws)
+        .await?;
+        let page = OutboundMsg::from_ws(&mut ws).await?.chats_page();
+        let names: Vec<_> = page.chats.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, vec!["chat 0"]);
+        assert_eq!(page.cursor.map(|v| v.created_at), Some(1));
+        assert_eq!(page.next_cursor, None);
+
+        Ok(())
+    }
+}
@@ -1,12 +1,59 @@
 use std::fmt::Display;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
 use futures_util::{pin_mut, SinkExt, StreamExt};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::backend::msgpack;
+
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How messages are serialized in the websocket, negotiated by the client when connecting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WsEncoding {
+    /// Messages are JSON text frames, and raw binary data is sent as is.
+    #[default]
+    Json,
+    /// Messages are MessagePack binary frames, and raw binary data is sent as MessagePack
+    /// bin values, so that every frame can be decoded the same way.
+    MsgPack,
+}
+
+impl WsEncoding {
+    fn encode(self, msg: &impl Serialize) -> Message {
+        match self {
+            WsEncoding::Json => {
+                Message::Text(serde_json::to_string(msg).expect("Could not serialize msg"))
+            }
+            WsEncoding::MsgPack => {
+                Message::Binary(msgpack::to_vec(msg).expect("Could not serialize msg"))
+            }
+        }
+    }
+
+    fn encode_binary(self, bytes: Vec<u8>) -> Message {
+        match self {
+            WsEncoding::Json => Message::Binary(bytes),
+            WsEncoding::MsgPack => Message::Binary(msgpack::bin(&bytes)),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> anyhow::Result<T> {
+        match self {
+            WsEncoding::Json => Ok(serde_json::from_slice(bytes)?),
+            WsEncoding::MsgPack => msgpack::from_slice(bytes),
+        }
+    }
+}
 
 #[async_trait]
 pub trait WsHandler: Sized {
@@ -16,18 +63,32 @@ pub trait WsHandler: Sized {
     async fn handle_init(&self) -> Vec<Self::Outbound>;
     async fn handle_inbound_msg(&self, msg: Self::Inbound) -> Option<Self::Outbound>;
     fn handle_subscription(&self) -> impl StreamExt<Item = Self::Outbound> + Send + 'static;
+    /// Messages sent to the client as raw binary data instead of serialized.
+    fn handle_binary_subscription(&self) -> impl StreamExt<Item = Vec<u8>> + Send + 'static {
+        futures_util::stream::empty()
+    }
     async fn handle_error(&self, _: impl Display + Send) -> Option<Self::Outbound>;
+    /// How often the client is pinged. Connections from which nothing is received, not
+    /// even a pong, for two intervals are considered dead and closed.
+    fn ping_interval(&self) -> Duration {
+        DEFAULT_PING_INTERVAL
+    }
+    fn encoding(&self) -> WsEncoding {
+        WsEncoding::Json
+    }
 
-    async fn handle(self, ws: WebSocket) {
+    /// Handles the websocket until the client closes it, or until `shutdown` is cancelled,
+    /// in which case the websocket is closed with a proper close frame.
+    async fn handle(self, ws: WebSocket, shutdown: CancellationToken) {
         let (tx, mut rx) = ws.split();
         let tx = Arc::new(Mutex::new(tx));
+        let encoding = self.encoding();
 
         // Initialization messages.
         {
             let mut tx = tx.lock().await;
             for msg in self.handle_init().await {
-                let msg = serde_json::to_string(&msg).expect("Could not serialize msg");
-                let _ = tx.send(Message::Text(msg)).await;
+                let _ = tx.send(encoding.encode(&msg)).await;
             }
             // <- drop tx
         }
@@ -38,16 +99,56 @@ pub trait WsHandler: Sized {
         let task = tokio::spawn(async move {
             pin_mut!(subscription);
             while let Some(msg) = subscription.next().await {
-                let msg = serde_json::to_string(&msg).expect("Could not serialize msg");
-                let _ = tx_clone.lock().await.send(Message::Text(msg)).await;
+                let _ = tx_clone.lock().await.send(encoding.encode(&msg)).await;
+            }
+        });
+        let tx_clone = tx.clone();
+        let binary_subscription = self.handle_binary_subscription();
+        let binary_task = tokio::spawn(async move {
+            pin_mut!(binary_subscription);
+            while let Some(msg) = binary_subscription.next().await {
+                let _ = tx_clone
+                    .lock()
+                    .await
+                    .send(encoding.encode_binary(msg))
+                    .await;
             }
         });
 
         // Inbound messages.
-        while let Some(Ok(msg)) = rx.next().await {
+        let ping_interval = self.ping_interval();
+        let mut ping = tokio::time::interval_at(Instant::now() + ping_interval, ping_interval);
+        let mut last_seen = Instant::now();
+        loop {
+            let msg = tokio::select! {
+                msg = rx.next() => msg,
+                _ = ping.tick() => {
+                    if last_seen.elapsed() >= ping_interval * 2 {
+                        info!("Closing websocket that stopped responding");
+                        break;
+                    }
+                    if tx.lock().await.send(Message::Ping(vec![])).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                _ = shutdown.cancelled() => {
+                    let close = CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "Server shutting down".into(),
+                    };
+                    let _ = tx.lock().await.send(Message::Close(Some(close))).await;
+                    break;
+                }
+            };
+            let Some(Ok(msg)) = msg else {
+                break;
+            };
+            last_seen = Instant::now();
             let msg = match msg {
-                Message::Text(text) => serde_json::from_str(&text),
-                Message::Binary(bin) => serde_json::from_slice(&bin),
+                // Text frames are always JSON, so that clients can still send them.
+                Message::Text(text) => WsEncoding::Json.decode(text.as_bytes()),
+                Message::Binary(bin) => encoding.decode(&bin),
                 Message::Close(_) => break,
                 _ => continue,
             };
@@ -57,12 +158,12 @@ pub trait WsHandler: Sized {
             };
             if let Some(response) = maybe_response {
                 let mut tx = tx.lock().await;
-                let msg = serde_json::to_string(&response).expect("Could not serialize msg");
-                let _ = tx.send(Message::Text(msg)).await;
+                let _ = tx.send(encoding.encode(&response)).await;
                 // <- drop tx
             }
         }
         // TODO: use a cancellation token?
-        task.abort()
+        task.abort();
+        binary_task.abort()
     }
 }
@@ -0,0 +1,99 @@
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::logging;
+
+/// What MusicGPT was doing, written to the debug bundles along with the panic.
+#[derive(Clone, Default, Serialize)]
+struct Context {
+    model: Option<String>,
+    device: Option<String>,
+    last_request: Option<LastRequest>,
+}
+
+/// The parameters of the last generation, without the audio.
+#[derive(Clone, Serialize)]
+struct LastRequest {
+    prompt: String,
+    secs: usize,
+}
+
+#[derive(Serialize)]
+struct DebugBundle {
+    version: &'static str,
+    os: &'static str,
+    arch: &'static str,
+    panic: String,
+    #[serde(flatten)]
+    context: Context,
+    recent_logs: Vec<String>,
+}
+
+static CONTEXT: Mutex<Context> = Mutex::new(Context {
+    model: None,
+    device: None,
+    last_request: None,
+});
+
+/// Records the model and the device in which it runs, once loaded.
+pub fn set_model(model: impl Into<String>, device: impl Into<String>) {
+    let mut context = CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+    context.model = Some(model.into());
+    context.device = Some(device.into());
+}
+
+/// Records the parameters of a generation that is about to start.
+pub fn set_last_request(prompt: &str, secs: usize) {
+    let mut context = CONTEXT.lock().unwrap_or_else(|e| e.into_inner());
+    context.last_request = Some(LastRequest {
+        prompt: prompt.to_string(),
+        secs,
+    });
+}
+
+/// Makes panics write a debug bundle in `dir` before the usual panic message, and print its
+/// path so that it can be attached to bug reports.
+pub fn install_panic_hook(dir: PathBuf) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        match write(&dir, info) {
+            Ok(path) => eprintln!(
+                "MusicGPT crashed, please attach {} when reporting the bug at \
+                https://github.com/gabotechs/MusicGPT/issues",
+                path.display()
+            ),
+            Err(err) => {
+                eprintln!("MusicGPT crashed, and the debug bundle failed to be written: {err}")
+            }
+        }
+        default_hook(info)
+    }));
+}
+
+fn write(dir: &Path, info: &PanicHookInfo) -> std::io::Result<PathBuf> {
+    // The panic might have happened while holding the lock.
+    let context = match CONTEXT.try_lock() {
+        Ok(context) => context.clone(),
+        Err(_) => Context::default(),
+    };
+    let bundle = DebugBundle {
+        version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        panic: info.to_string(),
+        context,
+        recent_logs: logging::recent_lines(),
+    };
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("crash-{secs}.json"));
+    std::fs::write(&path, serde_json::to_vec_pretty(&bundle)?)?;
+    Ok(path)
+}
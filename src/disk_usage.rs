@@ -0,0 +1,175 @@
+use std::cmp::Reverse;
+
+use clap::ValueEnum;
+
+use crate::model_cache::MODELS_DIR;
+use crate::storage::Storage;
+
+/// The kinds of data that MusicGPT keeps in its data dir.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Category {
+    /// The downloaded AI models, by variant.
+    Models,
+    /// The downloaded onnxruntime dynamic libraries, by version.
+    Dynlibs,
+    /// The history of the chats in the web app.
+    Chats,
+    /// The generated audios.
+    Audios,
+    /// The reference audios uploaded in the web app.
+    References,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Models,
+        Category::Dynlibs,
+        Category::Chats,
+        Category::Audios,
+        Category::References,
+    ];
+
+    pub fn dir(self) -> &'static str {
+        match self {
+            Category::Models => MODELS_DIR,
+            Category::Dynlibs => "dynlibs",
+            Category::Chats => "chats",
+            Category::Audios => "audios",
+            Category::References => "references",
+        }
+    }
+
+    /// Whether the usage is broken down by each of the category's subdirectories.
+    fn by_subdir(self) -> bool {
+        matches!(self, Category::Models | Category::Dynlibs)
+    }
+}
+
+/// The bytes taken by `path`, including everything inside it if it's a directory.
+pub async fn size<S: Storage>(storage: &S, path: &str) -> anyhow::Result<u64> {
+    let mut size = 0;
+    let mut pending = vec![path.to_string()];
+    while let Some(path) = pending.pop() {
+        let Some(metadata) = storage.metadata(&path).await? else {
+            continue;
+        };
+        if metadata.is_dir {
+            pending.extend(storage.list(&path).await?)
+        } else {
+            size += metadata.size
+        }
+    }
+    Ok(size)
+}
+
+pub struct CategoryUsage {
+    pub total: u64,
+    /// The usage of each subdirectory, for the categories that are broken down.
+    pub entries: Vec<(String, u64)>,
+}
+
+pub async fn usage<S: Storage>(storage: &S, category: Category) -> anyhow::Result<CategoryUsage> {
+    let mut entries = vec![];
+    if category.by_subdir() {
+        for path in storage.list(category.dir()).await? {
+            let name = path.rsplit('/').next().unwrap_or_default().to_string();
+            entries.push((name, size(storage, &path).await?))
+        }
+        entries.sort_by_key(|(_, size)| Reverse(*size));
+    }
+    Ok(CategoryUsage {
+        total: size(storage, category.dir()).await?,
+        entries,
+    })
+}
+
+/// Removes everything in the category, returning the freed bytes.
+pub async fn prune<S: Storage>(storage: &S, category: Category) -> anyhow::Result<u64> {
+    let freed = size(storage, category.dir()).await?;
+    storage.rm_rf(category.dir()).await?;
+    Ok(freed)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = "";
+    for u in UNITS {
+        value /= 1000.0;
+        unit = u;
+        if value < 1000.0 {
+            break;
+        }
+    }
+    format!("{value:.1} {unit}")
+}
+
+/// Parses an amount of bytes in the same units as [format_bytes], like "1.5 GB".
+pub fn parse_bytes(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value.parse().ok()?;
+    let multiplier = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => 1e0,
+        "K" | "KB" => 1e3,
+        "M" | "MB" => 1e6,
+        "G" | "GB" => 1e9,
+        "T" | "TB" => 1e12,
+        _ => return None,
+    };
+    Some((value * multiplier) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn reports_usage_by_category() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        storage.write("v1/small_fp32/model.onnx", [0; 300]).await?;
+        storage.write("v1/small/config.json", [0; 10]).await?;
+        storage.write("chats/a/metadata.json", [0; 20]).await?;
+        storage.write("chats/b/metadata.json", [0; 20]).await?;
+
+        let models = usage(&storage, Category::Models).await?;
+        assert_eq!(models.total, 310);
+        assert_eq!(
+            models.entries,
+            vec![("small_fp32".to_string(), 300), ("small".to_string(), 10)]
+        );
+        let chats = usage(&storage, Category::Chats).await?;
+        assert_eq!(chats.total, 40);
+        assert!(chats.entries.is_empty());
+        assert_eq!(usage(&storage, Category::Audios).await?.total, 0);
+
+        assert_eq!(prune(&storage, Category::Chats).await?, 40);
+        assert_eq!(usage(&storage, Category::Chats).await?.total, 0);
+        assert!(storage.exists("v1/small/config.json").await?);
+        Ok(())
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(999), "999 B");
+        assert_eq!(format_bytes(1_500), "1.5 KB");
+        assert_eq!(format_bytes(23_400_000_000), "23.4 GB");
+    }
+
+    #[test]
+    fn parses_bytes() {
+        assert_eq!(parse_bytes("999"), Some(999));
+        assert_eq!(parse_bytes("5MB"), Some(5_000_000));
+        assert_eq!(parse_bytes("1.5 kb"), Some(1_500));
+        assert_eq!(parse_bytes("2G"), Some(2_000_000_000));
+        assert_eq!(parse_bytes("5 MiB"), None);
+        assert_eq!(parse_bytes("MB"), None);
+    }
+}
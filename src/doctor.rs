@@ -0,0 +1,250 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::audio::AudioManager;
+use crate::cli::Model;
+use crate::gpu::{self, EpOptions};
+use crate::onnxruntime_lib;
+use crate::storage::Storage;
+use crate::storage_ext::{NetworkOptions, StorageExt};
+
+/// The result of one of the checks of `musicgpt doctor`, with what was found if it passed
+/// or with the problem and how to fix it if it failed.
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Result<String, Failure>,
+}
+
+pub struct Failure {
+    pub problem: String,
+    pub fix: String,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Ok(detail.into()),
+        }
+    }
+
+    pub fn fail(name: &'static str, problem: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            outcome: Err(Failure {
+                problem: problem.into(),
+                fix: fix.into(),
+            }),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.outcome.is_ok()
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            Ok(detail) => write!(f, "[ok]   {}: {detail}", self.name),
+            Err(failure) => write!(
+                f,
+                "[fail] {}: {}\n       fix: {}",
+                self.name, failure.problem, failure.fix
+            ),
+        }
+    }
+}
+
+/// Checks that files can be written in the data dir.
+pub async fn data_dir<S: Storage>(storage: &S) -> Check {
+    const NAME: &str = "data dir";
+    const FILE: &str = "doctor.tmp";
+    let dir = storage.path_buf("");
+    match storage.write(FILE, b"ok").await {
+        Ok(()) => {
+            let _ = storage.rm(FILE).await;
+            Check::ok(NAME, format!("{} is writable", dir.display()))
+        }
+        Err(err) => Check::fail(
+            NAME,
+            format!("cannot write in {}: {err}", dir.display()),
+            "fix the permissions of the directory, or use another one with --data-dir",
+        ),
+    }
+}
+
+/// Checks that the files of the model are downloaded and not corrupted.
+pub async fn model_files<S: Storage>(
+    storage: &S,
+    model: Model,
+    files: &[(String, &'static str)],
+) -> Check {
+    const NAME: &str = "model files";
+    const READ_FIX: &str = "fix the permissions of the models dir";
+    let mut missing = vec![];
+    let mut corrupted = vec![];
+    for (_, local) in files {
+        match storage.exists(local).await {
+            Ok(true) => {}
+            Ok(false) => {
+                missing.push(*local);
+                continue;
+            }
+            Err(err) => return Check::fail(NAME, format!("cannot read {local}: {err}"), READ_FIX),
+        }
+        match storage.verify_file(local).await {
+            Ok(true) => {}
+            Ok(false) => corrupted.push(*local),
+            Err(err) => return Check::fail(NAME, format!("cannot read {local}: {err}"), READ_FIX),
+        }
+    }
+    if !corrupted.is_empty() {
+        return Check::fail(
+            NAME,
+            format!("corrupted files: {}", corrupted.join(", ")),
+            "run MusicGPT with --verify-models for downloading them again",
+        );
+    }
+    if !missing.is_empty() {
+        return Check::fail(
+            NAME,
+            format!("{model} is not downloaded, missing {}", missing.join(", ")),
+            "run MusicGPT once with internet access, or import the files with `musicgpt models import`",
+        );
+    }
+    Check::ok(NAME, format!("{model} is downloaded"))
+}
+
+/// Checks that the onnxruntime dynamic library is found and loads. The GPU can only be
+/// checked after it passes.
+pub async fn onnxruntime<S: Storage>(
+    storage: S,
+    network: &NetworkOptions,
+    dynlib: Option<&Path>,
+) -> Check {
+    const NAME: &str = "onnxruntime";
+    let result = match onnxruntime_lib::init::init(storage, network, dynlib).await {
+        Ok(builder) => builder.commit().map_err(anyhow::Error::from),
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(_) => Check::ok(NAME, "the dynamic library loads"),
+        Err(err) => Check::fail(
+            NAME,
+            err.to_string(),
+            "check the internet connection, or point --onnxruntime-lib to an onnxruntime library",
+        ),
+    }
+}
+
+/// Checks that the GPU execution providers MusicGPT was compiled with load, and that the
+/// model fits in the GPU.
+pub fn gpu(options: &EpOptions, model: Model) -> Check {
+    const NAME: &str = "gpu";
+    let compiled = [
+        ("TensorRT", cfg!(feature = "tensorrt")),
+        ("CUDA", cfg!(feature = "cuda")),
+        ("DirectML", cfg!(feature = "directml")),
+        ("CoreML", cfg!(feature = "coreml")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect::<Vec<_>>();
+    if compiled.is_empty() {
+        return Check::ok(
+            NAME,
+            "compiled without GPU support, inference runs in the CPU",
+        );
+    }
+    let device = match gpu::detect_gpu(options) {
+        Ok(Some((device, _))) => device,
+        Ok(None) => {
+            return Check::fail(
+                NAME,
+                format!("none of {} could be loaded", compiled.join(", ")),
+                "install the GPU drivers and the libraries the providers need, like CUDA and \
+                cuDNN, or run with --accelerator cpu",
+            )
+        }
+        Err(err) => return Check::fail(NAME, err.to_string(), "fix the --ort-ep-option values"),
+    };
+    match gpu::free_vram(device, options).map(|free| gpu::check_vram(model, free)) {
+        Some(Err(err)) => Check::fail(NAME, err.to_string(), "pick a model that fits with --model"),
+        _ => Check::ok(NAME, format!("{device} is available")),
+    }
+}
+
+/// Checks that there is an audio output device for playing the generated audios.
+pub fn audio_output(audio_manager: anyhow::Result<AudioManager>) -> Check {
+    const NAME: &str = "audio output";
+    const FIX: &str = "connect an audio device or pick another host with --audio-host, or run \
+        with --no-playback";
+    match audio_manager.and_then(|v| v.output_device_name()) {
+        Ok(name) => Check::ok(NAME, format!("playing audios in {name}")),
+        Err(err) => Check::fail(NAME, err.to_string(), FIX),
+    }
+}
+
+/// Checks that the host of the models can be reached, given the url of one of its files.
+pub async fn network(url: &str) -> Check {
+    const NAME: &str = "network";
+    let result = reqwest::Client::new()
+        .head(url)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    match result {
+        Ok(_) => Check::ok(NAME, format!("{url} is reachable")),
+        Err(err) => Check::fail(
+            NAME,
+            format!("cannot reach {url}: {err}"),
+            "check the internet connection and the HTTPS_PROXY variable, or download the models \
+            from a mirror with --model-mirror",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[tokio::test]
+    async fn checks_data_dir_and_model_files() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        assert!(data_dir(&storage).await.passed());
+        assert!(!storage.exists("doctor.tmp").await?);
+
+        let files = [
+            ("".to_string(), "v1/small/config.json"),
+            ("".to_string(), "v1/small_fp32/text_encoder.onnx"),
+        ];
+        let check = model_files(&storage, Model::Small, &files).await;
+        let text = check.to_string();
+        assert!(text.starts_with("[fail] model files: "), "{text}");
+        assert!(
+            text.contains("missing v1/small/config.json, v1/small_fp32"),
+            "{text}"
+        );
+        assert!(text.contains("\n       fix: "), "{text}");
+
+        storage.write(files[0].1, "{}").await?;
+        storage.write(files[1].1, "onnx").await?;
+        assert!(model_files(&storage, Model::Small, &files).await.passed());
+
+        storage
+            .write("v1/small_fp32/text_encoder.onnx.sha256", "0000")
+            .await?;
+        let text = model_files(&storage, Model::Small, &files)
+            .await
+            .to_string();
+        assert!(
+            text.contains("corrupted files: v1/small_fp32/text_encoder.onnx"),
+            "{text}"
+        );
+        Ok(())
+    }
+}
@@ -1,46 +1,382 @@
+use std::fmt::Display;
+use std::process::Command;
+use std::str::FromStr;
+
 use anyhow::anyhow;
-use log::{error, info};
+use clap::ValueEnum;
+use log::{info, warn};
 use ort::execution_providers::{
-    CUDAExecutionProvider, CoreMLExecutionProvider, ExecutionProvider, ExecutionProviderDispatch,
-    TensorRTExecutionProvider,
+    CUDAExecutionProvider, CoreMLExecutionProvider, DirectMLExecutionProvider, ExecutionProvider,
+    ExecutionProviderDispatch, OpenVINOExecutionProvider, TensorRTExecutionProvider,
 };
+use ort::session::builder::SessionBuilder;
+use musicgpt_core::ModelPart;
 use ort::session::Session;
 
-pub fn init_gpu() -> anyhow::Result<(&'static str, ExecutionProviderDispatch)> {
+use crate::cli::Model;
+use crate::disk_usage::format_bytes;
+
+/// Options of the execution providers, named like in ONNX Runtime's docs, for example
+/// gpu_mem_limit=4000000000 for CUDA. Each provider uses the ones it supports.
+#[derive(Clone, Debug, Default)]
+pub struct EpOptions(Vec<(String, String)>);
+
+const EP_OPTIONS: [&str; 11] = [
+    "device_id",
+    "gpu_mem_limit",
+    "cudnn_conv_use_max_workspace",
+    "use_tf32",
+    "trt_max_workspace_size",
+    "trt_fp16_enable",
+    "trt_engine_cache_enable",
+    "trt_engine_cache_path",
+    "device_type",
+    "num_of_threads",
+    "MLComputeUnits",
+];
+
+impl EpOptions {
+    /// Parses key=value pairs, failing with the unknown keys.
+    pub fn parse(options: &[String]) -> anyhow::Result<Self> {
+        let mut result = vec![];
+        for option in options {
+            let Some((key, value)) = option.split_once('=') else {
+                return Err(anyhow!(
+                    "Invalid execution provider option {option}, expected key=value"
+                ));
+            };
+            if !EP_OPTIONS.contains(&key) {
+                return Err(anyhow!(
+                    "Unknown execution provider option {key}, supported ones are {}",
+                    EP_OPTIONS.join(", ")
+                ));
+            }
+            result.push((key.to_string(), value.to_string()));
+        }
+        Ok(Self(result))
+    }
+
+    fn get<T: FromStr>(&self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T::Err: Display,
+    {
+        match self.0.iter().rev().find(|(k, _)| k == key) {
+            Some((_, value)) => match value.parse() {
+                Ok(v) => Ok(Some(v)),
+                Err(err) => Err(anyhow!("Invalid value {value} for {key}: {err}")),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn tensorrt(&self) -> anyhow::Result<TensorRTExecutionProvider> {
+        let mut provider = TensorRTExecutionProvider::default();
+        if let Some(v) = self.get("device_id")? {
+            provider = provider.with_device_id(v);
+        }
+        if let Some(v) = self.get("trt_max_workspace_size")? {
+            provider = provider.with_max_workspace_size(v);
+        }
+        if let Some(v) = self.get("trt_fp16_enable")? {
+            provider = provider.with_fp16(v);
+        }
+        if let Some(v) = self.get("trt_engine_cache_enable")? {
+            provider = provider.with_engine_cache(v);
+        }
+        if let Some(v) = self.get::<String>("trt_engine_cache_path")? {
+            provider = provider.with_engine_cache_path(v);
+        }
+        Ok(provider)
+    }
+
+    fn cuda(&self) -> anyhow::Result<CUDAExecutionProvider> {
+        let mut provider = CUDAExecutionProvider::default();
+        if let Some(v) = self.get("device_id")? {
+            provider = provider.with_device_id(v);
+        }
+        if let Some(v) = self.get("gpu_mem_limit")? {
+            provider = provider.with_memory_limit(v);
+        }
+        if let Some(v) = self.get("cudnn_conv_use_max_workspace")? {
+            provider = provider.with_conv_max_workspace(v);
+        }
+        if let Some(v) = self.get("use_tf32")? {
+            provider = provider.with_tf32(v);
+        }
+        Ok(provider)
+    }
+
+    fn directml(&self) -> anyhow::Result<DirectMLExecutionProvider> {
+        let mut provider = DirectMLExecutionProvider::default();
+        if let Some(v) = self.get("device_id")? {
+            provider = provider.with_device_id(v);
+        }
+        Ok(provider)
+    }
+
+    fn coreml(&self) -> anyhow::Result<CoreMLExecutionProvider> {
+        let provider = CoreMLExecutionProvider::default();
+        match self.get::<String>("MLComputeUnits")?.as_deref() {
+            None | Some("CPUAndNeuralEngine") => Ok(provider.with_ane_only()),
+            Some("CPUOnly") => Ok(provider.with_cpu_only()),
+            Some("ALL") => Ok(provider),
+            Some(other) => Err(anyhow!(
+                "Invalid value {other} for MLComputeUnits, use CPUOnly, CPUAndNeuralEngine or ALL"
+            )),
+        }
+    }
+
+    fn openvino(&self) -> anyhow::Result<OpenVINOExecutionProvider> {
+        // Runs in the integrated GPU if there's one, and in the CPU otherwise.
+        let device_type = self.get::<String>("device_type")?;
+        let mut provider = OpenVINOExecutionProvider::default()
+            .with_device_type(device_type.as_deref().unwrap_or("AUTO:GPU,CPU"));
+        if let Some(v) = self.get::<String>("device_id")? {
+            provider = provider.with_device_id(v);
+        }
+        if let Some(v) = self.get("num_of_threads")? {
+            provider = provider.with_num_threads(v);
+        }
+        Ok(provider)
+    }
+}
+
+/// Parses part=device_id pairs, like decoder=1, placing parts of the model in other GPUs.
+pub fn parse_device_map(map: &[String]) -> anyhow::Result<Vec<(ModelPart, i32)>> {
+    let mut result = vec![];
+    for entry in map {
+        let Some((part, device_id)) = entry.split_once('=') else {
+            return Err(anyhow!("Invalid device map entry {entry}, expected part=device_id"));
+        };
+        let part = part.parse::<ModelPart>()?;
+        let Ok(device_id) = device_id.parse() else {
+            return Err(anyhow!("Invalid device id {device_id} for {part:?}"));
+        };
+        result.push((part, device_id));
+    }
+    Ok(result)
+}
+
+/// The providers for the parts of the model in `map`, which are the same as the one for
+/// `device` but in other devices.
+pub fn device_map_providers(
+    device: &str,
+    options: &EpOptions,
+    map: &[(ModelPart, i32)],
+) -> anyhow::Result<Vec<(ModelPart, ExecutionProviderDispatch)>> {
+    let mut result = vec![];
+    for (part, device_id) in map {
+        let mut options = options.clone();
+        options.0.push(("device_id".to_string(), device_id.to_string()));
+        let provider = match device {
+            "TensorRT" => options.tensorrt()?.build(),
+            "Cuda" => options.cuda()?.build(),
+            "DirectML" => options.directml()?.build(),
+            _ => return Err(anyhow!("{device} cannot place the models in several devices")),
+        };
+        result.push((*part, provider));
+    }
+    Ok(result)
+}
+
+/// Tries the GPU execution providers that MusicGPT was compiled with, in order of
+/// preference, and returns the first one that loads. None if none of them does.
+pub fn detect_gpu(
+    options: &EpOptions,
+) -> anyhow::Result<Option<(&'static str, ExecutionProviderDispatch)>> {
     let mut dummy_builder = Session::builder()?;
 
     if cfg!(feature = "tensorrt") {
-        let provider = TensorRTExecutionProvider::default();
-        match provider.register(&mut dummy_builder) {
-            Ok(_) => {
-                info!("{} detected", provider.as_str());
-                return Ok(("TensorRT", provider.build()));
-            }
-            Err(err) => error!("Could not load {}: {}", provider.as_str(), err),
+        let provider = options.tensorrt()?;
+        if let Some(v) = try_register(&mut dummy_builder, "TensorRT", provider) {
+            return Ok(Some(v));
         }
     }
     if cfg!(feature = "cuda") {
-        let provider = CUDAExecutionProvider::default();
-        match provider.register(&mut dummy_builder) {
-            Ok(_) => {
-                info!("{} detected", provider.as_str());
-                return Ok(("Cuda", provider.build()));
-            }
-            Err(err) => error!("Could not load {}: {}", provider.as_str(), err),
+        let provider = options.cuda()?;
+        if let Some(v) = try_register(&mut dummy_builder, "Cuda", provider) {
+            return Ok(Some(v));
+        }
+    }
+    if cfg!(feature = "directml") {
+        let provider = options.directml()?;
+        if let Some(v) = try_register(&mut dummy_builder, "DirectML", provider) {
+            return Ok(Some(v));
         }
     }
     if cfg!(feature = "coreml") {
-        let provider = CoreMLExecutionProvider::default().with_ane_only();
-        match provider.register(&mut dummy_builder) {
-            Ok(_) => {
-                info!("{} detected", provider.as_str());
-                return Ok(("CoreML", provider.build()));
-            }
-            Err(err) => error!("Could not load {}: {}", provider.as_str(), err),
+        let provider = options.coreml()?;
+        if let Some(v) = try_register(&mut dummy_builder, "CoreML", provider) {
+            return Ok(Some(v));
+        }
+    }
+    Ok(None)
+}
+
+pub fn init_gpu(options: &EpOptions) -> anyhow::Result<(&'static str, ExecutionProviderDispatch)> {
+    detect_gpu(options)?.ok_or_else(|| {
+        anyhow!(
+            "No hardware accelerator was detected, try running the program with --accelerator cpu"
+        )
+    })
+}
+
+pub fn init_openvino(
+    options: &EpOptions,
+) -> anyhow::Result<(&'static str, ExecutionProviderDispatch)> {
+    if !cfg!(feature = "openvino") {
+        return Err(anyhow!(
+            "MusicGPT was not compiled with OpenVINO support, it needs the `openvino` feature"
+        ));
+    }
+    let mut dummy_builder = Session::builder()?;
+
+    let provider = options.openvino()?;
+    match provider.register(&mut dummy_builder) {
+        Ok(_) => {
+            info!("{} detected", provider.as_str());
+            Ok(("OpenVINO", provider.build()))
+        }
+        Err(err) => Err(anyhow!("Could not load {}: {}", provider.as_str(), err)),
+    }
+}
+
+fn try_register<P>(
+    builder: &mut SessionBuilder,
+    device: &'static str,
+    provider: P,
+) -> Option<(&'static str, ExecutionProviderDispatch)>
+where
+    P: ExecutionProvider + Into<ExecutionProviderDispatch>,
+{
+    match provider.register(builder) {
+        Ok(_) => {
+            info!("{} detected", provider.as_str());
+            Some((device, provider.into()))
+        }
+        Err(err) => {
+            let name = provider.as_str();
+            warn!("Could not load {name}, falling back to the next accelerator: {err}");
+            None
+        }
+    }
+}
+
+/// The free memory of the GPU used by `device`, for the devices in which it can be known.
+/// Apple Silicon GPUs share the memory with the CPU, and how much of it they can take is
+/// not known, so it's never reported for CoreML.
+pub fn free_vram(device: &str, options: &EpOptions) -> Option<u64> {
+    match device {
+        "TensorRT" | "Cuda" => {
+            let device_id = options.get::<usize>("device_id").ok()?.unwrap_or_default();
+            let visible_devices = std::env::var("CUDA_VISIBLE_DEVICES").ok();
+            let id = nvidia_smi_id(device_id, visible_devices.as_deref())?;
+            let output = Command::new("nvidia-smi")
+                .args([
+                    &format!("--id={id}"),
+                    "--query-gpu=memory.free",
+                    "--format=csv,noheader,nounits",
+                ])
+                .output()
+                .ok()?;
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let mib: u64 = stdout.lines().next()?.trim().parse().ok()?;
+            Some(mib * 1024 * 1024)
         }
+        _ => None,
     }
+}
+
+/// The id that nvidia-smi gives to the CUDA device `device_id`. CUDA only numbers the
+/// devices in CUDA_VISIBLE_DEVICES, if set, while nvidia-smi numbers all of them.
+fn nvidia_smi_id(device_id: usize, visible_devices: Option<&str>) -> Option<String> {
+    match visible_devices {
+        Some(devices) => Some(devices.split(',').nth(device_id)?.trim().to_string()),
+        None => Some(device_id.to_string()),
+    }
+}
 
+/// Fails if `model` does not fit in `free_vram` bytes of GPU memory, suggesting the
+/// models that do fit.
+pub fn check_vram(model: Model, free_vram: u64) -> anyhow::Result<()> {
+    if model.required_memory() <= free_vram {
+        return Ok(());
+    }
+    let name = |model: &Model| model.to_possible_value().unwrap().get_name().to_string();
+    let fitting = Model::value_variants()
+        .iter()
+        .filter(|v| v.required_memory() <= free_vram)
+        .collect::<Vec<_>>();
+    let suggestion = match fitting.iter().max_by_key(|v| v.required_memory()) {
+        Some(largest) => format!(
+            "{} fit, try with --model {} or --accelerator cpu",
+            fitting.iter().map(|v| name(v)).collect::<Vec<_>>().join(", "),
+            name(largest),
+        ),
+        None => "no model fits, try with --accelerator cpu".to_string(),
+    };
     Err(anyhow!(
-        "No hardware accelerator was detected, try running the program without the --gpu flag",
+        "{model} needs about {} of GPU memory, but only {} are free: {suggestion}",
+        format_bytes(model.required_memory()),
+        format_bytes(free_vram),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: u64 = 1_000_000_000;
+
+    #[test]
+    fn parses_ep_options() -> anyhow::Result<()> {
+        let options = EpOptions::parse(&[
+            "gpu_mem_limit=4000000000".to_string(),
+            "trt_engine_cache_path=/tmp/a=b".to_string(),
+            "gpu_mem_limit=2000000000".to_string(),
+        ])?;
+        assert_eq!(options.get::<usize>("gpu_mem_limit")?, Some(2000000000));
+        assert_eq!(options.get::<String>("trt_engine_cache_path")?, Some("/tmp/a=b".to_string()));
+        assert_eq!(options.get::<i32>("device_id")?, None);
+
+        let options = EpOptions::parse(&["device_id=first".to_string()])?;
+        assert!(options.get::<i32>("device_id").is_err());
+
+        assert!(EpOptions::parse(&["unknown=1".to_string()]).is_err());
+        assert!(EpOptions::parse(&["device_id".to_string()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_device_maps() -> anyhow::Result<()> {
+        let map = parse_device_map(&["decoder=1".to_string(), "text-encoder=0".to_string()])?;
+        assert_eq!(map, [(ModelPart::Decoder, 1), (ModelPart::TextEncoder, 0)]);
+
+        assert!(parse_device_map(&["decoder".to_string()]).is_err());
+        assert!(parse_device_map(&["vocoder=1".to_string()]).is_err());
+        assert!(parse_device_map(&["decoder=second".to_string()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn maps_cuda_devices_to_nvidia_smi_ids() {
+        assert_eq!(nvidia_smi_id(1, None).as_deref(), Some("1"));
+        assert_eq!(nvidia_smi_id(1, Some("2, 3")).as_deref(), Some("3"));
+        assert_eq!(nvidia_smi_id(2, Some("2,3")), None);
+        assert_eq!(free_vram("CoreML", &EpOptions::default()), None);
+    }
+
+    #[test]
+    fn suggests_models_that_fit_in_vram() {
+        assert!(check_vram(Model::Large, 32 * GB).is_ok());
+        assert!(check_vram(Model::Small, 4 * GB).is_ok());
+
+        let err = check_vram(Model::Large, 6 * GB).unwrap_err().to_string();
+        assert!(err.starts_with("MusicGen Large needs about 16.0 GB"), "{err}");
+        assert!(err.ends_with("try with --model medium-fp16 or --accelerator cpu"), "{err}");
+        assert!(!err.contains(", medium,"), "{err}");
+
+        let err = check_vram(Model::Small, GB / 2).unwrap_err().to_string();
+        assert!(err.ends_with("no model fits, try with --accelerator cpu"), "{err}");
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use tracing_subscriber::fmt::time::UtcTime;
+use tracing_subscriber::{fmt, EnvFilter};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, useful for ingesting the logs of remote deployments.
+    Json,
+}
+
+/// Number of log lines kept in memory for the debug bundles.
+const RECENT_LINES: usize = 200;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// The last lines that were logged, oldest first.
+pub fn recent_lines() -> Vec<String> {
+    match RECENT.try_lock() {
+        Ok(recent) => recent.iter().cloned().collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Writes the logs to stdout, keeping the last lines in memory.
+struct RecentLinesWriter;
+
+impl Write for RecentLinesWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = std::io::stdout().write(buf)?;
+        if let Ok(mut recent) = RECENT.lock() {
+            for line in String::from_utf8_lossy(&buf[..written]).lines() {
+                if recent.len() == RECENT_LINES {
+                    recent.pop_front();
+                }
+                recent.push_back(line.to_string());
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+pub fn init(format: LogFormat) {
+    let time_format = time::format_description::parse(
+        "[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3]",
+    )
+    .expect("Failed to create timestamp format");
+    let timer = UtcTime::new(time_format);
+    let filter = EnvFilter::new("info,ort=off");
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_env_filter(filter)
+        .with_writer(|| RecentLinesWriter);
+    match format {
+        LogFormat::Text => {
+            let format = fmt::format().with_target(false).with_timer(timer);
+            subscriber.event_format(format).init()
+        }
+        LogFormat::Json => {
+            let format = fmt::format().json().with_target(false).with_timer(timer);
+            subscriber.event_format(format).init()
+        }
+    }
+}
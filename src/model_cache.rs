@@ -0,0 +1,231 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use axum::http::header::CONTENT_LENGTH;
+use log::info;
+
+use crate::disk_usage;
+use crate::storage::Storage;
+
+/// Directory of the data dir in which the models are downloaded, with one subdirectory per
+/// model variant, like "v1/small_fp32".
+pub const MODELS_DIR: &str = "v1";
+/// When each model variant was used for the last time, in milliseconds since the epoch.
+const USAGE_FILE: &str = "models_usage.json";
+
+/// The model variants in which the given model files are, like "v1/small_fp32" for
+/// "v1/small_fp32/decoder_model.onnx".
+pub fn variants<'a>(files: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+    for file in files {
+        let Some((variant, _)) = file.rsplit_once('/') else {
+            continue;
+        };
+        if !result.iter().any(|v| v == variant) {
+            result.push(variant.to_string())
+        }
+    }
+    result
+}
+
+async fn load_usage<S: Storage>(storage: &S) -> anyhow::Result<HashMap<String, u128>> {
+    match storage.read(USAGE_FILE).await? {
+        Some(content) => Ok(serde_json::from_slice(&content).unwrap_or_default()),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Records that the model variants were just used.
+pub async fn touch<S: Storage>(storage: &S, variants: &[String]) -> anyhow::Result<()> {
+    let mut usage = load_usage(storage).await?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    for variant in variants {
+        usage.insert(variant.clone(), now);
+    }
+    Ok(storage
+        .write(USAGE_FILE, serde_json::to_vec(&usage)?)
+        .await?)
+}
+
+/// The bytes of a remote file, as it would be downloaded.
+pub async fn remote_size(url: &str) -> anyhow::Result<u64> {
+    let resp = reqwest::Client::new().head(url).send().await?;
+    // The response's content_length() is the one of its empty body.
+    let size = resp
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse().ok());
+    Ok(size.unwrap_or_default())
+}
+
+/// Removes the least recently used model variants until `needed` more bytes fit in
+/// `budget`, never removing the `active` ones. Returns the removed variants.
+pub async fn make_room<S: Storage>(
+    storage: &S,
+    budget: u64,
+    needed: u64,
+    active: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let usage = load_usage(storage).await?;
+    let active: HashSet<&String> = active.iter().collect();
+    let mut total = 0;
+    let mut candidates = vec![];
+    for variant in storage.list(MODELS_DIR).await? {
+        if !storage.metadata(&variant).await?.is_some_and(|v| v.is_dir) {
+            continue;
+        }
+        let size = disk_usage::size(storage, &variant).await?;
+        total += size;
+        if !active.contains(&variant) {
+            // Variants downloaded before usage was tracked are evicted first.
+            let last_used = usage.get(&variant).copied().unwrap_or_default();
+            candidates.push((last_used, variant, size))
+        }
+    }
+    candidates.sort();
+
+    let mut evicted = vec![];
+    for (_, variant, size) in candidates {
+        if total + needed <= budget {
+            break;
+        }
+        info!("Removing model {variant} to stay within the cache budget");
+        storage.rm_rf(&variant).await?;
+        total -= size;
+        evicted.push(variant)
+    }
+    if total + needed > budget {
+        info!("Models take more than the cache budget even after removing the unused ones");
+    }
+    Ok(evicted)
+}
+
+/// Finds a model file in a manually downloaded directory, either in the same layout as in
+/// the remote repository, like "small_fp32/text_encoder.onnx", or directly in it.
+async fn find_in_dir(dir: &Path, file: &str) -> anyhow::Result<Option<PathBuf>> {
+    let relative = file.strip_prefix(&format!("{MODELS_DIR}/")).unwrap_or(file);
+    let name = relative.rsplit('/').next().unwrap_or(relative);
+    for candidate in [dir.join(relative), dir.join(name)] {
+        if tokio::fs::try_exists(&candidate).await? {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+/// Copies the model `files`, like "v1/small_fp32/text_encoder.onnx", from a manually
+/// downloaded directory to the data dir, so that they do not need to be downloaded. All
+/// the files are checked before copying any of them.
+pub async fn import<S: Storage>(storage: &S, dir: &Path, files: &[&str]) -> anyhow::Result<()> {
+    let mut found = vec![];
+    let mut missing = vec![];
+    for file in files {
+        match find_in_dir(dir, file).await? {
+            Some(path) if tokio::fs::metadata(&path).await?.len() > 0 => found.push((path, file)),
+            Some(path) => return Err(anyhow!("{} is empty", path.display())),
+            None => missing.push(file.strip_prefix(&format!("{MODELS_DIR}/")).unwrap_or(file)),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Missing model files in {}: {}",
+            dir.display(),
+            missing.join(", ")
+        ));
+    }
+    for (path, file) in found {
+        info!("Importing {}", path.display());
+        let dest = storage.path_buf(file);
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Copied to a temporary file first, like downloads, so that an interrupted copy
+        // is not taken for a valid model file.
+        let temp = format!("{file}.temp");
+        tokio::fs::copy(&path, storage.path_buf(&temp)).await?;
+        storage.mv(&temp, file).await?;
+    }
+    touch(storage, &variants(files.iter().copied())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[test]
+    fn computes_variants() {
+        let files = [
+            "v1/small/config.json",
+            "v1/small_fp32/text_encoder.onnx",
+            "v1/small_i8/decoder_model_merged.onnx",
+            "v1/small_fp32/encodec_decode.onnx",
+        ];
+        assert_eq!(
+            variants(files),
+            vec!["v1/small", "v1/small_fp32", "v1/small_i8"]
+        );
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_models() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        for variant in ["small_fp32", "small_i8", "medium_fp32"] {
+            storage
+                .write(&format!("{MODELS_DIR}/{variant}/model.onnx"), [0; 100])
+                .await?;
+        }
+        touch(&storage, &["v1/medium_fp32".to_string()]).await?;
+        touch(&storage, &["v1/small_i8".to_string()]).await?;
+        let active = ["v1/small_fp32".to_string()];
+
+        // Fits without removing anything.
+        assert!(make_room(&storage, 400, 100, &active).await?.is_empty());
+
+        let evicted = make_room(&storage, 300, 100, &active).await?;
+        assert_eq!(evicted, vec!["v1/medium_fp32"]);
+        assert!(!storage.exists("v1/medium_fp32/model.onnx").await?);
+
+        // The active model is kept even if it does not fit.
+        let evicted = make_room(&storage, 50, 100, &active).await?;
+        assert_eq!(evicted, vec!["v1/small_i8"]);
+        assert!(storage.exists("v1/small_fp32/model.onnx").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn imports_downloaded_models() -> anyhow::Result<()> {
+        let storage = AppFs::new_tmp();
+        let downloaded = AppFs::new_tmp();
+        downloaded.write("small/config.json", "{}").await?;
+        downloaded.write("text_encoder.onnx", [1; 10]).await?;
+        let files = [
+            "v1/small/config.json",
+            "v1/small_fp32/text_encoder.onnx",
+            "v1/small_i8/decoder_model_merged.onnx",
+        ];
+
+        let err = import(&storage, &downloaded.root, &files)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("small_i8/decoder_model_merged.onnx"));
+        assert!(!storage.exists("v1/small/config.json").await?);
+
+        downloaded
+            .write("small_i8/decoder_model_merged.onnx", [2; 10])
+            .await?;
+        import(&storage, &downloaded.root, &files).await?;
+        for file in files {
+            assert!(storage.exists(file).await?);
+        }
+        assert_eq!(
+            storage.read("v1/small_fp32/text_encoder.onnx").await?,
+            Some(vec![1; 10])
+        );
+        Ok(())
+    }
+}
@@ -1,307 +1,301 @@
-use half::f16;
+use anyhow::anyhow;
 use indicatif::{ProgressBar, ProgressStyle};
-use ort::session::Session;
-use ort::value::DynValue;
+use musicgpt_core::{DecoderFiles, ModelFiles, MusicGenModels, SessionOptions};
 use std::collections::VecDeque;
 use std::path::PathBuf;
-use std::sync::mpsc::Receiver;
-use std::sync::Arc;
 use std::time::Duration;
-use tokenizers::Tokenizer;
+use tracing::warn;
 
-use crate::backend::JobProcessor;
-use crate::cli::{Model, INPUT_IDS_BATCH_PER_SECOND};
-use crate::musicgen::{
-    MusicGenAudioEncodec, MusicGenDecoder, MusicGenMergedDecoder, MusicGenSplitDecoder,
-    MusicGenTextEncoder,
-};
-use crate::storage_ext::StorageExt;
-use crate::PROJECT_FS;
+use crate::cli::Model;
+use crate::model_cache;
+use crate::storage::Storage;
+use crate::storage_ext::{NetworkOptions, StorageExt};
 
-pub struct MusicGenModels {
-    text_encoder: MusicGenTextEncoder,
-    decoder: Box<dyn MusicGenDecoder>,
-    audio_encodec: MusicGenAudioEncodec,
+/// How the files of a model are downloaded.
+pub struct ModelDownloadOptions {
+    /// Downloads the files even if they were already downloaded.
+    pub force: bool,
+    /// Maximum bytes that the downloaded models can take on disk.
+    pub cache_budget: Option<u64>,
+    /// Checks the downloaded files before using them, downloading again the corrupted ones.
+    pub verify: bool,
+    /// See [models_base_url].
+    pub base_url: String,
+    pub network: NetworkOptions,
 }
 
-impl MusicGenModels {
-    pub fn encode_text(&self, text: &str) -> ort::Result<(DynValue, DynValue)> {
-        self.text_encoder.encode(text)
-    }
-
-    pub fn generate_tokens(
-        &self,
-        last_hidden_state: DynValue,
-        encoder_attention_mask: DynValue,
-        max_len: usize,
-    ) -> ort::Result<Receiver<ort::Result<[i64; 4]>>> {
-        self.decoder
-            .generate_tokens(last_hidden_state, encoder_attention_mask, max_len)
-    }
-
-    pub fn encode_audio(
-        &self,
-        tokens: impl IntoIterator<Item = [i64; 4]>,
-    ) -> ort::Result<VecDeque<f32>> {
-        self.audio_encodec.encode(tokens)
-    }
-
-    pub async fn new(
-        model: Model,
-        use_split_decoder: bool,
-        force_download: bool,
-    ) -> anyhow::Result<Self> {
-        macro_rules! hf_url {
-            ($t: expr) => {
-                (
-                    concat!(
-                        "https://huggingface.co/gabotechs/music_gen/resolve/main/",
-                        $t
-                    ),
-                    concat!("v1/", $t,),
-                )
-            };
+/// Downloads the files of `model` if needed, and loads it.
+pub async fn load<S: Storage>(
+    storage: &S,
+    model: Model,
+    use_split_decoder: bool,
+    options: &ModelDownloadOptions,
+    session_options: &SessionOptions,
+) -> anyhow::Result<MusicGenModels> {
+    let force_download = options.force;
+    let remote_file_spec = remote_file_spec(model, use_split_decoder, &options.base_url);
+    let results = if storage.is_read_only() {
+        read_only_files(storage, &remote_file_spec, options.verify).await?
+    } else {
+        if options.verify {
+            for (_, local) in &remote_file_spec {
+                if !storage.verify_file(local).await? {
+                    warn!("{local} is corrupted, downloading it again");
+                    storage.rm(local).await?;
+                }
+            }
+        }
+        let variants = model_cache::variants(remote_file_spec.iter().map(|(_, local)| *local));
+        if let Some(budget) = options.cache_budget {
+            let mut needed = 0;
+            for (url, local) in &remote_file_spec {
+                if force_download || !storage.exists(local).await? {
+                    needed += model_cache::remote_size(url).await?;
+                }
+            }
+            if needed > 0 {
+                model_cache::make_room(storage, budget, needed, &variants).await?;
+            }
         }
-        let remote_file_spec = match (model, use_split_decoder) {
-            (Model::Small, true) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp32/text_encoder.onnx"),
-                hf_url!("small_fp32/decoder_model.onnx"),
-                hf_url!("small_fp32/decoder_with_past_model.onnx"),
-                hf_url!("small_fp32/encodec_decode.onnx"),
-            ],
-            (Model::SmallQuant, true) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp32/text_encoder.onnx"),
-                hf_url!("small_i8/decoder_model.onnx"),
-                hf_url!("small_i8/decoder_with_past_model.onnx"),
-                hf_url!("small_fp32/encodec_decode.onnx"),
-            ],
-            (Model::SmallFp16, true) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp16/text_encoder.onnx"),
-                hf_url!("small_fp16/decoder_model.onnx"),
-                hf_url!("small_fp16/decoder_with_past_model.onnx"),
-                hf_url!("small_fp16/encodec_decode.onnx"),
-            ],
-            (Model::Medium, true) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp32/text_encoder.onnx"),
-                hf_url!("medium_fp32/decoder_model.onnx"),
-                hf_url!("medium_fp32/decoder_with_past_model.onnx"),
-                hf_url!("medium_fp32/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("medium_fp32/decoder_model.onnx_data"),
-                hf_url!("medium_fp32/decoder_with_past_model.onnx_data"),
-            ],
-            (Model::MediumQuant, true) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp32/text_encoder.onnx"),
-                hf_url!("medium_i8/decoder_model.onnx"),
-                hf_url!("medium_i8/decoder_with_past_model.onnx"),
-                hf_url!("medium_fp32/encodec_decode.onnx"),
-            ],
-            (Model::MediumFp16, true) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp16/text_encoder.onnx"),
-                hf_url!("medium_fp16/decoder_model.onnx"),
-                hf_url!("medium_fp16/decoder_with_past_model.onnx"),
-                hf_url!("medium_fp16/encodec_decode.onnx"),
-            ],
-            (Model::Large, true) => vec![
-                hf_url!("large/config.json"),
-                hf_url!("large/tokenizer.json"),
-                hf_url!("large_fp32/text_encoder.onnx"),
-                hf_url!("large_fp32/decoder_model.onnx"),
-                hf_url!("large_fp32/decoder_with_past_model.onnx"),
-                hf_url!("large_fp32/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("large_fp32/decoder_model.onnx_data"),
-                hf_url!("large_fp32/decoder_with_past_model.onnx_data"),
-            ],
-            (Model::Small, false) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp32/text_encoder.onnx"),
-                hf_url!("small_fp32/decoder_model_merged.onnx"),
-                hf_url!("small_fp32/encodec_decode.onnx"),
-            ],
-            (Model::SmallQuant, false) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp32/text_encoder.onnx"),
-                hf_url!("small_i8/decoder_model_merged.onnx"),
-                hf_url!("small_fp32/encodec_decode.onnx"),
-            ],
-            (Model::SmallFp16, false) => vec![
-                hf_url!("small/config.json"),
-                hf_url!("small/tokenizer.json"),
-                hf_url!("small_fp16/text_encoder.onnx"),
-                hf_url!("small_fp16/decoder_model_merged.onnx"),
-                hf_url!("small_fp16/encodec_decode.onnx"),
-            ],
-            (Model::Medium, false) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp32/text_encoder.onnx"),
-                hf_url!("medium_fp32/decoder_model_merged.onnx"),
-                hf_url!("medium_fp32/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("medium_fp32/decoder_model_merged.onnx_data"),
-            ],
-            (Model::MediumQuant, false) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp32/text_encoder.onnx"),
-                hf_url!("medium_i8/decoder_model_merged.onnx"),
-                hf_url!("medium_fp32/encodec_decode.onnx"),
-            ],
-            (Model::MediumFp16, false) => vec![
-                hf_url!("medium/config.json"),
-                hf_url!("medium/tokenizer.json"),
-                hf_url!("medium_fp16/text_encoder.onnx"),
-                hf_url!("medium_fp16/decoder_model_merged.onnx"),
-                hf_url!("medium_fp16/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("medium_fp16/decoder_model_merged.onnx_data"),
-            ],
-            (Model::Large, false) => vec![
-                hf_url!("large/config.json"),
-                hf_url!("large/tokenizer.json"),
-                hf_url!("large_fp32/text_encoder.onnx"),
-                hf_url!("large_fp32/decoder_model_merged.onnx"),
-                hf_url!("large_fp32/encodec_decode.onnx"),
-                // Files below will just be downloaded,
-                hf_url!("large_fp32/decoder_model_merged.onnx_data"),
-            ],
-        };
 
-        let mut results = PROJECT_FS
+        let remote_file_spec = remote_file_spec
+            .into_iter()
+            .map(|(url, local)| (url, local, model_sha256(local)))
+            .collect();
+        let results = storage
             .download_many(
                 remote_file_spec,
                 force_download,
+                &options.network,
                 "Some AI models need to be downloaded, this only needs to be done once",
                 "AI models downloaded correctly",
             )
             .await?;
 
-        // First result is the decoder config.
-        let config = results.pop_front().unwrap();
-        // Second result is the tokenizer.
-        let tokenizer = results.pop_front().unwrap();
-        let mut tokenizer = Tokenizer::from_file(tokenizer).expect("Could not load tokenizer");
-        tokenizer
-            .with_padding(None)
-            .with_truncation(None)
-            .expect("Could not configure tokenizer");
-
-        let mut sessions = build_sessions(results).await?;
+        model_cache::touch(storage, &variants).await?;
+        results
+    };
 
-        let text_encoder = MusicGenTextEncoder {
-            tokenizer,
-            // third result is the text encoder.
-            text_encoder: sessions.pop_front().unwrap(),
-        };
-
-        let config = tokio::fs::read_to_string(config)
-            .await
-            .expect("Error reading config file from disk");
-        let config = serde_json::from_str(&config).expect("Could not deserialize config file");
-        #[allow(clippy::collapsible_else_if)]
-        let decoder: Box<dyn MusicGenDecoder> = if use_split_decoder {
-            macro_rules! load {
-                ($ty: ty) => {
-                    Box::new(MusicGenSplitDecoder::<$ty> {
-                        // forth and fifth result are the decoder parts if split.
-                        decoder_model: sessions.pop_front().unwrap(),
-                        decoder_with_past_model: Arc::new(sessions.pop_front().unwrap()),
-                        config,
-                        _phantom_data: Default::default(),
-                    })
-                };
-            }
-            if matches!(model, Model::SmallFp16 | Model::MediumFp16) {
-                load!(f16)
-            } else {
-                load!(f32)
-            }
-        } else {
-            macro_rules! load {
-                ($ty: ty) => {
-                    Box::new(MusicGenMergedDecoder::<$ty> {
-                        // forth result is the decoder.
-                        decoder_model_merged: Arc::new(sessions.pop_front().unwrap()),
-                        config,
-                        _phantom_data: Default::default(),
-                    })
-                };
-            }
-            if matches!(model, Model::SmallFp16 | Model::MediumFp16) {
-                load!(f16)
-            } else {
-                load!(f32)
-            }
-        };
-        let audio_encodec = MusicGenAudioEncodec {
-            // last result is the audio encodec.
-            audio_encodec_decode: sessions.pop_front().unwrap(),
-        };
+    let bar = spinner("Loading the AI models...");
+    let files = model_files(model, use_split_decoder, results);
+    let models = MusicGenModels::load_with_progress(files, session_options, &|loaded, total| {
+        bar.set_message(format!("Loading the AI models... ({loaded}/{total})"))
+    });
+    bar.finish_and_clear();
+    models
+}
 
-        Ok(MusicGenModels {
-            text_encoder,
-            decoder,
-            audio_encodec,
-        })
+/// Sorts out the downloaded files, given in the order of [remote_file_spec].
+fn model_files(model: Model, use_split_decoder: bool, mut results: VecDeque<PathBuf>) -> ModelFiles {
+    let mut next = || results.pop_front().expect("Missing model file");
+    let config = next();
+    let tokenizer = next();
+    let text_encoder = next();
+    let decoder = if use_split_decoder {
+        DecoderFiles::Split {
+            decoder: next(),
+            decoder_with_past: next(),
+        }
+    } else {
+        DecoderFiles::Merged(next())
+    };
+    // The .onnx_data files after it are loaded along with the decoder.
+    let encodec = next();
+    ModelFiles {
+        config,
+        tokenizer,
+        text_encoder,
+        decoder,
+        encodec,
+        fp16: matches!(model, Model::SmallFp16 | Model::MediumFp16),
     }
 }
 
-impl JobProcessor for MusicGenModels {
-    fn process(
-        &self,
-        prompt: &str,
-        secs: usize,
-        on_progress: Box<dyn Fn(f32, f32) -> bool + Sync + Send + 'static>,
-    ) -> ort::Result<VecDeque<f32>> {
-        let max_len = secs * INPUT_IDS_BATCH_PER_SECOND;
+/// Looks up the model files in a read-only storage, in which they cannot be downloaded.
+async fn read_only_files<S: Storage>(
+    storage: &S,
+    remote_file_spec: &[(String, &'static str)],
+    verify: bool,
+) -> anyhow::Result<VecDeque<PathBuf>> {
+    let mut missing = vec![];
+    let mut results = VecDeque::new();
+    for (_, local) in remote_file_spec {
+        if !storage.exists(local).await? {
+            missing.push(*local);
+        } else if verify && !storage.verify_file(local).await? {
+            return Err(anyhow!("{local} is corrupted in the read-only models dir"));
+        }
+        results.push_back(storage.path_buf(local));
+    }
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Missing model files in the read-only models dir: {}",
+            missing.join(", ")
+        ));
+    }
+    Ok(results)
+}
 
-        let (lhs, am) = self.encode_text(prompt)?;
-        let token_stream = self.generate_tokens(lhs, am, max_len)?;
+const HF_ENDPOINT: &str = "https://huggingface.co";
+const HF_REPO_PATH: &str = "gabotechs/music_gen/resolve/main";
 
-        let mut data = VecDeque::new();
-        while let Ok(tokens) = token_stream.recv() {
-            data.push_back(tokens?);
-            let should_exit = on_progress(data.len() as f32, max_len as f32);
-            if should_exit {
-                return Err(ort::Error::new("Aborted"));
-            }
+/// The base URL from which the models are downloaded, which is either a `mirror` with the
+/// same layout as the Hugging Face repository or the repository in `hf_endpoint`.
+pub fn models_base_url(mirror: Option<&str>, hf_endpoint: Option<&str>) -> String {
+    match (mirror, hf_endpoint) {
+        (Some(mirror), _) if !mirror.is_empty() => mirror.trim_end_matches('/').to_string(),
+        (_, Some(endpoint)) if !endpoint.is_empty() => {
+            format!("{}/{HF_REPO_PATH}", endpoint.trim_end_matches('/'))
         }
-
-        self.encode_audio(data)
+        _ => format!("{HF_ENDPOINT}/{HF_REPO_PATH}"),
     }
 }
 
-async fn build_sessions(
-    files: impl IntoIterator<Item = PathBuf>,
-) -> anyhow::Result<VecDeque<Session>> {
-    let mut results = VecDeque::new();
-    for file in files {
-        if file.extension() != Some("onnx".as_ref()) {
-            continue;
-        }
-        let bar =
-            spinner(format!("Loading {:?}...", file.file_name().unwrap_or_default()).as_str());
+/// The SHA-256 of the files in the Hugging Face repository, as recorded in their Git LFS
+/// pointers. Downloads of these files are verified against them instead of against whatever
+/// digest the server reports, which might not be there at all in mirrors.
+const MODEL_SHA256S: &[(&str, &str)] = &[];
+
+/// The pinned SHA-256 of a local file given by [remote_file_spec], if any.
+pub fn model_sha256(local: &str) -> Option<&'static str> {
+    let file = local.strip_prefix("v1/")?;
+    MODEL_SHA256S
+        .iter()
+        .find(|(pinned, _)| *pinned == file)
+        .map(|(_, sha256)| *sha256)
+}
 
-        let result = Session::builder()?.commit_from_file(file)?;
-        bar.finish_and_clear();
-        results.push_back(result);
+/// The files of a model, as (remote url, local file) pairs. The remote urls are relative
+/// to `base_url`, and the local files are in the variants' directories under "v1/".
+pub fn remote_file_spec(
+    model: Model,
+    use_split_decoder: bool,
+    base_url: &str,
+) -> Vec<(String, &'static str)> {
+    macro_rules! hf_url {
+        ($t: expr) => {
+            (format!("{base_url}/{}", $t), concat!("v1/", $t,))
+        };
+    }
+    match (model, use_split_decoder) {
+        (Model::Small, true) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp32/text_encoder.onnx"),
+            hf_url!("small_fp32/decoder_model.onnx"),
+            hf_url!("small_fp32/decoder_with_past_model.onnx"),
+            hf_url!("small_fp32/encodec_decode.onnx"),
+        ],
+        (Model::SmallQuant, true) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp32/text_encoder.onnx"),
+            hf_url!("small_i8/decoder_model.onnx"),
+            hf_url!("small_i8/decoder_with_past_model.onnx"),
+            hf_url!("small_fp32/encodec_decode.onnx"),
+        ],
+        (Model::SmallFp16, true) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp16/text_encoder.onnx"),
+            hf_url!("small_fp16/decoder_model.onnx"),
+            hf_url!("small_fp16/decoder_with_past_model.onnx"),
+            hf_url!("small_fp16/encodec_decode.onnx"),
+        ],
+        (Model::Medium, true) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp32/text_encoder.onnx"),
+            hf_url!("medium_fp32/decoder_model.onnx"),
+            hf_url!("medium_fp32/decoder_with_past_model.onnx"),
+            hf_url!("medium_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("medium_fp32/decoder_model.onnx_data"),
+            hf_url!("medium_fp32/decoder_with_past_model.onnx_data"),
+        ],
+        (Model::MediumQuant, true) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp32/text_encoder.onnx"),
+            hf_url!("medium_i8/decoder_model.onnx"),
+            hf_url!("medium_i8/decoder_with_past_model.onnx"),
+            hf_url!("medium_fp32/encodec_decode.onnx"),
+        ],
+        (Model::MediumFp16, true) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp16/text_encoder.onnx"),
+            hf_url!("medium_fp16/decoder_model.onnx"),
+            hf_url!("medium_fp16/decoder_with_past_model.onnx"),
+            hf_url!("medium_fp16/encodec_decode.onnx"),
+        ],
+        (Model::Large, true) => vec![
+            hf_url!("large/config.json"),
+            hf_url!("large/tokenizer.json"),
+            hf_url!("large_fp32/text_encoder.onnx"),
+            hf_url!("large_fp32/decoder_model.onnx"),
+            hf_url!("large_fp32/decoder_with_past_model.onnx"),
+            hf_url!("large_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("large_fp32/decoder_model.onnx_data"),
+            hf_url!("large_fp32/decoder_with_past_model.onnx_data"),
+        ],
+        (Model::Small, false) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp32/text_encoder.onnx"),
+            hf_url!("small_fp32/decoder_model_merged.onnx"),
+            hf_url!("small_fp32/encodec_decode.onnx"),
+        ],
+        (Model::SmallQuant, false) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp32/text_encoder.onnx"),
+            hf_url!("small_i8/decoder_model_merged.onnx"),
+            hf_url!("small_fp32/encodec_decode.onnx"),
+        ],
+        (Model::SmallFp16, false) => vec![
+            hf_url!("small/config.json"),
+            hf_url!("small/tokenizer.json"),
+            hf_url!("small_fp16/text_encoder.onnx"),
+            hf_url!("small_fp16/decoder_model_merged.onnx"),
+            hf_url!("small_fp16/encodec_decode.onnx"),
+        ],
+        (Model::Medium, false) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp32/text_encoder.onnx"),
+            hf_url!("medium_fp32/decoder_model_merged.onnx"),
+            hf_url!("medium_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("medium_fp32/decoder_model_merged.onnx_data"),
+        ],
+        (Model::MediumQuant, false) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp32/text_encoder.onnx"),
+            hf_url!("medium_i8/decoder_model_merged.onnx"),
+            hf_url!("medium_fp32/encodec_decode.onnx"),
+        ],
+        (Model::MediumFp16, false) => vec![
+            hf_url!("medium/config.json"),
+            hf_url!("medium/tokenizer.json"),
+            hf_url!("medium_fp16/text_encoder.onnx"),
+            hf_url!("medium_fp16/decoder_model_merged.onnx"),
+            hf_url!("medium_fp16/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("medium_fp16/decoder_model_merged.onnx_data"),
+        ],
+        (Model::Large, false) => vec![
+            hf_url!("large/config.json"),
+            hf_url!("large/tokenizer.json"),
+            hf_url!("large_fp32/text_encoder.onnx"),
+            hf_url!("large_fp32/decoder_model_merged.onnx"),
+            hf_url!("large_fp32/encodec_decode.onnx"),
+            // Files below will just be downloaded,
+            hf_url!("large_fp32/decoder_model_merged.onnx_data"),
+        ],
     }
-    Ok(results)
 }
 
 pub fn spinner(msg: impl Into<String>) -> ProgressBar {
@@ -311,3 +305,53 @@ pub fn spinner(msg: impl Into<String>) -> ProgressBar {
     pb.set_message(msg.into());
     pb
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn resolves_models_base_url() {
+        let default = "https://huggingface.co/gabotechs/music_gen/resolve/main";
+        assert_eq!(models_base_url(None, None), default);
+        assert_eq!(models_base_url(Some(""), Some("")), default);
+        assert_eq!(
+            models_base_url(None, Some("https://hf-mirror.com/")),
+            "https://hf-mirror.com/gabotechs/music_gen/resolve/main"
+        );
+        assert_eq!(
+            models_base_url(Some("http://mirror.local/music_gen/"), Some("https://hf-mirror.com")),
+            "http://mirror.local/music_gen"
+        );
+        let spec = remote_file_spec(Model::Small, false, "http://mirror.local");
+        assert_eq!(
+            spec[0],
+            ("http://mirror.local/small/config.json".to_string(), "v1/small/config.json")
+        );
+    }
+
+    #[test]
+    fn sorts_out_model_files() {
+        let results = remote_file_spec(Model::Large, true, "")
+            .iter()
+            .map(|(_, local)| PathBuf::from(local))
+            .collect();
+        let files = model_files(Model::Large, true, results);
+        assert_eq!(files.text_encoder, Path::new("v1/large_fp32/text_encoder.onnx"));
+        assert_eq!(files.encodec, Path::new("v1/large_fp32/encodec_decode.onnx"));
+        let DecoderFiles::Split {
+            decoder,
+            decoder_with_past,
+        } = files.decoder
+        else {
+            panic!("the decoder should be split")
+        };
+        assert_eq!(decoder, Path::new("v1/large_fp32/decoder_model.onnx"));
+        assert_eq!(
+            decoder_with_past,
+            Path::new("v1/large_fp32/decoder_with_past_model.onnx")
+        );
+        assert!(!files.fp16);
+    }
+}
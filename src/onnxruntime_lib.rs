@@ -1,24 +1,56 @@
+#[cfg(all(feature = "onnxruntime-static", feature = "onnxruntime-from-source"))]
+compile_error!("onnxruntime-static links onnxruntime, it cannot be used with onnxruntime-from-source");
+
+#[cfg(all(
+    feature = "onnxruntime-static",
+    any(feature = "cuda", feature = "tensorrt", feature = "directml", feature = "openvino")
+))]
+compile_error!("onnxruntime-static only supports the CPU and CoreML execution providers");
+
+#[cfg(all(
+    feature = "onnxruntime-system",
+    any(
+        feature = "onnxruntime-from-source",
+        feature = "onnxruntime-from-cdn",
+        feature = "onnxruntime-static"
+    )
+))]
+compile_error!("onnxruntime-system loads the system onnxruntime, use it with --no-default-features");
+
 #[cfg(feature = "onnxruntime-from-source")]
 pub mod init {
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use ort::environment::EnvironmentBuilder;
     
     use crate::storage::Storage;
-    use crate::storage_ext::StorageExt;
+    use crate::storage_ext::{NetworkOptions, StorageExt};
 
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
     include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
 
-    pub async fn init<S: Storage>(storage: S) -> anyhow::Result<EnvironmentBuilder> {
-        Ok(ort::init_from(
-            lookup_dynlib(storage)
-                .await?
-                .to_str()
-                .unwrap_or_default())
-        )
+    /// Loads onnxruntime from `dynlib` if provided, or otherwise from the libraries compiled
+    /// by build.rs or downloaded from the GitHub release.
+    pub async fn init<S: Storage>(
+        storage: S,
+        network: &NetworkOptions,
+        dynlib: Option<&Path>,
+    ) -> anyhow::Result<EnvironmentBuilder> {
+        let dynlib = match dynlib {
+            Some(dynlib) => {
+                if !tokio::fs::try_exists(dynlib).await? {
+                    return Err(anyhow::anyhow!("dynamic library file {dynlib:?} not found"));
+                }
+                dynlib.to_path_buf()
+            }
+            None => lookup_dynlib(storage, network).await?,
+        };
+        Ok(ort::init_from(dynlib.to_str().unwrap_or_default()))
     }
 
-    async fn lookup_dynlib<S: Storage>(storage: S) -> anyhow::Result<PathBuf> {
+    async fn lookup_dynlib<S: Storage>(
+        storage: S,
+        network: &NetworkOptions,
+    ) -> anyhow::Result<PathBuf> {
         // If running with Cargo, build.rs have set this ONNXRUNTIME_LOCAL_FILES env to the
         // path of the generated dynamic library files compiled from source.
         // If not running with cargo, this will not be set.
@@ -30,7 +62,15 @@ pub mod init {
             }
         }
 
-        // If there's no local file, attempt to download it from a GitHub release.
+        // If there's no local file, attempt to download it from a GitHub release, which only
+        // has the libraries of the default onnxruntime release.
+        if ONNXRUNTIME_VERSION != RELEASED_ONNXRUNTIME_VERSION {
+            return Err(anyhow::anyhow!(
+                "MusicGPT was compiled with onnxruntime {ONNXRUNTIME_VERSION}, but its dynamic \
+                libraries are not in {LOCAL_DYNLIB_FILEPATHS:?} and only the ones of \
+                {RELEASED_ONNXRUNTIME_VERSION} can be downloaded, pass them with --onnxruntime-lib"
+            ));
+        }
         let remote_file_spec = DYNLIB_FILENAMES
             .iter()
             .map(|v| {
@@ -39,12 +79,14 @@ pub mod init {
                     // https://github.com/gabotechs/MusicGPT/blob/main/.github/workflows/ci.yml#L188
                     format!("{PKG_REPOSITORY}/releases/download/v{PKG_VERSION}/{TARGET}-{v}"),
                     format!("dynlibs/{ONNXRUNTIME_VERSION}/{v}"),
+                    None,
                 )
             })
             .collect::<Vec<_>>();
         storage.download_many(
             remote_file_spec,
             false,
+            network,
             &format!("Dynamic libraries not found in path set by ONNXRUNTIME_LOCAL_FILES env variable. Downloading them from GitHub release {PKG_VERSION}..."),
             "Dynamic libraries downloaded successfully",
         )
@@ -62,12 +104,52 @@ pub mod init {
 }
 
 
-#[cfg(not(feature = "onnxruntime-from-source"))]
+#[cfg(feature = "onnxruntime-system")]
+pub mod init {
+    use std::path::Path;
+    use ort::environment::EnvironmentBuilder;
+    use crate::storage::Storage;
+    use crate::storage_ext::NetworkOptions;
+
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+    /// Loads onnxruntime from `dynlib` if provided, or otherwise from the system library
+    /// found by build.rs. Nothing is ever downloaded.
+    pub async fn init<S: Storage>(
+        _: S,
+        _: &NetworkOptions,
+        dynlib: Option<&Path>,
+    ) -> anyhow::Result<EnvironmentBuilder> {
+        let dynlib = dynlib.unwrap_or(Path::new(SYSTEM_DYNLIB_FILEPATH));
+        if !tokio::fs::try_exists(dynlib).await? {
+            return Err(anyhow::anyhow!(
+                "dynamic library file {dynlib:?} not found, was onnxruntime uninstalled after \
+                compiling MusicGPT?"
+            ));
+        }
+        Ok(ort::init_from(dynlib.to_str().unwrap_or_default()))
+    }
+}
+
+#[cfg(not(any(feature = "onnxruntime-from-source", feature = "onnxruntime-system")))]
 pub mod init {
+    use std::path::Path;
     use ort::environment::EnvironmentBuilder;
     use crate::storage::Storage;
+    use crate::storage_ext::NetworkOptions;
     
-    pub async fn init<S: Storage>(_: S) -> anyhow::Result<EnvironmentBuilder> {
+    pub async fn init<S: Storage>(
+        _: S,
+        _: &NetworkOptions,
+        dynlib: Option<&Path>,
+    ) -> anyhow::Result<EnvironmentBuilder> {
+        if let Some(dynlib) = dynlib {
+            return Err(anyhow::anyhow!(
+                "Cannot load onnxruntime from {dynlib:?}, MusicGPT was compiled with onnxruntime \
+                linked, loading it from a file needs the `onnxruntime-from-source` or \
+                `onnxruntime-system` features"
+            ));
+        }
         Ok(ort::init())
     }
 }
@@ -0,0 +1,43 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::anyhow;
+
+/// Shows `path` in the file manager of the OS, selecting it where possible, or otherwise
+/// opening the folder that contains it.
+pub fn reveal(path: &Path) -> anyhow::Result<()> {
+    let path = path.canonicalize()?;
+    if cfg!(target_os = "macos") {
+        Command::new("open").arg("-R").arg(&path).spawn()?;
+        return Ok(());
+    }
+    if cfg!(target_os = "windows") {
+        let mut select = std::ffi::OsString::from("/select,");
+        select.push(&path);
+        Command::new("explorer").arg(select).spawn()?;
+        return Ok(());
+    }
+    // Most Linux file managers implement the freedesktop interface for selecting files.
+    let shown = Command::new("dbus-send")
+        .args([
+            "--session",
+            "--dest=org.freedesktop.FileManager1",
+            "--type=method_call",
+            "/org/freedesktop/FileManager1",
+            "org.freedesktop.FileManager1.ShowItems",
+        ])
+        .arg(format!("array:string:file://{}", path.display()))
+        .arg("string:")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if shown {
+        return Ok(());
+    }
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("{} has no parent folder", path.display()))?;
+    open::that(dir)?;
+    Ok(())
+}
@@ -1,17 +1,27 @@
 use async_trait::async_trait;
+use std::io::SeekFrom;
 
-use crate::storage::{Storage, StorageFile};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::storage::{Metadata, Storage, StorageFile, StorageReader};
+
+/// Where [AppFs::write] leaves the content until it is completely written.
+const TEMP_DIR: &str = ".tmp";
 
 #[derive(Clone)]
 pub struct AppFs {
     pub root: std::path::PathBuf,
+    read_only: bool,
 }
 
 impl StorageFile for tokio::fs::File {}
 
+impl StorageReader for tokio::fs::File {}
+
 #[async_trait]
 impl Storage for AppFs {
     type File = tokio::fs::File;
+    type Reader = tokio::fs::File;
 
     async fn exists(&self, path: &str) -> std::io::Result<bool> {
         let (abs_filepath, _, _) = self.relative_file_to_path_buf(path);
@@ -33,18 +43,93 @@ impl Storage for AppFs {
     }
 
     async fn write(&self, path: &str, content: impl AsRef<[u8]> + Send) -> std::io::Result<()> {
+        self.check_writable(path)?;
+        let content = content.as_ref();
         let (abs_filepath, abs_filedir, _) = self.relative_file_to_path_buf(path);
         tokio::fs::create_dir_all(abs_filedir).await?;
-        tokio::fs::write(abs_filepath, content).await?;
-        Ok(())
+        // The content is written apart and then renamed, which replaces the previous file at
+        // once, so that a crash in the middle never leaves it half-written. The temporary
+        // files are not in the file's dir for not showing up while listing it.
+        let temp_dir = self.root.join(TEMP_DIR);
+        tokio::fs::create_dir_all(&temp_dir).await?;
+        let temp_filepath = temp_dir.join(format!("{}.temp", uuid::Uuid::new_v4()));
+        let result = async {
+            let mut file = tokio::fs::File::create(&temp_filepath).await?;
+            file.write_all(content).await?;
+            file.sync_all().await?;
+            drop(file);
+            tokio::fs::rename(&temp_filepath, abs_filepath).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&temp_filepath).await;
+        }
+        result
     }
 
     async fn create(&self, path: &str) -> std::io::Result<Self::File> {
+        self.check_writable(path)?;
         let (abs_filepath, abs_filedir, _) = self.relative_file_to_path_buf(path);
         tokio::fs::create_dir_all(abs_filedir).await?;
         tokio::fs::File::create(abs_filepath).await
     }
 
+    async fn append(&self, path: &str) -> std::io::Result<Self::File> {
+        self.check_writable(path)?;
+        let (abs_filepath, abs_filedir, _) = self.relative_file_to_path_buf(path);
+        tokio::fs::create_dir_all(abs_filedir).await?;
+        tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(abs_filepath)
+            .await
+    }
+
+    async fn read_stream(&self, path: &str) -> std::io::Result<Option<Self::Reader>> {
+        let (abs_filepath, _, _) = self.relative_file_to_path_buf(path);
+        match tokio::fs::File::open(abs_filepath).await {
+            Ok(v) => Ok(Some(v)),
+            Err(err) => {
+                if err.kind() == std::io::ErrorKind::NotFound {
+                    Ok(None)
+                } else {
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    async fn read_range(
+        &self,
+        path: &str,
+        start: u64,
+        _end: u64,
+    ) -> std::io::Result<Option<Self::Reader>> {
+        let Some(mut file) = self.read_stream(path).await? else {
+            return Ok(None);
+        };
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Some(file))
+    }
+
+    async fn metadata(&self, path: &str) -> std::io::Result<Option<Metadata>> {
+        let (abs_filepath, _, _) = self.relative_file_to_path_buf(path);
+        match tokio::fs::metadata(abs_filepath).await {
+            Ok(metadata) => Ok(Some(Metadata {
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: metadata.modified()?,
+                is_dir: metadata.is_dir(),
+            })),
+            Err(err) => {
+                if err.kind() == std::io::ErrorKind::NotFound {
+                    Ok(None)
+                } else {
+                    Err(err)
+                }
+            }
+        }
+    }
+
     async fn list(&self, path: &str) -> std::io::Result<Vec<String>> {
         let (abs_dir, _, _) = self.relative_file_to_path_buf(path);
         let mut files = vec![];
@@ -76,6 +161,7 @@ impl Storage for AppFs {
     }
 
     async fn mv(&self, from: &str, to: &str) -> std::io::Result<()> {
+        self.check_writable(to)?;
         let (from_filepath, _, _) = self.relative_file_to_path_buf(from);
         let (to_filepath, to_dirpath, _) = self.relative_file_to_path_buf(to);
         tokio::fs::create_dir_all(to_dirpath).await?;
@@ -83,6 +169,7 @@ impl Storage for AppFs {
     }
 
     async fn rm(&self, path: &str) -> std::io::Result<bool> {
+        self.check_writable(path)?;
         let (abs_filepath, _, _) = self.relative_file_to_path_buf(path);
         match tokio::fs::remove_file(abs_filepath).await {
             Ok(_) => Ok(true),
@@ -97,6 +184,7 @@ impl Storage for AppFs {
     }
 
     async fn rm_rf(&self, path: &str) -> std::io::Result<bool> {
+        self.check_writable(path)?;
         let (abs_dirpath, _, _) = self.relative_file_to_path_buf(path);
         match tokio::fs::remove_dir_all(abs_dirpath).await {
             Ok(_) => Ok(true),
@@ -114,11 +202,37 @@ impl Storage for AppFs {
         let (abs_filepath, _, _) = self.relative_file_to_path_buf(path);
         abs_filepath
     }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 }
 
 impl AppFs {
     pub fn new(value: impl Into<std::path::PathBuf>) -> Self {
-        Self { root: value.into() }
+        Self {
+            root: value.into(),
+            read_only: false,
+        }
+    }
+
+    /// Opens a directory that is never modified, like one shared by several MusicGPT
+    /// instances. All the operations that would modify it fail.
+    pub fn read_only(value: impl Into<std::path::PathBuf>) -> Self {
+        Self {
+            root: value.into(),
+            read_only: true,
+        }
+    }
+
+    fn check_writable(&self, path: &str) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("Cannot modify {path} in read-only {}", self.root.display()),
+            ));
+        }
+        Ok(())
     }
 
     /// Gets a / separated relative path and returns:
@@ -161,7 +275,7 @@ mod tests {
     use rand::{thread_rng, Rng};
 
     use crate::storage::tests::test_storage;
-    use crate::storage::AppFs;
+    use crate::storage::{AppFs, Storage};
 
     fn rand_string() -> String {
         thread_rng()
@@ -176,4 +290,33 @@ mod tests {
         let app_fs = AppFs::new(format!("/tmp/{}", rand_string()));
         test_storage(app_fs).await
     }
+
+    #[tokio::test]
+    async fn write_replaces_files_through_temporary_ones() -> std::io::Result<()> {
+        let app_fs = AppFs::new(format!("/tmp/{}", rand_string()));
+        app_fs.write("chats/.metadata.json", "{}").await?;
+        app_fs.write("chats/.metadata.json", "{\"name\":\"foo\"}").await?;
+        assert_eq!(
+            app_fs.read("chats/.metadata.json").await?,
+            Some(b"{\"name\":\"foo\"}".to_vec())
+        );
+        assert_eq!(app_fs.list(".tmp").await?, Vec::<String>::new());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_only_app_fs_is_not_modified() -> std::io::Result<()> {
+        let root = format!("/tmp/{}", rand_string());
+        AppFs::new(&root).write("v1/model.onnx", "model").await?;
+        let app_fs = AppFs::read_only(&root);
+        assert!(app_fs.is_read_only());
+        assert_eq!(app_fs.read("v1/model.onnx").await?, Some(b"model".to_vec()));
+
+        let err = app_fs.write("v1/model.onnx", "other").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(app_fs.rm_rf("v1").await.is_err());
+        assert!(app_fs.mv("v1/model.onnx", "v1/moved.onnx").await.is_err());
+        assert_eq!(app_fs.read("v1/model.onnx").await?, Some(b"model".to_vec()));
+        Ok(())
+    }
 }
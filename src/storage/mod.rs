@@ -1,25 +1,58 @@
 mod app_fs;
+mod webdav;
 
 pub use app_fs::*;
+pub use webdav::*;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 use async_trait::async_trait;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncWriteExt};
 
 // Don't know why clippy says that this is dead code,
 // it's used in app_fs.rs
 #[allow(dead_code)]
 pub trait StorageFile: AsyncWriteExt + Unpin + Send + Sync {}
 
+#[allow(dead_code)]
+pub trait StorageReader: AsyncRead + Unpin + Send + Sync {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Metadata {
+    /// The size in bytes, zero for directories.
+    pub size: u64,
+    pub modified: SystemTime,
+    pub is_dir: bool,
+}
+
 #[allow(unused)]
 #[async_trait]
 pub trait Storage: Sync + Send + Clone + 'static {
     type File: StorageFile;
+    type Reader: StorageReader;
 
     async fn exists(&self, path: &str) -> std::io::Result<bool>;
     async fn read(&self, path: &str) -> std::io::Result<Option<Vec<u8>>>;
+    /// Opens a file for reading it in chunks, none if it does not exist. Useful for big
+    /// files that should not be loaded in memory all at once.
+    async fn read_stream(&self, path: &str) -> std::io::Result<Option<Self::Reader>>;
+    /// Like [Storage::read_stream], but reading from the byte `start`, without going through
+    /// the ones before it. Only the bytes up to `end`, included, are guaranteed to be read,
+    /// so callers must not read past it. The range must be within the file.
+    async fn read_range(
+        &self,
+        path: &str,
+        start: u64,
+        end: u64,
+    ) -> std::io::Result<Option<Self::Reader>>;
+    /// The metadata of a file or directory, none if it does not exist.
+    async fn metadata(&self, path: &str) -> std::io::Result<Option<Metadata>>;
+    /// Replaces the content of a file atomically, readers get either the previous content
+    /// or the new one but never a half-written file, even if MusicGPT crashes meanwhile.
     async fn write(&self, path: &str, content: impl AsRef<[u8]> + Send) -> std::io::Result<()>;
     async fn create(&self, path: &str) -> std::io::Result<Self::File>;
+    /// Opens a file for writing at its end, creating it if it does not exist.
+    async fn append(&self, path: &str) -> std::io::Result<Self::File>;
     async fn list(&self, path: &str) -> std::io::Result<Vec<String>>;
     async fn mv(&self, from: &str, to: &str) -> std::io::Result<()>;
     async fn rm(&self, path: &str) -> std::io::Result<bool>;
@@ -27,16 +60,38 @@ pub trait Storage: Sync + Send + Clone + 'static {
     fn path_buf(&self, path: &str) -> PathBuf {
         PathBuf::from(path)
     }
+    /// The size in bytes of a file, none if it does not exist.
+    async fn len(&self, path: &str) -> std::io::Result<Option<u64>> {
+        Ok(self.metadata(path).await?.map(|v| v.size))
+    }
+    /// Whether the storage can only be read, failing all the operations that modify it.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+    /// Whether the files are in the local disk, at [Storage::path_buf]. Otherwise, they
+    /// can only be accessed through this trait.
+    fn is_local(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use tokio::io::AsyncReadExt;
+
     use super::*;
 
     pub async fn test_storage<S: Storage>(s: S) -> std::io::Result<()> {
         // it should create a file
         s.write("foo/bar.txt", "test content").await?;
 
+        // it should not leave temporary files next to it
+        assert_eq!(s.list("foo").await?, vec!["foo/bar.txt"]);
+
+        // it should replace the content of existing files
+        s.write("foo/bar.txt", "old content").await?;
+        s.write("foo/bar.txt", "test content").await?;
+
         // it should very that it exists
         assert!(s.exists("foo/bar.txt").await?);
         // it should return false if file does not exist
@@ -62,6 +117,43 @@ mod tests {
         let content = content.unwrap();
         assert_eq!(String::from_utf8_lossy(&content), "test content");
 
+        // it should append to files
+        let mut file = s.append("foo/appended.txt").await?;
+        file.write_all(b"foo").await?;
+        drop(file);
+        let mut file = s.append("foo/appended.txt").await?;
+        file.write_all(b"bar").await?;
+        file.flush().await?;
+        let content = s.read("foo/appended.txt").await?.unwrap();
+        assert_eq!(String::from_utf8_lossy(&content), "foobar");
+        assert_eq!(s.len("foo/appended.txt").await?, Some(6));
+        assert_eq!(s.len("foo/NON_EXISTING.txt").await?, None);
+
+        // it should stat files and directories
+        let metadata = s.metadata("foo/appended.txt").await?.unwrap();
+        assert_eq!(metadata.size, 6);
+        assert!(!metadata.is_dir);
+        assert!(metadata.modified.elapsed().unwrap_or_default().as_secs() < 60);
+        assert!(s.metadata("foo").await?.unwrap().is_dir);
+        assert!(s.metadata("foo/NON_EXISTING.txt").await?.is_none());
+
+        // it should read files in chunks
+        let mut reader = s.read_stream("foo/appended.txt").await?.unwrap();
+        let mut chunk = [0; 3];
+        reader.read_exact(&mut chunk).await?;
+        assert_eq!(&chunk, b"foo");
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).await?;
+        assert_eq!(rest, b"bar");
+        assert!(s.read_stream("foo/NON_EXISTING.txt").await?.is_none());
+
+        // it should read ranges of files
+        let mut reader = s.read_range("foo/appended.txt", 2, 4).await?.unwrap();
+        let mut chunk = [0; 3];
+        reader.read_exact(&mut chunk).await?;
+        assert_eq!(&chunk, b"oba");
+        assert!(s.read_range("foo/NON_EXISTING.txt", 0, 1).await?.is_none());
+
         // it should list files
         for i in 0..3 {
             let mut file = s.create(&format!("list/{i}.txt")).await?;
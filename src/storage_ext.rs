@@ -1,52 +1,193 @@
 use std::collections::VecDeque;
-use std::error;
 use std::fmt::{Display, Write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use async_trait::async_trait;
+use axum::http::header::RANGE;
 use axum::http::StatusCode;
 use futures_util::StreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
-use log::info;
-use tokio::io::AsyncWriteExt;
+use log::{info, warn};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
 
 use crate::storage::Storage;
 
+/// How failed downloads are retried. Only the errors that might go away by trying again,
+/// like network drops or server errors, are retried, and the downloads resume from where
+/// they failed.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// How many times in a row a download is retried before giving up. Attempts that get
+    /// further than the previous ones reset the count, as resuming is making progress.
+    pub retries: u32,
+    /// How long to wait before the first retry, doubled for each following one.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Fraction of the backoff, from 0 to 1, that is randomly shaved off for each retry,
+    /// so that parallel downloads do not retry all at once.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before the `retry`th retry, starting at 1.
+    fn delay(&self, retry: u32) -> Duration {
+        let exp = 2_u32.saturating_pow(retry.saturating_sub(1));
+        let backoff = self.backoff.saturating_mul(exp).min(self.max_backoff);
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::thread_rng().gen::<f64>();
+        backoff.mul_f64(1.0 - jitter)
+    }
+}
+
+/// How the remote files are fetched from the network.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkOptions {
+    pub retry: RetryPolicy,
+    /// Maximum bytes per second downloaded, among all the files downloaded at once.
+    pub max_rate: Option<u64>,
+}
+
+/// Limits the rate at which bytes are downloaded, shared by all the parallel downloads.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rate: u64,
+    /// When the bytes downloaded so far would have been downloaded at the maximum rate.
+    next: Arc<Mutex<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            next: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Waits until `bytes` more can be downloaded without going over the rate.
+    async fn consume(&self, bytes: usize) {
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+            start
+        };
+        tokio::time::sleep_until(start).await
+    }
+}
+
+/// Whether a download that failed with `err` might succeed by trying again.
+fn is_transient(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        err.kind(),
+        Other
+            // Checksum mismatches, the file might have been corrupted on the way.
+            | InvalidData
+            | UnexpectedEof
+            | TimedOut
+            | Interrupted
+            | ConnectionReset
+            | ConnectionAborted
+            | ConnectionRefused
+            | BrokenPipe
+    )
+}
+
+fn status_err(url: &str, status_code: StatusCode) -> std::io::Error {
+    use std::io::ErrorKind::*;
+    let kind = match status_code {
+        StatusCode::NOT_FOUND | StatusCode::GONE => NotFound,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => PermissionDenied,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => Other,
+        _ if status_code.is_server_error() => Other,
+        _ => InvalidInput,
+    };
+    std::io::Error::new(
+        kind,
+        format!("Error downloading {url}. Invalid status code {status_code}"),
+    )
+}
+
 #[async_trait]
 pub trait StorageExt: Storage
 {
-    async fn download_many<T: Display + Send + Sync + 'static>(
+    /// Downloads the files in `remote_file_spec`, as (remote url, local file, SHA-256)
+    /// tuples. Files without a known SHA-256 are verified against the one reported by the
+    /// server, if any.
+    async fn download_many<R: Display + Send + Sync + 'static, L: Display + Send + Sync + 'static>(
         &self,
-        remote_file_spec: Vec<(T, T)>,
+        remote_file_spec: Vec<(R, L, Option<&'static str>)>,
         force_download: bool,
+        network: &NetworkOptions,
         on_download_msg: &str,
         on_finished_msg: &str,
     ) -> anyhow::Result<VecDeque<PathBuf>> {
         let mut has_to_download = force_download;
-        for (_, local_filename) in remote_file_spec.iter() {
+        for (_, local_filename, _) in remote_file_spec.iter() {
             has_to_download = has_to_download || !self.exists(&local_filename.to_string()).await?
         }
 
         if has_to_download {
             info!("{on_download_msg}");
         }
+        let retry = network.retry;
+        let limiter = network.max_rate.map(RateLimiter::new);
         let m = MultiProgress::new();
         let mut tasks = vec![];
-        for (remote_file, local_filename) in remote_file_spec {
+        for (remote_file, local_filename, sha256) in remote_file_spec {
             let remote_file = remote_file.to_string();
             let local_filename = local_filename.to_string();
             let bar = m.add(download_bar(&local_filename));
             let this = self.clone();
+            let limiter = limiter.clone();
             tasks.push(tokio::spawn(async move {
-                this.fetch_remote_data_file(
-                    &remote_file,
-                    &local_filename,
-                    force_download,
-                    Box::new(move |el, t| {
-                        bar.set_length(t as u64);
-                        bar.set_position(el as u64);
-                    }),
-                )
-                .await
+                let mut retries = 0;
+                let furthest = Arc::new(AtomicUsize::new(0));
+                loop {
+                    let bar = bar.clone();
+                    let attempt_furthest = furthest.clone();
+                    let previous_furthest = furthest.load(Ordering::Relaxed);
+                    let result = this.fetch_remote_data_file(
+                        &remote_file,
+                        &local_filename,
+                        sha256,
+                        force_download,
+                        limiter.as_ref(),
+                        Box::new(move |el, t| {
+                            attempt_furthest.fetch_max(el, Ordering::Relaxed);
+                            bar.set_length(t as u64);
+                            bar.set_position(el as u64);
+                        }),
+                    )
+                    .await;
+                    if furthest.load(Ordering::Relaxed) > previous_furthest {
+                        retries = 0;
+                    }
+                    match result {
+                        Err(err) if retries < retry.retries && is_transient(&err) => {
+                            retries += 1;
+                            let delay = retry.delay(retries);
+                            warn!("{err}, retrying in {:.1}s", delay.as_secs_f64());
+                            tokio::time::sleep(delay).await;
+                        }
+                        result => break result,
+                    }
+                }
             }));
         }
         let mut results = VecDeque::new();
@@ -67,7 +208,9 @@ pub trait StorageExt: Storage
     ///
     /// * `url`: The URL of the remote file
     /// * `file_name`: The filename in the local data directory
+    /// * `sha256`: The expected SHA-256 of the file, if known beforehand
     /// * `force`: Force the download even if the file exists
+    /// * `limiter`: Limits the download rate, if set
     /// * `cbk`: A callback for tracking progress of the download (elapsed, total)
     ///
     /// returns: Result<PathBuf, Error>
@@ -75,7 +218,9 @@ pub trait StorageExt: Storage
         &self,
         url: &str,
         local_file: &str,
+        sha256: Option<&str>,
         force: bool,
+        limiter: Option<&RateLimiter>,
         cbk: Cb,
     ) -> std::io::Result<PathBuf> {
         // At this point, the file might already exist on disk, so nothing else to do.
@@ -84,32 +229,91 @@ pub trait StorageExt: Storage
         }
 
         // If the file was not in disk, we need to download it.
-        let resp = reqwest::get(url).await.map_err(io_err)?;
-        let status_code = resp.status();
-        if status_code != StatusCode::OK {
-            return Err(io_err(format!(
-                "Error downloading {url}. Invalid status code {status_code}"
-            )));
+        let expected_digest = match sha256 {
+            Some(sha256) => Some(sha256.to_string()),
+            None => remote_sha256(url).await,
+        };
+        if expected_digest.is_none() {
+            warn!("No SHA-256 is known for {url}, its download cannot be verified");
         }
-        let total_bytes = resp.content_length().unwrap_or_default() as usize;
 
-        // The file will be first downloaded to a temporary file, to avoid corruptions.
+        // The file will be first downloaded to a temporary file, to avoid corruptions. If
+        // a previous download was interrupted, it continues from where it was left.
         let temp_file = format!("{local_file}.temp");
-        let mut file = self.create(&temp_file).await?;
+        let mut offset = self.len(&temp_file).await?.unwrap_or_default();
+        let client = reqwest::Client::new();
+        let mut resp = client
+            .get(url)
+            .header(RANGE, format!("bytes={offset}-"))
+            .send()
+            .await
+            .map_err(io_err)?;
+        if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            // The partial file is as big as, or bigger than, the whole file, so it cannot
+            // be trusted.
+            offset = 0;
+            resp = client.get(url).send().await.map_err(io_err)?;
+        }
+        let status_code = resp.status();
+        let (mut file, mut hasher) = match status_code {
+            StatusCode::PARTIAL_CONTENT if offset > 0 => {
+                info!("Resuming the download of {local_file}");
+                let hasher = self.hash_file(&temp_file).await?.unwrap_or_default();
+                (self.append(&temp_file).await?, hasher)
+            }
+            // The server does not support resuming downloads, so it starts from scratch.
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT => {
+                offset = 0;
+                (self.create(&temp_file).await?, Sha256::new())
+            }
+            _ => return Err(status_err(url, status_code)),
+        };
+        let offset = offset as usize;
+        let total_bytes = match resp.content_length() {
+            Some(len) => offset + len as usize,
+            None => 0,
+        };
 
         // Stream the HTTP response to the file stream.
         let mut stream = resp.bytes_stream();
-        let mut downloaded_bytes = 0;
+        let mut downloaded_bytes = offset;
         while let Some(item) = stream.next().await {
             match item {
                 Ok(chunk) => {
+                    if let Some(limiter) = limiter {
+                        limiter.consume(chunk.len()).await;
+                    }
                     downloaded_bytes += chunk.len();
                     cbk(downloaded_bytes, total_bytes);
+                    hasher.update(&chunk);
                     file.write_all(&chunk).await?
                 }
                 Err(err) => return Err(io_err(err)),
             }
         }
+        file.flush().await?;
+        if total_bytes > 0 && downloaded_bytes != total_bytes {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Download of {url} was truncated, got {downloaded_bytes} of {total_bytes} bytes"
+                ),
+            ));
+        }
+        let digest = to_hex(&hasher.finalize());
+        if let Some(expected) = expected_digest {
+            if expected != digest {
+                self.rm(&temp_file).await?;
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Checksum mismatch downloading {url}, expected {expected} but got {digest}"
+                    ),
+                ));
+            }
+        }
+        // Recorded for verifying the file later, as it might get corrupted on disk.
+        self.write(&digest_file(local_file), &digest).await?;
 
         // If everything succeeded, we are fine to promote the newly stored temporary
         // file to the actual destination.
@@ -117,6 +321,58 @@ pub trait StorageExt: Storage
 
         Ok(self.path_buf(local_file))
     }
+
+    /// Checks a downloaded file against the SHA-256 digest recorded when it was downloaded.
+    /// Files that do not exist, or that were not downloaded by MusicGPT, are assumed to be
+    /// fine.
+    async fn verify_file(&self, local_file: &str) -> std::io::Result<bool> {
+        let Some(expected) = self.read(&digest_file(local_file)).await? else {
+            return Ok(true);
+        };
+        let Some(hasher) = self.hash_file(local_file).await? else {
+            return Ok(true);
+        };
+        let digest = to_hex(&hasher.finalize());
+        Ok(digest.as_bytes() == expected.trim_ascii())
+    }
+
+    /// Feeds the contents of a file to a SHA-256 hasher, none if the file does not exist.
+    async fn hash_file(&self, local_file: &str) -> std::io::Result<Option<Sha256>> {
+        let Some(mut reader) = self.read_stream(local_file).await? else {
+            return Ok(None);
+        };
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 1 << 20];
+        loop {
+            match reader.read(&mut buf).await? {
+                0 => break,
+                n => hasher.update(&buf[..n]),
+            }
+        }
+        Ok(Some(hasher))
+    }
+}
+
+fn digest_file(local_file: &str) -> String {
+    format!("{local_file}.sha256")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hugging Face reports the SHA-256 digest of the files stored with Git LFS, like the
+/// models, in the X-Linked-Etag header of the redirect to the actual file.
+async fn remote_sha256(url: &str) -> Option<String> {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .ok()?;
+    let resp = client.head(url).send().await.ok()?;
+    let etag = resp.headers().get("x-linked-etag")?.to_str().ok()?;
+    let etag = etag.trim_matches('"').to_lowercase();
+    let is_sha256 = etag.len() == 64 && etag.chars().all(|c| c.is_ascii_hexdigit());
+    is_sha256.then_some(etag)
 }
 
 pub fn download_bar(file: &str) -> ProgressBar {
@@ -149,11 +405,17 @@ pub fn download_bar(file: &str) -> ProgressBar {
 
 impl<T: Storage + 'static> StorageExt for T {}
 
-fn io_err<E>(e: E) -> std::io::Error
-where
-    E: Into<Box<dyn error::Error + Send + Sync>>,
-{
-    std::io::Error::new(std::io::ErrorKind::Other, e)
+fn io_err(err: reqwest::Error) -> std::io::Error {
+    // Requests that cannot even be built, like the ones with invalid URLs, or that redirect
+    // in a loop fail the same way every time.
+    let kind = if err.is_builder() || err.is_redirect() {
+        std::io::ErrorKind::InvalidInput
+    } else if err.is_timeout() {
+        std::io::ErrorKind::TimedOut
+    } else {
+        std::io::ErrorKind::Other
+    };
+    std::io::Error::new(kind, err)
 }
 
 #[cfg(test)]
@@ -163,9 +425,11 @@ mod tests {
     use std::path::Path;
     use std::time::SystemTime;
 
-    use crate::storage::AppFs;
+    use crate::storage::{AppFs, Storage};
     use crate::storage_ext::StorageExt;
 
+    use super::*;
+
     fn rand_string() -> String {
         thread_rng()
             .sample_iter(&Alphanumeric)
@@ -174,6 +438,110 @@ mod tests {
             .collect()
     }
 
+    #[test]
+    fn retries_only_transient_errors() {
+        let url = "https://huggingface.co/foo";
+        assert!(is_transient(&status_err(url, StatusCode::BAD_GATEWAY)));
+        assert!(is_transient(&status_err(url, StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!is_transient(&status_err(url, StatusCode::NOT_FOUND)));
+        assert!(!is_transient(&status_err(url, StatusCode::FORBIDDEN)));
+        assert!(is_transient(&std::io::Error::other("connection closed")));
+        let invalid_url = reqwest::Client::new().get("not a url").build().unwrap_err();
+        assert!(!is_transient(&io_err(invalid_url)));
+        let mismatch = std::io::Error::new(std::io::ErrorKind::InvalidData, "Checksum mismatch");
+        assert!(is_transient(&mismatch));
+
+        let retry = RetryPolicy {
+            retries: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.0,
+        };
+        let delays: Vec<u64> = (1..=4).map(|i| retry.delay(i).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5]);
+        let retry = RetryPolicy { jitter: 0.5, ..retry };
+        let delay = retry.delay(2);
+        assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn limits_download_rate() {
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.consume(100).await;
+        }
+        // The first chunk is not delayed.
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn verifies_downloaded_files() -> std::io::Result<()> {
+        let app_fs = AppFs::new(format!("/tmp/verifies_downloaded_files_test/{}", rand_string()));
+        app_fs.write("model.onnx", "foo").await?;
+        // Files that were not downloaded cannot be verified.
+        assert!(app_fs.verify_file("model.onnx").await?);
+
+        let digest = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+        app_fs.write("model.onnx.sha256", digest).await?;
+        assert!(app_fs.verify_file("model.onnx").await?);
+
+        app_fs.write("model.onnx", "fo").await?;
+        assert!(!app_fs.verify_file("model.onnx").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_retrying_while_downloads_progress() -> anyhow::Result<()> {
+        use axum::body::{Body, Bytes};
+        use axum::http::HeaderMap;
+        use axum::routing::get;
+
+        // Drops the connection after sending two bytes of the requested range.
+        const CONTENT: &[u8] = b"0123456789";
+        let app = axum::Router::new().route(
+            "/model.onnx",
+            get(|headers: HeaderMap| async move {
+                let range = headers.get(RANGE).and_then(|v| v.to_str().ok());
+                let start = range
+                    .and_then(|v| v.strip_prefix("bytes=")?.strip_suffix('-')?.parse().ok())
+                    .unwrap_or(0);
+                let end = (start + 2).min(CONTENT.len());
+                let chunk = Bytes::from_static(&CONTENT[start..end]);
+                let drop = async {
+                    // Gives some time for the chunk to be sent before dropping the connection.
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Err(std::io::Error::other("connection dropped"))
+                };
+                let chunks = futures_util::stream::once(async { Ok(chunk) })
+                    .chain(futures_util::stream::once(drop))
+                    .take(if end < CONTENT.len() { 2 } else { 1 });
+                let body = Body::from_stream(chunks);
+                (StatusCode::PARTIAL_CONTENT, body)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let app_fs = AppFs::new(format!("/tmp/keeps_retrying_test/{}", rand_string()));
+        let network = NetworkOptions {
+            retry: RetryPolicy {
+                retries: 1,
+                backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                jitter: 0.0,
+            },
+            max_rate: None,
+        };
+        let url = format!("http://{addr}/model.onnx");
+        let files = vec![(url, "model.onnx", None)];
+        app_fs.download_many(files, false, &network, "", "").await?;
+        assert_eq!(app_fs.read("model.onnx").await?.unwrap(), CONTENT);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn downloads_remote_file() -> std::io::Result<()> {
         let remote_file = "https://raw.githubusercontent.com/seanmonstar/reqwest/master/README.md";
@@ -183,13 +551,13 @@ mod tests {
 
         let time = SystemTime::now();
         app_fs
-            .fetch_remote_data_file(remote_file, &file_name, false, |_, _| {})
+            .fetch_remote_data_file(remote_file, &file_name, None, false, None, |_, _| {})
             .await?;
         let download_elapsed = SystemTime::now().duration_since(time).unwrap().as_micros();
 
         let time = SystemTime::now();
         app_fs
-            .fetch_remote_data_file(remote_file, &file_name, false, |_, _| {})
+            .fetch_remote_data_file(remote_file, &file_name, None, false, None, |_, _| {})
             .await?;
         let cached_elapsed = SystemTime::now().duration_since(time).unwrap().as_micros();
 
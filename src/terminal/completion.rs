@@ -0,0 +1,134 @@
+use rustyline::completion::Completer;
+use rustyline::hint::HistoryHinter;
+use rustyline::{Context, Helper, Highlighter, Hinter, Validator};
+
+/// Words that MusicGen understands well, suggested when pressing Tab.
+const KEYWORDS: &[&str] = &[
+    // genres
+    "ambient",
+    "blues",
+    "bossa nova",
+    "classical",
+    "country",
+    "disco",
+    "drum and bass",
+    "dubstep",
+    "edm",
+    "folk",
+    "funk",
+    "hip hop",
+    "house",
+    "jazz",
+    "lofi",
+    "metal",
+    "pop",
+    "punk",
+    "reggae",
+    "rock",
+    "soul",
+    "synthwave",
+    "techno",
+    "trance",
+    "trap",
+    // instruments
+    "acoustic guitar",
+    "bass",
+    "cello",
+    "drums",
+    "electric guitar",
+    "flute",
+    "harp",
+    "organ",
+    "piano",
+    "saxophone",
+    "strings",
+    "synth",
+    "trumpet",
+    "violin",
+    // moods
+    "calm",
+    "dark",
+    "dreamy",
+    "energetic",
+    "epic",
+    "happy",
+    "melancholic",
+    "relaxing",
+    "romantic",
+    "sad",
+    "upbeat",
+];
+
+/// The options that can be typed after a prompt, and the commands.
+const OPTIONS: &[&str] = &["--secs", "--output", "--format", "--playback"];
+const COMMANDS: &[&str] = &["/next", "/prev", "/playlist", "/settings"];
+
+/// Completes keywords, options and commands with Tab, and hints previous prompts that
+/// start with what was typed, which are accepted with the right arrow.
+#[derive(Helper, Highlighter, Validator, Hinter)]
+pub struct PromptHelper {
+    #[rustyline(Hinter)]
+    hinter: HistoryHinter,
+}
+
+impl Default for PromptHelper {
+    fn default() -> Self {
+        Self {
+            hinter: HistoryHinter::new(),
+        }
+    }
+}
+
+impl Completer for PromptHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete(line, pos))
+    }
+}
+
+/// The position in which the word being typed at `pos` starts, and its completions.
+fn complete(line: &str, pos: usize) -> (usize, Vec<String>) {
+    let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+    let word = &line[start..pos];
+    if word.is_empty() {
+        return (start, vec![]);
+    }
+    let words = if start == 0 && word.starts_with('/') {
+        COMMANDS
+    } else if word.starts_with("--") {
+        OPTIONS
+    } else {
+        KEYWORDS
+    };
+    let word = word.to_lowercase();
+    let candidates = words
+        .iter()
+        .filter(|v| v.starts_with(&word))
+        .map(|v| v.to_string())
+        .collect();
+    (start, candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_the_word_being_typed() {
+        assert_eq!(complete("relaxing lo", 11), (9, vec!["lofi".to_string()]));
+        assert_eq!(
+            complete("Sa beat", 2),
+            (0, vec!["saxophone".to_string(), "sad".to_string()])
+        );
+        assert_eq!(complete("jazz --s", 8), (5, vec!["--secs".to_string()]));
+        assert_eq!(complete("/se", 3), (0, vec!["/settings".to_string()]));
+        assert_eq!(complete("jazz ", 5), (5, vec![]));
+        assert_eq!(complete("zzz", 3), (0, vec![]));
+    }
+}
@@ -1,87 +1,278 @@
+use clap::builder::RangedU64ValueParser;
+use clap::Parser;
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use regex::Regex;
 use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
-use std::fmt::Write;
-use std::path::PathBuf;
-use std::str::FromStr;
+use rustyline::history::DefaultHistory;
+use rustyline::{Cmd, Config, Editor, KeyEvent};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::warn;
 
-use crate::audio::{AudioManager, AudioStream};
-use crate::backend::JobProcessor;
+use crate::audio::{AudioFile, AudioManager, AudioStream, Playlist};
+use crate::backend::{
+    GenerationMetadata, GenerationParams, GenerationStage, GenerationTimings, JobProcessor,
+};
+use crate::cli::{SampleFormat, Seed};
+use crate::debug_bundle;
+use crate::reveal::reveal;
+use crate::terminal::completion::PromptHelper;
+
+mod completion;
+mod session;
+
+pub use session::SavedSession;
+
+/// Maximum prompts kept in the history file, the oldest ones are forgotten.
+const HISTORY_SIZE: usize = 1000;
 
 pub struct RunTerminalOptions {
+    /// The name of the model, shown by the /settings command.
+    pub model: String,
+    /// The device in which the model runs, shown by the /settings command.
+    pub device: String,
+    /// The model as given to --model, written to the metadata of the generated audios.
+    pub model_id: String,
     pub init_prompt: String,
     pub init_secs: usize,
+    pub init_seed: Seed,
     pub init_output: String,
     pub no_playback: bool,
     pub no_interactive: bool,
+    /// Shows a desktop notification when each generation finishes or fails.
+    pub notify: bool,
+    /// Writes the parameters of each generation to a .json file next to its .wav file.
+    pub metadata: bool,
+    /// Shows each generated file in the file manager.
+    pub reveal: bool,
+    pub audio_manager: AudioManager,
+}
+
+/// The options that can follow a prompt in interactive mode, like
+/// `lofi beat --secs 20 --output "my song.wav"`. They apply to the rest of the prompts.
+#[derive(Parser, Debug, Default, PartialEq)]
+#[command(no_binary_name = true, disable_help_flag = true, disable_version_flag = true)]
+struct InlineArgs {
+    /// The seconds of audio to generate.
+    #[arg(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..=30))]
+    secs: Option<usize>,
+    /// Output path for the resulting .wav file.
+    #[arg(long)]
+    output: Option<String>,
+    /// The format of the samples in the resulting .wav file.
+    #[arg(long)]
+    format: Option<SampleFormat>,
+    /// Whether to play the generated audios.
+    #[arg(long)]
+    playback: Option<bool>,
+    /// The seed for sampling the audio, for generating the same audio again, or `random`.
+    #[arg(long)]
+    seed: Option<Seed>,
+}
+
+/// The parameters of the generations in interactive mode, which are changed for the rest of
+/// the session by the options typed after a prompt or after the /settings command.
+struct Settings {
+    model: String,
+    device: String,
+    secs: usize,
+    output: String,
+    format: cpal::SampleFormat,
+    playback: bool,
+    seed: Seed,
+}
+
+impl Settings {
+    fn apply(&mut self, args: InlineArgs) {
+        if let Some(secs) = args.secs {
+            self.secs = secs;
+        }
+        if let Some(output) = args.output {
+            self.output = output;
+        }
+        if let Some(format) = args.format {
+            self.format = format.into();
+        }
+        if let Some(playback) = args.playback {
+            self.playback = playback;
+        }
+        if let Some(seed) = args.seed {
+            self.seed = seed;
+        }
+    }
+}
+
+impl Display for Settings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "model      {}", self.model)?;
+        writeln!(f, "device     {}", self.device)?;
+        writeln!(f, "--secs     {}", self.secs)?;
+        writeln!(f, "--output   {}", self.output)?;
+        writeln!(f, "--format   {}", self.format)?;
+        writeln!(f, "--playback {}", self.playback)?;
+        write!(f, "--seed     {}", self.seed)
+    }
+}
+
+/// Splits a line typed in interactive mode into the prompt and the options after it. The
+/// prompt is kept as typed, and the options are split like in a shell, so values can be
+/// quoted.
+fn parse_line(line: &str) -> anyhow::Result<(String, InlineArgs)> {
+    let start = line
+        .match_indices("--")
+        .map(|(i, _)| i)
+        .find(|i| *i == 0 || line[..*i].ends_with(char::is_whitespace));
+    let Some(start) = start else {
+        return Ok((line.trim().to_string(), InlineArgs::default()));
+    };
+    let words = shell_words::split(&line[start..])?;
+    let args = InlineArgs::try_parse_from(words)?;
+    Ok((line[..start].trim().to_string(), args))
 }
 
+/// Runs the CLI mode. If `compare` is provided, every prompt is also generated with that
+/// model, for comparing both.
 pub async fn run_terminal_loop<T: JobProcessor>(
     root: PathBuf,
     processor: T,
+    compare: Option<(String, T)>,
     opts: RunTerminalOptions,
 ) -> anyhow::Result<()> {
-    let secs_re = Regex::new("--secs[ =](\\d+)")?;
-    let output_re = Regex::new(r"--output[ =]([.a-zA-Z_-]+)")?;
-
-    let audio_player = AudioManager::default();
+    let audio_player = opts.audio_manager;
+    // Generated audios are queued in this playlist, which is played gaplessly through
+    // a single output stream.
+    let playlist = Playlist::default();
     // This variable holds the audio stream. The stream stops when this is dropped,
-    // so we need to maintain it referenced here.
-    #[allow(unused_variables)]
+    // so we need to maintain it referenced here. It's lazily opened the first time
+    // something needs to be played.
     let mut curr_stream: Option<AudioStream> = None;
     let mut prompt = opts.init_prompt;
-    let mut secs = opts.init_secs;
-    let mut output = opts.init_output;
+    let mut settings = Settings {
+        model: opts.model,
+        device: opts.device,
+        secs: opts.init_secs,
+        output: opts.init_output,
+        format: audio_player.sample_format(),
+        playback: !opts.no_playback,
+        seed: opts.init_seed,
+    };
 
-    let mut rl = DefaultEditor::new()?;
-    let _ = rl.load_history(&root.join("history.txt"));
+    let config = Config::builder()
+        .max_history_size(HISTORY_SIZE)?
+        .history_ignore_dups(true)?
+        .history_ignore_space(true)
+        .build();
+    let mut rl = Editor::<PromptHelper, DefaultHistory>::with_config(config)?;
+    rl.set_helper(Some(PromptHelper::default()));
+    // Ctrl+R searches backwards through the previous prompts as they are typed.
+    rl.bind_sequence(KeyEvent::ctrl('R'), Cmd::ReverseSearchHistory);
+    let history_file = root.join("history.txt");
+    let _ = rl.load_history(&history_file);
     let _ = rl.add_history_entry(&prompt);
+    if !opts.no_interactive {
+        save_session(&root, &opts.model_id, &settings);
+    }
     loop {
         if prompt.is_empty() {
-            prompt = match rl.readline(">>> ") {
+            let line = match rl.readline(">>> ") {
                 Ok(line) => line,
                 Err(ReadlineError::Interrupted) => return Ok(()),
                 Err(ReadlineError::Eof) => return Ok(()),
                 Err(err) => return Err(anyhow::anyhow!(err)),
             };
-            secs = capture(&secs_re, &prompt).unwrap_or(secs);
-            output = capture(&output_re, &prompt).unwrap_or(output);
+            if let Ok(true) = rl.add_history_entry(&line) {
+                let _ = rl.save_history(&history_file);
+            }
+            match parse_line(&line) {
+                Ok((line_prompt, args)) => {
+                    prompt = line_prompt;
+                    settings.apply(args);
+                    save_session(&root, &opts.model_id, &settings);
+                }
+                Err(err) => {
+                    println!("{err}");
+                    continue;
+                }
+            };
         }
         if prompt.is_empty() {
             continue;
         }
-        let _ = rl.add_history_entry(&prompt);
-
         if prompt == "exit" {
             return Ok(());
         }
 
-        let bar = fixed_bar("Generating audio", 1);
-        let samples = processor.process(
-            &prompt,
-            secs,
-            Box::new(move |elapsed, total| {
-                bar.set_length(total as u64);
-                bar.set_position(elapsed as u64);
-                false
-            }),
-        )?;
-
-        // Last, play the audio.
-        if !opts.no_playback {
-            let samples_copy = samples.clone();
-            let stream = audio_player.play_from_queue(samples_copy);
-            #[allow(unused_assignments)]
-            if let Ok(stream) = stream {
-                curr_stream = Some(stream);
+        if let Some(command) = prompt.strip_prefix('/') {
+            match command.trim() {
+                "settings" => println!("{settings}"),
+                command => run_playlist_command(&playlist, command),
             }
+            prompt = "".into();
+            continue;
+        }
+
+        debug_bundle::set_last_request(&prompt, settings.secs);
+        if !settings.output.ends_with(".wav") {
+            settings.output += ".wav";
+        }
+        let mut runs = vec![(opts.model_id.as_str(), &processor)];
+        if let Some((model_id, processor)) = &compare {
+            runs.push((model_id.as_str(), processor));
+        }
+        // A random seed is chosen here rather than by the sampler, so that it's written to
+        // the metadata and the audio can be generated again.
+        let seed = settings.seed.value().unwrap_or_else(rand::random);
+        let mut outputs = vec![];
+        for (model_id, processor) in runs {
+            let (samples, timings) = match generate(processor, &prompt, settings.secs, seed) {
+                Ok((samples, timings)) => {
+                    println!("Generated in {timings}");
+                    (samples, timings)
+                }
+                Err(err) => {
+                    if opts.notify {
+                        notify("Generation failed", &err.to_string());
+                    }
+                    return Err(err.into());
+                }
+            };
+
+            let output = if compare.is_some() {
+                comparison_output(&settings.output, model_id)
+            } else {
+                settings.output.clone()
+            };
+            // Last, queue the audio for playing it once the previous ones finished.
+            if settings.playback {
+                playlist.push(&output, samples.clone());
+                if curr_stream.is_none() {
+                    curr_stream = audio_player.play_playlist(playlist.clone()).ok();
+                }
+            }
+            let metadata = GenerationMetadata {
+                seed: Some(seed),
+                ..GenerationMetadata::new(&prompt, model_id, settings.secs, timings)
+            };
+            let bytes = audio_player.to_wav_as(samples, settings.format)?;
+            tokio::fs::write(&output, metadata.embed(bytes)?).await?;
+            if opts.metadata {
+                metadata.write_sidecar(Path::new(&output)).await?;
+            }
+            outputs.push(output);
+        }
+        if opts.reveal {
+            if let Err(err) = reveal(Path::new(&outputs[0])) {
+                warn!("Could not show {} in the file manager: {err}", outputs[0]);
+            }
+        }
+        let outputs = outputs.join(" and ");
+        if compare.is_some() {
+            println!("Compare {outputs}");
         }
-        if !output.ends_with(".wav") {
-            output += ".wav";
+        if opts.notify {
+            notify("Audio generated", &format!("\"{prompt}\" was saved in {outputs}"));
         }
-        let bytes = audio_player.to_wav(samples)?;
-        tokio::fs::write(&output, bytes).await?;
 
         prompt = "".into();
         if opts.no_interactive {
@@ -92,12 +283,131 @@ pub async fn run_terminal_loop<T: JobProcessor>(
     Ok(())
 }
 
+/// Saves the settings of the session, for restoring them in the next one.
+fn save_session(root: &Path, model_id: &str, settings: &Settings) {
+    let session = SavedSession {
+        model: Some(model_id.to_string()),
+        secs: Some(settings.secs),
+        output: Some(settings.output.clone()),
+        format: Some(settings.format.to_string()),
+        playback: Some(settings.playback),
+        seed: Some(settings.seed.to_string()),
+    };
+    if let Err(err) = session.save(root) {
+        warn!("Could not save the settings of the session: {err}");
+    }
+}
+
+/// Generates `secs` seconds of audio based on `prompt`, showing the progress.
+fn generate<T: JobProcessor>(
+    processor: &T,
+    prompt: &str,
+    secs: usize,
+    seed: u64,
+) -> ort::Result<(VecDeque<f32>, GenerationTimings)> {
+    let bar = fixed_bar("Generating audio", 1);
+    processor.process(
+        prompt,
+        secs,
+        &GenerationParams {
+            seed: Some(seed),
+            reference: None,
+        },
+        Box::new(move |stage| {
+            match stage {
+                GenerationStage::TextEncoding => bar.set_prefix("Encoding prompt"),
+                GenerationStage::TokenGeneration { done, total } => {
+                    bar.set_prefix("Generating audio");
+                    bar.set_length(total as u64);
+                    bar.set_position(done as u64);
+                }
+                GenerationStage::EncodecDecoding { .. } => bar.set_prefix("Decoding audio"),
+            }
+            false
+        }),
+        None,
+    )
+}
+
+/// Where the audio generated with `model_id` is written when comparing models, like
+/// `song_small.wav` for `song.wav`.
+fn comparison_output(output: &str, model_id: &str) -> String {
+    let stem = output.strip_suffix(".wav").unwrap_or(output);
+    format!("{stem}_{model_id}.wav")
+}
+
+/// Plays an audio file through `audio_manager`, showing the playback progress and
+/// returning once the whole file has been played.
+pub async fn run_play_file(
+    name: &str,
+    audio: AudioFile,
+    audio_manager: AudioManager,
+) -> anyhow::Result<()> {
+    let duration = Duration::from_secs_f64(audio.samples.len() as f64 / audio.sampling_rate as f64);
+    let playlist = Playlist::default();
+    playlist.push(name, audio.samples);
+    let _stream = audio_manager.play_playlist(playlist.clone())?;
+
+    let bar = fixed_bar(format!("Playing {name}"), duration.as_millis() as usize);
+    let start = Instant::now();
+    while playlist.tracks().1.is_some() {
+        bar.set_position(start.elapsed().min(duration).as_millis() as u64);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    bar.finish();
+    Ok(())
+}
+
+fn run_playlist_command(playlist: &Playlist, command: &str) {
+    match command {
+        "next" => {
+            if !playlist.next() {
+                println!("There is no next track in the playlist");
+            }
+        }
+        "prev" => {
+            if !playlist.prev() {
+                println!("There is no previous track in the playlist");
+            }
+        }
+        "playlist" => {
+            let (tracks, current) = playlist.tracks();
+            for (i, track) in tracks.iter().enumerate() {
+                let marker = if Some(i) == current { ">" } else { " " };
+                println!("{marker} {}. {track}", i + 1);
+            }
+        }
+        _ => println!(
+            "Unknown command /{command}, available commands are /next, /prev, /playlist and \
+            /settings"
+        ),
+    }
+}
+
+/// Shows a desktop notification, for knowing when a generation finished while using other
+/// apps.
+#[cfg(feature = "notifications")]
+fn notify(summary: &str, body: &str) {
+    let result = notify_rust::Notification::new()
+        .appname("MusicGPT")
+        .summary(summary)
+        .body(body)
+        .show();
+    if let Err(err) = result {
+        warn!("Could not show a desktop notification: {err}");
+    }
+}
+
+#[cfg(not(feature = "notifications"))]
+fn notify(summary: &str, _: &str) {
+    warn!("{summary}, but MusicGPT was compiled without the `notifications` feature");
+}
+
 pub fn fixed_bar(prefix: impl Into<String>, len: usize) -> ProgressBar {
     let pb = ProgressBar::new(len as u64);
     pb.set_style(
         ProgressStyle::with_template(
-            &(prefix.into()
-                + " {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] ({eta})"),
+            "{prefix} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] ({eta})",
         )
         .unwrap()
         .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
@@ -105,14 +415,77 @@ pub fn fixed_bar(prefix: impl Into<String>, len: usize) -> ProgressBar {
         })
         .progress_chars("#>-"),
     );
+    pb.set_prefix(prefix.into());
     pb
 }
 
-fn capture<T: FromStr>(re: &Regex, text: &str) -> Option<T> {
-    if let Some(Some(capture)) = re.captures(text).map(|c| c.get(1)) {
-        if let Ok(v) = T::from_str(capture.as_str()) {
-            return Some(v);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_inline_options() -> anyhow::Result<()> {
+        let (prompt, args) = parse_line("80's rock, synth--pads")?;
+        assert_eq!(prompt, "80's rock, synth--pads");
+        assert_eq!(args, InlineArgs::default());
+
+        let (prompt, args) =
+            parse_line("lofi beat 2 --secs 20 --output \"songs/take 2.wav\" --format i16")?;
+        assert_eq!(prompt, "lofi beat 2");
+        assert_eq!(args.secs, Some(20));
+        assert_eq!(args.output.as_deref(), Some("songs/take 2.wav"));
+        assert_eq!(args.format, Some(SampleFormat::I16));
+
+        let (prompt, args) = parse_line("--secs=5")?;
+        assert_eq!(prompt, "");
+        assert_eq!(args.secs, Some(5));
+
+        let (prompt, args) = parse_line("jazz --seed 42")?;
+        assert_eq!(prompt, "jazz");
+        assert_eq!(args.seed, Some(Seed::Fixed(42)));
+
+        assert!(parse_line("jazz --secs five").is_err());
+        assert!(parse_line("jazz --secs 0").is_err());
+        assert!(parse_line("jazz --secs 60").is_err());
+        assert!(parse_line("jazz --seed -1").is_err());
+        assert!(parse_line("jazz --unknown 1").is_err());
+        assert!(parse_line("jazz --output \"unclosed").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn changes_settings() -> anyhow::Result<()> {
+        let mut settings = Settings {
+            model: "MusicGen Small".to_string(),
+            device: "Cpu".to_string(),
+            secs: 10,
+            output: "musicgpt-generated.wav".to_string(),
+            format: cpal::SampleFormat::F32,
+            playback: true,
+            seed: Seed::Random,
+        };
+        let (prompt, args) = parse_line("/settings --playback false --format i32 --seed 7")?;
+        assert_eq!(prompt, "/settings");
+        settings.apply(args);
+        assert_eq!(settings.secs, 10);
+        assert_eq!(settings.format, cpal::SampleFormat::I32);
+        assert!(!settings.playback);
+        assert_eq!(settings.seed, Seed::Fixed(7));
+        assert!(settings
+            .to_string()
+            .ends_with("--format   i32\n--playback false\n--seed     7"));
+
+        settings.apply(parse_line("--seed random")?.1);
+        assert_eq!(settings.seed, Seed::Random);
+        Ok(())
+    }
+
+    #[test]
+    fn names_comparison_outputs() {
+        assert_eq!(comparison_output("song.wav", "small"), "song_small.wav");
+        assert_eq!(
+            comparison_output("songs/take 2.wav", "medium-quant"),
+            "songs/take 2_medium-quant.wav"
+        );
     }
-    None
 }
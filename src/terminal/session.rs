@@ -0,0 +1,69 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+const SESSION_FILE: &str = "session.json";
+
+/// The settings of the last interactive session, which are restored in the next one unless
+/// they are given in the command line or `--fresh` is set.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedSession {
+    /// The model as given to --model.
+    pub model: Option<String>,
+    pub secs: Option<usize>,
+    pub output: Option<String>,
+    /// The sample format as given to --sample-format.
+    pub format: Option<String>,
+    pub playback: Option<bool>,
+    /// The seed as given to --seed, `random` for a random one in each generation.
+    #[serde(default)]
+    pub seed: Option<String>,
+}
+
+impl SavedSession {
+    /// Loads the last session saved in `root`, an empty one if there is none or if it can't
+    /// be read.
+    pub fn load(root: &Path) -> Self {
+        let Ok(content) = std::fs::read(root.join(SESSION_FILE)) else {
+            return Self::default();
+        };
+        serde_json::from_slice(&content).unwrap_or_else(|err| {
+            warn!("Ignoring the settings of the last session: {err}");
+            Self::default()
+        })
+    }
+
+    pub fn save(&self, root: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(root)?;
+        std::fs::write(root.join(SESSION_FILE), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AppFs;
+
+    #[test]
+    fn saves_and_loads_sessions() -> anyhow::Result<()> {
+        let root = AppFs::new_tmp().root;
+        assert_eq!(SavedSession::load(&root), SavedSession::default());
+
+        let session = SavedSession {
+            model: Some("medium".to_string()),
+            secs: Some(20),
+            output: Some("song.wav".to_string()),
+            format: Some("i16".to_string()),
+            playback: Some(false),
+            seed: Some("42".to_string()),
+        };
+        session.save(&root)?;
+        assert_eq!(SavedSession::load(&root), session);
+
+        std::fs::write(root.join(SESSION_FILE), "not json")?;
+        assert_eq!(SavedSession::load(&root), SavedSession::default());
+        Ok(())
+    }
+}
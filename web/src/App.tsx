@@ -10,14 +10,18 @@ import { useChats } from "./backend/useChats.ts";
 import ResponsiveDrawer, { ResponsiveDrawerEntry } from "./components/ResponsiveDrawer.tsx";
 import { ToggleButton } from "./components/ToggleButton.tsx";
 import { useRoutedApp } from "./RoutedAppHooks.ts";
+import { CHATS_URL } from "./backend/useBackend.ts";
+import { DownloadIcon } from "./Icons/DownloadIcon.tsx";
+import { usePartialAudio } from "./backend/usePartialAudio.ts";
 
 function App () {
   const { chatId, goToChat } = useRoutedApp()
   const chatContainerRef = useRef<HTMLDivElement>(null);
   const [drawerOpen, setDrawerOpen] = useState(false)
 
-  const { chats, setChatMetadata } = useChats()
-  const { sendMessage, abortLast, history } = useChat(chatId, goToChat)
+  const { chats, setChatMetadata, loadMoreChats, hasMoreChats } = useChats()
+  const { sendMessage, abortLast, revealEntry, history } = useChat(chatId, goToChat)
+  usePartialAudio(chatId)
 
   useEffect(() => {
     if (chatContainerRef.current) {
@@ -46,6 +50,7 @@ function App () {
         entries={drawerEntries}
         selectedEntry={chatId}
         onSelectEntry={goToChat}
+        onLoadMore={hasMoreChats ? loadMoreChats : undefined}
       />
       <div className="absolute top-0 w-full z-10 flex items-center justify-between px-4 py-2">
         <div className="w-1/3 flex flex-row justify-start">
@@ -53,12 +58,20 @@ function App () {
         </div>
         <StatusIndicator className="m-2 w-fit"/>
         <div className="w-1/3 flex flex-row justify-end">
+          {chatId !== undefined && <a
+            className="p-2 mr-2 rounded-full"
+            href={`${CHATS_URL}/${chatId}/zip`}
+            title="Download chat"
+            download
+          >
+            <DownloadIcon/>
+          </a>}
           <ThemeToggle className="mr-2" onToggle={toggleTheme} theme={theme}/>
         </div>
       </div>
       <div className="overflow-auto px-2" ref={chatContainerRef}>
         <div className="h-20"/>
-        <ChatHistory messages={history?.list ?? []}/>
+        <ChatHistory messages={history?.list ?? []} onReveal={revealEntry}/>
         <div className="h-20"/>
       </div>
       <div className="absolute bottom-0 w-full">
@@ -7,10 +7,11 @@ import { ChatMessage } from "./backend/useChat.ts";
 
 export interface ChatHistoryProps {
   messages: ChatMessage[]
+  onReveal?: (id: string) => void
   className?: string
 }
 
-export function ChatHistory ({ messages, className = '' }: ChatHistoryProps) {
+export function ChatHistory ({ messages, onReveal, className = '' }: ChatHistoryProps) {
   return <div className={`flex-1 flex flex-col max-w-3xl mx-auto ${className}`}>
     {messages.map(msg => {
         const key = msg.type + msg.id
@@ -19,12 +20,16 @@ export function ChatHistory ({ messages, className = '' }: ChatHistoryProps) {
             className={'ml-16 self-end mb-8'}
             key={key}
             text={msg.text}
+            enhancedPrompt={msg.enhancedPrompt}
           />
         } else if (msg.progress < 1) {
           return <AudioGenerating
             className={'mb-8'}
             key={key}
             progress={msg.progress}
+            stage={msg.stage}
+            queuePosition={msg.queuePosition}
+            etaSecs={msg.etaSecs}
           />
         } else if (msg.error !== undefined) {
           return <AudioFailure
@@ -38,6 +43,8 @@ export function ChatHistory ({ messages, className = '' }: ChatHistoryProps) {
             key={key}
             autoPlay={msg.justSucceeded}
             src={msg.url}
+            stats={msg.stats}
+            onReveal={onReveal && (() => onReveal(msg.id))}
           />
         } else {
           return null
@@ -1,34 +1,81 @@
 // This file has been generated by Specta. DO NOT EDIT.
 
-export type AiChatEntry = { id: string; chat_id: string; relpath: string; error: string }
+export type AiChatEntry = { id: string; chat_id: string; relpath: string; error: string; favorite: boolean; rating: number | null; title: string | null; note: string | null; compared_with: string | null; stats: GenerationStats | null }
 
 export type Chat = { chat_id: string; name: string; created_at: number }
 
-export type UserChatEntry = { id: string; chat_id: string; text: string }
+export type UserChatEntry = { id: string; chat_id: string; text: string; enhanced_prompt: string | null }
+
+export type ChatExport = { chat_id: string; url: string }
 
 export type SetChatMetadataRequest = { chat_id: string; name: string | null }
 
-export type AudioGenerationResult = { id: string; chat_id: string; relpath: string }
+export type AudioGenerationResult = { id: string; chat_id: string; relpath: string; requested_by: Client | null; timings: GenerationTimings; stats: GenerationStats }
+
+export type GenerationTimings = { text_encoding_secs: number; token_generation_secs: number; audio_decoding_secs: number }
+
+export type GenerationStats = { wall_secs: number; tokens_per_sec: number | null; peak_rss_bytes: number | null; device: string; seed: number | null }
 
-export type AudioGenerationStart = { id: string; chat_id: string; prompt: string; secs: number }
+export type AudioGenerationStart = { id: string; chat_id: string; prompt: string; enhanced_prompt: string | null; secs: number; requested_by: Client | null }
 
 export type AudioGenerationError = { id: string; chat_id: string; error: string }
 
-export type GenerateAudioRequest = { id: string; chat_id: string; prompt: string; secs: number }
+export type GenerateAudioRequest = { id: string; chat_id: string; prompt: string; secs: number; reference_id: string | null; model: string | null; dedupe: boolean | null; seed: number | null }
+
+export type RegenerateRequest = { id: string; chat_id: string; entry_id: string; secs: number | null; new_seed: number | null }
+
+export type ComparisonSide = { id: string; secs: number; model: string | null }
+
+export type GenerateComparisonRequest = { chat_id: string; new_chat: boolean; prompt: string; a: ComparisonSide; b: ComparisonSide }
+
+export type UploadReferenceRequest = { format: string; data: string }
+
+export type ReferenceAudio = { id: string; relpath: string; secs: number }
+
+export type Schedule = { Once: number } | { Cron: string }
+
+export type ScheduledJob = { id: string; prompt: string; secs: number; schedule: Schedule; next_run: number }
+
+export type ScheduleJobRequest = { prompt: string; secs: number; schedule: Schedule }
+
+export type ScheduledJobRequest = { id: string }
 
-export type GenerationMessage = { Start: AudioGenerationStart } | { Progress: AudioGenerationProgress } | { Error: AudioGenerationError } | { Result: AudioGenerationResult }
+export type JobState = "Pending" | "Running"
+
+export type QueuedJob = { id: string; chat_id: string; prompt: string; secs: number; state: JobState; requested_by: Client | null }
+
+export type EntryRequest = { chat_id: string; id: string }
+
+export type SetEntryMetadataRequest = { chat_id: string; id: string; title: string | null; note: string | null }
+
+export type RateEntryRequest = { chat_id: string; id: string; favorite: boolean | null; rating: number | null }
+
+export type GenerationMessage = { Start: AudioGenerationStart } | { Queued: AudioGenerationQueued } | { Progress: AudioGenerationProgress } | { Error: AudioGenerationError } | { Result: AudioGenerationResult }
 
 export type ChatEntry = { User: UserChatEntry } | { Ai: AiChatEntry }
 
-export type AudioGenerationProgress = { id: string; chat_id: string; progress: number }
+export type AudioGenerationProgress = { id: string; chat_id: string; progress: number; stage: GenerationStage }
+
+export type GenerationStage = "TextEncoding" | { TokenGeneration: { done: number; total: number } } | { EncodecDecoding: { done: number; total: number } }
 
-export type Info = { model: string; device: string }
+export type AudioGenerationQueued = { id: string; chat_id: string; prompt: string; secs: number; position: number; eta_secs: number | null }
 
-export type OutboundMsg = { Generation: GenerationMessage } | { Info: Info } | { Chat: [Chat, ChatEntry[]] } | { Chats: Chat[] } | { Error: string }
+export type Client = { session_id: string; name: string | null }
 
-export type InboundMsg = { GenerateAudioNewChat: GenerateAudioRequest } | { GenerateAudio: GenerateAudioRequest } | { AbortGeneration: AbortGenerationRequest } | { GetChat: ChatRequest } | { SetChatMetadata: SetChatMetadataRequest } | { DelChat: ChatRequest }
+export type Info = { model: string; model_id: string; device: string; protocol_version: number; plugins: string[]; session_id: string }
+
+export type OutboundMsg = { Generation: GenerationMessage } | { Info: Info } | { Chat: [Chat, ChatEntry[]] } | { Chats: ChatsPage } | { RecoveredJobs: AudioGenerationStart[] } | { ChatExport: ChatExport } | { ReferenceUploaded: ReferenceAudio } | { ScheduledJobs: ScheduledJob[] } | { Favorites: AiChatEntry[] } | { Queue: QueuedJob[] } | { Clients: Client[] } | { Error: string }
+
+export type InboundMsg = { GenerateAudioNewChat: GenerateAudioRequest } | { GenerateAudio: GenerateAudioRequest } | { Regenerate: RegenerateRequest } | { GenerateComparison: GenerateComparisonRequest } | { AbortGeneration: AbortGenerationRequest } | "AbortAll" | { GetChat: ChatRequest } | { GetChats: ChatsRequest } | { SetChatMetadata: SetChatMetadataRequest } | { DelChat: ChatRequest } | { ExportChat: ChatRequest } | { UploadReference: UploadReferenceRequest } | { ScheduleJob: ScheduleJobRequest } | "GetScheduledJobs" | { CancelScheduledJob: ScheduledJobRequest } | { RateEntry: RateEntryRequest } | "GetFavorites" | { SetEntryMetadata: SetEntryMetadataRequest } | { DelEntry: EntryRequest } | { RevealEntry: EntryRequest } | "GetQueue"
 
 export type ChatRequest = { chat_id: string }
 
+export type ChatsPage = { chats: Chat[]; cursor: ChatsCursor | null; next_cursor: ChatsCursor | null }
+
+export type ChatsCursor = { created_at: number; chat_id: string }
+
+export type ChatsRequest = { cursor: ChatsCursor | null; limit: number | null }
+
 export type AbortGenerationRequest = { id: string; chat_id: string }
 
+export type Renderer = { name: string; location: string }
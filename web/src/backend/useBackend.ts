@@ -3,8 +3,13 @@ import { useCallback, useEffect, useState } from "react";
 import { InboundMsg, Info, OutboundMsg } from "./bindings.ts";
 
 const BACKEND_URL: string = import.meta.env.VITE_BACKEND_URL ?? window.location.origin
-export const WS_URL = `${BACKEND_URL.replace('http', 'ws')}/ws`
+// Bump together with PROTOCOL_VERSION in the backend's websocket handler.
+const PROTOCOL_VERSION = 1
+// Reconnecting with the same session replays the generation messages missed meanwhile.
+const SESSION_ID = crypto.randomUUID()
+export const WS_URL = `${BACKEND_URL.replace('http', 'ws')}/ws?protocol=${PROTOCOL_VERSION}&session=${SESSION_ID}`
 export const FILES_URL = `${BACKEND_URL}/files`
+export const CHATS_URL = `${BACKEND_URL}/chats`
 
 export function useBackend () {
   const [info, setInfo] = useState<Info>()
@@ -15,6 +20,8 @@ export function useBackend () {
     useWebSocket<OutboundMsg>(WS_URL, {
       share: true,
       retryOnError: true,
+      // Binary messages are partial audios, handled in usePartialAudio.
+      filter: msg => typeof msg.data === 'string',
       shouldReconnect: close => {
         setCloseEvent(close)
         return true
@@ -5,24 +5,32 @@ import { FILES_URL, useBackend } from "./useBackend.ts";
 import {
   AudioGenerationError,
   AudioGenerationProgress,
+  AudioGenerationQueued,
   AudioGenerationResult,
   AudioGenerationStart,
   Chat,
-  ChatEntry
+  ChatEntry,
+  GenerationStage,
+  GenerationStats
 } from './bindings.ts'
 
 export interface UserMessage {
   type: "user";
   id: string;
   text: string;
+  enhancedPrompt?: string;
 }
 
 export interface AiMessage {
   type: "ai";
   id: string;
   progress: number;
+  stage?: GenerationStage;
+  queuePosition?: number;
+  etaSecs?: number;
   url?: string
   error?: string;
+  stats?: GenerationStats;
   justSucceeded: boolean
 }
 
@@ -51,6 +59,9 @@ export function useChat (chat_id: string | undefined, onNewChat: (chat_id: strin
     } else if ('Generation' in last && 'Start' in last.Generation) {
       const msg = last.Generation.Start
       setHistory(prev => prev?.audioGenerationStart(msg))
+    } else if ('Generation' in last && 'Queued' in last.Generation) {
+      const msg = last.Generation.Queued
+      setHistory(prev => prev?.audioGenerationQueued(msg))
     } else if ('Generation' in last && 'Progress' in last.Generation) {
       const msg = last.Generation.Progress
       setHistory(prev => prev?.audioGenerationProgress(msg))
@@ -70,10 +81,10 @@ export function useChat (chat_id: string | undefined, onNewChat: (chat_id: strin
   function sendMessage (prompt: string, secs: number) {
     const id = uuid();
     if (chat_id !== undefined) {
-      send({ GenerateAudio: { id, chat_id, prompt, secs: clamp(1, secs, 30) } });
+      send({ GenerateAudio: { id, chat_id, prompt, secs: clamp(1, secs, 30), reference_id: null, model: null, dedupe: null, seed: null } });
     } else {
       const chat_id = uuid()
-      send({ GenerateAudioNewChat: { id, chat_id, prompt, secs: clamp(1, secs, 30) } })
+      send({ GenerateAudioNewChat: { id, chat_id, prompt, secs: clamp(1, secs, 30), reference_id: null, model: null, dedupe: null, seed: null } })
       setHistory(new ChatHistory(chat_id))
       onNewChat(chat_id)
     }
@@ -87,7 +98,12 @@ export function useChat (chat_id: string | undefined, onNewChat: (chat_id: strin
     }
   }
 
-  return { sendMessage, abortLast, history, chatMetadata }
+  function revealEntry (id: string) {
+    if (chat_id === undefined) return
+    send({ RevealEntry: { id, chat_id } })
+  }
+
+  return { sendMessage, abortLast, revealEntry, history, chatMetadata }
 }
 
 class ChatHistory {
@@ -109,27 +125,63 @@ class ChatHistory {
 
   audioGenerationStart (msg: AudioGenerationStart) {
     if (msg.chat_id != this.chatId) return this
-    if (msg.id in this.userDict) return this
+    if (msg.id in this.userDict) {
+      // Queued jobs already have their message, but the prompt is enhanced when they start.
+      if (msg.enhanced_prompt === null) return this
+      this.userDict[msg.id].enhancedPrompt = msg.enhanced_prompt
+      return this.shallowCopy()
+    }
     const userMsg: UserMessage = {
       type: 'user',
       id: msg.id,
-      text: msg.prompt
+      text: msg.prompt,
+      enhancedPrompt: msg.enhanced_prompt ?? undefined
     }
     this.userDict[msg.id] = userMsg
     this.list.push(userMsg)
     return this.shallowCopy()
   }
 
+  audioGenerationQueued (msg: AudioGenerationQueued) {
+    if (msg.chat_id != this.chatId) return this
+    if (!(msg.id in this.userDict)) {
+      const userMsg: UserMessage = {
+        type: 'user',
+        id: msg.id,
+        text: msg.prompt
+      }
+      this.userDict[msg.id] = userMsg
+      this.list.push(userMsg)
+    }
+    if (!(msg.id in this.aiDict)) {
+      const aiMsg: AiMessage = {
+        type: "ai",
+        id: msg.id,
+        progress: 0,
+        justSucceeded: false
+      }
+      this.aiDict[msg.id] = aiMsg
+      this.list.push(aiMsg)
+    }
+    this.aiDict[msg.id].queuePosition = msg.position
+    this.aiDict[msg.id].etaSecs = msg.eta_secs ?? undefined
+    return this.shallowCopy()
+  }
+
   audioGenerationProgress (msg: AudioGenerationProgress) {
     if (msg.chat_id != this.chatId) return this
     if (msg.id in this.aiDict) {
       this.aiDict[msg.id].progress = msg.progress
+      this.aiDict[msg.id].stage = msg.stage
+      this.aiDict[msg.id].queuePosition = undefined
+      this.aiDict[msg.id].etaSecs = undefined
       return this.shallowCopy()
     }
     const aiMsg: AiMessage = {
       type: "ai",
       id: msg.id,
       progress: msg.progress,
+      stage: msg.stage,
       justSucceeded: false
     }
     this.aiDict[msg.id] = aiMsg
@@ -143,6 +195,7 @@ class ChatHistory {
       this.aiDict[msg.id].progress = 1
       if ('relpath' in msg) {
         this.aiDict[msg.id].url = relpathToUrl(msg.relpath)
+        this.aiDict[msg.id].stats = msg.stats
       } else if ('error' in msg) {
         this.aiDict[msg.id].error = msg.error
       }
@@ -155,6 +208,7 @@ class ChatHistory {
       progress: 1,
       url: 'relpath' in msg ? relpathToUrl(msg.relpath) : undefined,
       error: 'error' in msg ? msg.error : undefined,
+      stats: 'stats' in msg ? msg.stats : undefined,
       justSucceeded: false
     }
     this.aiDict[msg.id] = aiMsg
@@ -174,6 +228,7 @@ class ChatHistory {
           type: 'user',
           id: entry.User.id,
           text: entry.User.text,
+          enhancedPrompt: entry.User.enhanced_prompt ?? undefined
         }
         chatHistory.list.push(msg)
         chatHistory.userDict[msg.id] = msg
@@ -186,6 +241,7 @@ class ChatHistory {
         }
         if (entry.Ai.relpath) msg.url = relpathToUrl(entry.Ai.relpath)
         if (entry.Ai.error) msg.error = entry.Ai.error
+        if (entry.Ai.stats) msg.stats = entry.Ai.stats
         chatHistory.list.push(msg)
         chatHistory.aiDict[msg.id] = msg
       }
@@ -1,10 +1,11 @@
 import { useCallback, useEffect, useState } from "react";
 
 import { useBackend } from "./useBackend.ts";
-import { Chat } from "./bindings.ts";
+import { Chat, ChatsCursor } from "./bindings.ts";
 
 export function useChats () {
   const [chats, setChats] = useState<Chat[]>([]);
+  const [nextCursor, setNextCursor] = useState<ChatsCursor | null>(null);
 
   const { send, last } = useBackend();
 
@@ -12,7 +13,10 @@ export function useChats () {
     if (last == null) {
       // do nothing
     } else if ("Chats" in last) {
-      setChats(last.Chats);
+      const page = last.Chats
+      // The first page replaces the list, the following ones are appended to it.
+      setChats(prev => page.cursor === null ? page.chats : [...prev, ...page.chats]);
+      setNextCursor(page.next_cursor);
     }
   }, [last]);
 
@@ -32,5 +36,13 @@ export function useChats () {
     [send]
   );
 
-  return { chats, setChatMetadata, deleteChat };
+  const loadMoreChats = useCallback(
+    () => {
+      if (nextCursor === null) return;
+      send({ GetChats: { cursor: nextCursor, limit: null } });
+    },
+    [send, nextCursor]
+  );
+
+  return { chats, setChatMetadata, deleteChat, loadMoreChats, hasMoreChats: nextCursor !== null };
 }
@@ -0,0 +1,59 @@
+import useWebSocket from "react-use-websocket";
+import { useCallback, useEffect, useRef } from "react";
+import { WS_URL } from "./useBackend.ts";
+
+const HEADER_LEN = 40
+
+function uuidFromBytes (bytes: Uint8Array): string {
+  const hex = Array.from(bytes, b => b.toString(16).padStart(2, '0')).join('')
+  return `${hex.slice(0, 8)}-${hex.slice(8, 12)}-${hex.slice(12, 16)}-${hex.slice(16, 20)}-${hex.slice(20)}`
+}
+
+/**
+ * Plays the audios of the given chat while they are being generated, if the
+ * server was started with --ui-stream-audio.
+ */
+export function usePartialAudio (chatId?: string) {
+  const ctxRef = useRef<AudioContext>()
+  // Time in the AudioContext at which the next samples of each audio should play.
+  const nextStartRef = useRef<Record<string, number>>({})
+  const chatIdRef = useRef(chatId)
+  useEffect(() => {
+    chatIdRef.current = chatId
+  }, [chatId])
+
+  const play = useCallback(async (data: Blob) => {
+    const buffer = await data.arrayBuffer()
+    const view = new DataView(buffer)
+    const id = uuidFromBytes(new Uint8Array(buffer, 0, 16))
+    if (uuidFromBytes(new Uint8Array(buffer, 16, 16)) !== chatIdRef.current) return
+    const samplingRate = view.getUint32(32, true)
+    const offset = view.getUint32(36, true)
+    const samples = new Float32Array((buffer.byteLength - HEADER_LEN) / 4)
+    for (let i = 0; i < samples.length; i++) {
+      samples[i] = view.getFloat32(HEADER_LEN + i * 4, true)
+    }
+    if (samples.length === 0) return
+
+    ctxRef.current ??= new AudioContext()
+    const ctx = ctxRef.current
+    const audioBuffer = ctx.createBuffer(1, samples.length, samplingRate)
+    audioBuffer.copyToChannel(samples, 0)
+    const source = ctx.createBufferSource()
+    source.buffer = audioBuffer
+    source.connect(ctx.destination)
+
+    if (offset === 0) nextStartRef.current[id] = ctx.currentTime
+    const start = Math.max(nextStartRef.current[id] ?? ctx.currentTime, ctx.currentTime)
+    source.start(start)
+    nextStartRef.current[id] = start + audioBuffer.duration
+  }, [])
+
+  useWebSocket(WS_URL, {
+    share: true,
+    filter: () => false,
+    onMessage: msg => {
+      if (msg.data instanceof Blob) play(msg.data)
+    }
+  })
+}
@@ -1,18 +1,33 @@
 import React from 'react';
 import { LoadingIcon } from "../Icons/LoadingIcon.tsx";
+import { GenerationStage } from "../backend/bindings.ts";
 
 interface GeneratingAudioProps {
   className?: string;
   progress: number;
+  stage?: GenerationStage;
+  queuePosition?: number;
+  etaSecs?: number;
 }
 
-const AudioGenerating: React.FC<GeneratingAudioProps> = ({ className = '', progress }) => {
+const AudioGenerating: React.FC<GeneratingAudioProps> = ({ className = '', progress, stage, queuePosition, etaSecs }) => {
   const percentProgress = Math.round(progress * 100)
+  let status = 'Generating audio response...'
+  if (stage === 'TextEncoding') {
+    status = 'Encoding the prompt...'
+  } else if (stage !== undefined && 'EncodecDecoding' in stage) {
+    status = 'Decoding the audio...'
+  }
+  if (queuePosition !== undefined) {
+    status = `Waiting in queue, position ${queuePosition}`
+    if (etaSecs !== undefined) status += ` (ready in ~${Math.ceil(etaSecs)}s)`
+    status += '...'
+  }
   return (
     <div className={`space-y-2 ${className}`}>
       <div className="flex items-center space-x-2 text-[var(--text-faded-color)]">
         <LoadingIcon/>
-        <span>Generating audio response...</span>
+        <span>{status}</span>
       </div>
       <div className="w-full bg-gray-200 rounded-full h-2">
         <div
@@ -1,27 +1,45 @@
 import H5AudioPlayer from "react-h5-audio-player";
 import './AudioSucess.css'
 import { DownloadIcon } from "../Icons/DownloadIcon.tsx";
+import { GenerationStats } from "../backend/bindings.ts";
 
+export interface AudioSuccessProps {
+  stats?: GenerationStats
+  onReveal?: () => void
+}
+
+function formatStats (stats: GenerationStats): string {
+  const parts = [`${stats.wall_secs.toFixed(1)}s on ${stats.device}`]
+  if (stats.tokens_per_sec !== null) parts.push(`${stats.tokens_per_sec.toFixed(1)} tokens/s`)
+  if (stats.peak_rss_bytes !== null) parts.push(`${(stats.peak_rss_bytes / 1e9).toFixed(1)} GB peak memory`)
+  return parts.join(' · ')
+}
 
-export function AudioSuccess ({ className = '', src, ...rest }: typeof H5AudioPlayer.defaultProps) {
+export function AudioSuccess ({ className = '', src, stats, onReveal, ...rest }: typeof H5AudioPlayer.defaultProps & AudioSuccessProps) {
   return (
-    <div className={`relative w-96 ${className}`}>
-      <H5AudioPlayer
-        className={`rounded-b-lg rounded-tr-lg bg-[var(--card-background-color)]`}
-        style={{ boxShadow: 'none' }}
-        autoPlay={true}
-        src={src}
-        {...rest}
-      >
-      </H5AudioPlayer>
-      <a
-        className="absolute top-[53%] left-[14%] text-[var(--text-faded-color)] hover:shadow-sm h-fit hover:opacity-75"
-        href={src}
-        download
-        target="_blank"
-      >
-        <DownloadIcon className={'hover:font-bold'}/>
-      </a>
+    <div className={`w-96 ${className}`}>
+      <div className="relative">
+        <H5AudioPlayer
+          className={`rounded-b-lg rounded-tr-lg bg-[var(--card-background-color)]`}
+          style={{ boxShadow: 'none' }}
+          autoPlay={true}
+          src={src}
+          {...rest}
+        >
+        </H5AudioPlayer>
+        <a
+          className="absolute top-[53%] left-[14%] text-[var(--text-faded-color)] hover:shadow-sm h-fit hover:opacity-75"
+          href={src}
+          download
+          target="_blank"
+        >
+          <DownloadIcon className={'hover:font-bold'}/>
+        </a>
+      </div>
+      <div className="mt-1 flex gap-2 text-xs text-[var(--text-faded-color)]">
+        {stats && <p>{formatStats(stats)}</p>}
+        {onReveal && <button className="ml-auto hover:opacity-75" onClick={onReveal}>Show in folder</button>}
+      </div>
     </div>
   )
 }
@@ -15,9 +15,10 @@ export interface ChatDrawerProps {
   entries: ResponsiveDrawerEntry[];
   selectedEntry?: string;
   onSelectEntry: (chatId?: string) => void;
+  onLoadMore?: () => void;
 }
 
-const ResponsiveDrawer: React.FC<ChatDrawerProps> = ({ open, setOpen, entries, selectedEntry, onSelectEntry }) => {
+const ResponsiveDrawer: React.FC<ChatDrawerProps> = ({ open, setOpen, entries, selectedEntry, onSelectEntry, onLoadMore }) => {
   function handleSelectEntry () {
     onSelectEntry(undefined)
     setOpen(false)
@@ -53,6 +54,11 @@ const ResponsiveDrawer: React.FC<ChatDrawerProps> = ({ open, setOpen, entries, s
             </li>
           ))}
         </ul>
+        {onLoadMore !== undefined && (
+          <button className="new-chat-button" onClick={onLoadMore}>
+            Load more
+          </button>
+        )}
       </div>
     </>
   );
@@ -2,12 +2,14 @@ import React, { HTMLProps } from 'react';
 
 interface UserQuestionProps extends HTMLProps<HTMLDivElement> {
   text: string;
+  enhancedPrompt?: string;
 }
 
-const UserQuestion: React.FC<UserQuestionProps> = ({ text, className = '' }) => {
+const UserQuestion: React.FC<UserQuestionProps> = ({ text, enhancedPrompt, className = '' }) => {
   return (
     <div className={`p-4 rounded-b-lg rounded-tl-lg bg-[var(--card-background-color)] ${className}`}>
       <p>{text}</p>
+      {enhancedPrompt && <p className="mt-2 text-sm opacity-60">{enhancedPrompt}</p>}
     </div>
   );
 };